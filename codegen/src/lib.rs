@@ -5,75 +5,133 @@ use convert_case::{Case, Casing};
 use proc_macro2::{Ident, Span};
 use quote::{format_ident, quote, quote_spanned};
 use syn::{
-    parse_macro_input, parse_quote, spanned::Spanned, Attribute, FnArg, GenericArgument, ItemFn,
-    ItemStruct, Lit, LitBool, LitStr, Meta, Pat, PathArguments, ReturnType, Signature, Type,
-    TypePath, Visibility,
+    parse_macro_input, parse_quote, punctuated::Punctuated, spanned::Spanned, Attribute,
+    AttributeArgs, FnArg, GenericArgument, GenericParam, Generics, ItemFn, ItemStruct, Lit,
+    LitBool, LitStr, Meta, NestedMeta, Pat, PathArguments, ReturnType, Signature, Token, Type,
+    TypePath, TypeReference, Visibility,
 };
 
-use generator::parse_config;
+use generator::{parse_config, string_to_u32};
 
 #[derive(thiserror::Error, Debug)]
 enum Error {
     #[error("duplicate output type found")]
-    DuplicateOutputType,
+    DuplicateOutputType(Span),
     #[error("duplicate component type found")]
-    DuplicateComponentType,
+    DuplicateComponentType(Span),
     #[error("duplicate resource type found")]
-    DuplicateResourceType,
+    DuplicateResourceType(Span),
     #[error("duplicate state type found")]
-    DuplicateStateType,
+    DuplicateStateType(Span),
     #[error("duplicate storage type found")]
-    DuplicateStorageType,
+    DuplicateStorageType(Span),
     #[error("WriteStorage should not intersect Component")]
-    WriteStorageFoundInComponents,
+    WriteStorageFoundInComponents(Span),
     #[error("ReadStorage should not intersect with mutable Component")]
-    ReadStorageFoundInMutableComponents,
+    ReadStorageFoundInMutableComponents(Span),
     #[error("invalid key")]
     InvalidKey(Span),
+    #[error("invalid attribute value, integer or string expected")]
+    InvalidAttributeValue(Span),
+    #[error("cooldown_ms is only allowed in system with input")]
+    CooldownWithoutInput,
     #[error("invalid meta found in dynamic")]
     InvalidMetaForDynamic(Span),
     #[error("duplicate dynamic library name")]
-    DuplicateDynamicLibraryName,
+    DuplicateDynamicLibraryName(Span),
     #[error("duplicate dynamic function name")]
-    DuplicateDynamicFunctionName,
+    DuplicateDynamicFunctionName(Span),
     #[error("method not found for system, use function instead")]
-    SelfNotAllowed,
+    SelfNotAllowed(Span),
     #[error("system function parameters must be component references, state references or resource references")]
     InvalidArgument(Span),
     #[error(
         "system function parameters must be one of input, component, state and resource, no more no less"
     )]
-    ConflictParameterAttribute,
-    #[error("only one input allowed in system")]
-    MultipleInputFound,
+    ConflictParameterAttribute(Span),
     #[error("#[dynamic(\"lib\", \"func\")] is not allowed, use #[dynamic(lib = \"lib\", func = \"func\")] instead")]
     LiteralFoundInDynamicAttribute(Span),
     #[error("Entity type cannot be mutable, remove &mut")]
     EntityCantBeMutable(Span),
+    #[error("LazyUpdate type cannot be mutable, remove &mut")]
+    LazyUpdateCantBeMutable(Span),
     #[error("GameReadStorage type cannot be mutable, remove &mut")]
     ReadStorageCantBeMutable(Span),
     #[error("GameWriteStorage type must be mutable, add &mut")]
     WriteStorageIsNotMutable(Span),
     #[error(
-        "invalid return type only Option<Component> or tuple of Option<Component> is accepted"
+        "invalid return type only Option<Component>, Vec<(Entity, Component)>, tuple of them or Result of them is accepted"
     )]
     InvalidReturnType(Span),
     #[error("invalid storage type, use GameReadStorage<T> or GameWriteStorage<T>")]
     InvalidStorageType(Span),
+    #[error("double system needs the same component and entity parameters twice, one group for each entity")]
+    InvalidDoubleParameters,
+    #[error(
+        "double system does not support Option outputs, mutable or optional components or slice_ms"
+    )]
+    UnsupportedInDouble,
+    #[error("batch parameter must be a slice of tuple like &[(&A, &mut B, Entity)]")]
+    InvalidBatchType(Span),
+    #[error("multiple system needs exactly one batch parameter and no component or entity parameters outside it")]
+    InvalidMultipleParameters,
+    #[error("multiple system does not support Option outputs or slice_ms")]
+    UnsupportedInMultiple,
+    #[error("parallel system does not support slice_ms or mutable state, resource and storage parameters")]
+    UnsupportedInParallel,
+    #[error("generic system must be static with #[dynamic(false)], without request input and lifetime parameters")]
+    InvalidGenericSystem,
+    #[error("async system must be static with #[dynamic(false)], take only immutable component, Option<&Component> and Entity parameters, and return no Result")]
+    InvalidAsyncSystem,
+    #[error("close_on_error needs a Result return type in a single or parallel system")]
+    InvalidCloseOnError,
+    #[error("changed is not supported with slice_ms")]
+    ChangedWithSlice,
+    #[error("invalid filter, use #[with(A, B)] or #[without(A, B)] with component types")]
+    InvalidFilter(Span),
+    #[error("filter component should not be used as parameter or output")]
+    FilterFoundInComponents(Span),
+    #[error("Option<&Component> needs at least one required component or entity to join with")]
+    OptionalWithoutRequired,
+    #[error("no_register type is not used as component or output in this system")]
+    InvalidNoRegister(Span),
+    #[error("setup_hook expects a function path string like setup_hook = \"module::hook\"")]
+    InvalidSetupHook(Span),
+    #[error("#[input] only applies to request component references like &Request or &mut Request")]
+    InvalidInput(Span),
 }
 
 impl Error {
     fn span(&self) -> Span {
         match self {
+            Error::DuplicateOutputType(span)
+            | Error::DuplicateComponentType(span)
+            | Error::DuplicateResourceType(span)
+            | Error::DuplicateStateType(span)
+            | Error::DuplicateStorageType(span)
+            | Error::WriteStorageFoundInComponents(span)
+            | Error::ReadStorageFoundInMutableComponents(span)
+            | Error::DuplicateDynamicLibraryName(span)
+            | Error::DuplicateDynamicFunctionName(span)
+            | Error::SelfNotAllowed(span)
+            | Error::ConflictParameterAttribute(span)
+            | Error::FilterFoundInComponents(span) => *span,
             Error::InvalidKey(span) => *span,
+            Error::InvalidAttributeValue(span) => *span,
             Error::InvalidMetaForDynamic(span) => *span,
             Error::InvalidArgument(span) => *span,
             Error::LiteralFoundInDynamicAttribute(span) => *span,
             Error::EntityCantBeMutable(span) => *span,
+            Error::LazyUpdateCantBeMutable(span) => *span,
             Error::ReadStorageCantBeMutable(span) => *span,
             Error::WriteStorageIsNotMutable(span) => *span,
             Error::InvalidReturnType(span) => *span,
             Error::InvalidStorageType(span) => *span,
+            Error::InvalidBatchType(span) => *span,
+            Error::InvalidFilter(span) => *span,
+            Error::InvalidInput(span) => *span,
+            Error::InvalidNoRegister(span) => *span,
+            Error::InvalidSetupHook(span) => *span,
             _ => Span::call_site(),
         }
     }
@@ -84,28 +142,139 @@ impl Error {
     }
 }
 
+#[derive(PartialEq)]
+enum SystemType {
+    /// 逐个实体调用
+    Single,
+    /// 对匹配实体两两调用
+    Double,
+    /// 每帧以所有匹配实体的切片调用一次
+    Multiple,
+    /// 使用par_join并行逐个实体调用
+    Parallel,
+    /// 复制匹配实体的组件交给线程池处理，之后的帧收集结果
+    Async,
+}
+
+impl Default for SystemType {
+    fn default() -> Self {
+        SystemType::Single
+    }
+}
+
 #[derive(Default)]
 struct SystemAttr {
     system_name: Option<Ident>,
+    system_type: SystemType,
+    /// 输入请求的冷却时间，单位毫秒
+    cooldown_ms: Option<u64>,
+    /// 每帧的处理时间预算，单位毫秒，超出后下一帧继续
+    slice_ms: Option<u64>,
+    /// 需要在此系统之前执行的系统名称
+    deps: Vec<LitStr>,
+    /// 只处理这些组件发生变化（插入或修改）的实体，任意一个变化即可
+    changed: Vec<Type>,
+    /// 注册后添加屏障，之后注册的系统在此系统完成后才执行
+    barrier: bool,
+    /// 函数返回错误时关闭当前实体的连接
+    close_on_error: bool,
+    /// 不在setup中注册的组件，由使用者自行注册
+    no_register: Vec<Type>,
+    /// setup中注册组件之后调用的函数，签名为fn(&mut World)
+    setup_hook: Option<syn::Path>,
 }
 
 impl SystemAttr {
-    fn new(system_name: Option<Ident>) -> Self {
-        Self { system_name }
+    fn parse_args(args: &AttributeArgs) -> Result<Self, Error> {
+        let mut attr = Self::default();
+        for arg in args {
+            match arg {
+                NestedMeta::Meta(meta) => attr.parse_meta(meta)?,
+                NestedMeta::Lit(lit) => return Err(Error::InvalidKey(lit.span())),
+            }
+        }
+        Ok(attr)
     }
 
-    fn parse_meta(meta: &Meta) -> Result<Self, Error> {
-        let result = match meta {
-            Meta::Path(path) => {
-                if let Some(ident) = path.get_ident() {
-                    Self::new(Some(ident.clone()))
-                } else {
-                    Self::new(None)
+    fn parse_meta(&mut self, meta: &Meta) -> Result<(), Error> {
+        match meta {
+            Meta::Path(path) => match path.get_ident() {
+                Some(ident) if ident == "double" => self.system_type = SystemType::Double,
+                Some(ident) if ident == "multiple" => self.system_type = SystemType::Multiple,
+                Some(ident) if ident == "parallel" => self.system_type = SystemType::Parallel,
+                Some(ident) if ident == "async" => self.system_type = SystemType::Async,
+                Some(ident) if ident == "barrier" => self.barrier = true,
+                Some(ident) if ident == "close_on_error" => self.close_on_error = true,
+                Some(ident) => self.system_name = Some(ident.clone()),
+                None => {}
+            },
+            Meta::NameValue(name_value) => match name_value.path.get_ident() {
+                Some(ident) if ident == "cooldown_ms" => {
+                    self.cooldown_ms = Some(Self::parse_u64(&name_value.lit)?);
                 }
-            }
-            _ => Self::new(None),
-        };
-        Ok(result)
+                Some(ident) if ident == "slice_ms" => {
+                    self.slice_ms = Some(Self::parse_u64(&name_value.lit)?);
+                }
+                Some(ident) if ident == "setup_hook" => match &name_value.lit {
+                    Lit::Str(hook) => {
+                        let hook = hook
+                            .parse()
+                            .map_err(|_| Error::InvalidSetupHook(hook.span()))?;
+                        self.setup_hook = Some(hook);
+                    }
+                    lit => return Err(Error::InvalidSetupHook(lit.span())),
+                },
+                Some(ident) => return Err(Error::InvalidKey(ident.span())),
+                None => return Err(Error::InvalidKey(name_value.span())),
+            },
+            Meta::List(list) => match list.path.get_ident() {
+                Some(ident) if ident == "deps" => {
+                    for item in &list.nested {
+                        match item {
+                            NestedMeta::Lit(Lit::Str(dep)) => self.deps.push(dep.clone()),
+                            _ => return Err(Error::InvalidAttributeValue(item.span())),
+                        }
+                    }
+                }
+                Some(ident) if ident == "changed" => {
+                    for item in &list.nested {
+                        match item {
+                            NestedMeta::Meta(Meta::Path(path)) => {
+                                self.changed.push(Type::Path(TypePath {
+                                    qself: None,
+                                    path: path.clone(),
+                                }))
+                            }
+                            _ => return Err(Error::InvalidKey(item.span())),
+                        }
+                    }
+                }
+                Some(ident) if ident == "no_register" => {
+                    for item in &list.nested {
+                        match item {
+                            NestedMeta::Meta(Meta::Path(path)) => {
+                                self.no_register.push(Type::Path(TypePath {
+                                    qself: None,
+                                    path: path.clone(),
+                                }))
+                            }
+                            _ => return Err(Error::InvalidKey(item.span())),
+                        }
+                    }
+                }
+                _ => return Err(Error::InvalidKey(list.span())),
+            },
+        }
+        Ok(())
+    }
+
+    fn parse_u64(lit: &Lit) -> Result<u64, Error> {
+        if let Lit::Int(int) = lit {
+            int.base10_parse()
+                .map_err(|_| Error::InvalidAttributeValue(lit.span()))
+        } else {
+            Err(Error::InvalidAttributeValue(lit.span()))
+        }
     }
 }
 
@@ -115,22 +284,20 @@ struct Config {
     lib_name: Option<Lit>,
     func_name: Option<Lit>,
     signature: Sig,
+    /// 实体必须拥有的组件，不会传入函数
+    with: Vec<Type>,
+    /// 实体必须没有的组件
+    without: Vec<Type>,
 }
 
-fn contains_duplicate(data: &Vec<Type>) -> bool {
-    if data.len() < 2 {
-        false
-    } else {
-        for i in 0..data.len() {
-            let t1 = &data[i];
-            for j in (i + 1)..data.len() {
-                if t1 == &data[j] {
-                    return true;
-                }
-            }
+/// 返回第一个重复类型的位置，用于错误提示
+fn find_duplicate(data: &[Type]) -> Option<Span> {
+    for i in 1..data.len() {
+        if data[..i].contains(&data[i]) {
+            return Some(data[i].span());
         }
-        false
     }
+    None
 }
 
 impl Config {
@@ -139,37 +306,52 @@ impl Config {
         let mut dynamic = true;
         let mut lib_name = None;
         let mut func_name = None;
+        let mut with = Vec::new();
+        let mut without = Vec::new();
         for (i, attribute) in item.attrs.iter().enumerate() {
             if let Some(ident) = attribute.path.get_ident() {
-                if ident == "dynamic" {
+                if ident == "with" || ident == "without" {
+                    to_remove.push(i);
+                    let types = attribute
+                        .parse_args_with(Punctuated::<Type, Token![,]>::parse_terminated)
+                        .map_err(|_err| Error::InvalidFilter(attribute.span()))?;
+                    if ident == "with" {
+                        with.extend(types);
+                    } else {
+                        without.extend(types);
+                    }
+                } else if ident == "dynamic" {
                     to_remove.push(i);
                     let meta = attribute
                         .parse_meta()
                         .map_err(|_err| Error::InvalidMetaForDynamic(ident.span()))?;
                     let (l, f) = Self::parse_dynamic_meta(&meta)?;
                     if let Some(l) = l {
-                        if let Lit::Bool(_) = l {
-                            dynamic = false;
+                        if let Lit::Bool(b) = l {
+                            dynamic = b.value;
                         } else {
-                            if lib_name.replace(l).is_some() {
-                                return Err(Error::DuplicateDynamicLibraryName);
+                            if let Some(l) = lib_name.replace(l) {
+                                return Err(Error::DuplicateDynamicLibraryName(l.span()));
                             }
                         }
                     }
                     if let Some(f) = f {
-                        if func_name.replace(f).is_some() {
-                            return Err(Error::DuplicateDynamicFunctionName);
+                        if let Some(f) = func_name.replace(f) {
+                            return Err(Error::DuplicateDynamicFunctionName(f.span()));
                         }
                     }
                 }
             }
         }
 
-        for i in to_remove {
+        for i in to_remove.into_iter().rev() {
             item.attrs.remove(i);
         }
 
         let mut signature = Sig::parse(&mut item.sig)?;
+        if attr.system_type == SystemType::Double {
+            signature.split_pairs()?;
+        }
         signature.generate_output_names();
 
         Ok(Self {
@@ -178,6 +360,8 @@ impl Config {
             lib_name,
             func_name,
             signature,
+            with,
+            without,
         })
     }
 
@@ -205,18 +389,19 @@ impl Config {
                 for item in &items.nested {
                     let (l, f) = match item {
                         syn::NestedMeta::Meta(meta) => Self::parse_dynamic_meta(meta)?,
+                        syn::NestedMeta::Lit(lit @ Lit::Bool(_)) => (Some(lit.clone()), None),
                         syn::NestedMeta::Lit(_) => {
                             return Err(Error::LiteralFoundInDynamicAttribute(meta.span()));
                         }
                     };
                     if let Some(l) = l {
-                        if lib_name.replace(l).is_some() {
-                            return Err(Error::DuplicateDynamicLibraryName);
+                        if let Some(l) = lib_name.replace(l) {
+                            return Err(Error::DuplicateDynamicLibraryName(l.span()));
                         }
                     }
                     if let Some(f) = f {
-                        if func_name.replace(f).is_some() {
-                            return Err(Error::DuplicateDynamicFunctionName);
+                        if let Some(f) = func_name.replace(f) {
+                            return Err(Error::DuplicateDynamicFunctionName(f.span()));
                         }
                     }
                 }
@@ -232,26 +417,132 @@ impl Config {
         Ok(result)
     }
 
+    /// 是否逐个实体调用函数
+    fn per_entity(&self) -> bool {
+        self.attr.system_type == SystemType::Single || self.attr.system_type == SystemType::Parallel
+    }
+
+    /// 调用函数时是否需要当前实体
+    fn needs_entity(&self) -> bool {
+        self.signature.has_entity_outputs()
+            || (self.signature.error.is_some() && self.per_entity())
+            || self.attr.system_type == SystemType::Async
+    }
+
     fn validate(&self) -> Result<(), Error> {
-        if contains_duplicate(&self.signature.outputs) {
-            return Err(Error::DuplicateOutputType);
+        if self.attr.system_type == SystemType::Double
+            && (self.signature.has_entity_outputs()
+                || self.attr.slice_ms.is_some()
+                || self.signature.parameters.iter().any(|param| match param {
+                    Parameter::Component(_, _, mutable) => *mutable,
+                    Parameter::Optional(..) => true,
+                    _ => false,
+                }))
+        {
+            return Err(Error::UnsupportedInDouble);
+        }
+        if self.attr.system_type == SystemType::Parallel
+            && (self.attr.slice_ms.is_some()
+                || self.signature.parameters.iter().any(|param| match param {
+                    Parameter::Resource(_, _, mutable, _)
+                    | Parameter::State(_, _, mutable)
+                    | Parameter::Storage(_, _, mutable) => *mutable,
+                    _ => false,
+                }))
+        {
+            return Err(Error::UnsupportedInParallel);
+        }
+        // 动态库可能在任务执行期间重新加载，因此只支持静态函数
+        if self.attr.system_type == SystemType::Async
+            && (self.dynamic
+                || self.signature.error.is_some()
+                || self.attr.slice_ms.is_some()
+                || !self.signature.generics.params.is_empty()
+                || self.signature.parameters.iter().any(|param| match param {
+                    Parameter::Component(_, _, mutable) | Parameter::Optional(_, _, mutable) => {
+                        *mutable
+                    }
+                    Parameter::Entity => false,
+                    _ => true,
+                }))
+        {
+            return Err(Error::InvalidAsyncSystem);
+        }
+        if self.attr.system_type == SystemType::Multiple {
+            if self.signature.has_entity_outputs() || self.attr.slice_ms.is_some() {
+                return Err(Error::UnsupportedInMultiple);
+            }
+            let (start, count, _) = self
+                .signature
+                .batch
+                .ok_or(Error::InvalidMultipleParameters)?;
+            if self
+                .signature
+                .parameters
+                .iter()
+                .enumerate()
+                .any(|(position, param)| match param {
+                    Parameter::Component(..) | Parameter::Optional(..) | Parameter::Entity => {
+                        position < start || position >= start + count
+                    }
+                    _ => false,
+                })
+            {
+                return Err(Error::InvalidMultipleParameters);
+            }
+        } else if self.signature.batch.is_some() {
+            return Err(Error::InvalidMultipleParameters);
+        }
+        if self.signature.parameters.iter().any(|param| match param {
+            Parameter::Optional(..) => true,
+            _ => false,
+        }) && self.signature.outputs.is_empty()
+            && self.with.is_empty()
+            && self.attr.slice_ms.is_none()
+            && !self.signature.parameters.iter().any(|param| match param {
+                Parameter::Component(..) | Parameter::Entity => true,
+                _ => false,
+            })
+        {
+            return Err(Error::OptionalWithoutRequired);
+        }
+        if self.attr.close_on_error && (self.signature.error.is_none() || !self.per_entity()) {
+            return Err(Error::InvalidCloseOnError);
+        }
+        if !self.attr.changed.is_empty() && self.attr.slice_ms.is_some() {
+            return Err(Error::ChangedWithSlice);
+        }
+        let mut filters = self.with.clone();
+        filters.extend(self.without.clone().into_iter());
+        if let Some(span) = find_duplicate(&filters) {
+            return Err(Error::DuplicateComponentType(span));
         }
-        if contains_duplicate(&self.signature.component_args) {
-            return Err(Error::DuplicateComponentType);
+        if let Some(ty) = filters.iter().find(|ty| {
+            self.signature.component_args.contains(ty)
+                || self.signature.storage_args.contains(ty)
+                || self.signature.outputs.contains(ty)
+        }) {
+            return Err(Error::FilterFoundInComponents(ty.span()));
         }
-        if contains_duplicate(&self.signature.resource_args) {
-            return Err(Error::DuplicateResourceType);
+        if let Some(span) = find_duplicate(&self.signature.outputs) {
+            return Err(Error::DuplicateOutputType(span));
         }
-        if contains_duplicate(&self.signature.state_args) {
-            return Err(Error::DuplicateStateType);
+        if let Some(span) = find_duplicate(&self.signature.component_args) {
+            return Err(Error::DuplicateComponentType(span));
         }
-        if contains_duplicate(&self.signature.storage_args) {
-            return Err(Error::DuplicateStorageType);
+        if let Some(span) = find_duplicate(&self.signature.resource_args) {
+            return Err(Error::DuplicateResourceType(span));
+        }
+        if let Some(span) = find_duplicate(&self.signature.state_args) {
+            return Err(Error::DuplicateStateType(span));
+        }
+        if let Some(span) = find_duplicate(&self.signature.storage_args) {
+            return Err(Error::DuplicateStorageType(span));
         }
         let mut components = self.signature.component_args.clone();
         components.extend(self.signature.outputs.clone().into_iter());
-        if contains_duplicate(&components) {
-            return Err(Error::DuplicateComponentType);
+        if let Some(span) = find_duplicate(&components) {
+            return Err(Error::DuplicateComponentType(span));
         }
         let mut components = self.signature.component_args.clone();
         components.extend(self.signature.parameters.iter().filter_map(|param| {
@@ -262,20 +553,23 @@ impl Config {
             }
             None
         }));
-        if contains_duplicate(&components) {
-            return Err(Error::WriteStorageFoundInComponents);
+        if let Some(span) = find_duplicate(&components) {
+            return Err(Error::WriteStorageFoundInComponents(span));
         }
         let mut components = self.signature.storage_args.clone();
-        components.extend(self.signature.parameters.iter().filter_map(|param| {
-            if let Parameter::Component(_, index, mutable) = param {
-                if *mutable {
-                    return Some(self.signature.component_args[*index].clone());
-                }
-            }
-            None
-        }));
-        if contains_duplicate(&components) {
-            return Err(Error::ReadStorageFoundInMutableComponents);
+        components.extend(
+            self.signature
+                .parameters
+                .iter()
+                .filter_map(|param| match param {
+                    Parameter::Component(_, index, true) | Parameter::Optional(_, index, true) => {
+                        Some(self.signature.component_args[*index].clone())
+                    }
+                    _ => None,
+                }),
+        );
+        if let Some(span) = find_duplicate(&components) {
+            return Err(Error::ReadStorageFoundInMutableComponents(span));
         }
         Ok(())
     }
@@ -291,7 +585,22 @@ impl Config {
                 self.signature.ident.to_string().to_case(Case::UpperCamel)
             )
         };
-        add_system(system_name.to_string());
+        let generic = !self.signature.generics.params.is_empty();
+        if generic
+            && (self.dynamic
+                || self
+                    .signature
+                    .generics
+                    .params
+                    .iter()
+                    .any(|param| matches!(param, GenericParam::Lifetime(_))))
+        {
+            return Err(Error::InvalidGenericSystem);
+        }
+        // 泛型系统需要指定类型后手动调用setup
+        if !generic {
+            add_system(system_name.to_string());
+        }
         let system_fn = format_ident!("{}Fn", system_name);
 
         let lib_name = if let Some(lib_name) = &self.lib_name {
@@ -312,36 +621,68 @@ impl Config {
             ))
         };
 
-        let mut system_sname =
-            self.signature
-                .parameters
-                .iter()
-                .fold(Ok(String::new()), |name, param| {
-                    if let Parameter::Component(_, index, _) = param {
-                        let type_name = type_to_string(&self.signature.component_args[*index]);
-                        if is_input_string(&type_name) {
-                            let name = name?;
-                            return if name.is_empty() {
-                                Ok(type_name)
-                            } else {
-                                Err(Error::MultipleInputFound)
-                            };
-                        }
+        let inputs: Vec<_> = self
+            .signature
+            .parameters
+            .iter()
+            .filter_map(|param| match param {
+                Parameter::Component(_, index, _) | Parameter::Optional(_, index, _) => {
+                    let name = type_to_string(&self.signature.component_args[*index]);
+                    // 请求在其它crate生成时NAMES中没有记录，需要#[input]显式标记
+                    if self.signature.input_args.contains(index) || is_input_string(&name) {
+                        Some(name)
+                    } else {
+                        None
                     }
-                    name
-                })?;
-        let mut system_deps = quote!(&[]);
-        if system_sname.is_empty() {
-            system_sname = quote!(#system_name).to_string();
-        } else {
-            let name = system_sname.to_case(Case::Snake);
-            let dep = format!("{}_input", name);
-            system_sname = format!("{}_exec", name);
-            system_deps = quote!(&[#dep]);
+                }
+                _ => None,
+            })
+            .collect();
+        let mut system_deps: Vec<_> = self.attr.deps.iter().map(|dep| quote!(#dep)).collect();
+        let mut cooldown_setup = Vec::new();
+        // 其它输入的清理系统依赖`{input}_exec`，以空系统占位
+        let mut alias_setup = Vec::new();
+        if generic && !inputs.is_empty() {
+            return Err(Error::InvalidGenericSystem);
         }
+        let system_sname = if inputs.is_empty() {
+            if self.attr.cooldown_ms.is_some() {
+                return Err(Error::CooldownWithoutInput);
+            }
+            quote!(#system_name).to_string()
+        } else {
+            for input in &inputs {
+                let name = input.to_case(Case::Snake);
+                let dep = format!("{}_input", name);
+                if let Some(cooldown_ms) = self.attr.cooldown_ms {
+                    let ident = format_ident!("{}", input);
+                    let id = string_to_u32(input.as_bytes());
+                    let cooldown = format!("{}_cooldown", name);
+                    cooldown_setup.push(quote! {
+                        builder.add(
+                            ::ecs_engine::CooldownSystem::<#ident, #id>::new(::std::time::Duration::from_millis(#cooldown_ms)),
+                            #cooldown,
+                            &[#dep],
+                        );
+                    });
+                    system_deps.push(quote!(#dep));
+                    system_deps.push(quote!(#cooldown));
+                } else {
+                    system_deps.push(quote!(#dep));
+                }
+            }
+            let exec = format!("{}_exec", inputs[0].to_case(Case::Snake));
+            for input in &inputs[1..] {
+                let alias = format!("{}_exec", input.to_case(Case::Snake));
+                alias_setup.push(quote!(builder.add(::ecs_engine::AliasSystem, #alias, &[#exec]);));
+            }
+            exec
+        };
 
         // all components should be registered
         let mut component_types = Vec::new();
+        // resources inserted with default value if not exist
+        let mut resource_types = Vec::new();
         // field names
         let mut state_names = Vec::new();
         // field types
@@ -398,6 +739,25 @@ impl Config {
                         input_names.push(quote!(#jname));
                     }
                 }
+                Parameter::Optional(vname, index, mutable) => {
+                    let ty = self.signature.component_args[*index].clone();
+                    component_types.push(ty.clone());
+                    func_names.push(quote!(#vname));
+                    foreach_names.push(vname.clone());
+                    let jname = format_ident!("j{}", vname);
+                    if *mutable {
+                        join_names.push(quote!((&mut #jname).maybe()));
+                        system_data_types.push(quote!(::specs::WriteStorage<'a, #ty>));
+                        input_names.push(quote!(mut #jname));
+                        fn_input_types.push(quote!(Option<&mut #ty>));
+                        write_components.push(ty);
+                    } else {
+                        join_names.push(quote!((&#jname).maybe()));
+                        system_data_types.push(quote!(::specs::ReadStorage<'a, #ty>));
+                        input_names.push(quote!(#jname));
+                        fn_input_types.push(quote!(Option<&#ty>));
+                    }
+                }
                 Parameter::State(vname, index, mutable) => {
                     let ty = self.signature.state_args[*index].clone();
                     state_names.push(vname.clone());
@@ -412,6 +772,9 @@ impl Config {
                 }
                 Parameter::Resource(vname, index, mutable, expect) => {
                     let ty = self.signature.resource_args[*index].clone();
+                    if !*expect {
+                        resource_types.push(ty.clone());
+                    }
                     let data = if *mutable {
                         if *expect {
                             quote!(::specs::WriteExpect<'a, #ty>)
@@ -466,7 +829,7 @@ impl Config {
                             false
                         }
                     }) {
-                        if !self.signature.outputs.is_empty() {
+                        if self.needs_entity() {
                             foreach_names.push(vname.clone());
                             join_names.push(quote!(#jname));
                         }
@@ -477,6 +840,15 @@ impl Config {
                     input_alias.push(quote!(let #jname:&::ecs_engine::GameEntities = unsafe {std::mem::transmute(&#jname)};));
                     func_names.push(quote!(#jname));
                 }
+                Parameter::Pair(vname, index) => {
+                    let ty = self.signature.component_args[*index].clone();
+                    fn_input_types.push(quote!(&#ty));
+                    func_names.push(quote!(#vname));
+                }
+                Parameter::PairEntity => {
+                    fn_input_types.push(quote!(&::specs::Entity));
+                    func_names.push(quote!(&pentity));
+                }
                 Parameter::Storage(vname, index, mutable) => {
                     let sname = format_ident!("s{}", vname);
                     let ty = self.signature.storage_args[*index].clone();
@@ -516,21 +888,170 @@ impl Config {
             }
         }
 
+        for (i, ty) in self.with.iter().enumerate() {
+            let jname = format_ident!("jwith{}", i);
+            component_types.push(ty.clone());
+            system_data_types.push(quote!(::specs::ReadStorage<'a, #ty>));
+            input_names.push(quote!(#jname));
+            join_names.push(quote!(&#jname));
+            foreach_names.push(format_ident!("_"));
+        }
+        for (i, ty) in self.without.iter().enumerate() {
+            let jname = format_ident!("jwithout{}", i);
+            component_types.push(ty.clone());
+            system_data_types.push(quote!(::specs::ReadStorage<'a, #ty>));
+            input_names.push(quote!(#jname));
+            join_names.push(quote!(!&#jname));
+            foreach_names.push(format_ident!("_"));
+        }
+
+        let mut changed_init = Vec::new();
+        let mut changed_reads = Vec::new();
+        for (i, ty) in self.attr.changed.iter().enumerate() {
+            let state = format_ident!("changed{}", i);
+            state_names.push(state.clone());
+            state_types.push(parse_quote!(
+                Option<::specs::ReaderId<::specs::storage::ComponentEvent>>
+            ));
+            component_types.push(ty.clone());
+            changed_init
+                .push(quote!(self.#state = Some(world.write_storage::<#ty>().register_reader());));
+            // 复用参数中已有的存储，避免同时借用
+            let storage = self
+                .signature
+                .parameters
+                .iter()
+                .find_map(|param| match param {
+                    Parameter::Component(vname, index, _)
+                    | Parameter::Optional(vname, index, _)
+                        if &self.signature.component_args[*index] == ty =>
+                    {
+                        Some(format_ident!("j{}", vname))
+                    }
+                    _ => None,
+                })
+                .or_else(|| {
+                    self.signature
+                        .parameters
+                        .iter()
+                        .find_map(|param| match param {
+                            Parameter::Storage(vname, index, _)
+                                if &self.signature.storage_args[*index] == ty =>
+                            {
+                                Some(format_ident!("s{}", vname))
+                            }
+                            _ => None,
+                        })
+                })
+                .or_else(|| {
+                    self.signature
+                        .outputs
+                        .iter()
+                        .position(|output| output == ty)
+                        .map(|index| self.signature.output_names[index].clone())
+                })
+                .unwrap_or_else(|| {
+                    let jname = format_ident!("jchanged{}", i);
+                    system_data_types.push(quote!(::specs::ReadStorage<'a, #ty>));
+                    input_names.push(quote!(#jname));
+                    jname
+                });
+            changed_reads.push(quote!(#storage.channel().read(self.#state.as_mut().unwrap())));
+        }
+        let changed_code = if changed_reads.is_empty() {
+            quote!()
+        } else {
+            join_names.push(quote!(&changed));
+            foreach_names.push(format_ident!("_"));
+            quote! {
+                let mut changed = ::specs::BitSet::new();
+                #(
+                    for event in #changed_reads {
+                        match event {
+                            ::specs::storage::ComponentEvent::Inserted(id)
+                            | ::specs::storage::ComponentEvent::Modified(id) => {
+                                changed.add(*id);
+                            }
+                            ::specs::storage::ComponentEvent::Removed(id) => {
+                                changed.remove(*id);
+                            }
+                        }
+                    }
+                )*
+            }
+        };
+
+        // 批量参数的各个元素合并为一个切片参数
+        let mut batch_names = Vec::new();
+        if let Some((start, count, mutable)) = self.signature.batch {
+            let mut batch_types = Vec::new();
+            for param in &self.signature.parameters[start..start + count] {
+                match param {
+                    Parameter::Component(vname, index, mutable) => {
+                        let ty = &self.signature.component_args[*index];
+                        batch_names.push(vname.clone());
+                        if *mutable {
+                            batch_types.push(quote!(&mut #ty));
+                        } else {
+                            batch_types.push(quote!(&#ty));
+                        }
+                    }
+                    Parameter::Optional(vname, index, mutable) => {
+                        let ty = &self.signature.component_args[*index];
+                        batch_names.push(vname.clone());
+                        if *mutable {
+                            batch_types.push(quote!(Option<&mut #ty>));
+                        } else {
+                            batch_types.push(quote!(Option<&#ty>));
+                        }
+                    }
+                    Parameter::Entity => {
+                        batch_names.push(format_ident!("entity"));
+                        batch_types.push(quote!(::specs::Entity));
+                    }
+                    _ => unreachable!(),
+                }
+            }
+            let (items, items_type) = if mutable {
+                (quote!(&mut items), quote!(&mut [(#(#batch_types,)*)]))
+            } else {
+                (quote!(&items), quote!(&[(#(#batch_types,)*)]))
+            };
+            func_names.splice(start..start + count, vec![items]);
+            fn_input_types.splice(start..start + count, vec![items_type]);
+        }
+
         for (i, typ) in self.signature.outputs.iter().enumerate() {
             let vname = &self.signature.output_names[i];
             system_data_types.push(quote!(::specs::WriteStorage<'a, #typ>));
             input_names.push(quote!(mut #vname));
             output_snames.push(vname.clone());
-            fn_output_types.push(quote!(Option<#typ>));
             output_vnames.push(format_ident!("r{}", i));
             component_types.push(typ.clone());
-            join_names.push(quote!(!&#vname));
-            foreach_names.push(format_ident!("_"));
+            if self.signature.targeted[i] {
+                fn_output_types.push(quote!(Vec<(::specs::Entity, #typ)>));
+            } else {
+                fn_output_types.push(quote!(Option<#typ>));
+                join_names.push(quote!(!&#vname));
+                foreach_names.push(format_ident!("_"));
+            }
             output_enames.push(format_ident!("e{}", vname));
             write_components.push(typ.clone());
         }
 
-        if !self.signature.outputs.is_empty() && !self.signature.has_entities() {
+        if self.attr.cooldown_ms.is_some() {
+            for (i, input) in inputs.iter().enumerate() {
+                let id = string_to_u32(input.as_bytes());
+                let jname = format_ident!("jthrottled{}", i);
+                system_data_types
+                    .push(quote!(::specs::ReadStorage<'a, ::ecs_engine::Throttled<#id>>));
+                input_names.push(quote!(#jname));
+                join_names.push(quote!(!&#jname));
+                foreach_names.push(format_ident!("_"));
+            }
+        }
+
+        if self.needs_entity() && !self.signature.has_entities() {
             system_data_types.push(quote!(::specs::Entities<'a>));
             let vname = format_ident!("entity");
             let jname = format_ident!("j{}", vname);
@@ -539,57 +1060,408 @@ impl Config {
             join_names.push(quote!(&#jname));
         }
 
-        let output_code = quote! {
-           #(if let Some(#output_vnames) = #output_vnames{
-                #output_enames.push((entity, #output_vnames));
-            })*
+        // 运行时开关，由SystemToggles按系统名控制
+        state_names.push(format_ident!("paused"));
+        state_types.push(parse_quote!(
+            ::std::sync::Arc<::std::sync::atomic::AtomicBool>
+        ));
+        // bench特性下的运行记录，由TimeStatistic按系统名汇总
+        state_names.push(format_ident!("bench"));
+        state_types.push(parse_quote!(
+            ::std::option::Option<::std::sync::Arc<::ecs_engine::BenchRecord>>
+        ));
+
+        let slice_init = if let Some(slice_ms) = self.attr.slice_ms {
+            state_names.push(format_ident!("slice"));
+            state_types.push(parse_quote!(::ecs_engine::TimeSlice));
+            system_data_types.push(quote!(::specs::Entities<'a>));
+            input_names.push(quote!(jslice));
+            join_names.push(quote!(&jslice));
+            foreach_names.push(format_ident!("slice_entity"));
+            quote!(self.slice = ::ecs_engine::TimeSlice::new(::std::time::Duration::from_millis(#slice_ms));)
+        } else {
+            quote!()
+        };
+
+        let output_codes: Vec<_> = output_vnames
+            .iter()
+            .zip(output_enames.iter())
+            .enumerate()
+            .map(|(i, (vname, ename))| {
+                let ename = if self.attr.system_type == SystemType::Parallel {
+                    quote!(#ename.lock().unwrap())
+                } else {
+                    quote!(#ename)
+                };
+                if self.signature.targeted[i] {
+                    quote!(#ename.extend(#vname);)
+                } else {
+                    quote! {
+                        if let Some(#vname) = #vname {
+                            #ename.push((entity, #vname));
+                        }
+                    }
+                }
+            })
+            .collect();
+        let output_code = quote!(#(#output_codes)*);
+
+        let fn_return_type = if let Some(error) = &self.signature.error {
+            quote!(::std::result::Result<(#(#fn_output_types),*), #error>)
+        } else {
+            quote!((#(#fn_output_types),*))
+        };
+        let system_label = system_name.to_string();
+        // 异步系统等待中的任务结果
+        if self.attr.system_type == SystemType::Async {
+            state_names.push(format_ident!("pending"));
+            state_types.push(parse_quote!(
+                Option<::std::sync::mpsc::Receiver<Vec<(::specs::Entity, #fn_return_type)>>>
+            ));
+        }
+        let error_code = if self.per_entity() {
+            let close_code = if self.attr.close_on_error {
+                system_data_types.push(quote!(::specs::Read<'a, ::specs::LazyUpdate>));
+                input_names.push(quote!(jclose));
+                quote!(jclose.insert(entity, ::ecs_engine::Closing(false));)
+            } else {
+                quote!()
+            };
+            quote! {
+                log::error!("system {} entity:{} failed:{}", #system_label, entity.id(), err);
+                #close_code
+            }
+        } else {
+            quote!(log::error!("system {} failed:{}", #system_label, err);)
         };
 
         let (dynamic_init, dynamic_fn, func_call) = if self.dynamic {
             system_data_types.push(quote!(::specs::Read<'a, ::ecs_engine::DynamicManager>));
             state_names.push(format_ident!("lib"));
-            state_types.push(parse_quote!(::ecs_engine::DynamicSystem<fn(#(#fn_input_types,)*) -> ::std::option::Option<(#(#fn_output_types),*)>>));
+            state_types.push(parse_quote!(::ecs_engine::DynamicSystem<fn(#(#fn_input_types,)*) -> ::std::option::Option<#fn_return_type>>));
             input_names.push(quote!(dm));
             let dynamic_init = quote!(self.lib.init(#lib_name.into(), #func_name.into(), dm););
-            let dynamic_fn =
-                quote!(pub type #system_fn = fn(#(#fn_input_types,)*) ->(#(#fn_output_types),*););
-            let dynamic_call = quote! {
-                if let Some((#(#output_vnames),*)) = {(*symbol)(#(#func_names,)*)} {
-                    #output_code
+            // 以导出函数名命名的别名，供#[export]按函数名检查签名
+            let export_fn = match &func_name {
+                Lit::Str(name) => format_ident!("__ecs_fn_{}", name.value()),
+                lit => return Err(Error::InvalidMetaForDynamic(lit.span())),
+            };
+            let dynamic_fn = quote! {
+                pub type #system_fn = fn(#(#fn_input_types,)*) -> #fn_return_type;
+
+                #[doc(hidden)]
+                #[allow(non_camel_case_types)]
+                pub type #export_fn = #system_fn;
+            };
+            let dynamic_call = if self.signature.error.is_some() {
+                quote! {
+                    match (*symbol)(#(#func_names,)*) {
+                        Some(Ok((#(#output_vnames),*))) => {
+                            #output_code
+                        }
+                        Some(Err(err)) => {
+                            #error_code
+                        }
+                        None => lib.report_panic(&dm),
+                    }
+                }
+            } else {
+                quote! {
+                    if let Some((#(#output_vnames),*)) = {(*symbol)(#(#func_names,)*)} {
+                        #output_code
+                    } else {
+                        lib.report_panic(&dm);
+                    }
                 }
             };
             (dynamic_init, dynamic_fn, dynamic_call)
         } else {
             let symbol = self.signature.ident.clone();
-            let static_call = quote! {
-                let (#(#output_vnames),*) = #symbol(#(#func_names,)*);
-                #output_code
+            let (_, ty_generics, _) = self.signature.generics.split_for_impl();
+            let turbofish = ty_generics.as_turbofish();
+            let static_call = if self.signature.error.is_some() {
+                quote! {
+                    match #symbol #turbofish(#(#func_names,)*) {
+                        Ok((#(#output_vnames),*)) => {
+                            #output_code
+                        }
+                        Err(err) => {
+                            #error_code
+                        }
+                    }
+                }
+            } else {
+                quote! {
+                    let (#(#output_vnames),*) = #symbol #turbofish(#(#func_names,)*);
+                    #output_code
+                }
             };
             (quote!(), quote!(), static_call)
         };
 
+        let barrier = if self.attr.barrier {
+            quote!(builder.add_barrier();)
+        } else {
+            quote!()
+        };
+
+        // 泛型参数作为组件时需要能够注册
+        let mut generics = self.signature.generics.clone();
+        let type_params: Vec<_> = generics
+            .type_params()
+            .map(|param| param.ident.clone())
+            .collect();
+        for param in &type_params {
+            if component_types.contains(&parse_quote!(#param)) {
+                generics
+                    .make_where_clause()
+                    .predicates
+                    .push(parse_quote!(<#param as ::specs::Component>::Storage: Default));
+            }
+        }
+        let (impl_generics, ty_generics, where_clause) = generics.split_for_impl();
+        let params = &generics.params;
+        let (system_struct, system_sname) = if generic {
+            (
+                quote! {
+                    pub struct #system_name #impl_generics #where_clause {
+                        #(#state_names:#state_types,)*
+                        _phantom: ::std::marker::PhantomData<fn() -> (#(#type_params,)*)>,
+                    }
+
+                    impl #impl_generics Default for #system_name #ty_generics #where_clause {
+                        fn default() -> Self {
+                            Self {
+                                #(#state_names: Default::default(),)*
+                                _phantom: Default::default(),
+                            }
+                        }
+                    }
+                },
+                quote!(
+                    &format!("{}<{}>", #system_sname, vec![#(::std::any::type_name::<#type_params>()),*].join(","))
+                ),
+            )
+        } else {
+            (
+                quote! {
+                    #[derive(Default)]
+                    pub struct #system_name {
+                        #(#state_names:#state_types,)*
+                    }
+                },
+                quote!(#system_sname),
+            )
+        };
+
+        for ty in &self.attr.no_register {
+            if !component_types.contains(ty) {
+                return Err(Error::InvalidNoRegister(ty.span()));
+            }
+        }
+        let register_types: Vec<_> = component_types
+            .iter()
+            .filter(|ty| !self.attr.no_register.contains(ty))
+            .collect();
+        let setup_hook = if let Some(hook) = &self.attr.setup_hook {
+            quote!(#hook(world);)
+        } else {
+            quote!()
+        };
+
         let system_setup = quote! {
             #dynamic_fn
 
-            #[derive(Default)]
-            pub struct #system_name {
-                #(#state_names:#state_types,)*
-            }
+            #system_struct
 
-            impl #system_name {
+            impl #impl_generics #system_name #ty_generics #where_clause {
                     pub fn setup(mut self, world: &mut ::specs::World, builder: &mut ::ecs_engine::GameDispatcherBuilder, dm: &::ecs_engine::DynamicManager) {
-                        #(world.register::<#component_types>();)*
+                        #(world.register::<#register_types>();)*
+                        #(world.entry::<#resource_types>().or_insert_with(Default::default);)*
+                        #setup_hook
+                        self.paused = world
+                            .entry::<::ecs_engine::SystemToggles>()
+                            .or_insert_with(Default::default)
+                            .register(#system_sname);
+                        self.bench = ::ecs_engine::bench_register(world, #system_sname);
                         #dynamic_init
-                        builder.add(self, #system_sname, #system_deps);
+                        #slice_init
+                        #(#changed_init)*
+                        #(#cooldown_setup)*
+                        builder.add(self, #system_sname, &[#(#system_deps),*]);
+                        #(#alias_setup)*
+                        #barrier
                     }
                 }
         };
 
         let system_code = {
-            let run_code = quote! {
-                (#(#join_names,)*).join().for_each(|(#(#foreach_names,)*)| {
+            let counted_call = quote! {
+                if ::ecs_engine::BENCH {
+                    jbench_count.fetch_add(1, ::std::sync::atomic::Ordering::Relaxed);
+                }
+                #func_call
+            };
+            let join_code = if self.attr.system_type == SystemType::Async {
+                // 组件复制后移入任务，函数以引用方式调用
+                let mut capture_names = Vec::new();
+                let mut clone_exprs = Vec::new();
+                let mut arg_exprs = Vec::new();
+                for param in &self.signature.parameters {
+                    match param {
+                        Parameter::Component(vname, _, _) => {
+                            capture_names.push(vname.clone());
+                            clone_exprs.push(quote!(#vname.clone()));
+                            arg_exprs.push(quote!(&#vname));
+                        }
+                        Parameter::Optional(vname, _, _) => {
+                            capture_names.push(vname.clone());
+                            clone_exprs.push(quote!(#vname.cloned()));
+                            arg_exprs.push(quote!(#vname.as_ref()));
+                        }
+                        _ => arg_exprs.push(quote!(&entity)),
+                    }
+                }
+                let symbol = &self.signature.ident;
+                quote! {
+                    if let Some(pending) = &self.pending {
+                        match pending.try_recv() {
+                            Ok(results) => {
+                                self.pending = None;
+                                for (entity, (#(#output_vnames),*)) in results {
+                                    if !jentity.is_alive(entity) {
+                                        continue;
+                                    }
+                                    #output_code
+                                }
+                            }
+                            Err(::std::sync::mpsc::TryRecvError::Empty) => {}
+                            Err(::std::sync::mpsc::TryRecvError::Disconnected) => {
+                                self.pending = None;
+                                log::error!("async system {} job failed", #system_label);
+                            }
+                        }
+                    }
+                    if self.pending.is_none() {
+                        let items: Vec<_> = (#(#join_names,)*)
+                            .join()
+                            .map(|(#(#foreach_names,)*)| (entity, (#(#clone_exprs,)*)))
+                            .collect();
+                        if ::ecs_engine::BENCH {
+                            jbench_count.fetch_add(items.len(), ::std::sync::atomic::Ordering::Relaxed);
+                        }
+                        if !items.is_empty() {
+                            let (sender, receiver) = ::std::sync::mpsc::channel();
+                            ::specs::rayon::spawn(move || {
+                                let results: Vec<_> = items
+                                    .into_iter()
+                                    .map(|(entity, (#(#capture_names,)*))| (entity, #symbol(#(#arg_exprs,)*)))
+                                    .collect();
+                                let _ = sender.send(results);
+                            });
+                            self.pending = Some(receiver);
+                        }
+                    }
+                }
+            } else if self.attr.system_type == SystemType::Parallel {
+                quote! {
+                    #(let #output_enames = ::std::sync::Mutex::new(#output_enames);)*
+                    ::specs::rayon::iter::ParallelIterator::for_each(
+                        ::specs::ParJoin::par_join((#(#join_names,)*)),
+                        |(#(#foreach_names,)*)| {
+                            #counted_call
+                        },
+                    );
+                    #(let #output_enames = #output_enames.into_inner().unwrap();)*
+                }
+            } else if self.attr.system_type == SystemType::Multiple {
+                let items = if self
+                    .signature
+                    .batch
+                    .map_or(false, |(_, _, mutable)| mutable)
+                {
+                    quote!(mut items)
+                } else {
+                    quote!(items)
+                };
+                quote! {
+                    let #items: Vec<_> = (#(#join_names,)*)
+                        .join()
+                        .map(|(#(#foreach_names,)*)| (#(#batch_names,)*))
+                        .collect();
+                    if ::ecs_engine::BENCH {
+                        jbench_count.fetch_add(items.len(), ::std::sync::atomic::Ordering::Relaxed);
+                    }
                     #func_call
-                });
+                }
+            } else if self.attr.system_type == SystemType::Double {
+                let pair_names: Vec<_> = foreach_names
+                    .iter()
+                    .map(|name| {
+                        if name == "entity" {
+                            return format_ident!("pentity");
+                        }
+                        self.signature
+                            .parameters
+                            .iter()
+                            .find_map(|param| match param {
+                                Parameter::Component(vname, index, _) if vname == name => {
+                                    Some(index)
+                                }
+                                _ => None,
+                            })
+                            .and_then(|index| {
+                                self.signature
+                                    .parameters
+                                    .iter()
+                                    .find_map(|param| match param {
+                                        Parameter::Pair(pname, pindex) if pindex == index => {
+                                            Some(pname.clone())
+                                        }
+                                        _ => None,
+                                    })
+                            })
+                            .unwrap_or_else(|| name.clone())
+                    })
+                    .collect();
+                quote! {
+                    let items: Vec<_> = (#(#join_names,)*).join().collect();
+                    for i in 0..items.len() {
+                        let (#(#foreach_names,)*) = items[i];
+                        for j in (i + 1)..items.len() {
+                            let (#(#pair_names,)*) = items[j];
+                            #counted_call
+                        }
+                    }
+                }
+            } else if self.attr.slice_ms.is_some() {
+                quote! {
+                    self.slice.begin();
+                    let mut finished = true;
+                    for (#(#foreach_names,)*) in (#(#join_names,)*).join() {
+                        if self.slice.skip(slice_entity.id()) {
+                            continue;
+                        }
+                        if self.slice.is_exceeded(slice_entity.id()) {
+                            finished = false;
+                            break;
+                        }
+                        #counted_call
+                    }
+                    if finished {
+                        self.slice.finish();
+                    }
+                }
+            } else {
+                quote! {
+                    (#(#join_names,)*).join().for_each(|(#(#foreach_names,)*)| {
+                        #counted_call
+                    });
+                }
+            };
+            let run_code = quote! {
+                #changed_code
+                #join_code
                 #(#output_enames.into_iter().for_each(|(entity, c)|{
                     if let Err(err) = #output_snames.insert(entity, c) {
                         log::error!("insert component failed:{}", err);
@@ -599,9 +1471,10 @@ impl Config {
             let run_code = if self.dynamic {
                 quote! {
                    if let Some(symbol) = self.lib.get_symbol(&dm) {
+                        let lib = &self.lib;
                         #(#input_alias)*
                         #run_code
-                   } else {
+                   } else if !self.lib.is_quarantined(&dm) {
                         log::error!("symbol not found for system {}", #func_name);
                     }
                 }
@@ -612,14 +1485,22 @@ impl Config {
                 }
             };
             quote! {
-                impl<'a> ::specs::System<'a> for #system_name {
+                impl<'a, #params> ::specs::System<'a> for #system_name #ty_generics #where_clause {
                     type SystemData = (
                         #(#system_data_types,)*
                     );
 
                     fn run(&mut self, (#(#input_names,)*): Self::SystemData) {
+                        if self.paused.load(::std::sync::atomic::Ordering::Relaxed) {
+                            return;
+                        }
+                        let bench_begin = ::ecs_engine::bench_begin();
+                        let jbench_count = ::std::sync::atomic::AtomicUsize::new(0);
                         #(let mut #output_enames = Vec::new();)*
                         #run_code
+                        if ::ecs_engine::BENCH {
+                            ::ecs_engine::bench_end(&self.bench, bench_begin, jbench_count.into_inner());
+                        }
                     }
                 }
             }
@@ -642,19 +1523,27 @@ impl Config {
 enum ArgAttr {
     Resource(bool),
     State,
+    Input,
 }
 
 enum Parameter {
     Component(Ident, usize, bool),
+    /// Option<&Component>，实体没有此组件时传入None
+    Optional(Ident, usize, bool),
     Resource(Ident, usize, bool, bool),
     State(Ident, usize, bool),
     Storage(Ident, usize, bool),
     Entity,
     Entities,
+    /// 双实体系统中第二个实体的组件，与第一个实体的同类型组件共用索引
+    Pair(Ident, usize),
+    /// 双实体系统中第二个实体
+    PairEntity,
 }
 
 struct Sig {
     ident: Ident,
+    generics: Generics,
     parameters: Vec<Parameter>,
     state_args: Vec<Type>,
     resource_args: Vec<Type>,
@@ -662,9 +1551,22 @@ struct Sig {
     component_args: Vec<Type>,
     outputs: Vec<Type>,
     output_names: Vec<Ident>,
+    /// 输出是否为Vec<(Entity, Component)>
+    targeted: Vec<bool>,
+    /// Result<outputs, E>中的错误类型
+    error: Option<Type>,
+    /// 批量参数展开后在parameters中的起始位置、元素数量以及切片是否可变
+    batch: Option<(usize, usize, bool)>,
+    /// #[input]标记的请求参数在component_args中的位置
+    input_args: Vec<usize>,
 }
 
 impl Sig {
+    /// 是否有作用于当前实体的输出
+    fn has_entity_outputs(&self) -> bool {
+        self.targeted.iter().any(|targeted| !targeted)
+    }
+
     fn has_entities(&self) -> bool {
         self.parameters.iter().any(|param| match param {
             Parameter::Entity => true,
@@ -679,13 +1581,26 @@ impl Sig {
         let mut storage_args = Vec::new();
         let mut state_args = Vec::new();
         let mut component_args = Vec::new();
+        let mut batch = None;
+        let mut input_args = Vec::new();
         let mut index = 0usize;
         for param in &mut item.inputs {
             index += 1;
             match param {
-                syn::FnArg::Receiver(_) => return Err(Error::SelfNotAllowed),
+                syn::FnArg::Receiver(receiver) => {
+                    return Err(Error::SelfNotAllowed(receiver.span()))
+                }
                 syn::FnArg::Typed(arg) => {
                     let name = format_ident!("i{}", index);
+                    if let Some(ty) = get_optional_reference(arg.ty.as_ref()) {
+                        parameters.push(Parameter::Optional(
+                            name,
+                            component_args.len(),
+                            ty.mutability.is_some(),
+                        ));
+                        component_args.push(ty.elem.as_ref().clone());
+                        continue;
+                    }
                     match arg.ty.as_ref() {
                         Type::Reference(ty) => {
                             let mutable = ty.mutability.is_some();
@@ -709,8 +1624,72 @@ impl Sig {
                                     ));
                                     state_args.push(elem.clone())
                                 }
-                                _ => {
-                                    if is_storage(elem) {
+                                Some(ArgAttr::Input) => {
+                                    if !matches!(elem, Type::Path(_))
+                                        || is_storage(elem)
+                                        || is_entity(elem)
+                                        || is_lazy_update(elem)
+                                        || is_entities(elem)
+                                    {
+                                        return Err(Error::InvalidInput(arg.span()));
+                                    }
+                                    input_args.push(component_args.len());
+                                    parameters.push(Parameter::Component(
+                                        name,
+                                        component_args.len(),
+                                        mutable,
+                                    ));
+                                    component_args.push(elem.clone());
+                                }
+                                None => {
+                                    if let Type::Slice(slice) = elem {
+                                        if batch.is_some() {
+                                            return Err(Error::InvalidMultipleParameters);
+                                        }
+                                        let elems = match slice.elem.as_ref() {
+                                            Type::Tuple(tuple) => &tuple.elems,
+                                            _ => return Err(Error::InvalidBatchType(arg.span())),
+                                        };
+                                        let start = parameters.len();
+                                        for (i, elem) in elems.iter().enumerate() {
+                                            match elem {
+                                                Type::Reference(r) if !is_entity(&r.elem) => {
+                                                    if r.mutability.is_some() && !mutable {
+                                                        return Err(Error::InvalidBatchType(
+                                                            arg.span(),
+                                                        ));
+                                                    }
+                                                    parameters.push(Parameter::Component(
+                                                        format_ident!("{}_{}", name, i),
+                                                        component_args.len(),
+                                                        r.mutability.is_some(),
+                                                    ));
+                                                    component_args.push(r.elem.as_ref().clone());
+                                                }
+                                                _ if is_entity(elem) => {
+                                                    parameters.push(Parameter::Entity)
+                                                }
+                                                _ if get_optional_reference(elem).is_some() => {
+                                                    let r = get_optional_reference(elem).unwrap();
+                                                    if r.mutability.is_some() && !mutable {
+                                                        return Err(Error::InvalidBatchType(
+                                                            arg.span(),
+                                                        ));
+                                                    }
+                                                    parameters.push(Parameter::Optional(
+                                                        format_ident!("{}_{}", name, i),
+                                                        component_args.len(),
+                                                        r.mutability.is_some(),
+                                                    ));
+                                                    component_args.push(r.elem.as_ref().clone());
+                                                }
+                                                _ => {
+                                                    return Err(Error::InvalidBatchType(arg.span()))
+                                                }
+                                            }
+                                        }
+                                        batch = Some((start, elems.len(), mutable));
+                                    } else if is_storage(elem) {
                                         if mutable && is_read_storage(elem) {
                                             return Err(Error::ReadStorageCantBeMutable(
                                                 arg.span(),
@@ -733,6 +1712,17 @@ impl Sig {
                                             return Err(Error::EntityCantBeMutable(arg.span()));
                                         }
                                         parameters.push(Parameter::Entity);
+                                    } else if is_lazy_update(elem) {
+                                        if mutable {
+                                            return Err(Error::LazyUpdateCantBeMutable(arg.span()));
+                                        }
+                                        parameters.push(Parameter::Resource(
+                                            name,
+                                            resource_args.len(),
+                                            false,
+                                            false,
+                                        ));
+                                        resource_args.push(parse_quote!(::specs::LazyUpdate));
                                     } else if is_entities(elem) {
                                         if mutable {
                                             return Err(Error::EntityCantBeMutable(arg.span()));
@@ -749,35 +1739,37 @@ impl Sig {
                                 }
                             }
                         }
-                        _ => return Err(Error::InvalidArgument(Span::call_site())),
+                        _ => return Err(Error::InvalidArgument(arg.ty.span())),
                     }
                 }
             }
         }
 
         let mut outputs = Vec::new();
-        match &item.output {
-            ReturnType::Default => {}
-            ReturnType::Type(_, ty) => match ty.as_ref() {
-                Type::Path(path) => {
-                    if !is_type(ty, &["Option"]) {
-                        return Err(Error::InvalidReturnType(item.output.span()));
-                    }
-                    let typ = get_option_inner_type(path)?;
+        let mut targeted = Vec::new();
+        let mut error = None;
+        let output = match &item.output {
+            ReturnType::Type(_, ty) if is_result(ty) => {
+                let (output, err) = get_result_types(ty)?;
+                error.replace(err);
+                Some(output)
+            }
+            ReturnType::Type(_, ty) => Some(ty.as_ref().clone()),
+            ReturnType::Default => None,
+        };
+        match &output {
+            None => {}
+            Some(ty) => match ty {
+                Type::Path(_) => {
+                    let (typ, target) = Self::parse_output(ty)?;
                     outputs.push(typ);
+                    targeted.push(target);
                 }
                 Type::Tuple(tuple) => {
                     for elem in &tuple.elems {
-                        if !is_type(elem, &["Option"]) {
-                            return Err(Error::InvalidReturnType(item.output.span()));
-                        }
-                        match elem {
-                            Type::Path(path) => {
-                                let typ = get_option_inner_type(path)?;
-                                outputs.push(typ);
-                            }
-                            _ => return Err(Error::InvalidReturnType(elem.span())),
-                        }
+                        let (typ, target) = Self::parse_output(elem)?;
+                        outputs.push(typ);
+                        targeted.push(target);
                     }
                 }
                 _ => return Err(Error::InvalidReturnType(item.output.span())),
@@ -786,6 +1778,7 @@ impl Sig {
 
         Ok(Self {
             ident: item.ident.clone(),
+            generics: item.generics.clone(),
             parameters,
             resource_args,
             state_args,
@@ -793,9 +1786,70 @@ impl Sig {
             storage_args,
             outputs,
             output_names: Vec::default(),
+            targeted,
+            error,
+            batch,
+            input_args,
         })
     }
 
+    /// Option<Component>作用于当前实体，Vec<(Entity, Component)>作用于指定实体
+    fn parse_output(ty: &Type) -> Result<(Type, bool), Error> {
+        match ty {
+            Type::Path(path) if is_type(ty, &["Option"]) => {
+                Ok((get_option_inner_type(path)?, false))
+            }
+            Type::Path(path) if is_type(ty, &["Vec"]) => {
+                if let Type::Tuple(tuple) = get_option_inner_type(path)? {
+                    if tuple.elems.len() == 2 && is_entity(&tuple.elems[0]) {
+                        return Ok((tuple.elems[1].clone(), true));
+                    }
+                }
+                Err(Error::InvalidReturnType(ty.span()))
+            }
+            _ => Err(Error::InvalidReturnType(ty.span())),
+        }
+    }
+
+    /// 双实体系统的组件和实体参数分为相同的两组，后一组转换为第二个实体的参数
+    fn split_pairs(&mut self) -> Result<(), Error> {
+        let positions: Vec<_> = self
+            .parameters
+            .iter()
+            .enumerate()
+            .filter(|(_, param)| match param {
+                Parameter::Component(..) | Parameter::Entity => true,
+                _ => false,
+            })
+            .map(|(position, _)| position)
+            .collect();
+        if positions.is_empty() || positions.len() % 2 != 0 {
+            return Err(Error::InvalidDoubleParameters);
+        }
+        let half = positions.len() / 2;
+        let mut count = 0;
+        for k in 0..half {
+            let pair = match (
+                &self.parameters[positions[k]],
+                &self.parameters[positions[k + half]],
+            ) {
+                (Parameter::Component(_, index, _), Parameter::Component(name, pindex, _))
+                    if self.component_args[*index] == self.component_args[*pindex] =>
+                {
+                    count += 1;
+                    Parameter::Pair(name.clone(), *index)
+                }
+                (Parameter::Entity, Parameter::Entity) => Parameter::PairEntity,
+                _ => return Err(Error::InvalidDoubleParameters),
+            };
+            self.parameters[positions[k + half]] = pair;
+        }
+        // 第二组组件位于末尾
+        self.component_args
+            .truncate(self.component_args.len() - count);
+        Ok(())
+    }
+
     fn generate_output_names(&mut self) {
         let mut index = 0usize;
         for typ in &self.outputs {
@@ -833,21 +1887,27 @@ impl Sig {
         for i in (0..attributes.len()).rev() {
             match attributes[i].path.get_ident() {
                 Some(ident) if ident == "resource" => {
-                    attributes.remove(i);
+                    let attribute = attributes.remove(i);
                     if attr.replace(ArgAttr::Resource(false)).is_some() {
-                        return Err(Error::ConflictParameterAttribute);
+                        return Err(Error::ConflictParameterAttribute(attribute.span()));
                     }
                 }
                 Some(ident) if ident == "expect" => {
-                    attributes.remove(i);
+                    let attribute = attributes.remove(i);
                     if attr.replace(ArgAttr::Resource(true)).is_some() {
-                        return Err(Error::ConflictParameterAttribute);
+                        return Err(Error::ConflictParameterAttribute(attribute.span()));
                     }
                 }
                 Some(ident) if ident == "state" => {
-                    attributes.remove(i);
+                    let attribute = attributes.remove(i);
                     if attr.replace(ArgAttr::State).is_some() {
-                        return Err(Error::ConflictParameterAttribute);
+                        return Err(Error::ConflictParameterAttribute(attribute.span()));
+                    }
+                }
+                Some(ident) if ident == "input" => {
+                    let attribute = attributes.remove(i);
+                    if attr.replace(ArgAttr::Input).is_some() {
+                        return Err(Error::ConflictParameterAttribute(attribute.span()));
                     }
                 }
                 _ => {}
@@ -873,6 +1933,39 @@ fn get_option_inner_type(path: &TypePath) -> Result<Type, Error> {
     }
 }
 
+/// Result<T, E>中的T和E
+fn get_result_types(ty: &Type) -> Result<(Type, Type), Error> {
+    if let Type::Path(path) = ty {
+        let arguments = path.path.segments.last().map(|segment| &segment.arguments);
+        if let Some(PathArguments::AngleBracketed(bracketed)) = arguments {
+            let mut args = bracketed.args.iter();
+            if let (Some(GenericArgument::Type(output)), Some(GenericArgument::Type(error))) =
+                (args.next(), args.next())
+            {
+                return Ok((output.clone(), error.clone()));
+            }
+        }
+    }
+    Err(Error::InvalidReturnType(ty.span()))
+}
+
+/// Option<&T>或者Option<&mut T>
+fn get_optional_reference(ty: &Type) -> Option<&TypeReference> {
+    if !is_type(ty, &["Option"]) {
+        return None;
+    }
+    match ty {
+        Type::Path(path) => match &path.path.segments[0].arguments {
+            PathArguments::AngleBracketed(bracketed) => match bracketed.args.iter().next() {
+                Some(GenericArgument::Type(Type::Reference(r))) => Some(r),
+                _ => None,
+            },
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
 fn is_type(ty: &Type, segments: &[&str]) -> bool {
     if let Type::Path(path) = ty {
         path_match(path, segments)
@@ -882,7 +1975,13 @@ fn is_type(ty: &Type, segments: &[&str]) -> bool {
 }
 
 fn type_to_string(ty: &Type) -> String {
-    quote!(#ty).to_string().split("::").last().unwrap().into()
+    quote!(#ty)
+        .to_string()
+        .split("::")
+        .last()
+        .unwrap()
+        .trim()
+        .into()
 }
 
 fn path_match(path: &TypePath, segments: &[&str]) -> bool {
@@ -899,7 +1998,20 @@ fn is_entity(ty: &Type) -> bool {
 }
 
 fn is_entities(ty: &Type) -> bool {
-    is_type(ty, &["GameEntities"]) || is_type(ty, &["ecs_engine", "GameEntities"])
+    is_type(ty, &["GameEntities"])
+        || is_type(ty, &["ecs_engine", "GameEntities"])
+        || is_type(ty, &["Entities"])
+        || is_type(ty, &["specs", "Entities"])
+}
+
+fn is_result(ty: &Type) -> bool {
+    is_type(ty, &["Result"])
+        || is_type(ty, &["std", "result", "Result"])
+        || is_type(ty, &["core", "result", "Result"])
+}
+
+fn is_lazy_update(ty: &Type) -> bool {
+    is_type(ty, &["LazyUpdate"]) || is_type(ty, &["specs", "LazyUpdate"])
 }
 
 fn is_storage(ty: &Type) -> bool {
@@ -937,8 +2049,8 @@ pub fn system(attr: TokenStream, item: TokenStream) -> TokenStream {
     let attr = if attr.is_empty() {
         Ok(SystemAttr::default())
     } else {
-        let meta = parse_macro_input!(attr as Meta);
-        SystemAttr::parse_meta(&meta)
+        let args = parse_macro_input!(attr as AttributeArgs);
+        SystemAttr::parse_args(&args)
     };
 
     let result = attr
@@ -1004,11 +2116,18 @@ pub fn export(attr: TokenStream, item: TokenStream) -> TokenStream {
     };
 
     input.sig.ident = pname.clone();
+    // 不带参数时不检查签名，by_name时使用#[system]按函数名生成的别名，需要导入系统所在的模块
     let fn_check = if attr.is_empty() {
         quote!()
     } else {
         let attr = parse_macro_input!(attr as Meta);
-        let fn_type = attr.path().clone();
+        let fn_type = if attr.path().is_ident("by_name") {
+            let fn_type = format_ident!("__ecs_fn_{}", name);
+            quote!(#fn_type)
+        } else {
+            let fn_type = attr.path().clone();
+            quote!(#fn_type)
+        };
         let type_name = format_ident!("__FN_{}", name.clone().to_string().to_uppercase());
         quote!(static #type_name:#fn_type = #pname;)
     };
@@ -1,3 +1,10 @@
+mod migration;
 mod types;
+mod writer;
 
+pub use migration::{MigrateError, Migrator};
 pub use types::{BoolValue, Column, Index, Table};
+pub use writer::{
+    DatabaseHealth, RedisRequest, RedisWriter, RetryPolicy, SaveReport, SaveRequest, SaveResult,
+    Writer,
+};
@@ -0,0 +1,127 @@
+use mysql::{prelude::Queryable, PooledConn};
+use std::{fs::OpenOptions, io::Write, path::PathBuf};
+
+const MIGRATION_TABLE: &str = "_migrations";
+
+/// 缩小列类型的MODIFY COLUMN语句末尾的注释，迁移时视为破坏性语句
+pub(crate) const NARROWING_COMMENT: &str = "/* narrowing */";
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Mysql(mysql::Error),
+    Io(std::io::Error),
+    /// 会丢失数据的语句，需要`allow_destructive`才能执行
+    Destructive(String),
+    /// 迁移表中已经执行的语句与记录的校验和不一致，历史记录被修改过
+    ChecksumMismatch(String),
+}
+
+impl From<mysql::Error> for MigrateError {
+    fn from(err: mysql::Error) -> Self {
+        MigrateError::Mysql(err)
+    }
+}
+
+impl From<std::io::Error> for MigrateError {
+    fn from(err: std::io::Error) -> Self {
+        MigrateError::Io(err)
+    }
+}
+
+/// 执行表结构变更，每条语句连同校验和记录到迁移表中
+#[derive(Debug, Default, Clone)]
+pub struct Migrator {
+    dry_run: Option<PathBuf>,
+    allow_destructive: bool,
+}
+
+impl Migrator {
+    /// 不执行语句，只把语句追加写入到文件中
+    pub fn with_dry_run(mut self, path: impl Into<PathBuf>) -> Self {
+        self.dry_run = Some(path.into());
+        self
+    }
+
+    /// 允许执行DROP COLUMN、DROP KEY以及缩小列类型等会丢失数据的语句
+    pub fn allow_destructive(mut self) -> Self {
+        self.allow_destructive = true;
+        self
+    }
+
+    pub fn is_destructive(sql: &str) -> bool {
+        let upper = sql.to_ascii_uppercase();
+        upper.contains("DROP COLUMN")
+            || upper.contains("DROP KEY")
+            || upper.starts_with("DROP TABLE")
+            || sql.ends_with(NARROWING_COMMENT)
+    }
+
+    /// FNV-1a，与编译器版本无关，可以和历史记录比较
+    pub fn checksum(sql: &str) -> u64 {
+        sql.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+            (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3)
+        })
+    }
+
+    /// 执行一张表的变更语句，有破坏性语句且没有允许时一条都不执行，
+    /// 迁移表中该表已执行语句的校验和不一致时也不执行，返回执行的语句数量
+    pub fn apply(
+        &self,
+        conn: &mut PooledConn,
+        table: &str,
+        sqls: &[String],
+    ) -> Result<usize, MigrateError> {
+        if sqls.is_empty() {
+            return Ok(0);
+        }
+        if !self.allow_destructive {
+            if let Some(sql) = sqls.iter().find(|sql| Self::is_destructive(sql)) {
+                return Err(MigrateError::Destructive(sql.clone()));
+            }
+        }
+        if let Some(path) = &self.dry_run {
+            let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+            writeln!(file, "-- {}", table)?;
+            for sql in sqls {
+                writeln!(file, "{};", sql)?;
+            }
+            return Ok(0);
+        }
+        conn.query_drop(format!(
+            "CREATE TABLE IF NOT EXISTS `{}`(
+  `id` BIGINT(20) UNSIGNED NOT NULL AUTO_INCREMENT,
+  `table_name` VARCHAR(64) NOT NULL,
+  `statement` TEXT NOT NULL,
+  `checksum` BIGINT(20) UNSIGNED NOT NULL,
+  `applied_at` TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP,
+  PRIMARY KEY(`id`)
+) ENGINE=InnoDb DEFAULT CHARSET=utf8mb4",
+            MIGRATION_TABLE
+        ))?;
+        let history: Vec<(String, u64)> = conn.exec(
+            format!(
+                "SELECT `statement`, `checksum` FROM `{}` WHERE `table_name` = ? ORDER BY `id`",
+                MIGRATION_TABLE
+            ),
+            (table,),
+        )?;
+        if let Some((sql, _)) = history
+            .into_iter()
+            .find(|(sql, checksum)| Self::checksum(sql) != *checksum)
+        {
+            return Err(MigrateError::ChecksumMismatch(sql));
+        }
+        for sql in sqls {
+            log::warn!("migrate table:{} {}", table, sql);
+            conn.query_drop(sql)?;
+            conn.exec_drop(
+                format!(
+                    "INSERT INTO `{}` SET `table_name` = ?, `statement` = ?, `checksum` = ?",
+                    MIGRATION_TABLE
+                ),
+                (table, sql, Self::checksum(sql)),
+            )?;
+        }
+        Ok(sqls.len())
+    }
+}
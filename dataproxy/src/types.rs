@@ -3,6 +3,7 @@ use std::{collections::HashMap, fmt::Write};
 use bytes::BytesMut;
 use mysql::{prelude::Queryable, FromValueError, PooledConn, Value};
 
+use crate::migration::NARROWING_COMMENT;
 use codegen::FromRow;
 use mysql::prelude::{ConvIr, FromValue};
 use std::cmp::Ordering;
@@ -78,6 +79,56 @@ impl Column {
     }
 }
 
+/// 列类型的类别、容量以及是否无符号，整数的显示宽度不影响容量
+fn type_capacity(field_type: &str) -> (String, u64, bool) {
+    let field_type = field_type.to_ascii_lowercase();
+    let unsigned = field_type.contains("unsigned");
+    let end = field_type
+        .find(|c: char| c == '(' || c == ' ')
+        .unwrap_or(field_type.len());
+    let name = &field_type[..end];
+    let len = field_type[end..]
+        .trim_start_matches('(')
+        .split(|c: char| !c.is_ascii_digit())
+        .next()
+        .and_then(|len| len.parse().ok())
+        .unwrap_or(0);
+    let (family, size) = match name {
+        "tinyint" => ("integer", 1),
+        "smallint" => ("integer", 2),
+        "mediumint" => ("integer", 3),
+        "int" | "integer" => ("integer", 4),
+        "bigint" => ("integer", 8),
+        "float" => ("float", 4),
+        "double" => ("float", 8),
+        "tinyblob" => ("blob", 1 << 8),
+        "blob" => ("blob", 1 << 16),
+        "mediumblob" => ("blob", 1 << 24),
+        "longblob" => ("blob", 1 << 32),
+        "tinytext" => ("text", 1 << 8),
+        "text" => ("text", 1 << 16),
+        "mediumtext" => ("text", 1 << 24),
+        "longtext" => ("text", 1 << 32),
+        "char" | "varchar" => ("char", len),
+        "binary" | "varbinary" => ("binary", len),
+        name => (name, len),
+    };
+    (family.into(), size, unsigned)
+}
+
+impl Column {
+    /// 从old修改为当前类型时是否可能截断或者丢失数据，
+    /// 包括类型类别变化、容量变小、整数符号变化以及NULL改为NOT NULL
+    pub fn is_narrowing(&self, old: &Column) -> bool {
+        let (family, size, unsigned) = type_capacity(&self.field_type);
+        let (old_family, old_size, old_unsigned) = type_capacity(&old.field_type);
+        family != old_family
+            || size < old_size
+            || (family == "integer" && unsigned != old_unsigned)
+            || (self.null == BoolValue::No && old.null == BoolValue::Yes)
+    }
+}
+
 impl PartialEq for Column {
     fn eq(&self, other: &Self) -> bool {
         self.field.eq_ignore_ascii_case(other.field.as_str())
@@ -290,7 +341,7 @@ impl Table {
                 }
                 Ordering::Equal => {
                     if new_columns[i].1 != old_columns[j].1 {
-                        modified_columns.push(new_columns[i]);
+                        modified_columns.push((new_columns[i].1, old_columns[j].1));
                     }
                     i += 1;
                     j += 1;
@@ -325,12 +376,16 @@ impl Table {
             result.push(sql);
         }
 
-        for (_, column) in modified_columns {
-            let sql = format!(
+        for (column, old) in modified_columns {
+            let mut sql = format!(
                 "ALTER TABLE `{}` MODIFY COLUMN {}",
                 self.status.name,
                 column.to_sql()
             );
+            // 迁移时只能看到语句，缩小类型的修改通过注释标记为破坏性变更
+            if column.is_narrowing(old) {
+                sql = format!("{} {}", sql, NARROWING_COMMENT);
+            }
             result.push(sql);
         }
 
@@ -0,0 +1,497 @@
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use mysql::{prelude::Queryable, Params, Pool, PooledConn};
+use redis::{Client, Commands, Connection};
+use specs::{shrev::EventChannel, Component, Entity, HashMapStorage, System, Write, WriteStorage};
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+/// 一条保存请求，sql为生成的UPSERT语句
+pub struct SaveRequest {
+    pub entity: Entity,
+    pub table: &'static str,
+    /// 分片序号，由配置的分片字段取模得到，没有分片时为0，超出连接池数量的请求直接返回失败
+    pub shard: usize,
+    pub sql: String,
+    pub params: Params,
+}
+
+/// 一条redis保存请求，value为序列化后的数据集，保存在哈希表key的field字段中
+pub struct RedisRequest {
+    pub entity: Entity,
+    pub key: String,
+    pub field: &'static str,
+    pub value: Vec<u8>,
+}
+
+/// 保存结果，由写线程返回后作为组件插入到实体上
+#[derive(Debug, Clone, PartialEq)]
+pub enum SaveResult {
+    Saved,
+    Failed(String),
+}
+
+impl Component for SaveResult {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// 写线程返回的保存结果，table为表名或者redis的哈希字段，由写线程每帧发布到`EventChannel<SaveReport>`
+#[derive(Debug, Clone)]
+pub struct SaveReport {
+    pub entity: Entity,
+    pub table: &'static str,
+    pub result: SaveResult,
+}
+
+/// 写入失败时的重试以及熔断配置
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// 临时错误的最大重试次数
+    pub retries: usize,
+    /// 第一次重试的等待时间，之后每次翻倍
+    pub backoff: Duration,
+    /// 重试以及重连的最长等待时间
+    pub max_backoff: Duration,
+    /// 数据库断开期间最多缓存的请求数量，超出的请求直接返回失败
+    pub queue_cap: usize,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            retries: 3,
+            backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(10),
+            queue_cap: 100000,
+        }
+    }
+}
+
+impl RetryPolicy {
+    fn backoff(&self, attempt: usize) -> Duration {
+        self.backoff
+            .checked_mul(1 << attempt.min(16) as u32)
+            .map_or(self.max_backoff, |backoff| backoff.min(self.max_backoff))
+    }
+}
+
+/// 数据库连接状态，由`Writer`每帧更新到World中
+#[derive(Debug, Clone)]
+pub struct DatabaseHealth {
+    /// 数据库是否可用，不可用时保存请求被缓存起来
+    pub up: bool,
+    /// 断开期间缓存的请求数量
+    pub queued: usize,
+    /// 累计失败的请求数量
+    pub failures: usize,
+    pub last_error: Option<String>,
+}
+
+impl Default for DatabaseHealth {
+    fn default() -> Self {
+        Self {
+            up: true,
+            queued: 0,
+            failures: 0,
+            last_error: None,
+        }
+    }
+}
+
+/// 异步数据库写线程池，主循环只负责发送保存请求，不会阻塞在MySQL上。
+/// 同一个实体的请求总是由同一个写线程执行，保证写入顺序。
+/// 临时错误会按退避时间重试，连接断开时熔断，缓存后续请求直到重新连接成功
+pub struct Writer {
+    sender: Sender<SaveRequest>,
+    receiver: Receiver<SaveReport>,
+    health: Vec<Arc<Mutex<DatabaseHealth>>>,
+}
+
+impl Writer {
+    pub fn new(pool: Pool, workers: usize) -> Self {
+        Self::with_policy(pool, workers, RetryPolicy::default())
+    }
+
+    pub fn with_policy(pool: Pool, workers: usize, policy: RetryPolicy) -> Self {
+        Self::with_shards(vec![pool], 1, workers, policy)
+    }
+
+    /// 每个分片一个连接池，请求按`SaveRequest::shard`路由，每个分片有独立的写线程以及熔断状态，
+    /// shards为数据集配置的分片数量(`DataBackend::SHARDS`)，必须与连接池数量一致
+    pub fn with_shards(
+        pools: Vec<Pool>,
+        shards: usize,
+        workers: usize,
+        policy: RetryPolicy,
+    ) -> Self {
+        assert!(!pools.is_empty(), "no database pool for writer");
+        assert_eq!(
+            pools.len(),
+            shards,
+            "database pool count does not match shard count"
+        );
+        let (sender, requests) = unbounded::<SaveRequest>();
+        let (results, receiver) = unbounded();
+        let reports = results.clone();
+        let (shards, health): (Vec<_>, Vec<_>) = pools
+            .into_iter()
+            .enumerate()
+            .map(|(shard, pool)| {
+                Self::spawn_shard(shard, pool, workers, results.clone(), policy.clone())
+            })
+            .unzip();
+        std::thread::Builder::new()
+            .name("db_writer".into())
+            .spawn(move || {
+                for request in requests {
+                    let shard = request.shard;
+                    if shard >= shards.len() {
+                        log::error!("save request shard:{} out of range", shard);
+                        let _ = reports.send(SaveReport {
+                            entity: request.entity,
+                            table: request.table,
+                            result: SaveResult::Failed(format!("invalid shard:{}", shard)),
+                        });
+                        continue;
+                    }
+                    if let Err(err) = shards[shard].send(request) {
+                        log::error!("send save request to shard:{} failed:{}", shard, err);
+                    }
+                }
+            })
+            .unwrap();
+        Self {
+            sender,
+            receiver,
+            health,
+        }
+    }
+
+    fn spawn_shard(
+        shard: usize,
+        pool: Pool,
+        workers: usize,
+        results: Sender<SaveReport>,
+        policy: RetryPolicy,
+    ) -> (Sender<SaveRequest>, Arc<Mutex<DatabaseHealth>>) {
+        let (sender, requests) = unbounded::<SaveRequest>();
+        let health = Arc::new(Mutex::new(DatabaseHealth::default()));
+        let queues: Vec<_> = (0..workers.max(1))
+            .map(|index| {
+                let (sender, receiver) = unbounded();
+                let pool = pool.clone();
+                let results = results.clone();
+                let health = health.clone();
+                let policy = policy.clone();
+                std::thread::Builder::new()
+                    .name(format!("db_writer_{}_{}", shard, index))
+                    .spawn(move || Self::run_worker(pool, receiver, results, health, policy))
+                    .unwrap();
+                sender
+            })
+            .collect();
+        let router_health = health.clone();
+        std::thread::Builder::new()
+            .name(format!("db_router_{}", shard))
+            .spawn(move || Self::run_router(pool, requests, queues, results, router_health, policy))
+            .unwrap();
+        (sender, health)
+    }
+
+    /// 保存请求的发送端，可以在多个系统中使用
+    pub fn sender(&self) -> Sender<SaveRequest> {
+        self.sender.clone()
+    }
+
+    /// 还没有分配给写线程的请求数量
+    pub fn pending(&self) -> usize {
+        self.sender.len()
+    }
+
+    /// 当前的数据库连接状态，有分片时任意分片断开都视为断开
+    pub fn health(&self) -> DatabaseHealth {
+        self.health
+            .iter()
+            .fold(DatabaseHealth::default(), |mut total, health| {
+                let health = health.lock().unwrap();
+                total.up &= health.up;
+                total.queued += health.queued;
+                total.failures += health.failures;
+                if health.last_error.is_some() {
+                    total.last_error = health.last_error.clone();
+                }
+                total
+            })
+    }
+
+    /// 按实体分配请求到写线程，熔断期间缓存请求并按退避时间探测数据库
+    fn run_router(
+        pool: Pool,
+        requests: Receiver<SaveRequest>,
+        queues: Vec<Sender<SaveRequest>>,
+        results: Sender<SaveReport>,
+        health: Arc<Mutex<DatabaseHealth>>,
+        policy: RetryPolicy,
+    ) {
+        let mut backlog = VecDeque::new();
+        let mut attempt = 0;
+        let mut next_probe = Instant::now();
+        let mut was_up = true;
+        loop {
+            let up = health.lock().unwrap().up;
+            if was_up && !up {
+                attempt = 0;
+                next_probe = Instant::now() + policy.backoff(attempt);
+            }
+            was_up = up;
+            let request = if up {
+                match backlog.pop_front() {
+                    Some(request) => {
+                        health.lock().unwrap().queued = backlog.len();
+                        Some(request)
+                    }
+                    None => match requests.recv() {
+                        Ok(request) => Some(request),
+                        Err(_) => break,
+                    },
+                }
+            } else {
+                match requests.recv_timeout(next_probe.saturating_duration_since(Instant::now())) {
+                    Ok(request) => {
+                        if backlog.len() < policy.queue_cap {
+                            backlog.push_back(request);
+                        } else {
+                            let _ = results.send(SaveReport {
+                                entity: request.entity,
+                                table: request.table,
+                                result: SaveResult::Failed("database down, queue full".into()),
+                            });
+                        }
+                    }
+                    Err(RecvTimeoutError::Timeout) => {
+                        let alive = pool.get_conn().map_or(false, |mut conn| conn.ping());
+                        if alive {
+                            log::warn!("database reconnected, {} requests queued", backlog.len());
+                            attempt = 0;
+                            health.lock().unwrap().up = true;
+                        } else {
+                            attempt += 1;
+                            next_probe = Instant::now() + policy.backoff(attempt);
+                        }
+                    }
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+                health.lock().unwrap().queued = backlog.len();
+                None
+            };
+            if let Some(request) = request {
+                let index = request.entity.id() as usize % queues.len();
+                if let Err(err) = queues[index].send(request) {
+                    log::error!("send save request to worker failed:{}", err);
+                }
+            }
+        }
+        // 退出前尽量写完缓存的请求
+        for request in backlog {
+            let index = request.entity.id() as usize % queues.len();
+            let _ = queues[index].send(request);
+        }
+    }
+
+    /// 临时错误，重试可能成功
+    fn is_transient(err: &mysql::Error) -> bool {
+        match err {
+            mysql::Error::MySqlError(err) => matches!(err.code, 1205 | 1213 | 2006 | 2013),
+            _ => Self::is_disconnected(err),
+        }
+    }
+
+    /// 连接断开，需要熔断
+    fn is_disconnected(err: &mysql::Error) -> bool {
+        match err {
+            mysql::Error::IoError(_) | mysql::Error::DriverError(_) => true,
+            mysql::Error::MySqlError(err) => matches!(err.code, 2006 | 2013),
+            _ => false,
+        }
+    }
+
+    fn execute(
+        pool: &Pool,
+        conn: &mut Option<PooledConn>,
+        sql: &str,
+        params: &Params,
+        policy: &RetryPolicy,
+    ) -> mysql::Result<()> {
+        let mut attempt = 0;
+        loop {
+            let result = match conn {
+                Some(conn) => Ok(conn),
+                None => pool.get_conn().map(|c| conn.get_or_insert(c)),
+            }
+            .and_then(|c| c.exec_drop(sql, params.clone()));
+            match result {
+                Ok(_) => return Ok(()),
+                Err(err) if attempt < policy.retries && Self::is_transient(&err) => {
+                    if Self::is_disconnected(&err) {
+                        conn.take();
+                    }
+                    log::warn!("execute failed:{}, retry {}", err, attempt + 1);
+                    std::thread::sleep(policy.backoff(attempt));
+                    attempt += 1;
+                }
+                Err(err) => {
+                    if Self::is_disconnected(&err) {
+                        conn.take();
+                    }
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    fn run_worker(
+        pool: Pool,
+        receiver: Receiver<SaveRequest>,
+        results: Sender<SaveReport>,
+        health: Arc<Mutex<DatabaseHealth>>,
+        policy: RetryPolicy,
+    ) {
+        let mut conn = None;
+        for request in receiver {
+            let SaveRequest {
+                entity,
+                table,
+                sql,
+                params,
+                ..
+            } = request;
+            let result = match Self::execute(&pool, &mut conn, sql.as_str(), &params, &policy) {
+                Ok(_) => SaveResult::Saved,
+                Err(err) => {
+                    log::error!("save entity:{} to {} failed:{}", entity.id(), table, err);
+                    let mut health = health.lock().unwrap();
+                    if Self::is_disconnected(&err) && health.up {
+                        log::error!("database down, pause writing");
+                        health.up = false;
+                    }
+                    health.failures += 1;
+                    health.last_error = Some(err.to_string());
+                    SaveResult::Failed(err.to_string())
+                }
+            };
+            let report = SaveReport {
+                entity,
+                table,
+                result,
+            };
+            if results.send(report).is_err() {
+                log::warn!("writer dropped, save result discarded");
+            }
+        }
+    }
+}
+
+impl<'a> System<'a> for Writer {
+    type SystemData = (
+        WriteStorage<'a, SaveResult>,
+        Write<'a, EventChannel<SaveReport>>,
+        Write<'a, DatabaseHealth>,
+    );
+
+    fn run(&mut self, (mut results, mut reports, mut health): Self::SystemData) {
+        *health = self.health();
+        publish_reports(&self.receiver, &mut results, &mut reports);
+    }
+}
+
+/// 保存结果插入到实体上，同时发布给`DatabaseSystem`，写入失败时恢复修改记录
+fn publish_reports(
+    receiver: &Receiver<SaveReport>,
+    results: &mut WriteStorage<SaveResult>,
+    reports: &mut EventChannel<SaveReport>,
+) {
+    receiver.try_iter().for_each(|report| {
+        if let Err(err) = results.insert(report.entity, report.result.clone()) {
+            log::warn!("entity:{} dead before saved:{}", report.entity.id(), err);
+        }
+        reports.single_write(report);
+    });
+}
+
+/// redis后台写线程，用于需要持久化但不需要关系查询的热数据，单线程执行保证写入顺序
+pub struct RedisWriter {
+    sender: Sender<RedisRequest>,
+    receiver: Receiver<SaveReport>,
+}
+
+impl RedisWriter {
+    pub fn new(client: Client) -> Self {
+        let (sender, requests) = unbounded::<RedisRequest>();
+        let (results, receiver) = unbounded();
+        std::thread::Builder::new()
+            .name("redis_writer".into())
+            .spawn(move || {
+                let mut conn: Option<Connection> = None;
+                for request in requests {
+                    let result = match conn.take() {
+                        Some(conn) => Ok(conn),
+                        None => client.get_connection(),
+                    }
+                    .and_then(|mut c| {
+                        let result =
+                            c.hset::<_, _, _, ()>(&request.key, request.field, &request.value);
+                        // 出错时丢弃连接，下一次请求重新连接
+                        if result.is_ok() {
+                            conn = Some(c);
+                        }
+                        result
+                    });
+                    let result = match result {
+                        Ok(_) => SaveResult::Saved,
+                        Err(err) => {
+                            log::error!(
+                                "save entity:{} to redis {} failed:{}",
+                                request.entity.id(),
+                                request.key,
+                                err
+                            );
+                            SaveResult::Failed(err.to_string())
+                        }
+                    };
+                    let report = SaveReport {
+                        entity: request.entity,
+                        table: request.field,
+                        result,
+                    };
+                    if results.send(report).is_err() {
+                        log::warn!("redis writer dropped, save result discarded");
+                    }
+                }
+            })
+            .unwrap();
+        Self { sender, receiver }
+    }
+
+    /// 保存请求的发送端，可以在多个系统中使用
+    pub fn sender(&self) -> Sender<RedisRequest> {
+        self.sender.clone()
+    }
+
+    /// 还没有执行的请求数量
+    pub fn pending(&self) -> usize {
+        self.sender.len()
+    }
+}
+
+impl<'a> System<'a> for RedisWriter {
+    type SystemData = (
+        WriteStorage<'a, SaveResult>,
+        Write<'a, EventChannel<SaveReport>>,
+    );
+
+    fn run(&mut self, (mut results, mut reports): Self::SystemData) {
+        publish_reports(&self.receiver, &mut results, &mut reports);
+    }
+}
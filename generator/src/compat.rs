@@ -0,0 +1,169 @@
+use crate::{parse_config, read_files, ConfigFile, Error};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::Read,
+    path::PathBuf,
+};
+
+/// 上一次生成的消息定义，字段编号 -> (类型，名称)
+#[derive(Default)]
+struct ProtoMessage {
+    fields: HashMap<u32, (String, String)>,
+    reserved: HashSet<u32>,
+}
+
+/// 解析gen_message生成的.proto文件，只支持其生成的格式
+fn parse_proto(path: &PathBuf) -> Result<HashMap<String, ProtoMessage>, Error> {
+    let mut data = String::new();
+    File::open(path)?.read_to_string(&mut data)?;
+    let mut messages = HashMap::new();
+    let mut current: Option<(String, ProtoMessage)> = None;
+    for line in data.lines().map(str::trim) {
+        if let Some(name) = line
+            .strip_prefix("message ")
+            .and_then(|line| line.strip_suffix(" {"))
+        {
+            current = Some((name.to_string(), ProtoMessage::default()));
+        } else if line == "}" {
+            if let Some((name, message)) = current.take() {
+                messages.insert(name, message);
+            }
+        } else if let Some((_, message)) = &mut current {
+            let line = line.trim_end_matches(';');
+            if let Some(numbers) = line.strip_prefix("reserved ") {
+                numbers
+                    .split(',')
+                    .filter_map(|n| n.trim().parse().ok())
+                    .for_each(|n| {
+                        message.reserved.insert(n);
+                    });
+            } else if let Some((left, number)) = line.rsplit_once(" = ") {
+                if let (Some((r#type, name)), Ok(number)) = (left.rsplit_once(' '), number.parse())
+                {
+                    message
+                        .fields
+                        .insert(number, (r#type.to_string(), name.to_string()));
+                }
+            }
+        }
+    }
+    Ok(messages)
+}
+
+fn check_config(
+    path: &PathBuf,
+    cf: &ConfigFile,
+    previous: &HashMap<String, ProtoMessage>,
+    mask: bool,
+) -> Result<(), Error> {
+    for config in &cf.configs {
+        let reserved: HashSet<u32> = config
+            .reserved
+            .as_ref()
+            .map(|reserved| reserved.iter().cloned().collect())
+            .unwrap_or_default();
+        for field in &config.fields {
+            if mask && field.index >= 64 {
+                return Err(Error::FieldNumberOverflow(
+                    path.clone(),
+                    config.name.clone(),
+                    field.name.clone(),
+                ));
+            }
+            if reserved.contains(&field.index) {
+                return Err(Error::ReservedFieldReused(
+                    path.clone(),
+                    config.name.clone(),
+                    field.index,
+                ));
+            }
+        }
+
+        let message = match previous.get(&config.name) {
+            Some(message) => message,
+            None => continue,
+        };
+        // 数据集的_deleted和_mask字段编号跟随最大字段编号，编号不能因为增删字段而变化
+        let internals: HashMap<&str, (&str, u32)> = if mask {
+            vec![
+                ("_deleted", ("bool", config.max_number() + 1)),
+                ("_mask", ("uint64", config.max_number() + 2)),
+            ]
+            .into_iter()
+            .collect()
+        } else {
+            HashMap::new()
+        };
+        for n in &message.reserved {
+            if !reserved.contains(n) {
+                return Err(Error::RemovedFieldNotReserved(
+                    path.clone(),
+                    config.name.clone(),
+                    *n,
+                ));
+            }
+        }
+        for (n, (r#type, name)) in &message.fields {
+            if let Some((internal_type, number)) = internals.get(name.as_str()) {
+                if number != n || internal_type != r#type {
+                    return Err(Error::InternalFieldMoved(
+                        path.clone(),
+                        config.name.clone(),
+                        name.clone(),
+                        *n,
+                    ));
+                }
+                continue;
+            }
+            match config.fields.iter().find(|field| field.index == *n) {
+                Some(field) => {
+                    if field.r#type.to_pb_type() != *r#type {
+                        return Err(Error::IncompatibleFieldType(
+                            path.clone(),
+                            config.name.clone(),
+                            field.name.clone(),
+                        ));
+                    }
+                }
+                None => {
+                    if !reserved.contains(n) {
+                        return Err(Error::RemovedFieldNotReserved(
+                            path.clone(),
+                            config.name.clone(),
+                            *n,
+                        ));
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+/// 与上一次生成的.proto文件比较，检查协议的前后兼容性
+/// 已有字段不能修改类型，删除的字段编号需要保留在reserved中且不能再使用，数据集的字段编号不能超过63，
+/// 数据集内部的_deleted和_mask字段编号不能变化
+pub fn check_compatibility(
+    config_type: &str,
+    mut config_dir: PathBuf,
+    mut previous_dir: PathBuf,
+) -> Result<(), Error> {
+    config_dir.push(config_type);
+    previous_dir.push(config_type);
+    let mask = config_type == "dataset";
+
+    let mut previous = HashMap::new();
+    if previous_dir.exists() {
+        for path in read_files(previous_dir)? {
+            if path.extension().map_or(false, |ext| ext == "proto") {
+                previous.extend(parse_proto(&path)?);
+            }
+        }
+    }
+
+    for (path, cf) in parse_config(config_dir)? {
+        check_config(&path, &cf, &previous, mask)?;
+    }
+    Ok(())
+}
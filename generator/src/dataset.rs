@@ -1,12 +1,12 @@
 use crate::{
-    format_file, gen_messages, gen_protos, parse_config, string_to_u32, ConfigFile, DataType,
-    Error, IndexType, SyncDirection, Trait,
+    format_file, gen_messages, gen_protos, parse_config, string_to_u32, Config, ConfigFile,
+    DataType, Error, IndexType, SyncDirection, Trait,
 };
 use bytes::BytesMut;
 use convert_case::{Case, Casing};
 use proc_macro2::{Ident, TokenStream};
 use quote::{format_ident, quote};
-use std::{fmt::Write as _, fs::File, io::Write, path::PathBuf};
+use std::{collections::HashSet, fmt::Write as _, fs::File, io::Write, path::PathBuf};
 
 fn validate(configs: &Vec<(PathBuf, ConfigFile)>) -> Result<(), Error> {
     for (path, cf) in configs {
@@ -38,6 +38,82 @@ fn validate(configs: &Vec<(PathBuf, ConfigFile)>) -> Result<(), Error> {
                     }
                 }
             }
+            for f in &config.fields {
+                let expire = match &f.expire {
+                    Some(expire) => expire,
+                    None => continue,
+                };
+                let valid = match &f.r#type {
+                    DataType::Map { value, .. } => match value.as_ref() {
+                        DataType::Custom { r#type, .. } => find_config(configs, r#type)
+                            .and_then(|c| c.get_field(expire))
+                            .map_or(false, |field| matches!(field.r#type, DataType::U64)),
+                        _ => false,
+                    },
+                    _ => false,
+                };
+                if !valid {
+                    return Err(Error::InvalidExpireField(
+                        path.clone(),
+                        config.name.clone(),
+                        f.name.clone(),
+                    ));
+                }
+            }
+            if let Some(lod_fields) = &config.lod_fields {
+                for name in lod_fields {
+                    // 与生成掩码时一样按字段名精确匹配
+                    if !config.fields.iter().any(|field| field.name == *name) {
+                        return Err(Error::InvalidLodField(
+                            path.clone(),
+                            config.name.clone(),
+                            name.clone(),
+                        ));
+                    }
+                }
+            }
+            if let Some(pattern) = config.get_redis_key_pattern() {
+                let (_, fields) = Config::parse_redis_key_pattern(pattern);
+                for name in fields {
+                    if config.get_field(name.as_str()).is_none() {
+                        return Err(Error::InvalidRedisKeyField(
+                            path.clone(),
+                            config.name.clone(),
+                            name,
+                        ));
+                    }
+                }
+            }
+            if let Some(1) = config.group {
+                return Err(Error::InvalidGroup(path.clone(), config.name.clone()));
+            }
+            if let Some(0) = config.get_movement_rate() {
+                return Err(Error::InvalidMovementRate(
+                    path.clone(),
+                    config.name.clone(),
+                ));
+            }
+            if let Some(shard) = &config.shard {
+                let primary = config
+                    .indexes
+                    .as_ref()
+                    .and_then(|indexes| indexes.get(&IndexType::Primary))
+                    .map_or(false, |index| index.columns.contains(&shard.field));
+                let valid = shard.count > 0
+                    && primary
+                    && config.is_database_column(shard.field.as_str())
+                    && matches!(
+                        config.get_field(shard.field.as_str()).unwrap().r#type,
+                        DataType::U32 { .. } | DataType::U64 | DataType::S32 { .. } | DataType::S64
+                    );
+                if !valid {
+                    return Err(Error::InvalidShardKey(
+                        path.clone(),
+                        config.name.clone(),
+                        shard.field.clone(),
+                    ));
+                }
+            }
             if let Some(indexes) = &config.indexes {
                 for (index_type, index) in indexes {
                     let mut names = index.columns.clone();
@@ -81,9 +157,163 @@ fn validate(configs: &Vec<(PathBuf, ConfigFile)>) -> Result<(), Error> {
     Ok(())
 }
 
+fn find_config<'a>(configs: &'a Vec<(PathBuf, ConfigFile)>, name: &str) -> Option<&'a Config> {
+    let name = name.rsplit('.').next().unwrap();
+    configs
+        .iter()
+        .flat_map(|(_, cf)| cf.configs.iter())
+        .find(|c| c.name == name)
+}
+
+fn custom_type(data_type: &DataType) -> Option<&str> {
+    match data_type {
+        DataType::Custom { r#type, .. } => Some(r#type.rsplit('.').next().unwrap()),
+        _ => None,
+    }
+}
+
+/// 直接或者间接包含过期条目的数据类型
+fn expire_types(configs: &Vec<(PathBuf, ConfigFile)>) -> HashSet<String> {
+    let mut types = HashSet::new();
+    loop {
+        let count = types.len();
+        for (_, cf) in configs {
+            for c in &cf.configs {
+                if c.fields.iter().any(|f| {
+                    f.expire.is_some()
+                        || match &f.r#type {
+                            DataType::Map { value, .. } => custom_type(value),
+                            data_type => custom_type(data_type),
+                        }
+                        .map_or(false, |name| types.contains(name))
+                }) {
+                    types.insert(c.name.clone());
+                }
+            }
+        }
+        if count == types.len() {
+            return types;
+        }
+    }
+}
+
+fn gen_expire_code(
+    configs: &Vec<(PathBuf, ConfigFile)>,
+    types: &HashSet<String>,
+) -> Vec<TokenStream> {
+    let mut codes = Vec::new();
+    for (f, cf) in configs {
+        let mod_name = format_ident!("{}", f.file_stem().unwrap().to_str().unwrap());
+        for c in &cf.configs {
+            if !types.contains(&c.name) {
+                continue;
+            }
+            let name = format_ident!("{}", c.name);
+            let mut map_names = Vec::new();
+            let mut map_mut_names = Vec::new();
+            let mut map_expires = Vec::new();
+            let mut single_names = Vec::new();
+            let mut single_mut_names = Vec::new();
+            let mut nested_names = Vec::new();
+            let mut nested_mut_names = Vec::new();
+            for f in &c.fields {
+                let get_name = format_ident!("get_{}", f.name);
+                let mut_name = format_ident!("mut_{}", f.name);
+                if let Some(expire) = &f.expire {
+                    map_names.push(get_name.clone());
+                    map_mut_names.push(mut_name.clone());
+                    map_expires.push(format_ident!("get_{}", expire));
+                }
+                match &f.r#type {
+                    DataType::Map { value, .. } => {
+                        if custom_type(value).map_or(false, |name| types.contains(name)) {
+                            nested_names.push(get_name);
+                            nested_mut_names.push(mut_name);
+                        }
+                    }
+                    data_type => {
+                        if custom_type(data_type).map_or(false, |name| types.contains(name)) {
+                            single_names.push(get_name);
+                            single_mut_names.push(mut_name);
+                        }
+                    }
+                }
+            }
+            codes.push(quote!(
+                impl Expire for #mod_name::#name {
+                    fn has_expired(&self, now: u64) -> bool {
+                        #(
+                            if self.#map_names().values().any(|v| {
+                                let expire = v.#map_expires();
+                                expire != 0 && expire <= now
+                            }) {
+                                return true;
+                            }
+                        )*
+                        #(
+                            if self.#single_names().has_expired(now) {
+                                return true;
+                            }
+                        )*
+                        #(
+                            if self.#nested_names().values().any(|v| v.has_expired(now)) {
+                                return true;
+                            }
+                        )*
+                        false
+                    }
+
+                    fn expire(&mut self, now: u64) {
+                        #(
+                            let keys: Vec<_> = self
+                                .#map_names()
+                                .iter()
+                                .filter(|(_, v)| {
+                                    let expire = v.#map_expires();
+                                    expire != 0 && expire <= now
+                                })
+                                .map(|(k, _)| k.clone())
+                                .collect();
+                            if !keys.is_empty() {
+                                let map = self.#map_mut_names();
+                                for k in keys {
+                                    map.remove(&k);
+                                }
+                            }
+                        )*
+                        #(
+                            if self.#single_names().has_expired(now) {
+                                self.#single_mut_names().expire(now);
+                            }
+                        )*
+                        #(
+                            let keys: Vec<_> = self
+                                .#nested_names()
+                                .iter()
+                                .filter(|(_, v)| v.has_expired(now))
+                                .map(|(k, _)| k.clone())
+                                .collect();
+                            for k in keys {
+                                if let Some(v) = self.#nested_mut_names().get_mut(&k) {
+                                    v.expire(now);
+                                }
+                            }
+                        )*
+                    }
+                }
+            ));
+        }
+    }
+    codes
+}
+
 fn gen_position_code(name: &Ident, x: &Option<String>, y: &Option<String>) -> TokenStream {
-    let x = format_ident!("{}", x.clone().unwrap_or("get_x".into()));
-    let y = format_ident!("{}", y.clone().unwrap_or("get_y".into()));
+    let x = x.clone().unwrap_or("get_x".into());
+    let y = y.clone().unwrap_or("get_y".into());
+    let set_x = format_ident!("set_{}", x.trim_start_matches("get_"));
+    let set_y = format_ident!("set_{}", y.trim_start_matches("get_"));
+    let x = format_ident!("{}", x);
+    let y = format_ident!("{}", y);
 
     quote!(
         impl ecs_engine::Position for #name {
@@ -93,6 +323,12 @@ fn gen_position_code(name: &Ident, x: &Option<String>, y: &Option<String>) -> To
             fn y(&self) -> f32 {
                 self.data.#y()
             }
+            fn set_x(&mut self, x: f32) {
+                self.data.#set_x(x)
+            }
+            fn set_y(&mut self, y: f32) {
+                self.data.#set_y(y)
+            }
         }
     )
 }
@@ -147,13 +383,18 @@ fn gen_backend_code(
     select: &String,
     insert: &String,
     update: &String,
+    upsert: &String,
     delete: &String,
     columns: &Vec<TokenStream>,
     indexes: &Vec<TokenStream>,
     fields: &Vec<Ident>,
     field_types: &Vec<TokenStream>,
     customs: &Vec<u32>,
+    numbers: &Vec<u32>,
+    cond: &String,
     conds: &Vec<Ident>,
+    shard: TokenStream,
+    shards: usize,
 ) -> TokenStream {
     let rname = format_ident!("Mysql{}", name);
     let where_fields: Vec<_> = conds
@@ -193,6 +434,20 @@ fn gen_backend_code(
             }
         })
         .collect();
+    let (dirty_masks, (dirty_columns, dirty_fields)): (Vec<_>, (Vec<_>, Vec<_>)) = fields
+        .iter()
+        .enumerate()
+        .filter(|(index, _)| customs[*index] != 1)
+        .map(|(index, field)| {
+            let ident = format_ident!("get_{}", field);
+            let value = if customs[index] == 2 {
+                quote!(self.#ident().write_to_bytes()?)
+            } else {
+                quote!(self.#ident())
+            };
+            (1u64 << numbers[index], (format!("`{}` = ?", field), value))
+        })
+        .unzip();
     let select_fields: Vec<_> = fields
         .iter()
         .enumerate()
@@ -217,6 +472,12 @@ fn gen_backend_code(
         impl DataBackend for #name {
             type Connection = mysql::PooledConn;
             type Error = Error;
+            type Request = SaveRequest;
+            type Report = SaveReport;
+            const SHARDS: usize = #shards;
+            fn shard(&self) -> usize {
+                #shard
+            }
             fn patch_table(conn:&mut mysql::PooledConn, exec:bool, database:Option<&str>) -> Result<Vec<String>, Error> {
                 let mut new_table = Table::default();
                 new_table.set_engine("InnoDb");
@@ -232,9 +493,7 @@ fn gen_backend_code(
                 let old_table = Table::new(database, #table_name, conn)?;
                 let diff_sqls = new_table.diff(&old_table)?;
                 if exec {
-                    for sql in &diff_sqls {
-                        conn.exec_drop(sql, Params::Empty)?;
-                    }
+                    Migrator::default().apply(conn, #table_name, &diff_sqls)?;
                 }
                 Ok(diff_sqls)
             }
@@ -261,11 +520,99 @@ fn gen_backend_code(
                 Ok(result.affected_rows() == 1)
             }
 
+            fn dirty_request(&mut self, entity:Entity) -> Result<Option<SaveRequest>, Error> {
+                self.dirty_save_request(entity)
+            }
+
+            fn report_entity(report:&SaveReport) -> Option<Entity> {
+                if report.table == #table_name {
+                    Some(report.entity)
+                } else {
+                    None
+                }
+            }
+
+            fn confirm_save(&mut self, report:&SaveReport) -> bool {
+                self.save_confirmed(&report.result)
+            }
+
+            fn update_dirty(&mut self, conn:&mut mysql::PooledConn) -> Result<bool, Error> {
+                if let Some((sql, params)) = self.dirty_update()? {
+                    let result = conn.exec_iter(sql, params)?;
+                    self.database_mask.as_mut().unwrap().clear();
+                    Ok(result.affected_rows() == 1)
+                } else {
+                    Ok(false)
+                }
+            }
+
+            fn upsert(&mut self, conn:&mut mysql::PooledConn) -> Result<bool, Error> {
+                let result = conn.exec_iter(#upsert, self.upsert_params()?)?;
+                Ok(result.affected_rows() > 0)
+            }
+
             fn delete(self, conn:&mut mysql::PooledConn) -> Result<bool, Error> {
                 let result = conn.exec_iter(#delete, (#(#where_fields,)*))?;
                 Ok(result.affected_rows() == 1)
             }
         }
+
+        impl #name {
+            fn upsert_params(&self) -> Result<Params, Error> {
+                Ok(Params::Positional(vec![#(Value::from(#insert_fields),)*]))
+            }
+
+            /// 根据数据库方向的修改记录生成只包含修改字段的UPDATE语句，没有修改时返回None
+            fn dirty_update(&mut self) -> Result<Option<(String, Params)>, Error> {
+                let dirty = if let Some(mask) = &mut self.database_mask {
+                    self.data.mask_by_direction(SyncDirection::Database, mask);
+                    mask.mask
+                } else {
+                    return Ok(None);
+                };
+                let mut columns:Vec<&str> = Vec::new();
+                let mut values = Vec::new();
+                #(
+                    if dirty & #dirty_masks != 0 {
+                        columns.push(#dirty_columns);
+                        values.push(Value::from(#dirty_fields));
+                    }
+                )*
+                if columns.is_empty() {
+                    return Ok(None);
+                }
+                #(values.push(Value::from(#where_fields));)*
+                let sql = format!("UPDATE `{}` SET {} WHERE {}", #table_name, columns.join(", "), #cond);
+                Ok(Some((sql, Params::Positional(values))))
+            }
+
+            /// 生成只更新修改字段的保存请求，修改记录保留到写入结果返回，没有修改时返回None
+            pub fn dirty_save_request(&mut self, entity:Entity) -> Result<Option<SaveRequest>, Error> {
+                if let Some((sql, params)) = self.dirty_update()? {
+                    self.start_save();
+                    Ok(Some(SaveRequest {
+                        entity,
+                        table: #table_name,
+                        shard: <Self as DataBackend>::shard(self),
+                        sql,
+                        params,
+                    }))
+                } else {
+                    Ok(None)
+                }
+            }
+
+            /// 生成保存请求，交给`dataproxy::Writer`异步执行
+            pub fn save_request(&self, entity:Entity) -> Result<SaveRequest, Error> {
+                Ok(SaveRequest {
+                    entity,
+                    table: #table_name,
+                    shard: <Self as DataBackend>::shard(self),
+                    sql: #upsert.into(),
+                    params: self.upsert_params()?,
+                })
+            }
+        }
     }
 }
 
@@ -277,6 +624,12 @@ fn gen_dm_code(
     around_mask: u64,
     database_mask: u64,
     team_mask: u64,
+    observer_mask: u64,
+    world_mask: u64,
+    owner_mask: u64,
+    lod_mask: u64,
+    sync_rate: u32,
+    keyframe: u32,
     single_numbers: &Vec<usize>,
     single_names: &Vec<Ident>,
     map_numbers: &Vec<usize>,
@@ -284,6 +637,58 @@ fn gen_dm_code(
 ) -> TokenStream {
     quote! {
         impl DirectionMask for #mod_name::#name {
+            fn owner_mask() -> u64 {
+                #owner_mask
+            }
+
+            fn lod_mask() -> u64 {
+                #lod_mask
+            }
+
+            fn sync_rate() -> u32 {
+                #sync_rate
+            }
+
+            fn keyframe() -> u32 {
+                #keyframe
+            }
+
+            #[allow(unused_variables)]
+            fn diff_mask(&self, baseline: &mut DeltaBaseline, ms: &mut MaskSet, keyframe: bool) {
+                #(
+                    let mut unchanged = false;
+                    if let Some(set) = ms.set.get_mut(&#map_numbers) {
+                        let count = set.len();
+                        let last = baseline.entry(#map_numbers).or_default();
+                        if keyframe {
+                            last.clear();
+                        }
+                        let mut present = 0;
+                        for (k, f) in self.#map_names() {
+                            let key = k.clone().into();
+                            if !set.contains_key(&key) {
+                                continue;
+                            }
+                            present += 1;
+                            let digest = delta_digest(f);
+                            if last.insert(delta_digest(k), digest) == Some(digest) && !keyframe {
+                                set.remove(&key);
+                            }
+                        }
+                        // 删除的键不在map中，从基线中去掉已经不存在的键
+                        if present < count {
+                            let keys: std::collections::HashSet<_> =
+                                self.#map_names().keys().map(|k| delta_digest(k)).collect();
+                            last.retain(|k, _| keys.contains(k));
+                        }
+                        unchanged = count != 0 && set.is_empty();
+                    }
+                    if unchanged {
+                        ms.set.remove(&#map_numbers);
+                        ms.mask &= !(1u64 << #map_numbers);
+                    }
+                )*
+            }
 
             #[allow(unused_variables)]
             fn mask_by_direction(&self, dir:SyncDirection, ms: &mut MaskSet) {
@@ -292,6 +697,8 @@ fn gen_dm_code(
                     SyncDirection::Around => #around_mask,
                     SyncDirection::Database => #database_mask,
                     SyncDirection::Team => #team_mask,
+                    SyncDirection::Observer => #observer_mask,
+                    SyncDirection::World => #world_mask,
                 };
                 ms.mask &= mask;
                 ms.set.iter_mut().for_each(|(k, set)| {
@@ -322,13 +729,37 @@ fn gen_dm_code(
 
 fn gen_dataset_type() -> TokenStream {
     quote!(
-        #[derive(Debug, Default, Clone)]
+        #[derive(Debug, Default)]
         pub struct Type<T: Default + Clone, const N: usize, const C: u32> {
             data: T,
             database_mask: Option<MaskSet>,
             client_mask: Option<MaskSet>,
             around_mask: Option<MaskSet>,
             team_mask: Option<MaskSet>,
+            observer_mask: Option<MaskSet>,
+            world_mask: Option<MaskSet>,
+            /// 增量压缩时每个方向上次发送的map键值摘要以及距离上次完整数据的次数，
+            /// 基线按方向共享，同一方向的所有接收者收到的是同一份增量
+            last_sent: HashMap<usize, (DeltaBaseline, u32)>,
+            /// 已经发给写线程但还没有返回结果的数据库方向修改记录，以及请求数量
+            saving: (MaskSet, usize),
+        }
+
+        impl<T: Default + Clone, const N: usize, const C: u32> Clone for Type<T, N, C> {
+            /// 上次发送的数据不复制，复制出来的数据集第一次编码总是完整的
+            fn clone(&self) -> Self {
+                Self {
+                    data: self.data.clone(),
+                    database_mask: self.database_mask.clone(),
+                    client_mask: self.client_mask.clone(),
+                    around_mask: self.around_mask.clone(),
+                    team_mask: self.team_mask.clone(),
+                    observer_mask: self.observer_mask.clone(),
+                    world_mask: self.world_mask.clone(),
+                    last_sent: Default::default(),
+                    saving: Default::default(),
+                }
+            }
         }
 
         impl<T: Message + Default + Clone, const N: usize, const C: u32> Type<T, N, C> {
@@ -337,6 +768,8 @@ fn gen_dataset_type() -> TokenStream {
                 let client_mask: usize = SyncDirection::Client.into();
                 let database_mask: usize = SyncDirection::Database.into();
                 let team_mask: usize = SyncDirection::Team.into();
+                let observer_mask: usize = SyncDirection::Observer.into();
+                let world_mask: usize = SyncDirection::World.into();
 
                 let around_mask = if N & around_mask != 0 {
                     Some(MaskSet::default())
@@ -359,14 +792,53 @@ fn gen_dataset_type() -> TokenStream {
                 } else {
                     None
                 };
+                let observer_mask = if N & observer_mask != 0 {
+                    Some(MaskSet::default())
+                } else {
+                    None
+                };
+                let world_mask = if N & world_mask != 0 {
+                    Some(MaskSet::default())
+                } else {
+                    None
+                };
                 Self {
                     data: T::new(),
                     client_mask,
                     database_mask,
                     team_mask,
                     around_mask,
+                    observer_mask,
+                    world_mask,
+                    last_sent: Default::default(),
+                    saving: Default::default(),
+                }
+            }
+
+            /// 数据库方向的修改记录已经生成保存请求，转移到等待确认的记录中
+            fn start_save(&mut self) {
+                if let Some(mask) = &mut self.database_mask {
+                    self.saving.0 |= &*mask;
+                    self.saving.1 += 1;
+                    mask.clear();
                 }
             }
+
+            /// 处理写线程返回的保存结果，写入失败时把等待确认的修改记录合并回数据库方向，
+            /// 返回是否需要重新保存
+            pub fn save_confirmed(&mut self, result: &SaveResult) -> bool {
+                self.saving.1 = self.saving.1.saturating_sub(1);
+                let failed = matches!(result, SaveResult::Failed(_));
+                if failed {
+                    if let Some(mask) = &mut self.database_mask {
+                        *mask |= &self.saving.0;
+                    }
+                }
+                if failed || self.saving.1 == 0 {
+                    self.saving.0.clear();
+                }
+                failed
+            }
         }
 
         impl<T: Message + Default + Mask + DirectionMask + Clone, const N: usize, const C: u32>
@@ -390,6 +862,14 @@ fn gen_dataset_type() -> TokenStream {
                     let ms = ms.get_or_insert_with(|| self.data.mask_set());
                     *self.around_mask.as_mut().unwrap() |= ms;
                 }
+                if self.observer_mask.is_some() {
+                    let ms = ms.get_or_insert_with(|| self.data.mask_set());
+                    *self.observer_mask.as_mut().unwrap() |= ms;
+                }
+                if self.world_mask.is_some() {
+                    let ms = ms.get_or_insert_with(|| self.data.mask_set());
+                    *self.world_mask.as_mut().unwrap() |= ms;
+                }
                 self.data.clear_mask(true);
             }
 
@@ -427,7 +907,44 @@ fn gen_dataset_type() -> TokenStream {
                             return None;
                         }
                     }
+                    SyncDirection::Observer => {
+                        if let Some(mask) = &mut self.observer_mask {
+                            self.data.mask_by_direction(dir, mask);
+                            mask
+                        } else {
+                            return None;
+                        }
+                    }
+                    SyncDirection::World => {
+                        if let Some(mask) = &mut self.world_mask {
+                            self.data.mask_by_direction(dir, mask);
+                            mask
+                        } else {
+                            return None;
+                        }
+                    }
                 };
+                // 增量压缩，map字段只保留有变化的键，定期发送一次完整数据
+                let keyframe = T::keyframe();
+                if keyframe > 0 && dir != SyncDirection::Database {
+                    let index: usize = dir.into();
+                    match self.last_sent.get_mut(&index) {
+                        Some((baseline, count)) if *count < keyframe => {
+                            *count += 1;
+                            self.data.diff_mask(baseline, mask, false);
+                        }
+                        _ => {
+                            self.data.mask_all(true);
+                            let mut full = self.data.mask_set();
+                            *mask |= &mut full;
+                            self.data.clear_mask(true);
+                            self.data.mask_by_direction(dir, mask);
+                            let mut baseline = DeltaBaseline::default();
+                            self.data.diff_mask(&mut baseline, mask, true);
+                            self.last_sent.insert(index, (baseline, 0));
+                        }
+                    }
+                }
                 let mut data = vec![0u8; 12];
                 self.data.set_mask(mask);
                 if let Err(err) = self.data.write_to_vec(&mut data) {
@@ -445,6 +962,48 @@ fn gen_dataset_type() -> TokenStream {
                 Some(data)
             }
 
+            fn mask_owner_fields(&mut self) {
+                if let Some(mask) = &mut self.client_mask {
+                    let owner_mask = T::owner_mask();
+                    mask.mask &= !owner_mask;
+                    mask.set.retain(|k, _| owner_mask & (1 << *k as u64) == 0);
+                }
+            }
+
+            fn encode_lod(&mut self, id: u32) -> Option<Vec<u8>> {
+                let mut mask = self.around_mask.clone()?;
+                self.data
+                    .mask_by_direction(SyncDirection::Around, &mut mask);
+                let lod_mask = T::lod_mask();
+                mask.mask &= lod_mask;
+                mask.set.retain(|k, _| lod_mask & (1 << *k as u64) != 0);
+                if mask.mask == 0 {
+                    return None;
+                }
+                let mut data = vec![0u8; 12];
+                self.data.set_mask(&mask);
+                let result = self.data.write_to_vec(&mut data);
+                self.data.clear_mask(true);
+                if let Err(err) = result {
+                    log::error!("encode lod data failed:{}", err);
+                    return None;
+                }
+                let length = (data.len() - 4) as u32;
+                let header = data.as_mut_slice();
+                BigEndian::write_u32(header, length);
+                BigEndian::write_u32(&mut header[4..], id);
+                BigEndian::write_u32(&mut header[8..], C);
+                Some(data)
+            }
+
+            fn has_lod() -> bool {
+                T::lod_mask() != u64::MAX
+            }
+
+            fn sync_rate() -> u32 {
+                T::sync_rate()
+            }
+
             fn is_data_dirty(&self) -> bool {
                 self.data.is_dirty()
             }
@@ -455,6 +1014,32 @@ fn gen_dataset_type() -> TokenStream {
             }
         }
 
+        impl<T: Message + Default + Mask + Clone, const N: usize, const C: u32> Persist for Type<T, N, C> {
+            fn to_bytes(&self) -> Option<Vec<u8>> {
+                let mut data = self.data.clone();
+                data.mask_all(true);
+                match data.write_to_bytes() {
+                    Ok(bytes) => Some(bytes),
+                    Err(err) => {
+                        log::error!("encode snapshot failed:{}", err);
+                        None
+                    }
+                }
+            }
+
+            fn from_bytes(bytes: &[u8]) -> Option<Self> {
+                let mut data = T::new();
+                if let Err(err) = data.merge_from_bytes(bytes) {
+                    log::error!("decode snapshot failed:{}", err);
+                    return None;
+                }
+                data.clear_mask(true);
+                let mut t = Self::new();
+                t.data = data;
+                Some(t)
+            }
+        }
+
         impl<T: Default + Clone, const N: usize, const C: u32> Deref for Type<T, N, C> {
             type Target = T;
 
@@ -477,6 +1062,7 @@ pub fn gen_data_mask(configs: &Vec<(PathBuf, ConfigFile)>) -> Vec<TokenStream> {
         SyncDirection::Database,
         SyncDirection::Around,
         SyncDirection::Client,
+        SyncDirection::Observer,
     ];
     let mut dm_codes = Vec::new();
     for (f, cf) in configs {
@@ -486,23 +1072,43 @@ pub fn gen_data_mask(configs: &Vec<(PathBuf, ConfigFile)>) -> Vec<TokenStream> {
             let mut around_mask = 0u64;
             let mut database_mask = 0u64;
             let mut team_mask = 0u64;
+            let mut observer_mask = 0u64;
+            let mut world_mask = 0u64;
+            let mut owner_mask = 0u64;
             let mut single_numbers = Vec::new();
             let mut single_names = Vec::new();
             let mut map_numbers = Vec::new();
             let mut map_names = Vec::new();
 
+            let mut lod_mask = if c.lod_fields.is_some() {
+                0u64
+            } else {
+                u64::MAX
+            };
+            let sync_rate = c.get_movement_rate().unwrap_or(0);
+            let keyframe = c.delta_keyframe.unwrap_or(0);
             let vname = c.name.clone();
             let name = format_ident!("{}", c.name);
 
             for f in &c.fields {
                 let dirs = f.dirs.as_ref().unwrap_or(&all_dirs);
                 let mask = 1 << (f.index as u64);
+                if let Some(true) = f.owner {
+                    owner_mask |= mask;
+                }
+                if let Some(lod_fields) = &c.lod_fields {
+                    if lod_fields.contains(&f.name) {
+                        lod_mask |= mask;
+                    }
+                }
                 for dir in dirs {
                     match dir {
                         SyncDirection::Client => client_mask |= mask,
                         SyncDirection::Database => database_mask |= mask,
                         SyncDirection::Team => team_mask |= mask,
                         SyncDirection::Around => around_mask |= mask,
+                        SyncDirection::Observer => observer_mask |= mask,
+                        SyncDirection::World => world_mask |= mask,
                     }
                 }
                 let index = f.index as usize;
@@ -527,6 +1133,12 @@ pub fn gen_data_mask(configs: &Vec<(PathBuf, ConfigFile)>) -> Vec<TokenStream> {
                 around_mask,
                 database_mask,
                 team_mask,
+                observer_mask,
+                world_mask,
+                owner_mask,
+                lod_mask,
+                sync_rate,
+                keyframe,
                 &single_numbers,
                 &single_names,
                 &map_numbers,
@@ -538,6 +1150,68 @@ pub fn gen_data_mask(configs: &Vec<(PathBuf, ConfigFile)>) -> Vec<TokenStream> {
     dm_codes
 }
 
+fn gen_redis_code(name: &Ident, vname: &String, pattern: &String) -> TokenStream {
+    let (format, fields) = Config::parse_redis_key_pattern(pattern);
+    let getters: Vec<_> = fields
+        .iter()
+        .map(|field| format_ident!("get_{}", field))
+        .collect();
+    quote! {
+        impl #name {
+            /// redis中哈希表的键，同一实体的多个数据集可以共用一个键
+            pub fn redis_key(&self) -> String {
+                format!(#format, #(self.#getters(),)*)
+            }
+
+            /// 数据库方向有修改时序列化数据库方向的全部字段，需要在commit之后调用，
+            /// 修改记录保留到写入结果返回，`RedisWriter`返回的结果需要交给`save_confirmed`处理
+            pub fn redis_request(&mut self, entity:Entity) -> Result<Option<RedisRequest>, Error> {
+                if let Some(mask) = &mut self.database_mask {
+                    self.data.mask_by_direction(SyncDirection::Database, mask);
+                    if mask.is_empty() {
+                        return Ok(None);
+                    }
+                } else {
+                    return Ok(None);
+                }
+                self.start_save();
+                self.data.mask_all(true);
+                let mut mask = self.data.mask_set();
+                self.data.mask_by_direction(SyncDirection::Database, &mut mask);
+                self.data.set_mask(&mask);
+                let value = self.data.write_to_bytes();
+                self.data.clear_mask(true);
+                Ok(Some(RedisRequest {
+                    entity,
+                    key: self.redis_key(),
+                    field: #vname,
+                    value: value?,
+                }))
+            }
+        }
+    }
+}
+
+/// 是否需要生成数据库存取代码，保存到redis的数据集不生成
+fn has_backend(c: &Config) -> bool {
+    let all_dirs = vec![
+        SyncDirection::Team,
+        SyncDirection::Database,
+        SyncDirection::Around,
+        SyncDirection::Client,
+        SyncDirection::Observer,
+    ];
+    c.hide.is_none()
+        && c.get_redis_key_pattern().is_none()
+        && c.fields.iter().any(|field| {
+            field
+                .dirs
+                .as_ref()
+                .unwrap_or(&all_dirs)
+                .contains(&SyncDirection::Database)
+        })
+}
+
 pub fn gen_data_backend(
     configs: &Vec<(PathBuf, ConfigFile)>,
 ) -> Result<Vec<TokenStream>, std::fmt::Error> {
@@ -546,25 +1220,19 @@ pub fn gen_data_backend(
         SyncDirection::Database,
         SyncDirection::Around,
         SyncDirection::Client,
+        SyncDirection::Observer,
     ];
     let mut backend_codes = Vec::new();
 
     for (_, cf) in configs {
         for c in &cf.configs {
-            if c.hide.is_some()
-                || !c.fields.iter().any(|field| {
-                    field
-                        .dirs
-                        .as_ref()
-                        .unwrap_or(&all_dirs)
-                        .contains(&SyncDirection::Database)
-                })
-            {
+            if !has_backend(c) {
                 continue;
             }
 
             let mut columns = Vec::new();
             let mut customs = Vec::new();
+            let mut numbers = Vec::new();
             let mut fields = Vec::new();
             let mut rust_field_types = Vec::new();
 
@@ -575,6 +1243,7 @@ pub fn gen_data_backend(
             let mut select = BytesMut::new();
             let mut insert = BytesMut::new();
             let mut update = BytesMut::new();
+            let mut upsert = BytesMut::new();
             let mut delete = BytesMut::new();
             write!(select, "SELECT ")?;
             write!(insert, "INSERT INTO `{}` SET ", table_name)?;
@@ -600,11 +1269,13 @@ pub fn gen_data_backend(
                 let field_type = f.r#type.to_db_type();
 
                 fields.push(format_ident!("{}", field));
+                numbers.push(f.index);
                 rust_field_types.push(f.r#type.to_rust_type());
                 if c.is_primary_field(f.name.as_str()) {
                     customs.push(1);
                 } else {
                     write!(update, " `{}` = ?,", field)?;
+                    write!(upsert, " `{}` = VALUES(`{}`),", field, field)?;
                     if matches!(f.r#type, DataType::Custom { .. }) {
                         customs.push(2);
                     } else {
@@ -646,6 +1317,17 @@ pub fn gen_data_backend(
             )?;
             write!(update, " WHERE {}", c.get_primary_cond()?)?;
 
+            let (shard, shards) = match &c.shard {
+                Some(shard) => {
+                    let ident = format_ident!("get_{}", shard.field);
+                    let count = shard.count as u64;
+                    (
+                        quote!((self.#ident() as u64 % #count) as usize),
+                        shard.count as usize,
+                    )
+                }
+                None => (quote!(0), 1),
+            };
             let conds: Vec<_> = c
                 .get_primary_fields()
                 .iter()
@@ -654,6 +1336,15 @@ pub fn gen_data_backend(
             let select = unsafe { String::from_utf8_unchecked(select.to_vec()) };
             let insert = unsafe { String::from_utf8_unchecked(insert.to_vec()) };
             let update = unsafe { String::from_utf8_unchecked(update.to_vec()) };
+            let upsert = if upsert.is_empty() {
+                // 只有主键字段，已经存在时不需要更新
+                format!("INSERT IGNORE{}", &insert["INSERT".len()..])
+            } else {
+                upsert.truncate(upsert.len() - 1);
+                format!("{} ON DUPLICATE KEY UPDATE{}", insert, unsafe {
+                    String::from_utf8_unchecked(upsert.to_vec())
+                })
+            };
             let delete = unsafe { String::from_utf8_unchecked(delete.to_vec()) };
 
             let backend_code = gen_backend_code(
@@ -662,13 +1353,18 @@ pub fn gen_data_backend(
                 &select,
                 &insert,
                 &update,
+                &upsert,
                 &delete,
                 &columns,
                 &indexes,
                 &fields,
                 &rust_field_types,
                 &customs,
+                &numbers,
+                &c.get_primary_cond()?,
                 &conds,
+                shard,
+                shards,
             );
             backend_codes.push(backend_code);
         }
@@ -697,8 +1393,15 @@ pub fn gen_dataset(
     let mut inners = Vec::new();
     let mut ns = Vec::new();
     let mut cmds = Vec::new();
+    let mut groups = Vec::new();
     let mut vnames = Vec::new();
 
+    let mut deps = Vec::new();
+    let mut expire_names = Vec::new();
+    let mut expire_snames = Vec::new();
+    let types = expire_types(&configs);
+
+    let mut redis_codes = Vec::new();
     let mut position_code = quote!();
     let mut scene_data_code = quote!();
     for (f, cf) in &configs {
@@ -717,6 +1420,15 @@ pub fn gen_dataset(
                             storages.push(t.to_rust_type());
                             ns.push(c.get_dir_mask());
                             cmds.push(string_to_u32(vname.as_bytes()));
+                            groups.push(c.group.unwrap_or(0));
+                            if types.contains(&c.name) {
+                                let sname = format!("{}_expire", vname.to_case(Case::Snake));
+                                deps.push(quote!(&[#sname]));
+                                expire_names.push(name.clone());
+                                expire_snames.push(sname);
+                            } else {
+                                deps.push(quote!(&[]));
+                            }
                         }
                         Trait::Position { x, y } => {
                             if !position_code.is_empty() {
@@ -741,6 +1453,10 @@ pub fn gen_dataset(
                         Trait::DropEntity { .. } => {
                             return Err(Error::InvalidDropEntity);
                         }
+                        Trait::Redis { key_pattern } => {
+                            redis_codes.push(gen_redis_code(&name, &vname, key_pattern));
+                        }
+                        Trait::Movement { .. } => {}
                     }
                     if let Trait::Component { .. } = t {}
                 }
@@ -749,30 +1465,53 @@ pub fn gen_dataset(
             }
         }
     }
+    // 组件命令号由名字计算，不随编译顺序变化，但不同名字仍可能冲突
+    let mut n_cmds = cmds.clone();
+    n_cmds.sort();
+    n_cmds.dedup();
+    if n_cmds.len() != cmds.len() {
+        return Err(Error::DuplicateCmd);
+    }
+    let (backend_names, backend_snames): (Vec<_>, Vec<_>) = configs
+        .iter()
+        .flat_map(|(_, cf)| cf.configs.iter())
+        .filter(|c| has_backend(c))
+        .map(|c| (format_ident!("{}", c.name), c.name.clone()))
+        .unzip();
+    let database_snames: Vec<_> = backend_snames
+        .iter()
+        .map(|name| format!("{}_database", name.to_case(Case::Snake)))
+        .collect();
+    let check_names: Vec<_> = names.iter().map(|name| name.to_string()).collect();
     let dm_codes = gen_data_mask(&configs);
     let backend_codes = gen_data_backend(&configs)?;
     let dataset_type_code = gen_dataset_type();
+    let expire_codes = gen_expire_code(&configs, &types);
 
     let data = quote!(
             #![allow(unused_imports)]
             #(mod #mods;)*
 
             use byteorder::{BigEndian, ByteOrder};
-            use dataproxy::{BoolValue, Column, Index, Table};
+            use dataproxy::{
+                BoolValue, Column, Index, MigrateError, Migrator, RedisRequest, SaveReport, SaveRequest,
+                SaveResult, Table,
+            };
             use derive_more::From;
             use ecs_engine::{
-                CommitChangeSystem, DataBackend, DataSet, FromRow, GameDispatcherBuilder, SceneSyncBackend,
-                SyncDirection,
+                delta_digest, CommitChangeSystem, DataBackend, DataSet, DatabaseSystem, Expire, ExpireSystem, FromRow, GameDispatcherBuilder,
+                Persist, SceneSyncBackend, SnapshotRegistry, StartupCheck, SyncDirection,
             };
-            use mysql::{prelude::Queryable, Params};
+            use mysql::{prelude::Queryable, Params, Value};
             pub use player::Bag;
             use protobuf::{Mask, MaskSet, Message};
             use specs::{
-                Component, DefaultVecStorage, FlaggedStorage, HashMapStorage, NullStorage, Tracked, VecStorage,
-                World, WorldExt,
+                storage::MaskedStorage, Component, DefaultVecStorage, Entity, FlaggedStorage, HashMapStorage, NullStorage,
+                Tracked, VecStorage, World, WorldExt,
             };
             use std::{
                 any::Any,
+                collections::HashMap,
                 ops::{Deref, DerefMut},
             };
             #(pub use #inners;)*
@@ -790,20 +1529,34 @@ pub fn gen_dataset(
             #position_code
             #scene_data_code
 
+            /// 增量压缩的基线，字段编号到map键摘要以及值摘要
+            pub type DeltaBaseline = HashMap<usize, HashMap<u64, u64>>;
+
             pub trait DirectionMask {
+                fn owner_mask() -> u64;
+                fn lod_mask() -> u64;
+                fn sync_rate() -> u32;
+                fn keyframe() -> u32;
+                /// 去掉map字段中与基线相比没有变化的键并更新基线，keyframe时只重建基线
+                fn diff_mask(&self, baseline: &mut DeltaBaseline, ms: &mut MaskSet, keyframe: bool);
                 fn mask_by_direction(&self, direction: SyncDirection, ms: &mut MaskSet);
             }
             #(#dm_codes)*
 
+            #(#expire_codes)*
+
             #[derive(From, Debug)]
             pub enum Error {
                 Mysql(mysql::Error),
+                Migrate(MigrateError),
                 Format(std::fmt::Error),
                 Protobuf(protobuf::ProtobufError),
             }
 
             #(#backend_codes)*
 
+            #(#redis_codes)*
+
 
             pub fn setup<B>(world:&mut World, builder:&mut GameDispatcherBuilder)
             where
@@ -812,7 +1565,38 @@ pub fn gen_dataset(
                 <<B as SceneSyncBackend>::SceneData as Component>::Storage: Tracked + Default,
             {
                 #(
-                    builder.add(CommitChangeSystem::<#names, B>::new(world), #vnames, &[]);
+                    builder.add(ExpireSystem::<#expire_names>::default(), #expire_snames, &[]);
+                )*
+                #(
+                    builder.add(CommitChangeSystem::<#names, B, #groups>::new(world), #vnames, #deps);
+                )*
+                #(
+                    if let Some(system) = DatabaseSystem::<#backend_names>::try_new(world) {
+                        builder.add(system, #database_snames, &[#backend_snames]);
+                    }
+                )*
+                let registry = world.entry::<SnapshotRegistry>().or_insert_with(Default::default);
+                #(
+                    registry.register::<#names>(#check_names);
+                )*
+            }
+
+            /// 检查数据集组件是否已注册以及数据库表结构是否与配置一致
+            pub fn check(world:&World, conn:&mut mysql::PooledConn, database:Option<&str>, report:&mut StartupCheck) {
+                #(
+                    if !world.has_value::<MaskedStorage<#names>>() {
+                        report.report(format!("component {} is not registered", #check_names));
+                    }
+                )*
+                #(
+                    match #backend_names::patch_table(conn, false, database) {
+                        Ok(sqls) => {
+                            for sql in sqls {
+                                report.report(format!("table of {} drifted, need:{}", #backend_snames, sql));
+                            }
+                        }
+                        Err(err) => report.report(format!("check table of {} failed:{:?}", #backend_snames, err)),
+                    }
                 )*
             }
         )
@@ -1,6 +1,6 @@
 use crate::{
-    dataset::gen_dataset, format_file, gen_messages, gen_protos, parse_config,
-    request::gen_request, response::gen_response, string_to_u32, ConfigFile, Error,
+    compat::check_compatibility, dataset::gen_dataset, format_file, gen_messages, gen_protos,
+    parse_config, request::gen_request, response::gen_response, string_to_u32, ConfigFile, Error,
 };
 use proc_macro2::{Ident, TokenStream};
 use quote::{format_ident, quote};
@@ -26,6 +26,8 @@ pub struct Generator {
     keep_order: bool,
     /// 是否丢弃重复请求
     keep_duplicate: bool,
+    /// 上一次生成的.proto文件目录，设置后会检查协议的兼容性
+    previous_proto_dir: Option<PathBuf>,
 }
 
 impl Generator {
@@ -54,6 +56,11 @@ impl Generator {
         self
     }
 
+    pub fn previous_proto_dir(&mut self, previous_proto_dir: impl AsRef<Path>) -> &mut Self {
+        self.previous_proto_dir = Some(previous_proto_dir.as_ref().to_owned());
+        self
+    }
+
     pub fn keep_order(&mut self) -> &mut Self {
         self.keep_order = true;
         self
@@ -75,7 +82,18 @@ impl Generator {
         if self.response_dir == empty_path {
             self.response_dir = "src/response".into();
         }
+        if let Some(previous_proto_dir) = &self.previous_proto_dir {
+            for config_type in &["request", "response", "dataset"] {
+                check_compatibility(
+                    config_type,
+                    self.config_dir.clone(),
+                    previous_proto_dir.clone(),
+                )?;
+            }
+        }
+        let version = gen_protocol_version(self.config_dir.clone(), self.proto_dir.clone())?;
         gen_request(
+            version,
             self.keep_order,
             self.keep_duplicate,
             self.request_dir.clone(),
@@ -96,6 +114,57 @@ impl Generator {
     }
 }
 
+/// 根据请求和响应的命令号计算协议版本，同时生成供客户端使用的version.proto
+fn gen_protocol_version(config_dir: PathBuf, proto_dir: PathBuf) -> Result<u32, Error> {
+    let mut names = Vec::new();
+    for config_type in &["request", "response"] {
+        let mut dir = config_dir.clone();
+        dir.push(config_type);
+        for (_, cf) in parse_config(dir)? {
+            for c in cf.configs {
+                if c.hide != Some(true) {
+                    names.push(format!("{}:{}", config_type, c.name));
+                }
+            }
+        }
+    }
+    names.sort();
+    // protobuf的枚举值为int32，去掉最高位
+    let version = string_to_u32(names.join(",").as_bytes()) & 0x7fff_ffff;
+
+    if !proto_dir.exists() {
+        std::fs::create_dir_all(proto_dir.clone())?;
+    }
+    let mut name = proto_dir;
+    name.push("version.proto");
+    let mut file = File::create(name)?;
+    writeln!(file, r#"syntax = "proto3";"#)?;
+    writeln!(
+        file,
+        "// 连接建立后客户端需要首先发送 length(4) | version(4)"
+    )?;
+    writeln!(file, "enum ProtocolVersion {{")?;
+    writeln!(file, "\tPROTOCOL_VERSION_NONE = 0;")?;
+    writeln!(file, "\tPROTOCOL_VERSION = {};", version)?;
+    writeln!(file, "}}")?;
+    writeln!(
+        file,
+        "// 引擎控制帧 length(4) | 0(4) | cmd(4) | value(4)，收到错误帧后服务器会断开连接"
+    )?;
+    writeln!(file, "enum ControlCmd {{")?;
+    writeln!(file, "\tVERSION_MISMATCH = 0;")?;
+    writeln!(file, "\tDUPLICATE_LOGIN = 1;")?;
+    writeln!(
+        file,
+        "\t// 请求可以用 length(4) | SEQUENCE(4) | seq(4) | cmd(4) | body 携带序号，服务器处理后返回确认帧"
+    )?;
+    writeln!(file, "\tSEQUENCE = 2;")?;
+    writeln!(file, "\t// 在线人数已满，value为当前排队位置")?;
+    writeln!(file, "\tLOGIN_QUEUE = 3;")?;
+    writeln!(file, "}}")?;
+    Ok(version)
+}
+
 pub fn gen_io_config<F>(
     config_type: &str,
     dir: PathBuf,
@@ -1,3 +1,4 @@
+mod compat;
 mod dataset;
 mod generator;
 mod request;
@@ -28,6 +29,10 @@ pub enum SyncDirection {
     Client,
     Database,
     Team,
+    /// 观察者，GM工具以及录像等不加入队伍或场景的订阅者
+    Observer,
+    /// 全服所有在线玩家，例如世界boss的血量，不包含在默认方向中
+    World,
 }
 
 impl From<usize> for SyncDirection {
@@ -37,6 +42,8 @@ impl From<usize> for SyncDirection {
             2 => SyncDirection::Client,
             4 => SyncDirection::Database,
             8 => SyncDirection::Team,
+            16 => SyncDirection::Observer,
+            32 => SyncDirection::World,
             _ => panic!("invalid index:{}", index),
         }
     }
@@ -49,6 +56,8 @@ impl Into<usize> for SyncDirection {
             SyncDirection::Client => 2,
             SyncDirection::Database => 4,
             SyncDirection::Team => 8,
+            SyncDirection::Observer => 16,
+            SyncDirection::World => 32,
         }
     }
 }
@@ -83,6 +92,14 @@ pub enum Trait {
     DropEntity {
         entities: Option<String>,
     },
+    /// 保存到redis的哈希表中，key_pattern中的{字段名}会被替换为字段值，例如"player:{id}"
+    Redis {
+        key_pattern: String,
+    },
+    /// 移动数据，周围方向按照rate(每秒次数)批量同步，并在数据头之后带上8字节的unix毫秒时间戳
+    Movement {
+        rate: u32,
+    },
 }
 
 impl Trait {
@@ -233,6 +250,10 @@ pub struct Field {
     pub r#type: DataType,
     pub index: u32,
     pub dirs: Option<Vec<SyncDirection>>,
+    /// 由客户端权威(预测)的字段，修改后不再回传给所属客户端
+    pub owner: Option<bool>,
+    /// map字段的值中保存过期时间(unix毫秒)的字段名，过期的条目会被自动删除
+    pub expire: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -242,6 +263,28 @@ pub struct Config {
     pub traits: Option<Vec<Trait>>,
     pub indexes: Option<HashMap<IndexType, TableIndex>>,
     pub fields: Vec<Field>,
+    /// 已经删除的字段编号，不能再被使用
+    pub reserved: Option<Vec<u32>>,
+    /// 场景同步时外圈格子只同步这些字段，不设置则同步全部字段
+    pub lod_fields: Option<Vec<String>>,
+    /// 请求需要在cmd之后携带nonce(4)，重复的nonce会被丢弃，用于购买、交易等不能重复执行的请求
+    pub nonce: Option<bool>,
+    /// 数据库分片，保存请求按分片字段取模路由到不同的数据库
+    pub shard: Option<ShardKey>,
+    /// 组队方向同步到的组，对应`Member<group>`，默认为0即队伍，1被场景占用
+    pub group: Option<usize>,
+    /// 增量压缩，map字段只发送与上次发送相比有变化的键，每隔delta_keyframe次发送一次完整数据，
+    /// 不设置或者为0表示不开启。每个方向只保存一份键值摘要作为基线，该方向的所有接收者共享，
+    /// 中途加入的接收者需要通过完整数据同步获得初始状态
+    pub delta_keyframe: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShardKey {
+    /// 分片字段，必须是整数类型的主键字段，登录加载时只知道主键
+    pub field: String,
+    /// 分片数量
+    pub count: u32,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -267,7 +310,7 @@ impl Config {
                     mask |= dir;
                 }
             } else {
-                return 0x0f;
+                return 0x1f;
             }
         }
         mask
@@ -320,6 +363,39 @@ impl Config {
             .iter()
             .any(|c| c.eq_ignore_ascii_case(column))
     }
+
+    fn get_redis_key_pattern(&self) -> Option<&String> {
+        self.traits.as_ref()?.iter().find_map(|t| match t {
+            Trait::Redis { key_pattern } => Some(key_pattern),
+            _ => None,
+        })
+    }
+
+    fn get_movement_rate(&self) -> Option<u32> {
+        self.traits.as_ref()?.iter().find_map(|t| match t {
+            Trait::Movement { rate } => Some(*rate),
+            _ => None,
+        })
+    }
+
+    /// 把key_pattern拆成格式化字符串以及其中引用的字段名
+    fn parse_redis_key_pattern(pattern: &str) -> (String, Vec<String>) {
+        let mut format = String::new();
+        let mut fields = Vec::new();
+        let mut rest = pattern;
+        while let Some(start) = rest.find('{') {
+            let end = match rest[start..].find('}') {
+                Some(end) => start + end,
+                None => break,
+            };
+            format.push_str(&rest[..start]);
+            format.push_str("{}");
+            fields.push(rest[start + 1..end].to_string());
+            rest = &rest[end + 1..];
+        }
+        format.push_str(rest);
+        (format, fields)
+    }
 }
 
 #[derive(PartialOrd, PartialEq, Serialize, Deserialize, Debug, Eq, Hash, Clone)]
@@ -356,6 +432,28 @@ pub enum Error {
     MapUsedAsRootDatasetType(PathBuf, String, String),
     #[from(ignore)]
     ComponentListUsed(PathBuf, String, String),
+    #[from(ignore)]
+    InvalidExpireField(PathBuf, String, String),
+    #[from(ignore)]
+    IncompatibleFieldType(PathBuf, String, String),
+    #[from(ignore)]
+    RemovedFieldNotReserved(PathBuf, String, u32),
+    #[from(ignore)]
+    ReservedFieldReused(PathBuf, String, u32),
+    #[from(ignore)]
+    InternalFieldMoved(PathBuf, String, String, u32),
+    #[from(ignore)]
+    FieldNumberOverflow(PathBuf, String, String),
+    #[from(ignore)]
+    InvalidLodField(PathBuf, String, String),
+    #[from(ignore)]
+    InvalidRedisKeyField(PathBuf, String, String),
+    #[from(ignore)]
+    InvalidShardKey(PathBuf, String, String),
+    #[from(ignore)]
+    InvalidMovementRate(PathBuf, String),
+    #[from(ignore)]
+    InvalidGroup(PathBuf, String),
 }
 
 pub fn read_files(input_dir: PathBuf) -> std::io::Result<Vec<PathBuf>> {
@@ -450,6 +548,12 @@ pub fn gen_message(file: &mut File, cf: &ConfigFile, mask: bool) -> std::io::Res
     writeln!(file, r#"syntax = "proto3";"#)?;
     for v in &cf.configs {
         writeln!(file, "message {} {{", v.name)?;
+        if let Some(reserved) = &v.reserved {
+            if !reserved.is_empty() {
+                let reserved: Vec<_> = reserved.iter().map(|n| n.to_string()).collect();
+                writeln!(file, "\treserved {};", reserved.join(", "))?;
+            }
+        }
         for field in &v.fields {
             writeln!(
                 file,
@@ -9,12 +9,13 @@ fn keep_order_dispatch(
     files: &Vec<Ident>,
     names: &Vec<Ident>,
     vnames: &Vec<Ident>,
+    nonces: &Vec<TokenStream>,
 ) -> TokenStream {
     quote!(
-        fn dispatch(&mut self, ident:RequestIdent, data:Vec<u8>) {
+        fn dispatch(&mut self, ident:RequestIdent, data:Bytes) {
             if let Err(err) = match ident {
-                RequestIdent::Token(token) => self.token.send(token).map_err(|err|format!("{}", err)),
                 RequestIdent::Close(entity) => {
+                    self.nonces.remove(entity);
                     if !self.input_cache.contains_key(&entity) {
                         self.input_cache.insert(entity, (true, VecDeque::new()));
                     }
@@ -25,7 +26,7 @@ fn keep_order_dispatch(
                             .send((entity, Closing(true)))
                             .map_err(|err| format!("{}", err))
                     } else {
-                        cache.push_back(AllRequest::Closing(Closing(true)));
+                        cache.push_back((None, AllRequest::Closing(Closing(true))));
                         Ok(())
                     }
                 },
@@ -34,27 +35,36 @@ fn keep_order_dispatch(
                         self.input_cache.insert(entity, (true, VecDeque::new()));
                     }
                     let (next, cache) = self.input_cache.get_mut(&entity).unwrap();
-
-                    let mut buffer = data.as_slice();
-                    let cmd = BigEndian::read_u32(buffer);
+                    let mut buffer = &data[..];
+                    let mut cmd = BigEndian::read_u32(buffer);
                     buffer = &buffer[4..];
+                    let mut seq = None;
+                    if cmd == SEQUENCE_CMD && buffer.len() >= 8 {
+                        seq.replace(BigEndian::read_u32(buffer));
+                        cmd = BigEndian::read_u32(&buffer[4..]);
+                        buffer = &buffer[8..];
+                    }
                     match cmd {
                         #(
                             #cmds => {
+                                #nonces
                                 let mut data = #files::#names::new();
                                 data.merge_from_bytes(buffer).unwrap();
                                 let data = #names::new(data);
                                 if *next && cache.is_empty() {
                                     *next = false;
-                                    self.#vnames.send((entity, data)).map_err(|err|format!("{}", err))
+                                    let result = self.#vnames.send((entity, data)).map_err(|err|format!("{}", err));
+                                    Self::send_seq(&self.seq, entity, seq);
+                                    result
                                 } else {
                                     if self.keep_duplicate {
-                                        cache.push_back(AllRequest::#names(data));
+                                        cache.push_back((seq, AllRequest::#names(data)));
                                     } else {
-                                        if let Some(AllRequest::#names(old)) = cache.back_mut() {
+                                        if let Some((old_seq, AllRequest::#names(old))) = cache.back_mut() {
                                             *old = data;
+                                            *old_seq = seq;
                                         } else {
-                                            cache.push_back(AllRequest::#names(data));
+                                            cache.push_back((seq, AllRequest::#names(data)));
                                         }
                                     }
                                     if *next {
@@ -84,25 +94,37 @@ fn disorder_dispatch(
     files: &Vec<Ident>,
     names: &Vec<Ident>,
     vnames: &Vec<Ident>,
+    nonces: &Vec<TokenStream>,
 ) -> TokenStream {
     quote!(
-        fn dispatch(&mut self, ident:RequestIdent, data:Vec<u8>) {
+        fn dispatch(&mut self, ident:RequestIdent, data:Bytes) {
             if let Err(err) = match ident {
-                RequestIdent::Token(token) => self.token.send(token).map_err(|err|format!("{}", err)),
-                RequestIdent::Close(entity) => self.close
-                    .send((entity, Closing(true)))
-                    .map_err(|err| format!("{}", err)),
+                RequestIdent::Close(entity) => {
+                    self.nonces.remove(entity);
+                    self.close
+                        .send((entity, Closing(true)))
+                        .map_err(|err| format!("{}", err))
+                },
                 RequestIdent::Entity(entity) => {
-                    let mut buffer = data.as_slice();
-                    let cmd = BigEndian::read_u32(buffer);
+                    let mut buffer = &data[..];
+                    let mut cmd = BigEndian::read_u32(buffer);
                     buffer = &buffer[4..];
+                    let mut seq = None;
+                    if cmd == SEQUENCE_CMD && buffer.len() >= 8 {
+                        seq.replace(BigEndian::read_u32(buffer));
+                        cmd = BigEndian::read_u32(&buffer[4..]);
+                        buffer = &buffer[8..];
+                    }
                     match cmd {
                         #(
                             #cmds => {
+                                #nonces
                                 let mut data = #files::#names::new();
                                 data.merge_from_bytes(buffer).unwrap();
                                 let data = #names::new(data);
-                                self.#vnames.send((entity, data)).map_err(|err|format!("{}", err))
+                                let result = self.#vnames.send((entity, data)).map_err(|err|format!("{}", err));
+                                Self::send_seq(&self.seq, entity, seq);
+                                result
                             },
                         )*
                             _ => {
@@ -128,8 +150,9 @@ fn keep_order_do_next(names: &Vec<Ident>, vnames: &Vec<Ident>) -> TokenStream {
                 if cache.is_empty() {
                     *next = true;
                 } else {
+                    let (seq, request) = cache.pop_front().unwrap();
                     if let Err(err) = {
-                        match cache.pop_front().unwrap() {
+                        match request {
                             #(AllRequest::#names(data) => self.#vnames.send((entity, data)).map_err(|err|format!("{}", err)),)*
                             AllRequest::Closing(data) => {
                                 clean = true;
@@ -139,6 +162,7 @@ fn keep_order_do_next(names: &Vec<Ident>, vnames: &Vec<Ident>) -> TokenStream {
                     } {
                         log::error!("send request to ecs failed:{}", err);
                     }
+                    Self::send_seq(&self.seq, entity, seq);
                 }
             }
             if clean {
@@ -149,6 +173,7 @@ fn keep_order_do_next(names: &Vec<Ident>, vnames: &Vec<Ident>) -> TokenStream {
 }
 
 pub fn gen_request(
+    version: u32,
     keep_order: bool,
     keep_duplicate: bool,
     request_dir: PathBuf,
@@ -160,7 +185,30 @@ pub fn gen_request(
         request_dir,
         config_dir,
         proto_dir,
-        |_configs, mods, names, files, inners, cmds| {
+        |configs, mods, names, files, inners, cmds| {
+            let nonces: Vec<_> = configs
+                .iter()
+                .flat_map(|(_, cf)| cf.configs.iter())
+                .filter(|c| c.hide != Some(true))
+                .map(|c| {
+                    if c.nonce == Some(true) {
+                        quote!(
+                            if buffer.len() < 4 {
+                                log::error!("nonce not found in request");
+                                return;
+                            }
+                            let nonce = BigEndian::read_u32(buffer);
+                            buffer = &buffer[4..];
+                            if !self.nonces.check(entity, nonce) {
+                                log::warn!("entity:{} duplicate nonce:{}, request dropped", entity.id(), nonce);
+                                return;
+                            }
+                        )
+                    } else {
+                        quote!()
+                    }
+                })
+                .collect();
             let vnames: Vec<_> = names
                 .iter()
                 .map(|name| format_ident!("{}", name.to_string().to_case(Case::Snake)))
@@ -169,6 +217,7 @@ pub fn gen_request(
                 .iter()
                 .map(|name| format!("{}_input", name.to_string().to_case(Case::Snake)))
                 .collect();
+            let rnames: Vec<_> = names.iter().map(|name| name.to_string()).collect();
             let cnames: Vec<_> = names
                 .iter()
                 .map(|name| format!("{}_cleanup", name.to_string().to_case(Case::Snake)))
@@ -197,9 +246,9 @@ pub fn gen_request(
             };
 
             let dispatch = if keep_order {
-                keep_order_dispatch(&cmds, &files, &names, &vnames)
+                keep_order_dispatch(&cmds, &files, &names, &vnames, &nonces)
             } else {
-                disorder_dispatch(&cmds, &files, &names, &vnames)
+                disorder_dispatch(&cmds, &files, &names, &vnames, &nonces)
             };
 
             let all_request = if keep_order {
@@ -229,12 +278,12 @@ pub fn gen_request(
                     #(mod #mods;)*
 
                     use byteorder::{BigEndian, ByteOrder};
+                    use bytes::Bytes;
                     use crossbeam::channel::{Receiver, Sender};
                     use ecs_engine::{
-                        channel, CleanStorageSystem,  Closing, HandshakeSystem, HashComponent, Input,
-                        InputSystem, RequestIdent, CommandId, GameDispatcherBuilder,
+                        channel, AckSystem, CleanStorageSystem,  Closing, HashComponent, Input,
+                        InputSystem, NonceCache, RequestIdent, CommandId, GameDispatcherBuilder, SEQUENCE_CMD,
                     };
-                    use mio::Token;
                     use protobuf::Message;
                     use specs::Entity;
                     use std::collections::{HashMap, VecDeque};
@@ -242,15 +291,18 @@ pub fn gen_request(
                     #(pub type #names = HashComponent<#files::#names>;)*
                     #(pub use #inners;)*
 
+                    pub const PROTOCOL_VERSION: u32 = #version;
+
                     #all_request
 
                     pub struct Request {
                         keep_duplicate:bool,
-                        input_cache: HashMap<Entity, (bool, VecDeque<AllRequest>)>,
+                        input_cache: HashMap<Entity, (bool, VecDeque<(Option<u32>, AllRequest)>)>,
                         next_receiver: Receiver<Vec<Entity>>,
                         next_sender: Sender<Vec<Entity>>,
-                        token:Sender<Token>,
                         close:Sender<(Entity, Closing)>,
+                        seq:Sender<(Entity, u32)>,
+                        nonces: NonceCache,
                         #(#vnames: Sender<(Entity, #names)>,)*
                     }
 
@@ -258,22 +310,29 @@ pub fn gen_request(
                         pub fn new(bounded_size: usize, builder: &mut GameDispatcherBuilder) -> Self {
                             let (next_sender, next_receiver) = channel(0);
                             let input_cache = HashMap::new();
-                            let (token, receiver) = channel(bounded_size);
-                            builder.add(HandshakeSystem::new(receiver), "handshake", &[]);
                             let (close, receiver) = channel(bounded_size);
                             builder.add(InputSystem::new(receiver), "close_input", &[]);
+                            let (seq, receiver) = channel(bounded_size);
+                            builder.add_thread_local("input_ack", AckSystem::new(receiver));
                             #(
                                 let (#vnames, receiver) = channel(bounded_size);
                                 builder.add(InputSystem::new(receiver), #snames, &[]);
                             )*
                             Self {
-                                keep_duplicate:#keep_duplicate, token, close, next_receiver, next_sender, input_cache,
+                                keep_duplicate:#keep_duplicate, close, seq, next_receiver, nonces: Default::default(), next_sender, input_cache,
                                 #(#vnames,)*
                             }
                         }
 
                         #cleanup
 
+                        fn send_seq(sender:&Sender<(Entity, u32)>, entity:Entity, seq:Option<u32>) {
+                            if let Some(seq) = seq {
+                                if let Err(err) = sender.send((entity, seq)) {
+                                    log::error!("send request seq to ecs failed:{}", err);
+                                }
+                            }
+                        }
                     }
 
                     #(
@@ -292,6 +351,14 @@ pub fn gen_request(
                             self.next_receiver.clone()
                         }
 
+                        fn protocol_version(&self) -> u32 {
+                            PROTOCOL_VERSION
+                        }
+
+                        fn commands(&self) -> Vec<(u32, &'static str)> {
+                            vec![#((#cmds, #rnames),)*]
+                        }
+
                         #do_next
 
                     }
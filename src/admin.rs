@@ -0,0 +1,325 @@
+use crate::{
+    component::{Closing, NetToken},
+    dynamic::DynamicManager,
+    resource::{FrameCounter, NetworkMetrics, SystemToggles, WorldStats},
+};
+use crossbeam::channel::{Receiver, Sender};
+use specs::{RunNow, World, WorldExt};
+use std::{
+    io::{BufRead, BufReader, Error, ErrorKind, Read, Result, Write},
+    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+/// 管理端口同时允许的连接数量
+const MAX_ADMIN_CONNECTIONS: usize = 4;
+/// 设置了token时需要在连接后的这段时间内完成认证
+const AUTH_TIMEOUT: Duration = Duration::from_secs(5);
+/// 连接空闲超过这段时间后断开，避免一直占用连接数量
+const IDLE_TIMEOUT: Duration = Duration::from_secs(600);
+
+/// 管理端口的命令，每行一条，参数以空格分隔
+#[derive(Debug)]
+pub enum AdminCommand {
+    /// 网络以及世界统计
+    Stats,
+    /// 断开实体对应的连接
+    Kick(u32),
+    /// 重新加载动态库
+    Reload(String),
+    /// 暂停或者恢复系统
+    Toggle(String),
+}
+
+impl AdminCommand {
+    pub fn parse(line: &str) -> std::result::Result<Self, String> {
+        let mut args = line.split_whitespace();
+        let command = match args.next() {
+            Some(command) => command,
+            None => return Err("empty command".into()),
+        };
+        let arg = args.next();
+        match (command, arg) {
+            ("stats", None) => Ok(AdminCommand::Stats),
+            ("kick", Some(id)) => id
+                .parse()
+                .map(AdminCommand::Kick)
+                .map_err(|_| format!("invalid entity id:{}", id)),
+            ("reload", Some(lib)) => Ok(AdminCommand::Reload(lib.into())),
+            ("toggle", Some(system)) => Ok(AdminCommand::Toggle(system.into())),
+            _ => Err(format!("unknown command:{}", line.trim())),
+        }
+    }
+}
+
+pub type AdminRequest = (AdminCommand, Sender<String>);
+
+/// 回环以及内网地址
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private(),
+        IpAddr::V6(ip) => ip.is_loopback(),
+    }
+}
+
+/// 启动管理端口线程，每个连接一个线程，命令交给ECS执行后把结果写回；
+/// 没有设置token时只能监听回环或者内网地址
+pub(crate) fn run_admin(
+    address: SocketAddr,
+    token: Option<String>,
+    sender: Sender<AdminRequest>,
+) -> Result<()> {
+    if token.is_none() && !is_internal(address.ip()) {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "admin without token must listen on loopback or private address",
+        ));
+    }
+    let listener = TcpListener::bind(address)?;
+    log::info!("admin listening on {}", address);
+    let token = Arc::new(token);
+    let connections = Arc::new(AtomicUsize::new(0));
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if connections.fetch_add(1, Ordering::SeqCst) >= MAX_ADMIN_CONNECTIONS {
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                        log::warn!(
+                            "too many admin connections, {:?} rejected",
+                            stream.peer_addr()
+                        );
+                        continue;
+                    }
+                    let sender = sender.clone();
+                    let token = token.clone();
+                    let connections = connections.clone();
+                    std::thread::spawn(move || {
+                        if let Err(err) = serve_admin(stream, token.as_ref().as_deref(), sender) {
+                            log::error!("admin connection failed:{}", err);
+                        }
+                        connections.fetch_sub(1, Ordering::SeqCst);
+                    });
+                }
+                Err(err) => log::error!("accept admin connection failed:{}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// 认证完成前每次读取都按照剩余时间设置超时，逐字节发送也不能拖过认证期限
+struct AdminStream {
+    stream: TcpStream,
+    deadline: Option<Instant>,
+}
+
+impl Read for AdminStream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining == Duration::ZERO {
+                return Err(Error::new(ErrorKind::TimedOut, "authorization timeout"));
+            }
+            self.stream.set_read_timeout(Some(remaining))?;
+        }
+        self.stream.read(buf)
+    }
+}
+
+/// 设置了token时第一条命令必须是`auth <token>`
+fn serve_admin(stream: TcpStream, token: Option<&str>, sender: Sender<AdminRequest>) -> Result<()> {
+    let peer = stream.peer_addr()?;
+    log::warn!("admin connection from {}", peer);
+    let mut writer = stream.try_clone()?;
+    let mut authorized = token.is_none();
+    stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+    let deadline = if authorized {
+        None
+    } else {
+        Some(Instant::now() + AUTH_TIMEOUT)
+    };
+    let mut reader = BufReader::new(AdminStream { stream, deadline });
+    let mut line = String::new();
+    loop {
+        line.clear();
+        match reader.read_line(&mut line) {
+            Ok(0) => break,
+            Ok(_) => {}
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                log::warn!("[{}]admin connection timeout", peer);
+                writeln!(writer, "error: timeout")?;
+                return Ok(());
+            }
+            Err(err) => return Err(err),
+        }
+        let line = line.trim_end();
+        if line.trim().is_empty() {
+            continue;
+        }
+        if !authorized {
+            let mut args = line.split_whitespace();
+            if args.next() == Some("auth") && args.next() == token && args.next().is_none() {
+                authorized = true;
+                let admin = reader.get_mut();
+                admin.deadline = None;
+                admin.stream.set_read_timeout(Some(IDLE_TIMEOUT))?;
+                writeln!(writer, "ok")?;
+                continue;
+            }
+            log::warn!("[{}]admin authorization failed", peer);
+            writeln!(writer, "error: unauthorized")?;
+            return Ok(());
+        }
+        log::warn!("[{}]admin command:{}", peer, line);
+        let reply = match AdminCommand::parse(line) {
+            Ok(command) => {
+                let (reply, receiver) = crossbeam::channel::bounded(1);
+                if sender.send((command, reply)).is_err() {
+                    "error: engine stopped".into()
+                } else {
+                    // 命令在下一帧执行
+                    receiver
+                        .recv_timeout(Duration::from_secs(5))
+                        .unwrap_or_else(|_| "error: timeout".into())
+                }
+            }
+            Err(err) => format!("error: {}", err),
+        };
+        writeln!(writer, "{}", reply)?;
+    }
+    Ok(())
+}
+
+/// 每帧执行管理端口收到的命令，需要以thread local方式添加
+pub struct AdminSystem {
+    receiver: Receiver<AdminRequest>,
+}
+
+impl AdminSystem {
+    pub fn new(receiver: Receiver<AdminRequest>) -> Self {
+        Self { receiver }
+    }
+
+    fn execute(world: &World, command: AdminCommand) -> String {
+        match command {
+            AdminCommand::Stats => {
+                let metrics = world.read_resource::<NetworkMetrics>();
+                format!(
+                    "frame:{} entities:{} connections:{} bytes in:{} bytes out:{} pending bytes:{}",
+                    world.read_resource::<FrameCounter>().frame(),
+                    world.read_resource::<WorldStats>().entities(),
+                    metrics.connections(),
+                    metrics.bytes_in(),
+                    metrics.bytes_out(),
+                    metrics.pending_bytes()
+                )
+            }
+            AdminCommand::Kick(id) => {
+                let entity = world.entities().entity(id);
+                if !world.entities().is_alive(entity)
+                    || !world.read_storage::<NetToken>().contains(entity)
+                {
+                    return format!("error: entity:{} not connected", id);
+                }
+                if let Err(err) = world
+                    .write_storage::<Closing>()
+                    .insert(entity, Closing(false))
+                {
+                    return format!("error: {}", err);
+                }
+                format!("entity:{} kicked", id)
+            }
+            AdminCommand::Reload(lib) => {
+                if world.read_resource::<DynamicManager>().reload(&lib) {
+                    format!("library:{} reloaded", lib)
+                } else {
+                    format!("error: library:{} not loaded or reload failed", lib)
+                }
+            }
+            AdminCommand::Toggle(system) => {
+                let toggles = match world.try_fetch::<SystemToggles>() {
+                    Some(toggles) => toggles,
+                    None => return "error: no system toggles".into(),
+                };
+                let paused = !toggles.is_paused(system.as_str());
+                let found = if paused {
+                    toggles.pause(system.as_str())
+                } else {
+                    toggles.resume(system.as_str())
+                };
+                if found {
+                    format!("system:{} paused:{}", system, paused)
+                } else {
+                    format!("error: system:{} not found", system)
+                }
+            }
+        }
+    }
+}
+
+impl<'a> RunNow<'a> for AdminSystem {
+    fn run_now(&mut self, world: &'a World) {
+        self.receiver.try_iter().for_each(|(command, reply)| {
+            let result = Self::execute(world, command);
+            let _ = reply.send(result);
+        });
+    }
+
+    fn setup(&mut self, _world: &mut World) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 返回管理连接客户端的读写端，命令的接收端已经关闭，命令都会返回engine stopped
+    fn connect(token: Option<&'static str>) -> (BufReader<TcpStream>, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let (sender, _) = crossbeam::channel::unbounded();
+        std::thread::spawn(move || serve_admin(stream, token, sender));
+        (BufReader::new(client.try_clone().unwrap()), client)
+    }
+
+    fn reply(reader: &mut BufReader<TcpStream>) -> String {
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        line.trim_end().into()
+    }
+
+    #[test]
+    fn auth_and_command() {
+        let (mut reader, mut client) = connect(Some("secret"));
+        client.write_all(b"auth secret\r\nstats\n").unwrap();
+        assert_eq!(reply(&mut reader), "ok");
+        assert_eq!(reply(&mut reader), "error: engine stopped");
+        client.write_all(b"unknown\n").unwrap();
+        assert_eq!(reply(&mut reader), "error: unknown command:unknown");
+
+        let (mut reader, mut client) = connect(Some("secret"));
+        client.write_all(b"auth wrong\n").unwrap();
+        assert_eq!(reply(&mut reader), "error: unauthorized");
+        assert_eq!(reply(&mut reader), "");
+    }
+
+    #[test]
+    fn auth_timeout() {
+        let (mut reader, mut client) = connect(Some("secret"));
+        let begin = Instant::now();
+        // 逐字节发送也需要在期限内完成认证
+        for byte in b"auth sec" {
+            client.write_all(&[*byte]).unwrap();
+            std::thread::sleep(AUTH_TIMEOUT / 8);
+        }
+        assert_eq!(reply(&mut reader), "error: timeout");
+        assert_eq!(reply(&mut reader), "");
+        let elapsed = begin.elapsed();
+        assert!(elapsed >= AUTH_TIMEOUT && elapsed < AUTH_TIMEOUT * 2);
+    }
+}
@@ -1,5 +1,6 @@
-use crate::{Position, RequestIdent, SceneData};
+use crate::{GridIndex, Position, RequestIdent, SceneData, SpatialIndex};
 use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
 use crossbeam::channel::Receiver;
 use protobuf::{
     reflect::MessageDescriptor, Clear, CodedInputStream, CodedOutputStream, Message,
@@ -11,11 +12,22 @@ use std::{any::Any, ops::Deref};
 /// Trait for requests enum type, it's an aggregation of all requests
 pub trait Input {
     /// decode data and send by channels
-    fn dispatch(&mut self, ident: RequestIdent, data: Vec<u8>);
+    /// data直接引用网络层的读缓冲
+    fn dispatch(&mut self, ident: RequestIdent, data: Bytes);
 
     fn next_receiver(&self) -> Receiver<Vec<Entity>>;
 
     fn do_next(&mut self, entity: Entity);
+
+    /// 协议版本号，连接建立后客户端需要首先发送此版本号，0表示不校验
+    fn protocol_version(&self) -> u32 {
+        0
+    }
+
+    /// 所有请求的命令号及名称，用于启动时的一致性检查
+    fn commands(&self) -> Vec<(u32, &'static str)> {
+        Vec::new()
+    }
 }
 
 pub trait CommandId<T> {
@@ -47,6 +59,26 @@ pub trait DropEntity: Output + Default {
     fn mut_entities(&mut self) -> &mut Vec<u32>;
 }
 
+/// 可见性过滤，例如隐身或者阵营，目标实体没有此组件时对所有实体可见
+pub trait VisibilityFilter: Component + Send + Sync {
+    /// 目标实体是否可以被观察者看到，观察者可能没有此组件
+    fn can_see(&self, observer: Option<&Self>) -> bool;
+}
+
+/// 默认的可见性过滤，所有实体都可见
+#[derive(Default)]
+pub struct AlwaysVisible;
+
+impl VisibilityFilter for AlwaysVisible {
+    fn can_see(&self, _observer: Option<&Self>) -> bool {
+        true
+    }
+}
+
+impl Component for AlwaysVisible {
+    type Storage = NullStorage<Self>;
+}
+
 pub trait SceneSyncBackend
 where
     <<Self as SceneSyncBackend>::Position as Component>::Storage: Tracked + Default,
@@ -55,9 +87,14 @@ where
     type Position: Position + Component;
     type SceneData: SceneData + Component + Send + Sync;
     type DropEntity: DropEntity;
+    /// 场景中实体的空间索引，稀疏的大地图可以使用`QuadTreeIndex`
+    type SpatialIndex: SpatialIndex = GridIndex;
+    /// 周围同步时的可见性过滤，组件变化后需要调用`SceneManager::refresh_visibility`
+    type Visibility: VisibilityFilter<Storage: Default> = AlwaysVisible;
     fn setup(world: &mut World) {
         world.register::<Self::SceneData>();
         world.register::<Self::Position>();
+        world.register::<Self::Visibility>();
     }
 }
 
@@ -79,6 +116,14 @@ impl Position for DummyPosition {
     fn y(&self) -> f32 {
         todo!()
     }
+
+    fn set_x(&mut self, _x: f32) {
+        todo!()
+    }
+
+    fn set_y(&mut self, _y: f32) {
+        todo!()
+    }
 }
 
 impl Component for DummyPosition {
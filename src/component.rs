@@ -2,13 +2,14 @@
 use crate::{backend::Output, BytesSender, SyncDirection};
 use mio::Token;
 use specs::{
-    BitSet, Component, DenseVecStorage, Entity, FlaggedStorage, HashMapStorage, Join, ReadStorage,
-    VecStorage,
+    BitSet, Component, DenseVecStorage, Entity, FlaggedStorage, HashMapStorage, Join, NullStorage,
+    ReadStorage, VecStorage,
 };
 use specs_hierarchy::Parent;
 use std::{
     cmp::Ordering,
-    ops::{Deref, DerefMut},
+    ops::{Deref, DerefMut, RangeInclusive},
+    time::{Duration, Instant},
 };
 
 macro_rules! component {
@@ -69,6 +70,14 @@ impl NetToken {
     }
 }
 
+/// 连接所属的监听序号，按照EngineBuilder::with_address的调用顺序，UDP排在所有TCP地址之后
+#[derive(Default, Debug)]
+pub struct Origin(pub usize);
+
+impl Component for Origin {
+    type Storage = VecStorage<Self>;
+}
+
 #[derive(Default, Debug)]
 pub struct Closing(pub bool);
 
@@ -126,6 +135,97 @@ pub trait Position {
     fn x(&self) -> f32;
     /// y轴坐标
     fn y(&self) -> f32;
+    /// 设置x轴坐标，用于非法移动时回滚
+    fn set_x(&mut self, x: f32);
+    /// 设置y轴坐标
+    fn set_y(&mut self, y: f32);
+    /// z轴坐标，即高度，只有分层的场景会用到
+    fn z(&self) -> f32 {
+        0.0
+    }
+    /// 设置z轴坐标
+    fn set_z(&mut self, _z: f32) {}
+}
+
+/// 场景中的非均匀区域，例如城镇使用更小的格子，区域内的格子索引排在整个场景格子之后
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub min_x: f32,
+    pub min_y: f32,
+    pub column: i32,
+    pub row: i32,
+    pub grid_size: f32,
+}
+
+impl Region {
+    fn contains(&self, x: f32, y: f32) -> bool {
+        x >= self.min_x
+            && y >= self.min_y
+            && x < self.min_x + self.column as f32 * self.grid_size
+            && y < self.min_y + self.row as f32 * self.grid_size
+    }
+
+    /// 区域内位置的局部索引，调用前需要确认位置在区域内
+    fn local_index(&self, x: f32, y: f32) -> usize {
+        let x = (((x - self.min_x) / self.grid_size).floor() as i32).clamp(0, self.column - 1);
+        let y = (((y - self.min_y) / self.grid_size).floor() as i32).clamp(0, self.row - 1);
+        (y * self.column + x) as usize
+    }
+
+    /// 与矩形相交的格子的局部索引
+    fn intersect(&self, (x0, y0, x1, y1): (f32, f32, f32, f32)) -> Vec<usize> {
+        grids_in_rect(
+            (self.min_x, self.min_y),
+            self.column,
+            self.row,
+            self.grid_size,
+            (x0, y0, x1, y1),
+        )
+    }
+}
+
+fn grids_in_rect(
+    (min_x, min_y): (f32, f32),
+    column: i32,
+    row: i32,
+    grid_size: f32,
+    (x0, y0, x1, y1): (f32, f32, f32, f32),
+) -> Vec<usize> {
+    let clamp =
+        |v: f32, min: f32, max: i32| (((v - min) / grid_size).floor() as i32).clamp(0, max - 1);
+    let mut data = Vec::new();
+    if x1 <= min_x
+        || y1 <= min_y
+        || x0 >= min_x + column as f32 * grid_size
+        || y0 >= min_y + row as f32 * grid_size
+    {
+        return data;
+    }
+    let (begin_x, end_x) = (clamp(x0, min_x, column), clamp(x1, min_x, column));
+    let (begin_y, end_y) = (clamp(y0, min_y, row), clamp(y1, min_y, row));
+    for y in begin_y..=end_y {
+        for x in begin_x..=end_x {
+            data.push((y * column + x) as usize);
+        }
+    }
+    data
+}
+
+/// 场景中没有实体后的保留策略
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SceneLifetime {
+    /// 立即删除场景实体
+    Immediate,
+    /// 持续为空超过指定时间后删除
+    Linger(Duration),
+    /// 从不删除，例如主城
+    Persistent,
+}
+
+impl Default for SceneLifetime {
+    fn default() -> Self {
+        SceneLifetime::Immediate
+    }
 }
 
 /// 场景尺寸信息
@@ -140,9 +240,74 @@ pub trait SceneData: Clone {
     fn get_row(&self) -> i32;
     /// 场景分隔的正方形边长
     fn grid_size(&self) -> f32;
+    /// 场景为空后的保留策略，可以通过`SceneManager::set_scene_lifetime`覆盖
+    fn lifetime(&self) -> SceneLifetime {
+        SceneLifetime::Immediate
+    }
+    /// 默认视野半径，以格子为单位，1表示周围3x3的格子
+    fn view_radius(&self) -> i32 {
+        1
+    }
+    /// 非均匀区域，区域之间不能重叠
+    fn regions(&self) -> &[Region] {
+        &[]
+    }
+    /// 高度分层时每层的高度，0表示不分层，多层建筑中不同楼层的实体互相不可见
+    fn layer_height(&self) -> f32 {
+        0.0
+    }
+    /// 场景坐标的最小z值
+    fn get_min_z(&self) -> f32 {
+        0.0
+    }
+    /// 层数
+    fn get_layers(&self) -> i32 {
+        1
+    }
+    /// 可以看到的上下层数，0表示只能看到同一层
+    fn layer_radius(&self) -> i32 {
+        0
+    }
+    /// 每层的格子数量，包括非均匀区域
+    fn layer_grids(&self) -> usize {
+        self.region_offset(self.regions().len())
+    }
+    /// 高度所在的层
+    fn layer_index(&self, z: f32) -> usize {
+        if self.layer_height() <= 0.0 || self.get_layers() <= 1 {
+            return 0;
+        }
+        (((z - self.get_min_z()) / self.layer_height()).floor() as i32)
+            .clamp(0, self.get_layers() - 1) as usize
+    }
+    /// 格子可以看到的层
+    fn around_layers(&self, index: usize) -> RangeInclusive<usize> {
+        let layer = (index / self.layer_grids()) as i32;
+        let radius = self.layer_radius().max(0);
+        let begin = (layer - radius).max(0);
+        let end = (layer + radius).min(self.get_layers().max(1) - 1);
+        begin as usize..=end as usize
+    }
+    /// 根据三维位置计算格子索引，index = layer * layer_grids + 平面索引
+    fn position_index(&self, x: f32, y: f32, z: f32) -> Option<usize> {
+        self.grid_index(x, y)
+            .map(|index| self.layer_index(z) * self.layer_grids() + index)
+    }
+    /// 根据区域内的局部索引计算全局索引的偏移量
+    fn region_offset(&self, region: usize) -> usize {
+        let base = (self.get_column() * self.get_row()) as usize;
+        self.regions()[..region]
+            .iter()
+            .fold(base, |offset, r| offset + (r.column * r.row) as usize)
+    }
     /// 根据位置信息计算格子索引
     /// index = y * column + x
     fn grid_index(&self, x: f32, y: f32) -> Option<usize> {
+        for (i, region) in self.regions().iter().enumerate() {
+            if region.contains(x, y) {
+                return Some(region.local_index(x, y) + self.region_offset(i));
+            }
+        }
         let (min_x, min_y) = (self.get_min_x(), self.get_min_y());
         if x < min_x || y < min_y {
             return None;
@@ -158,29 +323,95 @@ pub trait SceneData: Clone {
         }
         Some((y * column + x) as usize)
     }
+    /// 格子的范围，返回左下角以及右上角坐标，不区分层
+    fn grid_rect(&self, index: usize) -> (f32, f32, f32, f32) {
+        let index = index % self.layer_grids();
+        let base = (self.get_column() * self.get_row()) as usize;
+        let (min_x, min_y, column, size, local) = if index < base {
+            (
+                self.get_min_x(),
+                self.get_min_y(),
+                self.get_column(),
+                self.grid_size(),
+                index,
+            )
+        } else {
+            let mut local = index - base;
+            let mut rect = (0.0, 0.0, 1, 0.0, 0);
+            for region in self.regions() {
+                let count = (region.column * region.row) as usize;
+                if local < count {
+                    rect = (
+                        region.min_x,
+                        region.min_y,
+                        region.column,
+                        region.grid_size,
+                        local,
+                    );
+                    break;
+                }
+                local -= count;
+            }
+            rect
+        };
+        let x = min_x + (local as i32 % column) as f32 * size;
+        let y = min_y + (local as i32 / column) as f32 * size;
+        (x, y, x + size, y + size)
+    }
     /// 获取周围格子的索引，包括当前格子
     fn around(&self, index: usize) -> Vec<usize> {
+        self.around_radius(index, self.view_radius())
+    }
+    /// 获取半径内格子的索引，包括当前格子，半径为r时每层最多为(2r+1)x(2r+1)个格子
+    fn around_radius(&self, index: usize, radius: i32) -> Vec<usize> {
+        let grids = self.layer_grids();
+        let plane = self.plane_around(index % grids, radius);
+        let mut data = Vec::with_capacity(plane.len());
+        for layer in self.around_layers(index) {
+            data.extend(plane.iter().map(|index| index + layer * grids));
+        }
+        data
+    }
+    /// 同一层中半径内格子的平面索引
+    fn plane_around(&self, index: usize, radius: i32) -> Vec<usize> {
+        let radius = radius.max(0);
+        if !self.regions().is_empty() {
+            // 存在非均匀区域时，按照当前格子尺寸向外扩展radius格，取所有相交的格子
+            let (x0, y0, x1, y1) = self.grid_rect(index);
+            let size = (x1 - x0) * radius as f32;
+            // 稍微收缩，避免把只有边界接触的格子算进来
+            let delta = (x1 - x0) * 0.01;
+            let rect = (
+                x0 - size + delta,
+                y0 - size + delta,
+                x1 + size - delta,
+                y1 + size - delta,
+            );
+            let mut data = grids_in_rect(
+                (self.get_min_x(), self.get_min_y()),
+                self.get_column(),
+                self.get_row(),
+                self.grid_size(),
+                rect,
+            );
+            for (i, region) in self.regions().iter().enumerate() {
+                let offset = self.region_offset(i);
+                data.extend(
+                    region
+                        .intersect(rect)
+                        .into_iter()
+                        .map(|local| local + offset),
+                );
+            }
+            data.sort_unstable();
+            return data;
+        }
         let mut data = Vec::new();
         let index = index as i32;
         let (row, column) = (self.get_row(), self.get_column());
-        let x = index % column;
-        let min_x = if x == 0 {
-            0
-        } else if x == column - 1 {
-            column - 3
-        } else {
-            x - 1
-        };
-        let y = index / column;
-        let min_y = if y == 0 {
-            0
-        } else if y == row - 1 {
-            row - 3
-        } else {
-            y - 1
-        };
-        for x in min_x..min_x + 2 {
-            for y in min_y..min_y + 2 {
+        let (x, y) = (index % column, index / column);
+        for y in (y - radius).max(0)..=(y + radius).min(row - 1) {
+            for x in (x - radius).max(0)..=(x + radius).min(column - 1) {
                 data.push((y * column + x) as usize)
             }
         }
@@ -188,8 +419,17 @@ pub trait SceneData: Clone {
     }
     /// 根据旧的索引以及新索引来得到三个数据，分别代表删除，未变，新增
     fn diff(&self, old: usize, new: usize) -> (Vec<usize>, Vec<usize>, Vec<usize>) {
-        let old = self.around(old);
-        let new = self.around(new);
+        self.diff_radius(old, new, self.view_radius())
+    }
+    /// 同`diff`，使用指定的视野半径
+    fn diff_radius(
+        &self,
+        old: usize,
+        new: usize,
+        radius: i32,
+    ) -> (Vec<usize>, Vec<usize>, Vec<usize>) {
+        let old = self.around_radius(old, radius);
+        let new = self.around_radius(new, radius);
         let mut only_old = Vec::new();
         let mut only_new = Vec::new();
         let mut share = Vec::new();
@@ -221,15 +461,212 @@ pub trait SceneData: Clone {
         (only_old, share, only_new)
     }
 }
+/// 非法移动标记，记录被拒绝的次数，由逻辑系统决定如何处理（警告、踢出等）
+#[derive(Default, Debug)]
+pub struct InvalidMove(pub usize);
+
+impl Component for InvalidMove {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// 账号标识，登录成功后由逻辑插入，用于检测重复登录
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct AccountId(pub u64);
+
+impl Component for AccountId {
+    type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+}
+
+/// 登录后正在从数据库加载数据集，加载完成后移除
+#[derive(Debug, Default)]
+pub struct Loading;
+
+impl Component for Loading {
+    type Storage = NullStorage<Self>;
+}
+
+/// 不会移动的实体，例如NPC以及怪物，在场景中单独按格子保存，
+/// 只在玩家进入周围格子时发送完整数据，位置修改事件会被忽略
+#[derive(Debug, Default)]
+pub struct StaticEntity;
+
+impl Component for StaticEntity {
+    type Storage = NullStorage<Self>;
+}
+
+/// 本帧进入实体视野的其他实体，由GridSystem每帧重新生成，
+/// 可以用于仇恨、聊天气泡以及光环等逻辑
+#[derive(Debug, Default)]
+pub struct EnteredAoi(pub BitSet);
+
+impl Component for EnteredAoi {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// 本帧离开实体视野的其他实体，由GridSystem每帧重新生成
+#[derive(Debug, Default)]
+pub struct LeftAoi(pub BitSet);
+
+impl Component for LeftAoi {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// 请求冷却，ID一般为请求的命令号
+pub struct Cooldown<const ID: u32> {
+    last: Instant,
+}
+
+impl<const ID: u32> Cooldown<ID> {
+    pub fn new() -> Self {
+        Self {
+            last: Instant::now(),
+        }
+    }
+
+    /// 冷却完成时更新时间并返回true
+    pub fn try_reset(&mut self, duration: Duration) -> bool {
+        if self.last.elapsed() < duration {
+            false
+        } else {
+            self.last = Instant::now();
+            true
+        }
+    }
+}
+
+impl<const ID: u32> Default for Cooldown<ID> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const ID: u32> Component for Cooldown<ID> {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// 冷却中的请求标记，本帧的请求会被逻辑系统忽略
+#[derive(Default)]
+pub struct Throttled<const ID: u32>;
+
+impl<const ID: u32> Component for Throttled<ID> {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// 实体的权威方，客户端预测的实体不会收到自己修改的客户端权威字段
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Authority {
+    Server,
+    Client,
+}
+
+impl Default for Authority {
+    fn default() -> Self {
+        Authority::Server
+    }
+}
+
+impl Component for Authority {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// 客户端请求序号，记录已处理的最大序号以及检测到的丢失和乱序数量
+#[derive(Debug, Default)]
+pub struct InputSequence {
+    last: u32,
+    lost: usize,
+    reordered: usize,
+}
+
+impl InputSequence {
+    /// 更新序号，返回需要确认的序号，旧序号不需要再次确认
+    pub(crate) fn update(&mut self, seq: u32) -> Option<u32> {
+        if seq <= self.last && self.last != 0 {
+            self.reordered += 1;
+            return None;
+        }
+        if self.last != 0 && seq > self.last + 1 {
+            self.lost += (seq - self.last - 1) as usize;
+        }
+        self.last = seq;
+        Some(seq)
+    }
+
+    pub fn last(&self) -> u32 {
+        self.last
+    }
+
+    pub fn lost(&self) -> usize {
+        self.lost
+    }
+
+    pub fn reordered(&self) -> usize {
+        self.reordered
+    }
+}
+
+impl Component for InputSequence {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// 连接的下行带宽统计，超出上限时周围实体的数据同步会延后发送
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    /// 最近一次统计的每秒发送字节数
+    rate: usize,
+    /// 每秒发送字节数上限，0表示不限制
+    cap: usize,
+}
+
+impl Bandwidth {
+    pub fn new(cap: usize) -> Self {
+        Self { rate: 0, cap }
+    }
+
+    pub fn rate(&self) -> usize {
+        self.rate
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    pub fn set_cap(&mut self, cap: usize) {
+        self.cap = cap;
+    }
+
+    pub(crate) fn set_rate(&mut self, rate: usize) {
+        self.rate = rate;
+    }
+
+    pub fn is_over_cap(&self) -> bool {
+        self.cap != 0 && self.rate > self.cap
+    }
+}
+
+impl Component for Bandwidth {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// 数据同步的优先级，数值越大越优先，没有此组件的实体优先级为0，
+/// 接收者超出`SyncBudget`时优先级低的实体会延后同步
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SyncPriority(pub u8);
+
+impl Component for SyncPriority {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// 组队方向可以同步到任意组，例如队伍、公会、团队，1被场景占用
 pub type TeamMember = Member<0>;
 pub type SceneMember = Member<1>;
 
+/// 需要接收完整数据的接收者，T为同步方向，G为组队方向所属的组
 #[derive(Default)]
-pub struct FullDataCommit<const T: usize> {
+pub struct FullDataCommit<const T: usize, const G: usize = 0> {
     mask: BitSet,
 }
 
-impl<const T: usize> FullDataCommit<T> {
+impl<const T: usize, const G: usize> FullDataCommit<T, G> {
     pub fn dir() -> SyncDirection {
         T.into()
     }
@@ -246,9 +683,169 @@ impl<const T: usize> FullDataCommit<T> {
     }
 }
 
-impl<const T: usize> Component for FullDataCommit<T> {
+impl<const T: usize, const G: usize> Component for FullDataCommit<T, G> {
     type Storage = HashMapStorage<Self>;
 }
 
 pub type AroundFullData = FullDataCommit<1>;
-pub type TeamFullData = FullDataCommit<8>;
+pub type GroupFullData<const G: usize> = FullDataCommit<8, G>;
+pub type TeamFullData = GroupFullData<0>;
+
+/// 其他服务器上的组成员在本服的代理实体，没有连接，
+/// 组队方向的数据通过中转连接发送给所在服务器的实体
+#[derive(Debug, Clone, Copy)]
+pub struct RemoteMember {
+    pub server: u32,
+    pub entity: u32,
+}
+
+impl Component for RemoteMember {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// 会话密钥，断线重连时用于恢复原来的实体
+#[derive(Debug)]
+pub struct SessionKey(pub u64);
+
+impl Component for SessionKey {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// 网络连接已经断开的时间，宽限期内客户端可以重连恢复
+#[derive(Debug)]
+pub struct Disconnected(pub Instant);
+
+impl Component for Disconnected {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// 客户端断线重连后恢复了原来的实体，只保留一帧，游戏逻辑需要重新下发完整数据
+#[derive(Debug, Default)]
+pub struct ReconnectEvent;
+
+impl Component for ReconnectEvent {
+    type Storage = HashMapStorage<Self>;
+}
+
+/// 服务器主动关闭的连接，例如重复登录、踢下线以及数据错误，连接断开后不保留实体等待重连
+#[derive(Debug, Default)]
+pub struct Kicked;
+
+impl Component for Kicked {
+    type Storage = NullStorage<Self>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone)]
+    struct TestScene {
+        regions: Vec<Region>,
+        layers: i32,
+    }
+
+    impl SceneData for TestScene {
+        fn id(&self) -> u32 {
+            1
+        }
+
+        fn get_min_x(&self) -> f32 {
+            0.0
+        }
+
+        fn get_min_y(&self) -> f32 {
+            0.0
+        }
+
+        fn get_column(&self) -> i32 {
+            10
+        }
+
+        fn get_row(&self) -> i32 {
+            10
+        }
+
+        fn grid_size(&self) -> f32 {
+            10.0
+        }
+
+        fn regions(&self) -> &[Region] {
+            &self.regions
+        }
+
+        fn layer_height(&self) -> f32 {
+            10.0
+        }
+
+        fn get_layers(&self) -> i32 {
+            self.layers
+        }
+    }
+
+    fn scene(layers: i32) -> TestScene {
+        TestScene {
+            regions: vec![Region {
+                min_x: 20.0,
+                min_y: 20.0,
+                column: 2,
+                row: 2,
+                grid_size: 5.0,
+            }],
+            layers,
+        }
+    }
+
+    #[test]
+    fn region_index() {
+        let scene = scene(1);
+        assert_eq!(scene.layer_grids(), 104);
+        assert_eq!(scene.grid_index(5.0, 5.0), Some(0));
+        assert_eq!(scene.grid_index(15.0, 25.0), Some(21));
+        // 区域内的格子排在整个场景格子之后
+        assert_eq!(scene.grid_index(21.0, 21.0), Some(100));
+        assert_eq!(scene.grid_index(26.0, 21.0), Some(101));
+        assert_eq!(scene.grid_index(29.0, 29.0), Some(103));
+        assert_eq!(scene.grid_index(30.0, 30.0), Some(33));
+        assert_eq!(scene.grid_index(-1.0, 5.0), None);
+        assert_eq!(scene.grid_index(100.0, 5.0), None);
+    }
+
+    #[test]
+    fn region_rect() {
+        let scene = scene(1);
+        assert_eq!(scene.grid_rect(12), (20.0, 10.0, 30.0, 20.0));
+        assert_eq!(scene.grid_rect(100), (20.0, 20.0, 25.0, 25.0));
+        assert_eq!(scene.grid_rect(103), (25.0, 25.0, 30.0, 30.0));
+    }
+
+    #[test]
+    fn region_around() {
+        let scene = scene(1);
+        assert_eq!(
+            scene.around_radius(100, 1),
+            vec![11, 12, 21, 22, 100, 101, 102, 103]
+        );
+        // 普通格子的视野同样包括相交的区域格子
+        assert_eq!(
+            scene.around_radius(11, 1),
+            vec![0, 1, 2, 10, 11, 12, 20, 21, 22, 100, 101, 102, 103]
+        );
+        assert_eq!(scene.around_radius(0, 1), vec![0, 1, 10, 11]);
+    }
+
+    #[test]
+    fn layer_around() {
+        let scene = scene(2);
+        assert_eq!(scene.position_index(21.0, 21.0, 15.0), Some(204));
+        assert_eq!(scene.grid_rect(204), (20.0, 20.0, 25.0, 25.0));
+        assert_eq!(scene.around_radius(104, 0), vec![104]);
+        // 不同层互相不可见，平面上的视野与第一层相同
+        let plane: Vec<_> = scene
+            .around_radius(100, 1)
+            .into_iter()
+            .map(|index| index + 104)
+            .collect();
+        assert_eq!(scene.around_radius(204, 1), plane);
+    }
+}
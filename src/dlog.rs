@@ -1,4 +1,12 @@
 use log::{LevelFilter, Log, Metadata, Record};
+use std::{
+    collections::HashSet,
+    fmt::Arguments,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        RwLock,
+    },
+};
 
 #[repr(C)]
 pub struct LogParam {
@@ -69,3 +77,38 @@ pub fn log_param() -> LogParam {
         level: log::max_level(),
     }
 }
+
+lazy_static::lazy_static! {
+    static ref TRACED: RwLock<HashSet<u32>> = Default::default();
+}
+
+/// 没有跟踪任何实体时跳过加锁
+static TRACING: AtomicBool = AtomicBool::new(false);
+
+/// 跟踪实体，引擎各个环节处理该实体时都会输出一行trace日志
+pub fn follow_entity(id: u32) {
+    let mut traced = TRACED.write().unwrap();
+    traced.insert(id);
+    TRACING.store(true, Ordering::Relaxed);
+}
+
+pub fn unfollow_entity(id: u32) {
+    let mut traced = TRACED.write().unwrap();
+    traced.remove(&id);
+    TRACING.store(!traced.is_empty(), Ordering::Relaxed);
+}
+
+pub fn followed_entities() -> Vec<u32> {
+    TRACED.read().unwrap().iter().cloned().collect()
+}
+
+pub fn is_followed(id: u32) -> bool {
+    TRACING.load(Ordering::Relaxed) && TRACED.read().unwrap().contains(&id)
+}
+
+/// 格式为 entity:{id} stage:{stage} {args}
+pub(crate) fn trace_entity(id: u32, stage: &str, args: Arguments) {
+    if is_followed(id) {
+        log::info!(target: "entity_trace", "entity:{} stage:{} {}", id, stage, args);
+    }
+}
@@ -2,11 +2,13 @@ use crate::{
     dlog::{log_param, LogParam},
     Symbol,
 };
+use crossbeam::channel::{Receiver, Sender};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     ffi::OsString,
     path::PathBuf,
-    sync::{Arc, RwLock},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant},
 };
 
 pub struct Library {
@@ -93,10 +95,45 @@ impl Library {
     }
 }
 
-#[derive(Default)]
+/// 函数被隔离时发出的事件
+#[derive(Debug, Clone)]
+pub struct QuarantineEvent {
+    pub library: String,
+    pub function: String,
+    pub panics: usize,
+}
+
+/// 动态库函数的健康状况
+#[derive(Debug, Clone, Default)]
+pub struct FunctionHealth {
+    /// 累计panic次数
+    pub panics: usize,
+    /// 统计窗口内的panic时间
+    recent: VecDeque<Instant>,
+    /// 被隔离时的库版本
+    quarantined: Option<usize>,
+}
+
+impl FunctionHealth {
+    pub fn is_quarantined(&self) -> bool {
+        self.quarantined.is_some()
+    }
+}
+
 pub struct DynamicManager {
     libraries: RwLock<HashMap<String, Arc<Library>>>,
     library_path: String,
+    /// 窗口时间内panic次数达到上限后隔离函数，0表示不隔离
+    max_panics: usize,
+    panic_window: Duration,
+    health: Mutex<HashMap<(String, String), FunctionHealth>>,
+    events: (Sender<QuarantineEvent>, Receiver<QuarantineEvent>),
+}
+
+impl Default for DynamicManager {
+    fn default() -> Self {
+        Self::new(Default::default())
+    }
 }
 
 impl DynamicManager {
@@ -104,9 +141,20 @@ impl DynamicManager {
         Self {
             libraries: Default::default(),
             library_path,
+            max_panics: 0,
+            panic_window: Duration::from_secs(60),
+            health: Default::default(),
+            events: crossbeam::channel::unbounded(),
         }
     }
 
+    /// 在`panic_window`时间内panic次数达到`max_panics`时停止调用该函数
+    pub fn with_quarantine(mut self, max_panics: usize, panic_window: Duration) -> Self {
+        self.max_panics = max_panics;
+        self.panic_window = panic_window;
+        self
+    }
+
     pub fn get(&self, lib: &String) -> Arc<Library> {
         {
             if let Some(lib) = self.libraries.read().unwrap().get(lib) {
@@ -123,6 +171,97 @@ impl DynamicManager {
             nlib
         }
     }
+
+    /// 重新加载已经加载过的动态库，库不存在或者加载失败时返回false
+    pub fn reload(&self, lib: &String) -> bool {
+        let lib = match self.libraries.read().unwrap().get(lib) {
+            Some(lib) => lib.clone(),
+            None => {
+                log::debug!("library {} not loaded, reload ignored", lib);
+                return false;
+            }
+        };
+        log::warn!("library {} reload", lib.name);
+        let generation = lib.generation();
+        let lib = unsafe { &mut *(lib.as_ref() as *const Library as *mut Library) };
+        lib.reload();
+        lib.generation() != generation
+    }
+
+    /// 记录一次panic，返回函数是否因此被隔离
+    pub fn report_panic(&self, lib: &String, func: &String) -> bool {
+        let mut health = self.health.lock().unwrap();
+        let health = health.entry((lib.clone(), func.clone())).or_default();
+        health.panics += 1;
+        if self.max_panics == 0 || health.quarantined.is_some() {
+            return false;
+        }
+        let now = Instant::now();
+        health.recent.push_back(now);
+        while let Some(time) = health.recent.front() {
+            if now.duration_since(*time) > self.panic_window {
+                health.recent.pop_front();
+            } else {
+                break;
+            }
+        }
+        if health.recent.len() < self.max_panics {
+            return false;
+        }
+        health.recent.clear();
+        health.quarantined.replace(self.get(lib).generation());
+        log::error!(
+            "function {} in library {} quarantined after {} panics",
+            func,
+            lib,
+            health.panics
+        );
+        let event = QuarantineEvent {
+            library: lib.clone(),
+            function: func.clone(),
+            panics: health.panics,
+        };
+        if let Err(err) = self.events.0.send(event) {
+            log::error!("send quarantine event failed:{}", err);
+        }
+        true
+    }
+
+    pub fn is_quarantined(&self, lib: &String, func: &String) -> bool {
+        self.health
+            .lock()
+            .unwrap()
+            .get(&(lib.clone(), func.clone()))
+            .map_or(false, FunctionHealth::is_quarantined)
+    }
+
+    /// 解除隔离，只有动态库重新加载过才能解除
+    pub fn unquarantine(&self, lib: &String, func: &String) -> bool {
+        let mut health = self.health.lock().unwrap();
+        if let Some(health) = health.get_mut(&(lib.clone(), func.clone())) {
+            if let Some(generation) = health.quarantined {
+                if self.get(lib).generation() == generation {
+                    log::warn!(
+                        "library {} not reloaded, function {} is still quarantined",
+                        lib,
+                        func
+                    );
+                    return false;
+                }
+                health.quarantined.take();
+                log::info!("function {} in library {} unquarantined", func, lib);
+            }
+        }
+        true
+    }
+
+    pub fn health(&self) -> HashMap<(String, String), FunctionHealth> {
+        self.health.lock().unwrap().clone()
+    }
+
+    pub fn quarantine_events(&self) -> Receiver<QuarantineEvent> {
+        self.events.1.clone()
+    }
 }
 
 pub struct DynamicSystem<T> {
@@ -147,6 +286,9 @@ impl<T> Default for DynamicSystem<T> {
 
 impl<T> DynamicSystem<T> {
     pub fn get_symbol(&mut self, dm: &DynamicManager) -> Option<Arc<Symbol<T>>> {
+        if dm.is_quarantined(&self.lname, &self.fname) {
+            return None;
+        }
         if let Some(lib) = &self.lib {
             if lib.generation() == self.generation {
                 return self.func.clone();
@@ -167,6 +309,15 @@ impl<T> DynamicSystem<T> {
         self.func.clone()
     }
 
+    pub fn is_quarantined(&self, dm: &DynamicManager) -> bool {
+        dm.is_quarantined(&self.lname, &self.fname)
+    }
+
+    /// 导出函数返回None表示调用时发生了panic
+    pub fn report_panic(&self, dm: &DynamicManager) {
+        dm.report_panic(&self.lname, &self.fname);
+    }
+
     pub fn init(&mut self, lname: String, fname: String, dm: &DynamicManager) {
         if self.generation != 0 {
             panic!(
@@ -0,0 +1,163 @@
+use crate::{dlog::followed_entities, resource::FrameCounter};
+use specs::{Component, RunNow, World, WorldExt};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::{Debug, Display, Formatter},
+};
+
+/// 一帧中被跟踪实体的组件状态，(实体id, 组件名) -> Debug输出
+pub type Snapshot = BTreeMap<(u32, &'static str), String>;
+
+/// 组件在两帧之间的差异，None表示组件不存在
+pub struct ComponentDiff {
+    pub entity: u32,
+    pub component: &'static str,
+    pub before: Option<String>,
+    pub after: Option<String>,
+}
+
+impl Display for ComponentDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "entity:{} component:{}", self.entity, self.component)?;
+        match (&self.before, &self.after) {
+            (None, Some(after)) => writeln!(f, "+ {}", after),
+            (Some(before), None) => writeln!(f, "- {}", before),
+            (Some(before), Some(after)) => {
+                let before: Vec<_> = before.lines().collect();
+                let after: Vec<_> = after.lines().collect();
+                for i in 0..before.len().max(after.len()) {
+                    match (before.get(i), after.get(i)) {
+                        (Some(b), Some(a)) if a == b => continue,
+                        (b, a) => {
+                            if let Some(b) = b {
+                                writeln!(f, "- {}", b)?;
+                            }
+                            if let Some(a) = a {
+                                writeln!(f, "+ {}", a)?;
+                            }
+                        }
+                    }
+                }
+                Ok(())
+            }
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// 最近若干帧的组件快照
+#[derive(Default)]
+pub struct ComponentHistory {
+    capacity: usize,
+    snapshots: VecDeque<(usize, Snapshot)>,
+}
+
+impl ComponentHistory {
+    pub fn snapshot(&self, frame: usize) -> Option<&Snapshot> {
+        self.snapshots
+            .iter()
+            .find(|(f, _)| *f == frame)
+            .map(|(_, snapshot)| snapshot)
+    }
+
+    /// 已保存快照的帧号
+    pub fn frames(&self) -> Vec<usize> {
+        self.snapshots.iter().map(|(frame, _)| *frame).collect()
+    }
+
+    /// 比较两帧的快照，任意一帧不存在时返回None
+    pub fn diff(&self, from: usize, to: usize) -> Option<Vec<ComponentDiff>> {
+        let before = self.snapshot(from)?;
+        let after = self.snapshot(to)?;
+        let mut diffs = Vec::new();
+        for (key, value) in before {
+            match after.get(key) {
+                Some(v) if v == value => {}
+                v => diffs.push(ComponentDiff {
+                    entity: key.0,
+                    component: key.1,
+                    before: Some(value.clone()),
+                    after: v.cloned(),
+                }),
+            }
+        }
+        for (key, value) in after {
+            if !before.contains_key(key) {
+                diffs.push(ComponentDiff {
+                    entity: key.0,
+                    component: key.1,
+                    before: None,
+                    after: Some(value.clone()),
+                });
+            }
+        }
+        Some(diffs)
+    }
+
+    fn push(&mut self, frame: usize, snapshot: Snapshot) {
+        if self.snapshots.len() >= self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back((frame, snapshot));
+    }
+}
+
+/// 每帧记录被跟踪实体（`follow_entity`）的组件状态，需要以thread local方式添加在最后，
+/// 数据集组件的Debug输出包含各方向的变更掩码
+pub struct ComponentDiffSystem {
+    capacity: usize,
+    capturers: Vec<Box<dyn Fn(&World, &[u32], &mut Snapshot)>>,
+}
+
+impl ComponentDiffSystem {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            capturers: Vec::new(),
+        }
+    }
+
+    pub fn watch<T>(mut self) -> Self
+    where
+        T: Component + Debug,
+    {
+        self.capturers.push(Box::new(|world, ids, snapshot| {
+            let storage = world.read_storage::<T>();
+            let entities = world.entities();
+            for id in ids {
+                let entity = entities.entity(*id);
+                if !entities.is_alive(entity) {
+                    continue;
+                }
+                if let Some(data) = storage.get(entity) {
+                    snapshot.insert((*id, std::any::type_name::<T>()), format!("{:#?}", data));
+                }
+            }
+        }));
+        self
+    }
+}
+
+impl<'a> RunNow<'a> for ComponentDiffSystem {
+    fn run_now(&mut self, world: &'a World) {
+        let ids = followed_entities();
+        if ids.is_empty() {
+            return;
+        }
+        let mut snapshot = Snapshot::new();
+        for capturer in &self.capturers {
+            capturer(world, &ids, &mut snapshot);
+        }
+        let frame = world.read_resource::<FrameCounter>().frame();
+        world
+            .write_resource::<ComponentHistory>()
+            .push(frame, snapshot);
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        world.insert(ComponentHistory {
+            capacity: self.capacity,
+            snapshots: Default::default(),
+        });
+    }
+}
@@ -1,19 +1,28 @@
 #![feature(trait_alias)]
 #![feature(associated_type_bounds)]
+#![feature(associated_type_defaults)]
 
+pub(crate) mod admin;
 pub(crate) mod backend;
 pub(crate) mod component;
 pub(crate) mod dlog;
 pub(crate) mod dynamic;
+#[cfg(feature = "debug")]
+pub(crate) mod inspect;
 pub(crate) mod network;
+pub(crate) mod replay;
 pub(crate) mod resource;
+pub(crate) mod snapshot;
+pub(crate) mod spatial;
 pub(crate) mod sync;
 pub(crate) mod system;
+pub(crate) mod testing;
+pub(crate) mod transaction;
 
 use crate::{
-    network::async_run,
+    network::{async_run, run_relay, TlsConfig},
     resource::TimeStatistic,
-    system::{GameSystem, PrintStatisticSystem, StatisticRunNow, StatisticSystem},
+    system::{GameSystem, PrintStatisticSystem, StageSystem, StatisticRunNow, StatisticSystem},
 };
 
 use crate::{component::AroundFullData, resource::FrameCounter};
@@ -24,29 +33,61 @@ use specs::{
 use std::{
     net::SocketAddr,
     ops::Deref,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
     thread::sleep,
     time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
-pub use backend::{CommandId, DropEntity, Input, Output, SceneSyncBackend};
+pub use admin::{AdminCommand, AdminSystem};
+pub use backend::{
+    AlwaysVisible, CommandId, DropEntity, Input, Output, SceneSyncBackend, VisibilityFilter,
+};
 pub use codegen::{export, init_log, request, setup, system, FromRow};
 pub use component::{
-    Closing, HashComponent, NetToken, Position, SceneData, SceneMember, SelfSender, TeamMember,
+    AccountId, Authority, Bandwidth, Closing, Cooldown, Disconnected, EnteredAoi, GroupFullData,
+    HashComponent, InputSequence, InvalidMove, Kicked, LeftAoi, Loading, Member, NetToken, Origin,
+    Position, ReconnectEvent, Region, RemoteMember, SceneData, SceneLifetime, SceneMember,
+    SelfSender, SessionKey, StaticEntity, SyncPriority, TeamMember, Throttled,
+};
+pub use dlog::{
+    follow_entity, followed_entities, init as init_logger, is_followed, unfollow_entity, LogParam,
 };
-pub use dlog::{init as init_logger, LogParam};
-pub use dynamic::{DynamicManager, DynamicSystem};
+pub use dynamic::{DynamicManager, DynamicSystem, FunctionHealth, QuarantineEvent};
 pub use generator::{Generator, SyncDirection};
+#[cfg(feature = "debug")]
+pub use inspect::{ComponentDiff, ComponentDiffSystem, ComponentHistory, Snapshot};
 #[cfg(target_os = "windows")]
 pub use libloading::os::windows::Symbol;
 #[cfg(not(target_os = "windows"))]
 pub use libloading::os::windows::Symbol;
-pub use network::{channel, BytesSender, RequestIdent};
-pub use resource::SceneManager;
-pub use sync::{DataBackend, DataSet};
+pub use network::{
+    channel, AccessControl, BytesSender, Connected, NonceCache, RateLimit, RateLimitAction,
+    RelaySender, RequestIdent, COMPRESSED_FLAG, DUPLICATE_LOGIN_CMD, HANDSHAKE_CMD, HEARTBEAT_CMD,
+    LOGIN_QUEUE_CMD, RESERVED_CMDS, RESUME_CMD, SEQUENCE_CMD, SERVER_FULL_CMD, SESSION_CMD,
+    VERSION_MISMATCH_CMD,
+};
+pub use replay::{InputJournal, ReplayRunner};
+pub use resource::{
+    BenchRecord, FrameBudget, GroupHierarchy, LoginQueue, NetworkMetrics, ObserverManager,
+    SceneManager, SceneMetrics, SceneSyncStat, SpawnBudget, StartupCheck, SyncBudget, SyncLimit,
+    SystemToggles, TeamError, TeamEvent, TeamHierarchy, TeamManager, TeamRole, WorldStats,
+};
+pub use snapshot::{restore_snapshot, SnapshotRegistry, SnapshotSystem};
+pub use spatial::{GridIndex, QuadTreeIndex, SpatialIndex};
+pub use sync::{delta_digest, DataBackend, DataSet, Expire, Persist};
 pub use system::{
-    CleanStorageSystem, CloseSystem, CommitChangeSystem, GridSystem, HandshakeSystem, InputSystem,
-    SceneSystem, TeamManagerSystem, TeamSystem,
+    bench_begin, bench_end, bench_register, AckSystem, AliasSystem, BandwidthSystem,
+    CleanStorageSystem, CloseSystem, CommitChangeSystem, CompactStorageSystem, CooldownSystem,
+    DatabaseSystem, DuplicateLoginSystem, ExpireSystem, GridSystem, GroupSystem, HandshakeSystem,
+    InputSystem, LoadEntitySystem, LoginPolicy, MovementValidatorSystem, RelaySystem, SceneSystem,
+    SessionSystem, TeamManagerSystem, TeamSystem, TimeSlice, BENCH,
 };
+pub use testing::{FakeNetwork, FaultInjection};
+pub use transaction::Transaction;
 pub type GameEntities = Entities<'static>;
 pub type GameReadStorage<T> = ReadStorage<'static, T>;
 pub type GameWriteStorage<T> = WriteStorage<'static, T>;
@@ -67,26 +108,91 @@ impl<T> Deref for ReadOnly<T> {
 #[derive(Debug)]
 pub enum BuildEngineError {
     AddressNotSet,
+    /// 监听地址数量超过network::MAX_LISTENERS
+    TooManyAddresses,
     DecoderNotSet,
+    /// 证书或私钥加载失败
+    InvalidTlsConfig(std::io::Error),
+}
+
+#[derive(Debug)]
+pub enum RunEngineError {
+    /// 快照文件存在但恢复失败
+    Snapshot(std::io::Error),
+    /// 严格模式下启动一致性检查发现的问题
+    StartupCheck(Vec<String>),
 }
 
 pub struct EngineBuilder {
-    address: Option<SocketAddr>,
+    addresses: Vec<SocketAddr>,
+    udp_address: Option<SocketAddr>,
+    admin_address: Option<SocketAddr>,
+    admin_token: Option<String>,
+    relay: Option<(SocketAddr, u32)>,
     fps: u32,
     idle_timeout: Duration,
     read_timeout: Duration,
     write_timeout: Duration,
     poll_timeout: Option<Duration>,
+    heartbeat: Option<(Duration, usize)>,
+    session_grace: Option<Duration>,
+    snapshot: Option<(PathBuf, Duration)>,
+    access: AccessControl,
+    max_connections: Option<usize>,
+    proxy_protocol: bool,
+    rate_limit: Option<RateLimit>,
     max_request_size: usize,
     max_response_size: usize,
+    compress_threshold: usize,
     bounded_size: usize,
     library_path: String,
     profile: bool,
+    max_entities: usize,
+    max_scene_entities: usize,
+    stats_interval: Duration,
+    login_policy: LoginPolicy,
+    max_players: usize,
+    bandwidth_cap: usize,
+    max_panics: usize,
+    panic_window: Duration,
+    stage_budgets: Vec<(String, Duration)>,
+    enforce_budget: bool,
+    sync_limits: Vec<(SyncDirection, SyncLimit)>,
+    strict_check: bool,
+    #[cfg(feature = "tls")]
+    tls: Option<(PathBuf, PathBuf)>,
+    encrypt: bool,
 }
 
 impl EngineBuilder {
+    /// 可以多次调用监听多个地址，所有地址在同一个网络线程中处理，实体的Origin组件记录连接来自哪个地址
     pub fn with_address(mut self, address: SocketAddr) -> Self {
-        self.address.replace(address);
+        self.addresses.push(address);
+        self
+    }
+
+    /// 同时监听UDP地址，客户端可以使用可靠UDP会话，请求与响应的语义与TCP一致
+    pub fn with_udp(mut self, address: SocketAddr) -> Self {
+        self.udp_address.replace(address);
+        self
+    }
+
+    /// 开启管理端口，支持`stats`、`kick <entity>`、`reload <lib>`、`toggle <system>`等文本命令，
+    /// 没有设置token时只能监听回环或者内网地址
+    pub fn with_admin(mut self, address: SocketAddr) -> Self {
+        self.admin_address.replace(address);
+        self
+    }
+
+    /// 管理端口的共享token，连接后需要先发送`auth <token>`
+    pub fn with_admin_token(mut self, token: String) -> Self {
+        self.admin_token.replace(token);
+        self
+    }
+
+    /// 连接跨服中转服务，server为本服编号，组队方向发给`RemoteMember`的数据通过中转转发
+    pub fn with_relay(mut self, address: SocketAddr, server: u32) -> Self {
+        self.relay.replace((address, server));
         self
     }
 
@@ -115,6 +221,53 @@ impl EngineBuilder {
         self
     }
 
+    /// 每隔interval向客户端发送心跳，连续max_missed次未收到回应时断开连接
+    pub fn with_heartbeat(mut self, interval: Duration, max_missed: usize) -> Self {
+        self.heartbeat.replace((interval, max_missed));
+        self
+    }
+
+    /// 最大连接数，超过后新的连接收到`SERVER_FULL_CMD`错误帧后被关闭
+    pub fn with_max_connections(mut self, max_connections: usize) -> Self {
+        self.max_connections.replace(max_connections);
+        self
+    }
+
+    /// TCP连接以PROXY协议v2头部开始，连接地址以及准入检查使用头部中的真实客户端地址
+    pub fn with_proxy_protocol(mut self) -> Self {
+        self.proxy_protocol = true;
+        self
+    }
+
+    /// 单个连接每秒最多rate个请求，最多累积burst个，超出后按action丢弃请求或者关闭连接
+    pub fn with_rate_limit(mut self, rate: u32, burst: u32, action: RateLimitAction) -> Self {
+        self.rate_limit.replace(RateLimit {
+            rate,
+            burst,
+            action,
+        });
+        self
+    }
+
+    /// 连接的IP黑白名单以及单个IP的连接数上限
+    pub fn with_access_control(mut self, access: AccessControl) -> Self {
+        self.access = access;
+        self
+    }
+
+    /// 开启断线重连，连接断开后实体保留grace时间，客户端使用会话密钥重连后恢复原来的实体
+    pub fn with_session_resume(mut self, grace: Duration) -> Self {
+        self.session_grace.replace(grace);
+        self
+    }
+
+    /// 定期保存快照，不包括属于网络连接的实体；启动时如果快照文件存在，在setup回调之后、
+    /// 系统setup以及网络启动之前从快照恢复，setup回调中不能创建实体，恢复失败时拒绝启动
+    pub fn with_snapshot(mut self, path: impl Into<PathBuf>, interval: Duration) -> Self {
+        self.snapshot = Some((path.into(), interval));
+        self
+    }
+
     pub fn with_max_request_size(mut self, max_request_size: usize) -> Self {
         self.max_request_size = max_request_size;
         self
@@ -125,6 +278,12 @@ impl EngineBuilder {
         self
     }
 
+    /// 包体超过threshold字节的响应使用lz4压缩，并在长度字段设置network::COMPRESSED_FLAG
+    pub fn with_compress_threshold(mut self, threshold: usize) -> Self {
+        self.compress_threshold = threshold;
+        self
+    }
+
     pub fn with_bounded_size(mut self, bounded_size: usize) -> Self {
         self.bounded_size = bounded_size;
         self
@@ -140,69 +299,246 @@ impl EngineBuilder {
         self
     }
 
+    /// 同一账号重复登录时的处理策略，默认踢掉旧连接
+    pub fn with_login_policy(mut self, login_policy: LoginPolicy) -> Self {
+        self.login_policy = login_policy;
+        self
+    }
+
+    /// 同时在线的玩家上限，超出后新连接进入登录队列，0表示不限制
+    pub fn with_max_players(mut self, max_players: usize) -> Self {
+        self.max_players = max_players;
+        self
+    }
+
+    /// 每个连接默认的每秒发送字节数上限，超出后延后周围实体的同步，0表示不限制
+    pub fn with_bandwidth_cap(mut self, bandwidth_cap: usize) -> Self {
+        self.bandwidth_cap = bandwidth_cap;
+        self
+    }
+
+    /// 动态库函数在`panic_window`时间内panic达到`max_panics`次后停止调用，0表示不隔离
+    pub fn with_panic_quarantine(mut self, max_panics: usize, panic_window: Duration) -> Self {
+        self.max_panics = max_panics;
+        self.panic_window = panic_window;
+        self
+    }
+
+    /// 设置阶段每帧的时间预算，超出时输出告警
+    pub fn with_stage_budget(mut self, stage: &str, budget: Duration) -> Self {
+        self.stage_budgets.push((stage.into(), budget));
+        self
+    }
+
+    /// 阶段超出预算时把周围实体的同步延后到下一帧
+    pub fn with_budget_enforce(mut self) -> Self {
+        self.enforce_budget = true;
+        self
+    }
+
+    /// 设置每个接收者每帧的同步预算，超出时低优先级实体的同步延后，参考`SyncPriority`
+    pub fn with_sync_budget(mut self, direction: SyncDirection, limit: SyncLimit) -> Self {
+        self.sync_limits.push((direction, limit));
+        self
+    }
+
+    /// 启动一致性检查发现问题时拒绝启动，`run`返回`RunEngineError::StartupCheck`，默认只输出告警
+    pub fn with_strict_check(mut self) -> Self {
+        self.strict_check = true;
+        self
+    }
+
+    /// 使用PEM格式的证书链以及私钥开启TLS，证书在build时加载
+    #[cfg(feature = "tls")]
+    pub fn with_tls(mut self, cert_path: impl Into<PathBuf>, key_path: impl Into<PathBuf>) -> Self {
+        self.tls.replace((cert_path.into(), key_path.into()));
+        self
+    }
+
+    /// 连接建立后先进行x25519密钥交换，之后的请求和响应都使用ChaCha20-Poly1305加密，
+    /// 用于无法部署TLS的场景
+    #[cfg(feature = "encrypt")]
+    pub fn with_encryption(mut self) -> Self {
+        self.encrypt = true;
+        self
+    }
+
+    /// 世界实体数量的软上限，0表示不限制，达到上限时新连接继续排队，场景实例创建失败
+    pub fn with_max_entities(mut self, max_entities: usize) -> Self {
+        self.max_entities = max_entities;
+        self
+    }
+
+    /// 世界统计的刷新间隔，默认1秒，刷新需要遍历所有实体以及统计的组件，
+    /// 两次刷新之间世界实体数量的上限按照上次统计的数量加上新申请的数量检查
+    pub fn with_stats_interval(mut self, interval: Duration) -> Self {
+        self.stats_interval = interval;
+        self
+    }
+
+    /// 单个场景实体数量的软上限，0表示不限制
+    pub fn with_max_scene_entities(mut self, max_scene_entities: usize) -> Self {
+        self.max_scene_entities = max_scene_entities;
+        self
+    }
+
     pub fn build(self) -> Result<Engine, BuildEngineError> {
-        if self.address.is_none() {
+        if self.addresses.is_empty() {
             return Err(BuildEngineError::AddressNotSet);
         }
-        let address = self.address.clone().unwrap();
+        if self.addresses.len() > network::MAX_LISTENERS {
+            return Err(BuildEngineError::TooManyAddresses);
+        }
+        let addresses = self.addresses.clone();
         let sleep = Duration::new(1, 0) / self.fps;
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "tls")] {
+                let tls = match &self.tls {
+                    Some((cert_path, key_path)) => Some(
+                        network::load_tls_config(cert_path, key_path)
+                            .map_err(BuildEngineError::InvalidTlsConfig)?,
+                    ),
+                    None => None,
+                };
+            } else {
+                let tls = None;
+            }
+        }
         Ok(Engine {
-            address,
+            addresses,
             sleep,
+            tls,
             builder: self,
         })
     }
 }
 
 pub struct Engine {
-    address: SocketAddr,
+    addresses: Vec<SocketAddr>,
     sleep: Duration,
+    tls: Option<TlsConfig>,
     builder: EngineBuilder,
 }
 
 impl Engine {
     pub fn builder() -> EngineBuilder {
         EngineBuilder {
-            address: None,
+            addresses: Vec::new(),
+            udp_address: None,
+            admin_address: None,
+            admin_token: None,
+            relay: None,
             fps: 30,
             idle_timeout: Duration::new(30 * 60, 0),
             read_timeout: Duration::new(30, 0),
             write_timeout: Duration::new(30, 0),
             max_request_size: 1024 * 16,
             max_response_size: 1024 * 16,
+            compress_threshold: 0,
             poll_timeout: None,
+            heartbeat: None,
+            session_grace: None,
+            snapshot: None,
+            access: Default::default(),
+            max_connections: None,
+            proxy_protocol: false,
+            rate_limit: None,
             bounded_size: 0,
             library_path: Default::default(),
             profile: false,
+            max_entities: 0,
+            max_scene_entities: 0,
+            stats_interval: Duration::from_secs(1),
+            login_policy: Default::default(),
+            max_players: 0,
+            bandwidth_cap: 0,
+            max_panics: 0,
+            panic_window: Duration::from_secs(60),
+            stage_budgets: Vec::new(),
+            enforce_budget: false,
+            sync_limits: Vec::new(),
+            strict_check: false,
+            #[cfg(feature = "tls")]
+            tls: None,
+            encrypt: false,
         }
     }
 
-    pub fn run<I, S>(self, setup: S)
+    pub fn run<I, S>(self, setup: S) -> Result<(), RunEngineError>
+    where
+        I: Input + Send + Sync + 'static,
+        S: Fn(&mut World, &mut GameDispatcherBuilder, &DynamicManager) -> I,
+    {
+        self.run_until(setup, ShutdownToken::default())
+    }
+
+    /// 运行直到shutdown被触发，之后停止监听并关闭所有连接，等待网络线程退出，
+    /// 再执行最后几帧处理连接关闭以及数据落地后返回；启动失败时不会启动网络，直接返回错误
+    pub fn run_until<I, S>(self, setup: S, shutdown: ShutdownToken) -> Result<(), RunEngineError>
     where
         I: Input + Send + Sync + 'static,
         S: Fn(&mut World, &mut GameDispatcherBuilder, &DynamicManager) -> I,
     {
         let mut builder = GameDispatcherBuilder::new(self.builder.profile);
         let mut world = World::new();
-        let dm = DynamicManager::new(self.builder.library_path.clone());
-        let request = setup(&mut world, &mut builder, &dm);
-        let sender = async_run(
-            self.address,
-            self.builder.idle_timeout,
-            self.builder.read_timeout,
-            self.builder.write_timeout,
-            self.builder.poll_timeout,
-            self.builder.max_request_size,
-            self.builder.max_response_size,
-            self.builder.bounded_size,
-            request,
+        let dm = DynamicManager::new(self.builder.library_path.clone())
+            .with_quarantine(self.builder.max_panics, self.builder.panic_window);
+        let mut stats = WorldStats::default().with_interval(self.builder.stats_interval);
+        stats.watch::<NetToken>();
+        stats.watch::<Closing>();
+        stats.watch::<AroundFullData>();
+        world.insert(stats);
+        world.insert(SpawnBudget::new(
+            self.builder.max_entities,
+            self.builder.max_scene_entities,
+        ));
+        world.insert(LoginQueue::new(self.builder.max_players));
+        let budget = self.builder.stage_budgets.iter().fold(
+            FrameBudget::new(self.builder.enforce_budget),
+            |budget, (stage, duration)| budget.with_budget(stage, *duration),
         );
-        world.insert(sender.clone());
+        world.insert(budget);
+        let sync_budget = self
+            .builder
+            .sync_limits
+            .iter()
+            .fold(SyncBudget::new(), |budget, (direction, limit)| {
+                budget.with_limit(*direction, *limit)
+            });
+        world.insert(sync_budget);
+        world.insert(StartupCheck::default());
+        // 生成的系统在setup时注册bench记录
+        if self.builder.profile {
+            world.insert(TimeStatistic::new());
+        }
+        let request = setup(&mut world, &mut builder, &dm);
+        world
+            .write_resource::<StartupCheck>()
+            .check_commands(request.commands());
+        let resume = self.builder.session_grace.map(|grace| {
+            let (resume, receiver) = channel(self.builder.bounded_size);
+            builder.add(SessionSystem::new(receiver, grace), "session", &[]);
+            resume
+        });
+        let admin = self.builder.admin_address.map(|address| {
+            let (admin, receiver) = channel(self.builder.bounded_size);
+            builder.add_thread_local("admin", AdminSystem::new(receiver));
+            (address, admin)
+        });
+        let relay = self.builder.relay.map(|(address, server)| {
+            let (received, receiver) = channel(self.builder.bounded_size);
+            builder.add(RelaySystem::new(receiver), "relay", &[]);
+            (address, server, received)
+        });
+        let (connected, receiver) = channel(self.builder.bounded_size);
+        builder.add(HandshakeSystem::new(receiver), "handshake", &[]);
+        let metrics = NetworkMetrics::default();
+        world.insert(metrics.clone());
+        world.insert(shutdown.clone());
         world.insert(FrameCounter::default());
         world.register::<NetToken>();
 
         if self.builder.profile {
-            world.insert(TimeStatistic::new());
             builder.add_thread_local("print_statistic", PrintStatisticSystem);
         }
         cfg_if::cfg_if! {
@@ -210,25 +546,97 @@ impl Engine {
                 builder.add_thread_local("reload", crate::system::FsNotifySystem::new(self.builder.library_path.clone(), false));
             }
         }
-        builder.add(CloseSystem, "close", &[]);
+        let login = DuplicateLoginSystem::new(&mut world, self.builder.login_policy);
+        builder.add(login, "duplicate_login", &[]);
+        builder.add(CloseSystem, "close", &["duplicate_login"]);
+        builder.add(
+            BandwidthSystem::new(self.builder.bandwidth_cap),
+            "bandwidth",
+            &[],
+        );
         builder.add(
             CleanStorageSystem::<AroundFullData>::default(),
             "around_full_data_clean",
             &[],
         );
 
+        if let Some((path, interval)) = &self.builder.snapshot {
+            builder.add_thread_local("snapshot", SnapshotSystem::new(path.clone(), *interval));
+        }
+
         world.insert(dm);
 
+        // 快照在系统setup以及网络启动之前恢复，恢复失败时不启动
+        if let Some((path, _)) = &self.builder.snapshot {
+            if path.exists() {
+                snapshot::restore_snapshot(&mut world, path).map_err(RunEngineError::Snapshot)?;
+            }
+        }
+
         // setup dispatcher
         let mut dispatcher = builder.build();
         dispatcher.setup(&mut world);
 
-        loop {
+        // 一致性检查在网络启动之前完成，严格模式下有问题时不启动
+        let check = world.read_resource::<StartupCheck>();
+        for problem in check.problems() {
+            log::error!("startup check failed: {}", problem);
+        }
+        if !check.is_empty() && self.builder.strict_check {
+            return Err(RunEngineError::StartupCheck(check.problems().clone()));
+        }
+        drop(check);
+
+        let (sender, handles) = async_run(
+            self.addresses.clone(),
+            self.builder.udp_address,
+            self.builder.idle_timeout,
+            self.builder.read_timeout,
+            self.builder.write_timeout,
+            self.builder.poll_timeout,
+            self.builder.max_request_size,
+            self.builder.max_response_size,
+            self.builder.compress_threshold,
+            self.builder.bounded_size,
+            self.tls.clone(),
+            self.builder.encrypt,
+            self.builder.heartbeat,
+            resume,
+            connected,
+            metrics,
+            self.builder.access.clone(),
+            self.builder.max_connections,
+            self.builder.proxy_protocol,
+            self.builder.rate_limit,
+            request,
+        );
+        world.insert(sender.clone());
+        if let Some((address, server, received)) = relay {
+            world.insert(run_relay(
+                address,
+                server,
+                self.builder.bounded_size,
+                received,
+            ));
+        }
+        if let Some((address, admin)) = admin {
+            if let Err(err) = admin::run_admin(address, self.builder.admin_token.clone(), admin) {
+                log::error!("admin listen on {} failed:{}", address, err);
+            }
+        }
+
+        while !shutdown.is_shutdown() {
             // input
             world.write_resource::<FrameCounter>().next_frame();
             let start_time = Instant::now();
+            world.write_resource::<FrameBudget>().begin_frame();
             dispatcher.dispatch(&world);
+            world.write_resource::<FrameBudget>().end_frame();
             world.maintain();
+            if world.write_resource::<WorldStats>().refresh(&world) {
+                let entities = world.read_resource::<WorldStats>().entities();
+                world.write_resource::<SpawnBudget>().refresh(entities);
+            }
             // notify network
             sender.flush();
             let elapsed = start_time.elapsed();
@@ -236,6 +644,36 @@ impl Engine {
                 sleep(self.sleep - elapsed);
             }
         }
+
+        log::info!("engine shutdown now");
+        sender.shutdown();
+        for handle in handles {
+            if handle.join().is_err() {
+                log::error!("network thread panicked");
+            }
+        }
+        // 连接关闭的请求先由输入系统写入，下一帧才会被处理
+        for _ in 0..2 {
+            world.write_resource::<FrameCounter>().next_frame();
+            dispatcher.dispatch(&world);
+            world.maintain();
+        }
+        log::info!("engine stopped");
+        Ok(())
+    }
+}
+
+/// 停服信号，可以在其他线程或者系统中触发，Engine::run_until在当前帧结束后开始停服
+#[derive(Clone, Default)]
+pub struct ShutdownToken(Arc<AtomicBool>);
+
+impl ShutdownToken {
+    pub fn shutdown(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_shutdown(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
     }
 }
 
@@ -326,6 +764,19 @@ impl<'a, 'b> GameDispatcherBuilder<'a, 'b> {
         Self { builder, profile }
     }
 
+    /// 开始新的阶段，之前添加的系统全部执行完后才会执行之后添加的系统
+    pub fn add_stage(&mut self, stage: &str) {
+        self.builder.add_barrier();
+        self.builder
+            .add(StageSystem(stage.into()), &format!("stage_{}", stage), &[]);
+        self.builder.add_barrier();
+    }
+
+    pub fn with_stage(mut self, stage: &str) -> Self {
+        self.add_stage(stage);
+        self
+    }
+
     pub fn build(self) -> Dispatcher<'a, 'b> {
         self.builder.build()
     }
@@ -1,21 +1,32 @@
 use std::{
+    collections::{HashMap, HashSet, VecDeque},
     io::{ErrorKind, Read, Result, Write},
-    net::{Shutdown, SocketAddr},
-    sync::Arc,
+    net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread::JoinHandle,
     time::{Duration, Instant},
 };
 
-use crossbeam::channel::{Receiver, Select, Sender};
+use crossbeam::channel::{Receiver, RecvTimeoutError, Select, Sender};
 use mio::{
-    event::Event,
-    net::{TcpListener, TcpStream},
+    event::{Event, Source},
+    net::{TcpListener, TcpStream, UdpSocket},
     Events, Interest, Poll, Registry, Token, Waker,
 };
 use slab::Slab;
+use socket2::{Domain, Socket, Type};
 use specs::Entity;
 
-use crate::backend::{Input, Output};
-use byteorder::{BigEndian, ByteOrder};
+use crate::{
+    backend::{Input, Output},
+    dlog::trace_entity,
+    resource::NetworkMetrics,
+};
+use byteorder::{BigEndian, ByteOrder, LittleEndian};
+use bytes::{Buf, Bytes, BytesMut};
 
 /// 请求标识
 #[derive(Clone)]
@@ -24,18 +35,9 @@ pub enum RequestIdent {
     Entity(Entity),
     /// 网络端连接已经关闭
     Close(Entity),
-    /// 握手包，通知当前的网络Token
-    Token(Token),
 }
 
 impl RequestIdent {
-    pub fn token(self) -> Token {
-        match self {
-            RequestIdent::Token(token) => token,
-            _ => panic!("not a token RequestIdent"),
-        }
-    }
-
     pub fn entity(self) -> Entity {
         match self {
             RequestIdent::Entity(entity) => entity,
@@ -55,16 +57,689 @@ impl RequestIdent {
         }
     }
 
-    pub fn replace_token(&mut self, token: Token) {
-        *self = RequestIdent::Token(token);
-    }
-
     pub fn is_entity(&self) -> bool {
         matches!(self, RequestIdent::Entity(_))
     }
+}
+
+/// 新连接事件，连接接受(开启加密时为握手完成)后直接发送给HandshakeSystem，不经过请求解码
+#[derive(Debug)]
+pub struct Connected {
+    pub token: Token,
+    /// 连接所属的监听序号
+    pub listener: usize,
+    /// 还没有收到实体的连接已经断开，需要从登录队列中移除
+    pub closed: bool,
+}
+
+/// 按实体缓存最近的请求nonce，用于丢弃重发的请求
+pub struct NonceCache {
+    capacity: usize,
+    nonces: HashMap<Entity, VecDeque<u32>>,
+}
+
+impl Default for NonceCache {
+    fn default() -> Self {
+        Self::new(64)
+    }
+}
+
+impl NonceCache {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            nonces: Default::default(),
+        }
+    }
+
+    /// 记录nonce，重复时返回false
+    pub fn check(&mut self, entity: Entity, nonce: u32) -> bool {
+        let nonces = self.nonces.entry(entity).or_default();
+        if nonces.contains(&nonce) {
+            return false;
+        }
+        if nonces.len() >= self.capacity {
+            nonces.pop_front();
+        }
+        nonces.push_back(nonce);
+        true
+    }
+
+    pub fn remove(&mut self, entity: Entity) {
+        self.nonces.remove(&entity);
+    }
+}
+
+/// 连接准入控制，在接受连接时检查
+#[derive(Clone, Default)]
+pub struct AccessControl {
+    allow: HashSet<IpAddr>,
+    deny: HashSet<IpAddr>,
+    max_per_ip: usize,
+}
+
+impl AccessControl {
+    /// 白名单不为空时只接受白名单中的地址
+    pub fn allow(mut self, ip: IpAddr) -> Self {
+        self.allow.insert(ip);
+        self
+    }
+
+    pub fn deny(mut self, ip: IpAddr) -> Self {
+        self.deny.insert(ip);
+        self
+    }
+
+    /// 单个IP的最大连接数，0表示不限制
+    pub fn with_max_per_ip(mut self, max_per_ip: usize) -> Self {
+        self.max_per_ip = max_per_ip;
+        self
+    }
+
+    fn check(&self, ip: &IpAddr, connections: usize) -> bool {
+        if self.deny.contains(ip) || (!self.allow.is_empty() && !self.allow.contains(ip)) {
+            return false;
+        }
+        self.max_per_ip == 0 || connections < self.max_per_ip
+    }
+}
+
+/// 请求超过限流后的处理方式
+#[derive(Clone, Copy, Debug)]
+pub enum RateLimitAction {
+    /// 丢弃超出的请求
+    Drop,
+    /// 关闭连接
+    Shutdown,
+}
+
+/// 单个连接的请求限流，每秒rate个请求，最多累积burst个
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub rate: u32,
+    pub burst: u32,
+    pub action: RateLimitAction,
+}
+
+/// 令牌桶
+struct TokenBucket {
+    limit: RateLimit,
+    tokens: f64,
+    last_time: Instant,
+}
+
+impl TokenBucket {
+    fn new(limit: RateLimit) -> Self {
+        Self {
+            limit,
+            tokens: limit.burst as f64,
+            last_time: Instant::now(),
+        }
+    }
+
+    fn acquire(&mut self) -> bool {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_time).as_secs_f64();
+        self.last_time = now;
+        self.tokens = (self.tokens + elapsed * self.limit.rate as f64).min(self.limit.burst as f64);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// 服务端TLS配置，未开启tls特性时为空类型
+#[cfg(feature = "tls")]
+pub type TlsConfig = Arc<rustls::ServerConfig>;
+#[cfg(not(feature = "tls"))]
+pub type TlsConfig = ();
+
+/// 从PEM文件加载证书链以及私钥，私钥支持PKCS8和RSA格式
+#[cfg(feature = "tls")]
+pub fn load_tls_config(
+    cert_path: &std::path::Path,
+    key_path: &std::path::Path,
+) -> Result<TlsConfig> {
+    use rustls::internal::pemfile::{certs, pkcs8_private_keys, rsa_private_keys};
+    use std::{fs::File, io::BufReader};
+
+    let invalid = |msg: &str| std::io::Error::new(ErrorKind::InvalidData, msg.to_string());
+    let certs = certs(&mut BufReader::new(File::open(cert_path)?))
+        .map_err(|_| invalid("invalid certificate file"))?;
+    let mut keys = pkcs8_private_keys(&mut BufReader::new(File::open(key_path)?))
+        .map_err(|_| invalid("invalid private key file"))?;
+    if keys.is_empty() {
+        keys = rsa_private_keys(&mut BufReader::new(File::open(key_path)?))
+            .map_err(|_| invalid("invalid private key file"))?;
+    }
+    if keys.is_empty() {
+        return Err(invalid("private key not found"));
+    }
+    let mut config = rustls::ServerConfig::new(rustls::NoClientAuth::new());
+    config
+        .set_single_cert(certs, keys.remove(0))
+        .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+    Ok(Arc::new(config))
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(feature = "encrypt")] {
+        use chacha20poly1305::{
+            aead::{Aead, NewAead},
+            ChaCha20Poly1305, Key, Nonce,
+        };
+
+        /// 连接加密，x25519交换密钥后使用ChaCha20-Poly1305加密长度字段之后的内容，
+        /// nonce为 方向(1) | 0(3) | 序号(8)，客户端到服务器方向为0
+        struct Cipher {
+            cipher: ChaCha20Poly1305,
+            send_sn: u64,
+            recv_sn: u64,
+        }
+
+        impl Cipher {
+            /// 根据客户端公钥生成会话密钥，返回服务器公钥
+            fn handshake(public: &[u8]) -> Option<(Self, [u8; 32])> {
+                use sha2::{Digest, Sha256};
+                use x25519_dalek::{EphemeralSecret, PublicKey};
+
+                if public.len() != 32 {
+                    return None;
+                }
+                let mut client = [0u8; 32];
+                client.copy_from_slice(public);
+                let secret = EphemeralSecret::new(rand_core::OsRng);
+                let server = PublicKey::from(&secret);
+                let shared = secret.diffie_hellman(&PublicKey::from(client));
+                let key = Sha256::digest(shared.as_bytes());
+                let cipher = Self {
+                    cipher: ChaCha20Poly1305::new(Key::from_slice(key.as_slice())),
+                    send_sn: 0,
+                    recv_sn: 0,
+                };
+                Some((cipher, *server.as_bytes()))
+            }
+
+            fn nonce(direction: u8, sn: u64) -> [u8; 12] {
+                let mut nonce = [0u8; 12];
+                nonce[0] = direction;
+                BigEndian::write_u64(&mut nonce[4..], sn);
+                nonce
+            }
+
+            fn encrypt(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+                let nonce = Self::nonce(1, self.send_sn);
+                self.send_sn += 1;
+                self.cipher.encrypt(Nonce::from_slice(&nonce), data).ok()
+            }
+
+            fn decrypt(&mut self, data: &[u8]) -> Option<Vec<u8>> {
+                let nonce = Self::nonce(0, self.recv_sn);
+                self.recv_sn += 1;
+                self.cipher.decrypt(Nonce::from_slice(&nonce), data).ok()
+            }
+        }
+    } else {
+        /// 未开启encrypt特性时不会建立加密连接
+        enum Cipher {}
+
+        impl Cipher {
+            fn handshake(_public: &[u8]) -> Option<(Self, [u8; 32])> {
+                None
+            }
+
+            fn encrypt(&mut self, _data: &[u8]) -> Option<Vec<u8>> {
+                match *self {}
+            }
+
+            fn decrypt(&mut self, _data: &[u8]) -> Option<Vec<u8>> {
+                match *self {}
+            }
+        }
+    }
+}
+
+const UDP_PUSH: u8 = 1;
+const UDP_ACK: u8 = 2;
+const UDP_CLOSE: u8 = 3;
+/// UDP数据报头 conv(4) | cmd(1) | sn(4)
+const UDP_HEADER: usize = 9;
+/// 单个数据段的最大负载
+const UDP_MSS: usize = 1200;
+/// 首次重传超时，之后每次翻倍
+const UDP_RTO: Duration = Duration::from_millis(200);
+const UDP_MAX_RETRIES: u32 = 10;
+/// 开启UDP后网络线程的最长等待时间，用于驱动重传
+const UDP_TICK: Duration = Duration::from_millis(10);
+/// 收发窗口的数据段数量，窗口外的数据段直接丢弃
+const UDP_WINDOW: u32 = 256;
+/// 已交付但未被读取的数据上限，超过后不再确认新数据段，由对端重传
+const UDP_MAX_RECEIVED: usize = UDP_WINDOW as usize * UDP_MSS;
+/// 单个监听端口的最大UDP会话数量
+const UDP_MAX_SESSIONS: usize = 4096;
+
+struct Segment {
+    sn: u32,
+    datagram: Vec<u8>,
+    sent: Option<Instant>,
+    retries: u32,
+}
+
+/// 类似KCP的可靠UDP会话，按序号确认、超时重传并按序交付，对上层表现为字节流
+struct UdpSession {
+    socket: Arc<UdpSocket>,
+    peer: SocketAddr,
+    conv: u32,
+    send_sn: u32,
+    unacked: VecDeque<Segment>,
+    recv_sn: u32,
+    /// 乱序到达的数据段
+    pending: HashMap<u32, Vec<u8>>,
+    received: Vec<u8>,
+    closed: bool,
+    dead: bool,
+}
+
+impl UdpSession {
+    fn new(socket: Arc<UdpSocket>, peer: SocketAddr, conv: u32) -> Self {
+        Self {
+            socket,
+            peer,
+            conv,
+            send_sn: 0,
+            unacked: VecDeque::new(),
+            recv_sn: 0,
+            pending: HashMap::new(),
+            received: Vec::new(),
+            closed: false,
+            dead: false,
+        }
+    }
+
+    fn datagram(conv: u32, cmd: u8, sn: u32, payload: &[u8]) -> Vec<u8> {
+        let mut datagram = vec![0u8; UDP_HEADER + payload.len()];
+        BigEndian::write_u32(&mut datagram, conv);
+        datagram[4] = cmd;
+        BigEndian::write_u32(&mut datagram[5..], sn);
+        datagram[UDP_HEADER..].copy_from_slice(payload);
+        datagram
+    }
+
+    /// 发送失败的数据报由重传补发
+    fn output(socket: &UdpSocket, peer: SocketAddr, datagram: &[u8]) -> bool {
+        match socket.send_to(datagram, peer) {
+            Ok(_) => true,
+            Err(err) if err.kind() == ErrorKind::WouldBlock => false,
+            Err(err) => {
+                log::error!("[{}]send datagram failed:{}", peer, err);
+                false
+            }
+        }
+    }
+
+    fn input(&mut self, data: &[u8]) {
+        if data.len() < UDP_HEADER {
+            log::error!("[{}]invalid datagram size:{}", self.peer, data.len());
+            return;
+        }
+        let conv = BigEndian::read_u32(data);
+        if conv != self.conv {
+            log::error!("[{}]conv mismatch {} != {}", self.peer, conv, self.conv);
+            return;
+        }
+        let sn = BigEndian::read_u32(&data[5..]);
+        match data[4] {
+            UDP_PUSH => {
+                let offset = sn.wrapping_sub(self.recv_sn);
+                if offset < UDP_WINDOW {
+                    if self.received.len() >= UDP_MAX_RECEIVED {
+                        log::warn!("[{}]receive buffer full, segment {} dropped", self.peer, sn);
+                        return;
+                    }
+                    self.pending
+                        .entry(sn)
+                        .or_insert_with(|| data[UDP_HEADER..].to_vec());
+                } else if offset < u32::MAX / 2 {
+                    log::debug!("[{}]segment {} out of window dropped", self.peer, sn);
+                    return;
+                }
+                // 已经交付过的重复数据段只回应确认
+                let ack = Self::datagram(self.conv, UDP_ACK, sn, &[]);
+                Self::output(&self.socket, self.peer, ack.as_slice());
+                while let Some(payload) = self.pending.remove(&self.recv_sn) {
+                    self.received.extend_from_slice(payload.as_slice());
+                    self.recv_sn = self.recv_sn.wrapping_add(1);
+                }
+            }
+            UDP_ACK => self.unacked.retain(|segment| segment.sn != sn),
+            UDP_CLOSE => self.closed = true,
+            cmd => log::error!("[{}]invalid datagram cmd:{}", self.peer, cmd),
+        }
+    }
+
+    /// 发送窗口已满时不再接收新数据，剩余部分留在连接的写缓冲中
+    fn send(&mut self, data: &[u8]) -> usize {
+        let window = UDP_WINDOW as usize - self.unacked.len().min(UDP_WINDOW as usize);
+        let size = data.len().min(window * UDP_MSS);
+        for payload in data[..size].chunks(UDP_MSS) {
+            let datagram = Self::datagram(self.conv, UDP_PUSH, self.send_sn, payload);
+            let sent = if Self::output(&self.socket, self.peer, datagram.as_slice()) {
+                Some(Instant::now())
+            } else {
+                None
+            };
+            self.unacked.push_back(Segment {
+                sn: self.send_sn,
+                datagram,
+                sent,
+                retries: 0,
+            });
+            self.send_sn = self.send_sn.wrapping_add(1);
+        }
+        size
+    }
+
+    /// 重传超时未确认的数据段，超过重试次数后会话失效
+    fn update(&mut self) {
+        let now = Instant::now();
+        for segment in &mut self.unacked {
+            if let Some(sent) = segment.sent {
+                let rto = UDP_RTO * (1 << segment.retries.min(4));
+                if now.duration_since(sent) < rto {
+                    continue;
+                }
+                if segment.retries >= UDP_MAX_RETRIES {
+                    log::error!(
+                        "[{}]segment {} retransmit too many times",
+                        self.peer,
+                        segment.sn
+                    );
+                    self.dead = true;
+                    return;
+                }
+                segment.retries += 1;
+            }
+            if Self::output(&self.socket, self.peer, segment.datagram.as_slice()) {
+                segment.sent = Some(now);
+            }
+        }
+    }
+
+    fn shutdown(&mut self) {
+        if !self.closed {
+            let datagram = Self::datagram(self.conv, UDP_CLOSE, self.send_sn, &[]);
+            Self::output(&self.socket, self.peer, datagram.as_slice());
+            self.closed = true;
+        }
+    }
+}
+
+impl Read for UdpSession {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if !self.received.is_empty() {
+            let size = buf.len().min(self.received.len());
+            buf[..size].copy_from_slice(&self.received[..size]);
+            self.received.drain(..size);
+            Ok(size)
+        } else if self.dead {
+            Err(ErrorKind::TimedOut.into())
+        } else if self.closed {
+            Ok(0)
+        } else {
+            Err(ErrorKind::WouldBlock.into())
+        }
+    }
+}
+
+impl Write for UdpSession {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        if self.closed || self.dead {
+            return Err(ErrorKind::BrokenPipe.into());
+        }
+        match self.send(buf) {
+            0 if !buf.is_empty() => Err(ErrorKind::WouldBlock.into()),
+            size => Ok(size),
+        }
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+enum Stream {
+    Tcp(TcpStream),
+    /// 共用监听的UDP socket，不需要单独注册
+    Udp(UdpSession),
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.read(buf),
+            Stream::Udp(session) => session.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        match self {
+            Stream::Tcp(stream) => stream.write(buf),
+            Stream::Udp(session) => session.write(buf),
+        }
+    }
 
-    pub fn is_token(&self) -> bool {
-        matches!(self, RequestIdent::Token(_))
+    fn flush(&mut self) -> Result<()> {
+        match self {
+            Stream::Tcp(stream) => stream.flush(),
+            Stream::Udp(session) => session.flush(),
+        }
+    }
+}
+
+/// 连接的底层传输，开启TLS后在读写时透明地完成握手以及加解密
+struct Transport {
+    stream: Stream,
+    #[cfg(feature = "tls")]
+    session: Option<rustls::ServerSession>,
+    /// 等待读取的PROXY协议头部
+    proxy: Option<Vec<u8>>,
+    /// PROXY协议头部中的真实客户端地址
+    proxied: Option<SocketAddr>,
+}
+
+impl Transport {
+    fn udp(session: UdpSession) -> Self {
+        Self {
+            stream: Stream::Udp(session),
+            #[cfg(feature = "tls")]
+            session: None,
+            proxy: None,
+            proxied: None,
+        }
+    }
+
+    /// 处理UDP会话收到的数据报
+    fn input(&mut self, data: &[u8]) {
+        if let Stream::Udp(session) = &mut self.stream {
+            session.input(data);
+        }
+    }
+
+    /// 驱动UDP会话重传，返回会话是否仍然有效
+    fn update(&mut self) -> bool {
+        if let Stream::Udp(session) = &mut self.stream {
+            session.update();
+            !session.dead
+        } else {
+            true
+        }
+    }
+
+    fn tcp(stream: TcpStream, tls: &Option<TlsConfig>, proxy_protocol: bool) -> Self {
+        let stream = Stream::Tcp(stream);
+        let proxy = if proxy_protocol {
+            Some(Vec::new())
+        } else {
+            None
+        };
+        cfg_if::cfg_if! {
+            if #[cfg(feature = "tls")] {
+                Self {
+                    stream,
+                    session: tls.as_ref().map(rustls::ServerSession::new),
+                    proxy,
+                    proxied: None,
+                }
+            } else {
+                let _ = tls;
+                Self { stream, proxy, proxied: None }
+            }
+        }
+    }
+
+    /// 读取PROXY协议v2头部，头部之后才是TLS或者请求数据，返回false表示连接已关闭
+    fn read_proxy(&mut self) -> Result<bool> {
+        while let Some(header) = &mut self.proxy {
+            let need = if header.len() < PROXY_HEADER {
+                PROXY_HEADER
+            } else if header[..12] != PROXY_SIGNATURE {
+                return Err(std::io::Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid proxy protocol signature",
+                ));
+            } else {
+                PROXY_HEADER + BigEndian::read_u16(&header[14..]) as usize
+            };
+            if header.len() < need {
+                let start = header.len();
+                header.resize(need, 0);
+                match self.stream.read(&mut header[start..]) {
+                    Ok(0) => {
+                        header.truncate(start);
+                        return Ok(false);
+                    }
+                    Ok(size) => header.truncate(start + size),
+                    Err(err) => {
+                        header.truncate(start);
+                        return Err(err);
+                    }
+                }
+            } else {
+                self.proxied = parse_proxy_header(header.as_slice())?;
+                self.proxy = None;
+            }
+        }
+        Ok(true)
+    }
+
+    /// TLS会话中是否还有未写出的密文
+    fn wants_write(&self) -> bool {
+        #[cfg(feature = "tls")]
+        if let Some(session) = &self.session {
+            return rustls::Session::wants_write(session);
+        }
+        false
+    }
+
+    #[cfg(feature = "tls")]
+    fn flush_tls(session: &mut rustls::ServerSession, stream: &mut Stream) -> Result<()> {
+        use rustls::Session;
+        while session.wants_write() {
+            match session.write_tls(stream) {
+                Ok(_) => {}
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> Result<()> {
+        #[cfg(feature = "tls")]
+        if let Some(session) = &mut self.session {
+            rustls::Session::send_close_notify(session);
+            let _ = Self::flush_tls(session, &mut self.stream);
+        }
+        match &mut self.stream {
+            Stream::Tcp(stream) => stream.shutdown(Shutdown::Both),
+            Stream::Udp(session) => {
+                session.shutdown();
+                Ok(())
+            }
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
+        if self.proxy.is_some() && !self.read_proxy()? {
+            return Ok(0);
+        }
+        #[cfg(feature = "tls")]
+        if let Some(session) = &mut self.session {
+            use rustls::Session;
+            loop {
+                let size = session.read(buf)?;
+                if size > 0 {
+                    return Ok(size);
+                }
+                // 没有明文时继续从socket读取密文，WouldBlock直接返回
+                if session.read_tls(&mut self.stream)? == 0 {
+                    return Ok(0);
+                }
+                session
+                    .process_new_packets()
+                    .map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+                // 握手过程中需要回应对端
+                Self::flush_tls(session, &mut self.stream)?;
+            }
+        }
+        self.stream.read(buf)
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> Result<usize> {
+        #[cfg(feature = "tls")]
+        if let Some(session) = &mut self.session {
+            // 明文先写入会话缓存，握手完成后才会真正加密发送
+            let size = session.write(buf)?;
+            Self::flush_tls(session, &mut self.stream)?;
+            return Ok(size);
+        }
+        self.stream.write(buf)
+    }
+
+    fn flush(&mut self) -> Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl Source for Transport {
+    fn register(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => stream.register(registry, token, interests),
+            Stream::Udp(_) => Ok(()),
+        }
+    }
+
+    fn reregister(&mut self, registry: &Registry, token: Token, interests: Interest) -> Result<()> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => stream.reregister(registry, token, interests),
+            Stream::Udp(_) => Ok(()),
+        }
+    }
+
+    fn deregister(&mut self, registry: &Registry) -> Result<()> {
+        match &mut self.stream {
+            Stream::Tcp(stream) => stream.deregister(registry),
+            Stream::Udp(_) => Ok(()),
+        }
     }
 }
 
@@ -80,6 +755,8 @@ enum ConnStatus {
 
 #[derive(Debug)]
 enum EcsStatus {
+    /// 网络连接建立，等待客户端公钥完成加密握手，之后才发送Token到ECS
+    Handshaking,
     /// 网络连接建立，正在初始化中，还未收到初始请求
     Initializing,
     /// 收到初始请求，并将Token发送到ECS
@@ -93,53 +770,177 @@ enum EcsStatus {
 }
 
 struct Connection {
-    stream: TcpStream,
+    stream: Transport,
     tag: String,
+    address: SocketAddr,
+    /// 通过PROXY协议更新地址之前的负载均衡地址，由Listener处理后清除
+    proxied: Option<SocketAddr>,
+    /// 请求限流
+    limiter: Option<TokenBucket>,
     token: Token,
-    read_bytes: Vec<u8>,
+    read_bytes: BytesMut,
     write_bytes: Vec<u8>,
     last_time: Instant,
     last_read_time: Instant,
     last_write_time: Instant,
     sender: Sender<NetworkInputData>,
-    ident: RequestIdent,
+    connected: Sender<Connected>,
+    /// 收到实体之前为None
+    ident: Option<RequestIdent>,
     conn_status: ConnStatus,
     ecs_status: EcsStatus,
     length: usize,
+    /// 当前请求的包体是否经过压缩
+    compressed: bool,
+    /// 加密握手完成后的会话密钥
+    cipher: Option<Cipher>,
+    /// 连接所属的监听序号
+    listener: usize,
+    metrics: NetworkMetrics,
+    /// 心跳序号
+    heartbeat_sn: u32,
+    /// 连续未收到回应的心跳次数
+    missed_heartbeats: usize,
     max_request_size: usize,
+    /// 服务器协议版本，0表示不校验
+    version: u32,
+    version_checked: bool,
 }
 
 impl Connection {
     pub fn new(
-        stream: TcpStream,
+        stream: Transport,
         address: SocketAddr,
         sender: Sender<NetworkInputData>,
+        connected: Sender<Connected>,
         max_request_size: usize,
+        version: u32,
+        encrypt: bool,
+        listener: usize,
+        metrics: NetworkMetrics,
     ) -> Self {
         let tag = address.to_string();
         Self {
             stream,
             tag,
+            address,
+            proxied: None,
+            limiter: None,
             token: Token(0),
-            read_bytes: Vec::with_capacity(1024),
-            write_bytes: Vec::with_capacity(1024),
+            read_bytes: BytesMut::new(),
+            write_bytes: Vec::new(),
             last_time: Instant::now(),
             last_read_time: Instant::now(),
             last_write_time: Instant::now(),
             sender,
-            ident: RequestIdent::Token(Token(0)),
+            connected,
+            ident: None,
             conn_status: ConnStatus::Established,
-            ecs_status: EcsStatus::Initializing,
+            ecs_status: if encrypt {
+                EcsStatus::Handshaking
+            } else {
+                EcsStatus::Initializing
+            },
             length: 0,
+            compressed: false,
+            cipher: None,
+            listener,
+            metrics,
+            heartbeat_sn: 0,
+            missed_heartbeats: 0,
             max_request_size,
+            version,
+            version_checked: version == 0,
         }
     }
 
+    /// 加密握手以及版本检查都完成后才通知HandshakeSystem
     fn set_token(&mut self, token: Token) {
         self.token = token;
-        self.ident.replace_token(token);
+        if let EcsStatus::Handshaking = self.ecs_status {
+            return;
+        }
+        if self.version_checked {
+            self.send_connected();
+        }
+    }
+
+    /// 通知HandshakeSystem新连接建立
+    fn send_connected(&mut self) {
         log::debug!("[{}]send Token to ecs", self.tag);
-        self.send_ecs(Vec::new());
+        self.ecs_status = EcsStatus::TokenSent;
+        let connected = Connected {
+            token: self.token,
+            listener: self.listener,
+            closed: false,
+        };
+        if let Err(err) = self.connected.send(connected) {
+            log::error!("[{}]send connected to ecs failed:{}", self.tag, err);
+        }
+    }
+
+    /// 握手请求为客户端公钥(32)，响应 length(4) | 0(4) | HANDSHAKE_CMD(4) | 服务器公钥(32)，
+    /// 之后双方的帧均为加密内容
+    fn handshake(&mut self, body: Bytes) -> bool {
+        let (cipher, public) = match Cipher::handshake(&body) {
+            Some(result) => result,
+            None => {
+                log::error!("[{}]invalid handshake request", self.tag);
+                self.metrics.add_parse_error();
+                self.shutdown();
+                return false;
+            }
+        };
+        let mut frame = vec![0u8; 12];
+        BigEndian::write_u32(&mut frame, 8 + public.len() as u32);
+        BigEndian::write_u32(&mut frame[8..], HANDSHAKE_CMD);
+        frame.extend_from_slice(&public);
+        self.write(frame.as_slice());
+        self.cipher = Some(cipher);
+        self.ecs_status = EcsStatus::Initializing;
+        log::debug!("[{}]handshake done", self.tag);
+        if self.version_checked {
+            self.send_connected();
+        }
+        true
+    }
+
+    /// 写入完整的响应帧
+    fn write_frames(&mut self, data: &[u8]) {
+        self.queue_frames(data);
+        self.write(&[]);
+    }
+
+    /// 响应帧追加到写缓冲中暂不发送，加密连接上逐帧加密长度字段之后的内容
+    fn queue_frames(&mut self, mut data: &[u8]) {
+        if !matches!(self.conn_status, ConnStatus::Established) {
+            return;
+        }
+        if self.cipher.is_none() {
+            self.write_bytes.extend_from_slice(data);
+            return;
+        }
+        while data.len() >= 4 {
+            let length = BigEndian::read_u32(data);
+            let size = ((length & !COMPRESSED_FLAG) as usize + 4).min(data.len());
+            let (frame, left) = data.split_at(size);
+            data = left;
+            let encrypted = match self.cipher.as_mut().unwrap().encrypt(&frame[4..]) {
+                Some(encrypted) => encrypted,
+                None => {
+                    log::error!("[{}]encrypt response failed", self.tag);
+                    self.shutdown();
+                    return;
+                }
+            };
+            let mut header = [0u8; 4];
+            BigEndian::write_u32(
+                &mut header,
+                encrypted.len() as u32 | (length & COMPRESSED_FLAG),
+            );
+            self.write_bytes.extend_from_slice(&header);
+            self.write_bytes.extend_from_slice(encrypted.as_slice());
+        }
     }
 
     fn setup(&mut self, registry: &Registry) {
@@ -168,41 +969,51 @@ impl Connection {
     }
 
     fn write(&mut self, data: &[u8]) {
-        let write_bytes = if self.write_bytes.is_empty() {
-            Vec::new()
-        } else {
-            self.write_bytes.extend_from_slice(data);
-            let mut write_bytes = Vec::new();
-            std::mem::swap(&mut write_bytes, &mut self.write_bytes);
-            write_bytes
-        };
-
-        let mut data = if write_bytes.is_empty() {
-            data
+        // 写缓冲原地复用，只移除已经发出的部分
+        let mut write_bytes = std::mem::take(&mut self.write_bytes);
+        let pending = !write_bytes.is_empty();
+        if pending {
+            write_bytes.extend_from_slice(data);
+        }
+        let total = if pending {
+            write_bytes.len()
         } else {
-            write_bytes.as_slice()
+            data.len()
         };
 
         self.last_write_time = Instant::now();
-        while !data.is_empty() {
+        let mut written = 0;
+        while written < total {
+            let data = if pending {
+                &write_bytes[written..]
+            } else {
+                &data[written..]
+            };
             match self.stream.write(data) {
-                Ok(size) => data = &data[size..],
-                Err(err) if err.kind() == ErrorKind::WouldBlock => {
-                    self.write_bytes.extend_from_slice(data);
-                    break;
+                Ok(size) => {
+                    self.metrics.add_bytes_out(size);
+                    written += size;
                 }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
                 Err(err) => {
                     log::error!("[{}]write failed {}", self.tag, err);
+                    self.write_bytes = write_bytes;
                     self.shutdown();
                     return;
                 }
             }
         }
+        if pending {
+            write_bytes.drain(..written);
+        } else {
+            write_bytes.extend_from_slice(&data[written..]);
+        }
+        self.write_bytes = write_bytes;
     }
 
     fn shutdown(&mut self) {
         if let ConnStatus::Established = self.conn_status {
-            if let Err(err) = self.stream.shutdown(Shutdown::Both) {
+            if let Err(err) = self.stream.shutdown() {
                 log::error!("[{}]close failed {}", self.tag, err);
             }
             self.conn_status = ConnStatus::Closed;
@@ -233,11 +1044,18 @@ impl Connection {
         self.reregister(registry);
     }
 
+    fn do_input(&mut self, data: &[u8]) {
+        self.last_time = Instant::now();
+        self.stream.input(data);
+        self.do_read();
+    }
+
     fn do_read(&mut self) {
         let mut bytes = [0u8; 1024];
         loop {
             match self.stream.read(&mut bytes) {
                 Ok(size) if size > 0 => {
+                    self.metrics.add_bytes_in(size);
                     self.read_bytes.extend_from_slice(&bytes[..size]);
                     log::debug!("[{}]read {} bytes data", self.tag, size);
                 }
@@ -254,6 +1072,12 @@ impl Connection {
                 }
             }
         }
+        if let Some(address) = self.stream.proxied.take() {
+            log::info!("[{}]connection proxied for {}", self.tag, address);
+            self.tag = address.to_string();
+            self.proxied = Some(self.address);
+            self.address = address;
+        }
         self.parse();
     }
 
@@ -262,24 +1086,67 @@ impl Connection {
             return;
         }
 
-        let mut read_bytes_vec = Vec::new();
-        std::mem::swap(&mut read_bytes_vec, &mut self.read_bytes);
-        let mut read_bytes = read_bytes_vec.as_slice();
         let mut new_header = false;
         loop {
-            if self.length > 0 && read_bytes.len() >= self.length {
-                let body: Vec<_> = read_bytes[..self.length].into();
-                read_bytes = &read_bytes[self.length..];
-                self.send_ecs(body);
+            if self.length > 0 && self.read_bytes.len() >= self.length {
+                // 包体直接引用读缓冲，不再复制
+                let body = self.read_bytes.split_to(self.length).freeze();
                 self.length = 0;
-            } else if self.length == 0 && read_bytes.len() >= 4 {
-                self.length = BigEndian::read_u32(read_bytes) as usize;
+                if let EcsStatus::Handshaking = self.ecs_status {
+                    if !self.handshake(body) {
+                        return;
+                    }
+                    continue;
+                }
+                let body = if let Some(cipher) = &mut self.cipher {
+                    match cipher.decrypt(&body) {
+                        Some(body) => Bytes::from(body),
+                        None => {
+                            log::error!("[{}]decrypt request failed", self.tag);
+                            self.metrics.add_parse_error();
+                            self.shutdown();
+                            return;
+                        }
+                    }
+                } else {
+                    body
+                };
+                let body = if self.compressed {
+                    match decompress_body(&body, self.max_request_size) {
+                        Some(body) => Bytes::from(body),
+                        None => {
+                            log::error!("[{}]decompress request failed", self.tag);
+                            self.metrics.add_parse_error();
+                            self.shutdown();
+                            return;
+                        }
+                    }
+                } else {
+                    body
+                };
+                if body.len() >= 4 && BigEndian::read_u32(&body) == HEARTBEAT_CMD {
+                    self.missed_heartbeats = 0;
+                    continue;
+                }
+                if self.version_checked {
+                    self.send_ecs(body);
+                    if !matches!(self.conn_status, ConnStatus::Established) {
+                        return;
+                    }
+                } else if !self.check_version(body) {
+                    return;
+                }
+            } else if self.length == 0 && self.read_bytes.len() >= 4 {
+                let length = BigEndian::read_u32(&self.read_bytes);
+                self.compressed = length & COMPRESSED_FLAG != 0;
+                self.length = (length & !COMPRESSED_FLAG) as usize;
                 if self.length > self.max_request_size {
                     log::error!("[{}]got invalid request size:{}", self.tag, self.length);
+                    self.metrics.add_parse_error();
                     self.shutdown();
                     return;
                 }
-                read_bytes = &read_bytes[4..];
+                self.read_bytes.advance(4);
                 new_header = true;
                 log::debug!("new request found with body length:{}", self.length);
             } else {
@@ -290,50 +1157,112 @@ impl Connection {
         if new_header {
             self.last_read_time = Instant::now();
         }
+    }
 
-        if read_bytes.len() == read_bytes_vec.len() {
-            std::mem::swap(&mut read_bytes_vec, &mut self.read_bytes);
+    /// 连接后的第一个请求为协议版本号，不一致时返回错误帧并断开连接
+    fn check_version(&mut self, body: Bytes) -> bool {
+        let version = if body.len() == 4 {
+            BigEndian::read_u32(&body)
         } else {
-            self.read_bytes.extend_from_slice(read_bytes);
+            0
+        };
+        if version == self.version {
+            self.version_checked = true;
+            self.send_connected();
+            return true;
         }
+        log::error!(
+            "[{}]protocol version mismatch, client:{}, server:{}",
+            self.tag,
+            version,
+            self.version
+        );
+        self.metrics.add_parse_error();
+        self.write_frames(control_frame(VERSION_MISMATCH_CMD, self.version).as_slice());
+        self.shutdown();
+        false
     }
 
-    fn send_ecs(&mut self, data: Vec<u8>) {
+    fn send_ecs(&mut self, data: Bytes) {
         match self.ecs_status {
-            EcsStatus::Initializing => self.ecs_status = EcsStatus::TokenSent,
-            EcsStatus::TokenSent => {
+            EcsStatus::Initializing | EcsStatus::TokenSent => {
                 log::error!(
                     "[{}]another request found while entity not received, dropped",
                     self.tag
                 );
                 return;
             }
-            EcsStatus::EntityReceived => {}
+            EcsStatus::EntityReceived => {
+                // 关闭通知的内容为空，不受限流影响
+                if !data.is_empty() && !self.acquire() {
+                    return;
+                }
+            }
             _ => {
                 log::error!("[{}]close sent to ecs, should not send more data", self.tag);
                 return;
             }
         }
-        if let Err(err) = self.sender.send((self.ident.clone(), data)) {
+        let ident = match &self.ident {
+            Some(ident) => ident.clone(),
+            None => return,
+        };
+        if let Err(err) = self.sender.send((ident, data)) {
             log::error!("[{}]send data to ecs failed:{}", self.tag, err);
         }
     }
 
+    /// 检查请求限流，超出时按配置丢弃请求或者关闭连接
+    fn acquire(&mut self) -> bool {
+        let limiter = match &mut self.limiter {
+            Some(limiter) => limiter,
+            None => return true,
+        };
+        if limiter.acquire() {
+            return true;
+        }
+        self.metrics.add_rate_limited();
+        match limiter.limit.action {
+            RateLimitAction::Drop => {
+                log::warn!("[{}]request rate limited, dropped", self.tag);
+            }
+            RateLimitAction::Shutdown => {
+                log::warn!("[{}]request rate limited, shutdown", self.tag);
+                self.shutdown();
+            }
+        }
+        false
+    }
+
     fn send_close(&mut self) {
         match self.ecs_status {
             EcsStatus::EntityReceived => {
-                self.ident.replace_close();
-                self.send_ecs(Vec::new());
+                if let Some(ident) = &mut self.ident {
+                    ident.replace_close();
+                }
+                self.send_ecs(Bytes::new());
                 self.ecs_status = EcsStatus::CloseSent;
                 log::debug!("[{}]connection send close to ecs", self.tag);
             }
-            EcsStatus::Initializing => {
+            EcsStatus::Initializing | EcsStatus::Handshaking => {
                 self.ecs_status = EcsStatus::CloseConfirmed;
                 log::debug!(
                     "[{}]connection is initializing, close confirm now",
                     self.tag
                 );
             }
+            EcsStatus::TokenSent => {
+                // 排队中的连接由HandshakeSystem移出队列后确认关闭，已经放行的连接收到实体后再关闭
+                let closed = Connected {
+                    token: self.token,
+                    listener: self.listener,
+                    closed: true,
+                };
+                if let Err(err) = self.connected.send(closed) {
+                    log::error!("[{}]send closed to ecs failed:{}", self.tag, err);
+                }
+                log::debug!("[{}]connection closed while queued", self.tag);
+            }
             _ => log::debug!(
                 "[{}]connection has not received entity, close later",
                 self.tag
@@ -342,15 +1271,20 @@ impl Connection {
     }
 
     fn do_write(&mut self) {
-        if self.write_bytes.is_empty() {
+        if self.write_bytes.is_empty() && !self.stream.wants_write() {
             return;
         }
         self.write(&[]);
     }
 
-    fn do_send(&mut self, registry: &Registry, data: &[u8]) {
+    fn do_send(&mut self, data: &[u8]) {
         log::debug!("[{}]got {} bytes data", self.tag, data.len());
-        self.write(data);
+        self.queue_frames(data);
+    }
+
+    /// 一次写出本轮累积的所有响应
+    fn do_flush(&mut self, registry: &Registry) {
+        self.do_write();
         self.reregister(registry);
     }
 
@@ -363,6 +1297,33 @@ impl Connection {
         }
     }
 
+    /// 已经收到ECS的实体，可以接收广播
+    fn is_playing(&self) -> bool {
+        matches!(self.conn_status, ConnStatus::Established)
+            && matches!(self.ecs_status, EcsStatus::EntityReceived)
+    }
+
+    /// 发送心跳，连续max_missed次未收到回应时关闭连接
+    fn heartbeat(&mut self, max_missed: usize) {
+        if !matches!(self.conn_status, ConnStatus::Established)
+            || matches!(self.ecs_status, EcsStatus::Handshaking)
+        {
+            return;
+        }
+        if self.missed_heartbeats >= max_missed {
+            log::warn!(
+                "[{}]{} heartbeats missed, connection lost",
+                self.tag,
+                self.missed_heartbeats
+            );
+            self.shutdown();
+            return;
+        }
+        self.missed_heartbeats += 1;
+        self.heartbeat_sn = self.heartbeat_sn.wrapping_add(1);
+        self.write_frames(control_frame(HEARTBEAT_CMD, self.heartbeat_sn).as_slice());
+    }
+
     fn is_timeout(
         &self,
         idle_timeout: Duration,
@@ -392,6 +1353,10 @@ impl Connection {
                 log::info!("[{}]ecs confirm closed, it's ok to release now", self.tag);
                 self.ecs_status = EcsStatus::CloseConfirmed;
             }
+            EcsStatus::TokenSent if !matches!(self.conn_status, ConnStatus::Established) => {
+                log::info!("[{}]removed from login queue, release now", self.tag);
+                self.ecs_status = EcsStatus::CloseConfirmed;
+            }
             _ => log::error!(
                 "[{}]connection received CloseConfirmed while in status:{:?}",
                 self.tag,
@@ -404,16 +1369,18 @@ impl Connection {
         matches!(self.ecs_status, EcsStatus::CloseConfirmed)
     }
 
-    fn set_entity(&mut self, entity: Entity, registry: &Registry) {
+    fn set_entity(&mut self, entity: Entity) {
         log::debug!("[{}]got entity:{:?}", self.tag, entity);
         if let EcsStatus::TokenSent = self.ecs_status {
-            self.ident.replace_entity(entity);
+            self.ident = Some(RequestIdent::Entity(entity));
             self.ecs_status = EcsStatus::EntityReceived;
             if !matches!(self.conn_status, ConnStatus::Established) {
                 self.send_close();
-            } else {
-                self.setup(registry);
             }
+        } else if let EcsStatus::EntityReceived = self.ecs_status {
+            // 断线重连，连接重新绑定到原来的实体
+            log::info!("[{}]connection rebind to entity:{}", self.tag, entity.id());
+            self.ident = Some(RequestIdent::Entity(entity));
         } else {
             log::error!(
                 "[{}]connection got entity while in status:{:?}",
@@ -427,70 +1394,394 @@ impl Connection {
 pub enum Response {
     /// 握手完成，返回对应的Entity
     Entity(Entity),
-    /// 需要发送给用户的数据
-    Data(Vec<u8>),
+    /// 需要发送给用户的数据，多个连接共享同一份内容
+    Data(Arc<[u8]>),
+    /// 发送给所有已经进入游戏的连接，由网络线程遍历，不需要在ECS中收集连接
+    DataAll(Arc<[u8]>),
     /// 逻辑端需要关闭网络连接
     /// true表示Ecs已经确认清理完成，网络端可以释放资源了
     /// false表示Ecs发现问题，需要网络端关闭连接
     Close(bool),
+    /// 停服，停止监听并关闭所有连接后网络线程退出
+    Shutdown,
 }
 
-pub type NetworkInputData = (RequestIdent, Vec<u8>);
+pub type NetworkInputData = (RequestIdent, Bytes);
 pub type NetworkOutputData = (Vec<Token>, Response);
 
+/// PROXY协议v2的签名
+const PROXY_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+/// PROXY协议v2固定头部长度，之后为地址信息
+const PROXY_HEADER: usize = 16;
+
+/// 解析PROXY协议v2头部，LOCAL命令以及不支持的地址族返回None
+fn parse_proxy_header(header: &[u8]) -> Result<Option<SocketAddr>> {
+    let invalid = |reason| Err(std::io::Error::new(ErrorKind::InvalidData, reason));
+    if header[12] >> 4 != 2 {
+        return invalid("invalid proxy protocol version");
+    }
+    match header[12] & 0x0f {
+        0 => return Ok(None),
+        1 => {}
+        _ => return invalid("invalid proxy protocol command"),
+    }
+    let body = &header[PROXY_HEADER..];
+    let address = match header[13] >> 4 {
+        1 if body.len() >= 12 => {
+            let mut ip = [0u8; 4];
+            ip.copy_from_slice(&body[..4]);
+            SocketAddr::new(IpAddr::from(ip), BigEndian::read_u16(&body[8..]))
+        }
+        2 if body.len() >= 36 => {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(&body[..16]);
+            SocketAddr::new(IpAddr::from(ip), BigEndian::read_u16(&body[32..]))
+        }
+        _ => return Ok(None),
+    };
+    Ok(Some(canonical_address(address)))
+}
+
+/// 双栈监听收到的IPv4连接地址为IPv4映射的IPv6地址，转换为IPv4地址后再用于日志以及准入检查
+fn canonical_address(address: SocketAddr) -> SocketAddr {
+    if let SocketAddr::V6(v6) = address {
+        if let [0, 0, 0, 0, 0, 0xffff, _, _] = v6.ip().segments() {
+            let octets = v6.ip().octets();
+            let ip = Ipv4Addr::new(octets[12], octets[13], octets[14], octets[15]);
+            return SocketAddr::new(IpAddr::V4(ip), v6.port());
+        }
+    }
+    address
+}
+
+/// 绑定TCP监听，IPv6地址默认同时接受IPv4连接，only_v6时只接受IPv6连接
+fn bind_listener(address: SocketAddr, only_v6: bool) -> Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(address), Type::STREAM, None)?;
+    if address.is_ipv6() {
+        socket.set_only_v6(only_v6)?;
+    }
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&address.into())?;
+    socket.listen(1024)?;
+    socket.set_nonblocking(true)?;
+    Ok(TcpListener::from_std(socket.into()))
+}
+
+/// 连接读写缓冲的初始大小
+const BUFFER_SIZE: usize = 1024;
+
+/// 可以放入缓冲池的缓冲类型
+trait PoolBuffer {
+    fn with_capacity(capacity: usize) -> Self;
+    fn capacity(&self) -> usize;
+    fn clear(&mut self);
+}
+
+impl PoolBuffer for Vec<u8> {
+    fn with_capacity(capacity: usize) -> Self {
+        Vec::with_capacity(capacity)
+    }
+
+    fn capacity(&self) -> usize {
+        Vec::capacity(self)
+    }
+
+    fn clear(&mut self) {
+        Vec::clear(self)
+    }
+}
+
+impl PoolBuffer for BytesMut {
+    fn with_capacity(capacity: usize) -> Self {
+        BytesMut::with_capacity(capacity)
+    }
+
+    fn capacity(&self) -> usize {
+        BytesMut::capacity(self)
+    }
+
+    fn clear(&mut self) {
+        BytesMut::clear(self)
+    }
+}
+
+/// 连接读写缓冲池，连接建立时取出，释放时回收，避免大量连接反复分配
+struct BufferPool<B> {
+    buffers: Vec<B>,
+    capacity: usize,
+    metrics: NetworkMetrics,
+}
+
+impl<B: PoolBuffer> BufferPool<B> {
+    fn new(capacity: usize, metrics: NetworkMetrics) -> Self {
+        Self {
+            buffers: Vec::new(),
+            capacity,
+            metrics,
+        }
+    }
+
+    fn get(&mut self) -> B {
+        if let Some(buffer) = self.buffers.pop() {
+            self.metrics.add_buffer_reused();
+            buffer
+        } else {
+            self.metrics.add_buffer_allocated();
+            B::with_capacity(BUFFER_SIZE)
+        }
+    }
+
+    /// 空闲缓冲超过容量或者缓冲增长过大时直接释放
+    fn put(&mut self, mut buffer: B) {
+        if self.buffers.len() < self.capacity
+            && buffer.capacity() >= BUFFER_SIZE
+            && buffer.capacity() <= BUFFER_SIZE * 64
+        {
+            buffer.clear();
+            self.buffers.push(buffer);
+        }
+    }
+}
+
 struct Listener {
-    listener: TcpListener,
+    listeners: Vec<TcpListener>,
+    udp: Option<Arc<UdpSocket>>,
+    /// UDP会话按对端地址查找连接
+    peers: HashMap<SocketAddr, usize>,
     conns: Slab<Connection>,
     sender: Sender<NetworkInputData>,
+    connected: Sender<Connected>,
     receiver: Option<Receiver<NetworkOutputData>>,
     idle_timeout: Duration,
     read_timeout: Duration,
     write_timeout: Duration,
+    version: u32,
+    tls: Option<TlsConfig>,
+    encrypt: bool,
+    metrics: NetworkMetrics,
+    stopping: bool,
+    access: AccessControl,
+    /// 每个IP当前的连接数
+    ip_connections: HashMap<IpAddr, usize>,
+    max_connections: Option<usize>,
+    pool: BufferPool<Vec<u8>>,
+    /// 读缓冲的包体直接交给ECS，单独使用BytesMut缓冲池
+    read_pool: BufferPool<BytesMut>,
+    proxy_protocol: bool,
+    rate_limit: Option<RateLimit>,
 }
 
 impl Listener {
     pub fn new(
-        listener: TcpListener,
-        capacity: usize,
+        listeners: Vec<TcpListener>,
+        udp: Option<UdpSocket>,
+        max_connections: Option<usize>,
         sender: Sender<NetworkInputData>,
+        connected: Sender<Connected>,
         receiver: Receiver<NetworkOutputData>,
         idle_timeout: Duration,
         read_timeout: Duration,
         write_timeout: Duration,
+        version: u32,
+        tls: Option<TlsConfig>,
+        encrypt: bool,
+        metrics: NetworkMetrics,
+        access: AccessControl,
+        proxy_protocol: bool,
+        rate_limit: Option<RateLimit>,
     ) -> Self {
+        let capacity = max_connections.unwrap_or(4096);
         Self {
-            listener,
+            listeners,
+            udp: udp.map(Arc::new),
+            peers: HashMap::new(),
             conns: Slab::with_capacity(capacity),
             sender,
+            connected,
             receiver: Some(receiver),
             idle_timeout,
             read_timeout,
             write_timeout,
+            version,
+            tls,
+            encrypt,
+            pool: BufferPool::new(capacity, metrics.clone()),
+            read_pool: BufferPool::new(capacity, metrics.clone()),
+            metrics,
+            stopping: false,
+            access,
+            ip_connections: HashMap::new(),
+            max_connections,
+            proxy_protocol,
+            rate_limit,
         }
     }
 
-    pub fn accept(&mut self, max_request_size: usize) -> Result<()> {
+    pub fn accept(
+        &mut self,
+        index: usize,
+        max_request_size: usize,
+        registry: &Registry,
+    ) -> Result<()> {
         loop {
-            match self.listener.accept() {
+            match self.listeners[index].accept() {
                 Err(err) if err.kind() == ErrorKind::WouldBlock => {
                     log::debug!("no more connection, stop now");
                     return Ok(());
                 }
                 Err(err) => return Err(err),
-                Ok((stream, addr)) => {
+                Ok((mut stream, addr)) => {
+                    let addr = canonical_address(addr);
+                    if self.is_full() {
+                        log::warn!("connection from {} rejected, server full", addr);
+                        self.metrics.add_rejected();
+                        // 加密连接尚未握手，只有明文连接能返回错误帧，尽力发送后关闭
+                        if self.tls.is_none() && !self.encrypt {
+                            let frame = control_frame(SERVER_FULL_CMD, self.conns.len() as u32);
+                            let _ = stream.write(frame.as_slice());
+                        }
+                        continue;
+                    }
+                    if !self.admit(&addr) {
+                        // 直接丢弃，连接随之关闭
+                        continue;
+                    }
                     log::debug!("accept connection:{}", addr);
-                    let conn = Connection::new(stream, addr, self.sender.clone(), max_request_size);
-                    self.insert(conn);
+                    let conn = Connection::new(
+                        Transport::tcp(stream, &self.tls, self.proxy_protocol),
+                        addr,
+                        self.sender.clone(),
+                        self.connected.clone(),
+                        max_request_size,
+                        self.version,
+                        self.encrypt,
+                        index,
+                        self.metrics.clone(),
+                    );
+                    self.insert(conn, registry);
+                }
+            }
+        }
+    }
+
+    /// 接收UDP数据报，新的对端发来数据段时建立会话
+    pub fn accept_udp(&mut self, max_request_size: usize, registry: &Registry) {
+        let socket = self.udp.clone().unwrap();
+        let mut buffer = [0u8; UDP_HEADER + UDP_MSS];
+        loop {
+            match socket.recv_from(&mut buffer) {
+                Ok((size, peer)) => {
+                    let data = &buffer[..size];
+                    let index = if let Some(index) = self.peers.get(&peer) {
+                        *index
+                    } else if size >= UDP_HEADER
+                        && data[4] == UDP_PUSH
+                        && !self.is_full()
+                        && self.peers.len() < UDP_MAX_SESSIONS
+                        && self.admit(&canonical_address(peer))
+                    {
+                        log::debug!("accept udp session:{}", peer);
+                        let session =
+                            UdpSession::new(socket.clone(), peer, BigEndian::read_u32(data));
+                        let conn = Connection::new(
+                            Transport::udp(session),
+                            canonical_address(peer),
+                            self.sender.clone(),
+                            self.connected.clone(),
+                            max_request_size,
+                            self.version,
+                            self.encrypt,
+                            self.listeners.len(),
+                            self.metrics.clone(),
+                        );
+                        let index = self.insert(conn, registry);
+                        self.peers.insert(peer, index);
+                        index
+                    } else {
+                        log::debug!("[{}]datagram without session dropped", peer);
+                        continue;
+                    };
+                    if let Some(conn) = self.conns.get_mut(index) {
+                        conn.do_input(data);
+                    }
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => break,
+                Err(err) => {
+                    log::error!("receive datagram failed:{}", err);
+                    break;
+                }
+            }
+        }
+    }
+
+    /// 驱动所有UDP会话的重传，失效的会话直接关闭；UDP会话没有独立的事件，在这里推进关闭状态
+    /// 和发送窗口打开后的写缓冲
+    pub fn update_udp(&mut self, registry: &Registry) {
+        for index in self.peers.values() {
+            if let Some(conn) = self.conns.get_mut(*index) {
+                if conn.stream.update() {
+                    conn.do_write();
+                } else {
+                    conn.shutdown();
                 }
+                conn.reregister(registry);
             }
         }
     }
 
-    fn insert(&mut self, conn: Connection) {
+    fn is_full(&self) -> bool {
+        self.max_connections
+            .map_or(false, |max_connections| self.conns.len() >= max_connections)
+    }
+
+    fn admit(&mut self, address: &SocketAddr) -> bool {
+        let ip = address.ip();
+        let connections = self.ip_connections.get(&ip).copied().unwrap_or_default();
+        if self.access.check(&ip, connections) {
+            true
+        } else {
+            log::warn!("connection from {} rejected", address);
+            self.metrics.add_rejected();
+            false
+        }
+    }
+
+    /// 连接通过PROXY协议得到真实地址后，按真实地址重新做准入检查
+    fn proxied(&mut self, index: usize, from: SocketAddr, registry: &Registry) {
+        self.release_ip(&from.ip());
+        let address = self.conns[index].address;
+        let admitted = self.admit(&address);
+        *self.ip_connections.entry(address.ip()).or_default() += 1;
+        if !admitted {
+            let conn = &mut self.conns[index];
+            conn.shutdown();
+            conn.reregister(registry);
+        }
+    }
+
+    fn release_ip(&mut self, ip: &IpAddr) {
+        if let Some(count) = self.ip_connections.get_mut(ip) {
+            *count -= 1;
+            if *count == 0 {
+                self.ip_connections.remove(ip);
+            }
+        }
+    }
+
+    /// 连接建立后立即注册，加密握手以及版本检查都需要读取数据，不能等到收到实体之后
+    fn insert(&mut self, mut conn: Connection, registry: &Registry) -> usize {
+        *self.ip_connections.entry(conn.address.ip()).or_default() += 1;
+        conn.read_bytes = self.read_pool.get();
+        conn.write_bytes = self.pool.get();
+        conn.limiter = self.rate_limit.map(TokenBucket::new);
         let index = self.conns.insert(conn);
         let conn = self.conns.get_mut(index).unwrap();
         conn.set_token(Self::index2token(index));
+        conn.setup(registry);
+        self.metrics.add_accepted();
         log::info!("connection:{} installed", index);
+        index
     }
 
     fn token2index(token: Token) -> usize {
@@ -502,8 +1793,12 @@ impl Listener {
     }
 
     pub fn do_event(&mut self, event: &Event, poll: &Poll) {
-        if let Some(conn) = self.conns.get_mut(Self::token2index(event.token())) {
+        let index = Self::token2index(event.token());
+        if let Some(conn) = self.conns.get_mut(index) {
             conn.do_event(event, poll.registry());
+            if let Some(from) = conn.proxied.take() {
+                self.proxied(index, from, poll.registry());
+            }
         } else {
             log::error!("connection:{} not found", Self::token2index(event.token()));
         }
@@ -511,20 +1806,75 @@ impl Listener {
 
     pub fn do_send(&mut self, registry: &Registry) {
         let receiver = self.receiver.take().unwrap();
+        // 数据响应先累积到写缓冲，全部取出后每个连接只写一次
+        let mut pending = HashSet::new();
         receiver.try_iter().for_each(|(tokens, data)| {
+            if let Response::Shutdown = data {
+                self.stopping = true;
+                return;
+            }
+            if let Response::DataAll(data) = &data {
+                for (index, conn) in self.conns.iter_mut() {
+                    if conn.is_playing() {
+                        conn.do_send(data);
+                        pending.insert(index);
+                    }
+                }
+                return;
+            }
             for token in tokens {
-                if let Some(conn) = self.conns.get_mut(Self::token2index(token)) {
+                let index = Self::token2index(token);
+                if let Some(conn) = self.conns.get_mut(index) {
                     match &data {
-                        Response::Data(data) => conn.do_send(registry, data.as_slice()),
-                        Response::Entity(entity) => conn.set_entity(*entity, registry),
-                        Response::Close(confirm) => conn.do_close(*confirm),
+                        Response::Data(data) => {
+                            conn.do_send(data);
+                            pending.insert(index);
+                            continue;
+                        }
+                        Response::Entity(entity) => {
+                            conn.do_flush(registry);
+                            conn.set_entity(*entity);
+                        }
+                        Response::Close(confirm) => {
+                            conn.do_flush(registry);
+                            conn.do_close(*confirm);
+                        }
+                        Response::DataAll(_) | Response::Shutdown => {}
                     }
+                    pending.remove(&index);
                 } else {
-                    log::error!("connection:{} not found", Self::token2index(token));
+                    log::error!("connection:{} not found", index);
                 }
             }
         });
         self.receiver.replace(receiver);
+        for index in pending {
+            if let Some(conn) = self.conns.get_mut(index) {
+                conn.do_flush(registry);
+            }
+        }
+    }
+
+    /// 停止监听，尽量发出写缓冲中的数据后关闭所有连接
+    pub fn shutdown(&mut self, registry: &Registry) {
+        for listener in &mut self.listeners {
+            if let Err(err) = registry.deregister(listener) {
+                log::error!("deregister listener failed:{}", err);
+            }
+        }
+        self.listeners.clear();
+        self.udp.take();
+        self.conns.iter_mut().for_each(|(_, conn)| {
+            conn.do_write();
+            conn.shutdown();
+        });
+        log::info!("network shutdown, {} connections closed", self.conns.len());
+    }
+
+    pub fn heartbeat(&mut self, max_missed: usize) {
+        self.conns
+            .iter_mut()
+            .for_each(|(_, conn)| conn.heartbeat(max_missed));
     }
 
     pub fn check_timeout(&mut self) {
@@ -545,57 +1895,224 @@ impl Listener {
             .map(|(index, _)| index)
             .collect();
         indexes.iter().for_each(|index| {
-            self.conns.remove(*index);
+            let mut conn = self.conns.remove(*index);
+            self.read_pool.put(std::mem::take(&mut conn.read_bytes));
+            self.pool.put(std::mem::take(&mut conn.write_bytes));
+            self.release_ip(&conn.address.ip());
             log::debug!("connection:{} released now", index);
         });
+        self.peers.retain(|_, index| !indexes.contains(index));
+        self.metrics.add_closed(indexes.len());
+    }
+
+    pub fn update_metrics(&self) {
+        let pending_bytes = self
+            .conns
+            .iter()
+            .map(|(_, conn)| conn.write_bytes.len())
+            .sum();
+        let queued_responses = self.receiver.as_ref().map_or(0, |receiver| receiver.len());
+        self.metrics.set_queue(pending_bytes, queued_responses);
+        self.metrics
+            .set_pooled_buffers(self.pool.buffers.len() + self.read_pool.buffers.len());
     }
 }
 
-const LISTENER: Token = Token(1);
 const ECS_SENDER: Token = Token(2);
-const MIN_CLIENT: usize = 3;
+const UDP_LISTENER: Token = Token(3);
+/// TCP监听的Token从此值开始
+const MIN_LISTENER: usize = 4;
+/// 最多支持的TCP监听地址数量
+pub const MAX_LISTENERS: usize = 16;
+const MIN_CLIENT: usize = MIN_LISTENER + MAX_LISTENERS;
+/// 协议版本不一致时返回的错误帧命令号，内容为服务器的版本号
+pub const VERSION_MISMATCH_CMD: u32 = 0;
+/// 账号重复登录被踢下线时返回的错误帧命令号，内容为0
+pub const DUPLICATE_LOGIN_CMD: u32 = 1;
+/// 请求序号命令号，请求以 cmd(4) | seq(4) 作为前缀时携带客户端序号，
+/// 处理完成后服务器返回相同命令号的确认帧，内容为已处理的最大序号
+pub const SEQUENCE_CMD: u32 = 2;
+/// 登录排队时定期返回的控制帧命令号，内容为当前排队位置，从1开始
+pub const LOGIN_QUEUE_CMD: u32 = 3;
+/// 加密握手响应帧命令号，内容为服务器的x25519公钥
+pub const HANDSHAKE_CMD: u32 = 4;
+/// 心跳命令号，服务器定期发送内容为心跳序号的控制帧，客户端需要以相同命令号的请求回应，
+/// 心跳只在网络线程中处理，不会转发给ECS
+pub const HEARTBEAT_CMD: u32 = 5;
+/// 会话密钥帧命令号，内容为 实体id(4) | 会话密钥(8)，开启断线重连后实体建立时发送
+pub const SESSION_CMD: u32 = 6;
+/// 断线重连请求命令号，请求内容为 实体id(4) | 会话密钥(8)，
+/// 服务器返回相同命令号的控制帧，内容为1表示恢复成功，0表示失败
+pub const RESUME_CMD: u32 = 7;
+/// 连接数达到上限时返回的错误帧命令号，内容为当前连接数，之后连接被关闭
+pub const SERVER_FULL_CMD: u32 = 8;
+/// 小于此值的命令号保留给控制帧，请求命令号不能落在此范围内
+pub const RESERVED_CMDS: u32 = 16;
+
+/// 包体长度的最高位，表示命令号之后的内容经过lz4压缩，压缩内容前4个字节为小端序的原始长度
+pub const COMPRESSED_FLAG: u32 = 1 << 31;
+
+/// 压缩响应帧 length(4) | id(4) | cmd(4) | body 中超过阈值的包体，压缩后没有变小时保持原样
+pub fn compress_frame(frame: Vec<u8>, threshold: usize) -> Vec<u8> {
+    if threshold == 0 || frame.len() < 12 + threshold {
+        return frame;
+    }
+    let body = lz4_flex::compress_prepend_size(&frame[12..]);
+    if body.len() >= frame.len() - 12 {
+        return frame;
+    }
+    let mut data = Vec::with_capacity(12 + body.len());
+    data.extend_from_slice(&frame[..12]);
+    data.extend_from_slice(body.as_slice());
+    let length = (data.len() - 4) as u32 | COMPRESSED_FLAG;
+    BigEndian::write_u32(data.as_mut_slice(), length);
+    data
+}
+
+/// 解压请求 cmd(4) | body 中的包体，原始长度超过限制时视为非法请求
+fn decompress_body(data: &[u8], max_request_size: usize) -> Option<Vec<u8>> {
+    if data.len() < 8 {
+        return None;
+    }
+    let size = LittleEndian::read_u32(&data[4..]) as usize;
+    if size + 4 > max_request_size {
+        return None;
+    }
+    let body = lz4_flex::decompress_size_prepended(&data[4..]).ok()?;
+    let mut request = Vec::with_capacity(4 + body.len());
+    request.extend_from_slice(&data[..4]);
+    request.extend_from_slice(body.as_slice());
+    Some(request)
+}
+
+/// 会话密钥帧 length(4) | 0(4) | SESSION_CMD(4) | id(4) | key(8)
+pub(crate) fn session_frame(id: u32, key: u64) -> Vec<u8> {
+    let mut data = vec![0u8; 24];
+    BigEndian::write_u32(&mut data, 20);
+    BigEndian::write_u32(&mut data[8..], SESSION_CMD);
+    BigEndian::write_u32(&mut data[12..], id);
+    BigEndian::write_u64(&mut data[16..], key);
+    data
+}
+
+/// 引擎控制帧 length(4) | 0(4) | cmd(4) | value(4)
+fn control_frame(cmd: u32, value: u32) -> Vec<u8> {
+    let mut data = vec![0u8; 16];
+    BigEndian::write_u32(&mut data, 12);
+    BigEndian::write_u32(&mut data[8..], cmd);
+    BigEndian::write_u32(&mut data[12..], value);
+    data
+}
 
 pub fn run_network(
     mut poll: Poll,
-    address: SocketAddr,
+    addresses: Vec<SocketAddr>,
+    udp_address: Option<SocketAddr>,
     sender: Sender<NetworkInputData>,
+    connected: Sender<Connected>,
     receiver: Receiver<NetworkOutputData>,
     idle_timeout: Duration,
     read_timeout: Duration,
     write_timeout: Duration,
     poll_timeout: Option<Duration>,
     max_request_size: usize,
+    version: u32,
+    tls: Option<TlsConfig>,
+    encrypt: bool,
+    heartbeat: Option<(Duration, usize)>,
+    metrics: NetworkMetrics,
+    access: AccessControl,
+    max_connections: Option<usize>,
+    proxy_protocol: bool,
+    rate_limit: Option<RateLimit>,
 ) -> Result<()> {
-    let mut listener = TcpListener::bind(address)?;
-    poll.registry()
-        .register(&mut listener, LISTENER, Interest::READABLE)?;
+    let mut listeners = Vec::with_capacity(addresses.len());
+    for (index, address) in addresses.iter().enumerate() {
+        // 同一端口另外监听了IPv4地址时，IPv6监听不能再接受IPv4连接，否则端口冲突
+        let only_v6 = address.is_ipv6()
+            && addresses
+                .iter()
+                .any(|other| other.is_ipv4() && other.port() == address.port());
+        let mut listener = bind_listener(*address, only_v6)?;
+        poll.registry().register(
+            &mut listener,
+            Token(MIN_LISTENER + index),
+            Interest::READABLE,
+        )?;
+        listeners.push(listener);
+    }
+    let udp = if let Some(udp_address) = udp_address {
+        let mut udp = UdpSocket::bind(udp_address)?;
+        poll.registry()
+            .register(&mut udp, UDP_LISTENER, Interest::READABLE)?;
+        Some(udp)
+    } else {
+        None
+    };
+    // UDP会话需要定期驱动重传
+    let poll_timeout = if udp.is_some() {
+        Some(poll_timeout.map_or(UDP_TICK, |timeout| timeout.min(UDP_TICK)))
+    } else {
+        poll_timeout
+    };
+    // 等待时间不能超过心跳间隔
+    let poll_timeout = match (poll_timeout, heartbeat) {
+        (Some(timeout), Some((interval, _))) => Some(timeout.min(interval)),
+        (None, Some((interval, _))) => Some(interval),
+        (timeout, None) => timeout,
+    };
     let mut listener = Listener::new(
-        listener,
-        4096,
+        listeners,
+        udp,
+        max_connections,
         sender,
+        connected,
         receiver,
         idle_timeout,
         read_timeout,
         write_timeout,
+        version,
+        tls,
+        encrypt,
+        metrics,
+        access,
+        proxy_protocol,
+        rate_limit,
     );
     let mut events = Events::with_capacity(1024);
     let mut last_check_time = Instant::now();
     let check_timeout = Duration::new(1, 0);
+    let mut last_heartbeat_time = Instant::now();
     loop {
         poll.poll(&mut events, poll_timeout)?;
         let registry = poll.registry();
         listener.do_send(registry);
+        if listener.stopping {
+            listener.shutdown(registry);
+            return Ok(());
+        }
         for event in &events {
             match event.token() {
-                LISTENER => listener.accept(max_request_size)?,
+                Token(index) if (MIN_LISTENER..MIN_CLIENT).contains(&index) => {
+                    listener.accept(index - MIN_LISTENER, max_request_size, poll.registry())?
+                }
                 ECS_SENDER => {}
+                UDP_LISTENER => listener.accept_udp(max_request_size, poll.registry()),
                 _ => listener.do_event(event, &poll),
             }
         }
+        listener.update_udp(poll.registry());
         if last_check_time.elapsed() >= check_timeout {
             last_check_time = Instant::now();
             listener.check_release();
             listener.check_timeout();
+            listener.update_metrics();
+        }
+        if let Some((interval, max_missed)) = heartbeat {
+            if last_heartbeat_time.elapsed() >= interval {
+                last_heartbeat_time = Instant::now();
+                listener.heartbeat(max_missed);
+            }
         }
     }
 }
@@ -609,16 +2126,28 @@ pub fn channel<T>(bounded_size: usize) -> (Sender<T>, Receiver<T>) {
 }
 
 pub fn async_run<T>(
-    address: SocketAddr,
+    addresses: Vec<SocketAddr>,
+    udp_address: Option<SocketAddr>,
     idle_timeout: Duration,
     read_timeout: Duration,
     write_timeout: Duration,
     poll_timeout: Option<Duration>,
     max_request_size: usize,
     max_response_size: usize,
+    compress_threshold: usize,
     bounded_size: usize,
+    tls: Option<TlsConfig>,
+    encrypt: bool,
+    heartbeat: Option<(Duration, usize)>,
+    resume: Option<Sender<(Entity, Bytes)>>,
+    connected: Sender<Connected>,
+    metrics: NetworkMetrics,
+    access: AccessControl,
+    max_connections: Option<usize>,
+    proxy_protocol: bool,
+    rate_limit: Option<RateLimit>,
     t: T,
-) -> BytesSender
+) -> (BytesSender, Vec<JoinHandle<()>>)
 where
     T: Send + Input + 'static,
 {
@@ -628,29 +2157,50 @@ where
     let (response_sender, response_receiver) = channel::<NetworkOutputData>(bounded_size);
     let poll = Poll::new().unwrap();
     let waker = Arc::new(Waker::new(poll.registry(), ECS_SENDER).unwrap());
-    rayon::spawn(move || {
+    let version = t.protocol_version();
+    let network = std::thread::spawn(move || {
         if let Err(err) = run_network(
             poll,
-            address,
+            addresses,
+            udp_address,
             network_sender,
+            connected,
             response_receiver,
             idle_timeout,
             read_timeout,
             write_timeout,
             poll_timeout,
             max_request_size,
+            version,
+            tls,
+            encrypt,
+            heartbeat,
+            metrics,
+            access,
+            max_connections,
+            proxy_protocol,
+            rate_limit,
         ) {
             log::error!("network thread quit with error:{}", err);
         }
     });
-    rayon::spawn(move || {
-        run_decode(t, network_receiver);
+    let decode = std::thread::spawn(move || {
+        run_decode(t, network_receiver, resume);
     });
-    BytesSender::new(response_sender, waker, max_response_size)
+    let sender = BytesSender::new(
+        response_sender,
+        waker,
+        max_response_size,
+        compress_threshold,
+    );
+    (sender, vec![network, decode])
 }
 
-fn run_decode<T>(mut t: T, net_receiver: Receiver<NetworkInputData>)
-where
+fn run_decode<T>(
+    mut t: T,
+    net_receiver: Receiver<NetworkInputData>,
+    resume: Option<Sender<(Entity, Bytes)>>,
+) where
     T: Input,
 {
     let ecs_receiver = t.next_receiver();
@@ -662,8 +2212,33 @@ where
         log::debug!("select receiver:{}", operation.index());
         match operation.index() {
             i if i == net_index => match operation.recv(&net_receiver) {
-                Ok((ident, data)) => t.dispatch(ident, data),
-                Err(err) => log::error!("receive from network failed:{}", err),
+                Ok((ident, data)) => {
+                    // 断线重连请求由SessionSystem处理，不经过请求解码
+                    if let (RequestIdent::Entity(entity), Some(resume)) = (&ident, &resume) {
+                        if data.len() >= 4 && BigEndian::read_u32(&data) == RESUME_CMD {
+                            if let Err(err) = resume.send((*entity, data)) {
+                                log::error!("send resume request to ecs failed:{}", err);
+                            }
+                            continue;
+                        }
+                    }
+                    match &ident {
+                        RequestIdent::Entity(entity) if data.len() >= 4 => trace_entity(
+                            entity.id(),
+                            "dispatch",
+                            format_args!("cmd:{} size:{}", BigEndian::read_u32(&data), data.len()),
+                        ),
+                        RequestIdent::Close(entity) => {
+                            trace_entity(entity.id(), "dispatch", format_args!("close"))
+                        }
+                        _ => {}
+                    }
+                    t.dispatch(ident, data)
+                }
+                Err(_) => {
+                    log::info!("network thread quit, stop decoding");
+                    return;
+                }
             },
             i if i == ecs_index => match operation.recv(&ecs_receiver) {
                 Ok(entities) => entities.into_iter().for_each(|entity| t.do_next(entity)),
@@ -679,6 +2254,12 @@ pub struct BytesSender {
     sender: Option<Sender<NetworkOutputData>>,
     waker: Option<Arc<Waker>>,
     max_response_size: usize,
+    /// 包体超过此大小的响应会被压缩，0表示不压缩
+    compress_threshold: usize,
+    /// 每个连接自上次统计以来的发送字节数
+    traffic: Arc<Mutex<HashMap<Token, usize>>>,
+    /// 本帧已经发送的同步数据以及接收的连接，相同内容对同一连接只发送一次，flush时清空
+    sent: Arc<Mutex<HashMap<Arc<[u8]>, HashSet<Token>>>>,
 }
 
 impl BytesSender {
@@ -686,17 +2267,33 @@ impl BytesSender {
         sender: Sender<NetworkOutputData>,
         waker: Arc<Waker>,
         max_response_size: usize,
+        compress_threshold: usize,
     ) -> Self {
         Self {
             sender: Some(sender),
             waker: Some(waker),
             max_response_size,
+            compress_threshold,
+            traffic: Default::default(),
+            sent: Default::default(),
+        }
+    }
+
+    /// 不经过网络线程，响应只写入通道，用于测试
+    pub(crate) fn detached(sender: Sender<NetworkOutputData>) -> Self {
+        Self {
+            sender: Some(sender),
+            max_response_size: usize::MAX,
+            ..Default::default()
         }
     }
 
+    /// 未连接网络时(如回放测试)直接丢弃响应
     fn broadcast(&self, tokens: Vec<Token>, response: Response) {
-        if let Err(err) = self.sender.as_ref().unwrap().send((tokens, response)) {
-            log::error!("send response to network failed {}", err);
+        if let Some(sender) = &self.sender {
+            if let Err(err) = sender.send((tokens, response)) {
+                log::error!("send response to network failed {}", err);
+            }
         }
     }
 
@@ -712,13 +2309,23 @@ impl BytesSender {
         self.broadcast(vec![token], Response::Close(done));
     }
 
+    /// 通知网络线程停服
+    pub fn shutdown(&self) {
+        self.broadcast(Vec::new(), Response::Shutdown);
+        self.flush();
+    }
+
     pub fn flush(&self) {
-        if let Err(err) = self.waker.as_ref().unwrap().wake() {
-            log::error!("wake poll failed:{}", err);
+        self.sent.lock().unwrap().clear();
+        if let Some(waker) = &self.waker {
+            if let Err(err) = waker.wake() {
+                log::error!("wake poll failed:{}", err);
+            }
         }
     }
 
-    pub fn broadcast_bytes(&self, tokens: Vec<Token>, bytes: Vec<u8>) {
+    fn encode_frame(&self, bytes: Vec<u8>) -> Arc<[u8]> {
+        let bytes = compress_frame(bytes, self.compress_threshold);
         if bytes.len() > self.max_response_size {
             log::error!(
                 "response size:{} is greater than {}",
@@ -726,10 +2333,64 @@ impl BytesSender {
                 self.max_response_size
             );
         }
+        bytes.into()
+    }
+
+    fn send_frame(&self, tokens: Vec<Token>, bytes: Arc<[u8]>) {
+        {
+            let mut traffic = self.traffic.lock().unwrap();
+            for token in &tokens {
+                *traffic.entry(*token).or_default() += bytes.len();
+            }
+        }
+        self.broadcast(tokens, Response::Data(bytes));
+    }
+
+    pub fn broadcast_bytes(&self, tokens: Vec<Token>, bytes: Vec<u8>) {
+        if tokens.is_empty() {
+            return;
+        }
+        self.send_frame(tokens, self.encode_frame(bytes));
+    }
+
+    /// 数据集同步使用，多个方向的目标经常重叠，同一帧内相同内容对同一连接只发送一次
+    pub fn sync_bytes(&self, tokens: Vec<Token>, bytes: Vec<u8>) {
         if tokens.is_empty() {
             return;
         }
-        self.broadcast(tokens, Response::Data(bytes));
+        let bytes = self.encode_frame(bytes);
+        // 相同内容复用已有的payload，只发给本帧还没有收到的连接
+        let (bytes, tokens) = {
+            let mut sent = self.sent.lock().unwrap();
+            let bytes = sent
+                .get_key_value(&bytes)
+                .map_or(bytes, |(payload, _)| payload.clone());
+            let received = sent.entry(bytes.clone()).or_default();
+            let tokens: Vec<_> = tokens
+                .into_iter()
+                .filter(|token| received.insert(*token))
+                .collect();
+            (bytes, tokens)
+        };
+        if tokens.is_empty() {
+            return;
+        }
+        self.send_frame(tokens, bytes);
+    }
+
+    /// 发送给所有已经进入游戏的连接，不去重，也不计入连接的发送统计
+    pub fn broadcast_all(&self, bytes: Vec<u8>) {
+        let bytes = self.encode_frame(bytes);
+        self.broadcast(Vec::new(), Response::DataAll(bytes));
+    }
+
+    pub fn broadcast_data_all(&self, id: u32, data: impl Output) {
+        self.broadcast_all(data.encode(id));
+    }
+
+    /// 取出自上次调用以来每个连接的发送字节数
+    pub(crate) fn take_traffic(&self) -> HashMap<Token, usize> {
+        std::mem::take(&mut *self.traffic.lock().unwrap())
     }
 
     pub fn broadcast_data(&self, tokens: Vec<Token>, id: u32, data: impl Output) {
@@ -743,4 +2404,380 @@ impl BytesSender {
     pub fn send_data(&self, token: Token, id: u32, data: impl Output) {
         self.send_bytes(token, data.encode(id));
     }
+
+    pub fn send_control(&self, token: Token, cmd: u32, value: u32) {
+        self.send_bytes(token, control_frame(cmd, value));
+    }
+}
+
+/// 连接中转服务失败或断开后的重连间隔
+const RELAY_RECONNECT: Duration = Duration::from_secs(3);
+
+type RelayFrame = (u32, u32, Vec<u8>);
+
+/// 跨服中转连接的发送端，未连接中转服务时直接丢弃数据。
+/// 帧格式均为大端：length(4) | server(4) | entity(4) | payload，length不包含自身，
+/// 连接建立后先发送本服的server(4)，发送的帧中server为目标服务器，收到的帧中entity为本服实体
+#[derive(Clone, Default)]
+pub struct RelaySender {
+    sender: Option<Sender<RelayFrame>>,
+}
+
+impl RelaySender {
+    /// 发送给其他服务器上的实体，队列已满时丢弃
+    pub fn send_bytes(&self, server: u32, entity: u32, bytes: Vec<u8>) {
+        if let Some(sender) = &self.sender {
+            if let Err(err) = sender.try_send((server, entity, bytes)) {
+                log::error!(
+                    "send to entity:{} of server:{} via relay failed:{}",
+                    entity,
+                    server,
+                    err
+                );
+            }
+        }
+    }
+}
+
+/// 启动到中转服务的持久连接，断开后自动重连，收到的数据通过received交给`RelaySystem`
+pub fn run_relay(
+    address: SocketAddr,
+    server: u32,
+    bounded_size: usize,
+    received: Sender<(u32, Vec<u8>)>,
+) -> RelaySender {
+    let (sender, receiver) = channel::<RelayFrame>(bounded_size);
+    std::thread::spawn(move || {
+        let mut pending = None;
+        loop {
+            let stream = match std::net::TcpStream::connect(address) {
+                Ok(stream) => stream,
+                Err(err) => {
+                    log::error!("connect relay {} failed:{}", address, err);
+                    std::thread::sleep(RELAY_RECONNECT);
+                    continue;
+                }
+            };
+            log::info!("relay {} connected", address);
+            match serve_relay(stream, server, &receiver, &received, &mut pending) {
+                Ok(()) => {
+                    log::info!("engine stopped, relay {} closed", address);
+                    return;
+                }
+                Err(err) => log::error!("relay {} disconnected:{}", address, err),
+            }
+            std::thread::sleep(RELAY_RECONNECT);
+        }
+    });
+    RelaySender {
+        sender: Some(sender),
+    }
+}
+
+/// 发送失败的帧保存在pending中，重连后重新发送，发送端全部释放后返回Ok
+fn serve_relay(
+    mut stream: std::net::TcpStream,
+    server: u32,
+    receiver: &Receiver<RelayFrame>,
+    received: &Sender<(u32, Vec<u8>)>,
+    pending: &mut Option<RelayFrame>,
+) -> Result<()> {
+    stream.set_nodelay(true)?;
+    stream.write_all(&server.to_be_bytes())?;
+    let mut reader = stream.try_clone()?;
+    let received = received.clone();
+    let closed = Arc::new(AtomicBool::new(false));
+    let reader_closed = closed.clone();
+    let handle = std::thread::spawn(move || {
+        let mut header = [0u8; 12];
+        while reader.read_exact(&mut header).is_ok() {
+            let length = BigEndian::read_u32(&header) as usize;
+            if length < 8 {
+                log::error!("invalid relay frame length:{}", length);
+                break;
+            }
+            let mut payload = vec![0u8; length - 8];
+            if reader.read_exact(&mut payload).is_err() {
+                break;
+            }
+            if received
+                .send((BigEndian::read_u32(&header[8..]), payload))
+                .is_err()
+            {
+                break;
+            }
+        }
+        reader_closed.store(true, Ordering::Release);
+    });
+    let result = loop {
+        let (server, entity, payload) = match pending.take() {
+            Some(frame) => frame,
+            None => match receiver.recv_timeout(RELAY_RECONNECT) {
+                Ok(frame) => frame,
+                // 没有数据要发送时也需要检查连接是否已经断开
+                Err(RecvTimeoutError::Timeout) => {
+                    if closed.load(Ordering::Acquire) {
+                        break Err(ErrorKind::ConnectionAborted.into());
+                    }
+                    continue;
+                }
+                Err(RecvTimeoutError::Disconnected) => break Ok(()),
+            },
+        };
+        let mut frame = Vec::with_capacity(payload.len() + 12);
+        frame.extend_from_slice(&(payload.len() as u32 + 8).to_be_bytes());
+        frame.extend_from_slice(&server.to_be_bytes());
+        frame.extend_from_slice(&entity.to_be_bytes());
+        frame.extend_from_slice(payload.as_slice());
+        if let Err(err) = stream.write_all(frame.as_slice()) {
+            pending.replace((server, entity, payload));
+            break Err(err);
+        }
+    };
+    // 关闭连接使读线程退出
+    let _ = stream.shutdown(Shutdown::Both);
+    let _ = handle.join();
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONV: u32 = 1;
+
+    /// 对端socket只用于接收确认，测试中不读取
+    fn udp_session() -> (UdpSession, UdpSocket) {
+        let socket = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let session = UdpSession::new(Arc::new(socket), peer.local_addr().unwrap(), CONV);
+        (session, peer)
+    }
+
+    fn push(sn: u32, payload: &[u8]) -> Vec<u8> {
+        UdpSession::datagram(CONV, UDP_PUSH, sn, payload)
+    }
+
+    fn read_all(session: &mut UdpSession) -> Vec<u8> {
+        let mut data = vec![0u8; 64];
+        let size = session.read(&mut data).unwrap_or(0);
+        data.truncate(size);
+        data
+    }
+
+    /// 与`run_network`一样分发一轮事件
+    fn poll_listener(poll: &mut Poll, events: &mut Events, listener: &mut Listener) {
+        poll.poll(events, Some(Duration::from_millis(50))).unwrap();
+        for event in events.iter() {
+            match event.token() {
+                Token(index) if (MIN_LISTENER..MIN_CLIENT).contains(&index) => listener
+                    .accept(index - MIN_LISTENER, 1024, poll.registry())
+                    .unwrap(),
+                _ => listener.do_event(event, poll),
+            }
+        }
+    }
+
+    /// PROXY协议v2头部，command为1表示PROXY，0表示LOCAL
+    fn proxy_header(command: u8, family: u8, body: &[u8]) -> Vec<u8> {
+        let mut header = PROXY_SIGNATURE.to_vec();
+        header.push(0x20 | command);
+        header.push(family << 4 | 1);
+        header.extend_from_slice(&(body.len() as u16).to_be_bytes());
+        header.extend_from_slice(body);
+        header
+    }
+
+    #[test]
+    fn proxy_v4() {
+        // 源地址 | 目的地址 | 源端口 | 目的端口
+        let body = [10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0, 80];
+        let header = proxy_header(1, 1, &body);
+        assert_eq!(
+            parse_proxy_header(&header).unwrap(),
+            Some("10.0.0.1:8080".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn proxy_v6() {
+        let mut body = [0u8; 36];
+        body[15] = 1;
+        body[32..34].copy_from_slice(&443u16.to_be_bytes());
+        let header = proxy_header(1, 2, &body);
+        assert_eq!(
+            parse_proxy_header(&header).unwrap(),
+            Some("[::1]:443".parse().unwrap())
+        );
+
+        // IPv4映射地址转换为IPv4地址
+        body[..16].copy_from_slice(&Ipv4Addr::new(1, 2, 3, 4).to_ipv6_mapped().octets());
+        let header = proxy_header(1, 2, &body);
+        assert_eq!(
+            parse_proxy_header(&header).unwrap(),
+            Some("1.2.3.4:443".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn proxy_local_and_invalid() {
+        // LOCAL命令以及不支持的地址族保留原地址
+        assert_eq!(
+            parse_proxy_header(&proxy_header(0, 1, &[0; 12])).unwrap(),
+            None
+        );
+        assert_eq!(
+            parse_proxy_header(&proxy_header(1, 3, &[0; 216])).unwrap(),
+            None
+        );
+        // 地址长度不足
+        assert_eq!(
+            parse_proxy_header(&proxy_header(1, 1, &[0; 8])).unwrap(),
+            None
+        );
+
+        let mut header = proxy_header(1, 1, &[0; 12]);
+        header[12] = 0x11;
+        assert!(parse_proxy_header(&header).is_err());
+        header[12] = 0x22;
+        assert!(parse_proxy_header(&header).is_err());
+    }
+
+    #[test]
+    fn proxy_read_stream() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (server, _) = listener.accept().unwrap();
+        let mut transport = Transport::tcp(TcpStream::from_std(server), &None, true);
+
+        // 头部分两次到达，之后的请求数据不能被头部读取消耗
+        let header = proxy_header(1, 1, &[10, 0, 0, 1, 10, 0, 0, 2, 0x1f, 0x90, 0, 80]);
+        client.write_all(&header[..10]).unwrap();
+        client.flush().unwrap();
+        std::thread::sleep(Duration::from_millis(20));
+        client.write_all(&header[10..]).unwrap();
+        client.write_all(&[1, 2, 3]).unwrap();
+        assert!(transport.read_proxy().unwrap());
+        assert!(transport.proxy.is_none());
+        assert_eq!(transport.proxied, Some("10.0.0.1:8080".parse().unwrap()));
+
+        let mut data = [0u8; 3];
+        transport.stream.read_exact(&mut data).unwrap();
+        assert_eq!(data, [1, 2, 3]);
+
+        // 签名错误
+        let (mut client, server) = {
+            let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            (client, listener.accept().unwrap().0)
+        };
+        let mut transport = Transport::tcp(TcpStream::from_std(server), &None, true);
+        client.write_all(&[0u8; PROXY_HEADER]).unwrap();
+        assert!(transport.read_proxy().is_err());
+
+        // 头部读取完成前断开
+        drop(client);
+        let (client, server) = {
+            let client = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            (client, listener.accept().unwrap().0)
+        };
+        let mut transport = Transport::tcp(TcpStream::from_std(server), &None, true);
+        drop(client);
+        assert!(!transport.read_proxy().unwrap());
+    }
+
+    #[test]
+    fn tcp_connected_after_version() {
+        let mut poll = Poll::new().unwrap();
+        let mut tcp = TcpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let address = tcp.local_addr().unwrap();
+        poll.registry()
+            .register(&mut tcp, Token(MIN_LISTENER), Interest::READABLE)
+            .unwrap();
+        let (sender, _requests) = channel(0);
+        let (connected, receiver) = channel(0);
+        let (_responses, output) = channel(0);
+        let timeout = Duration::from_secs(60);
+        let mut listener = Listener::new(
+            vec![tcp],
+            None,
+            None,
+            sender,
+            connected,
+            output,
+            timeout,
+            timeout,
+            timeout,
+            1,
+            None,
+            false,
+            NetworkMetrics::default(),
+            AccessControl::default(),
+            false,
+            None,
+        );
+        let mut events = Events::with_capacity(16);
+
+        let mut client = std::net::TcpStream::connect(address).unwrap();
+        for _ in 0..5 {
+            poll_listener(&mut poll, &mut events, &mut listener);
+        }
+        assert_eq!(listener.conns.len(), 1);
+        assert!(receiver.try_recv().is_err());
+
+        // 版本帧 length(4) | version(4)
+        client.write_all(&[0, 0, 0, 4, 0, 0, 0, 1]).unwrap();
+        let connected = (0..50)
+            .find_map(|_| {
+                poll_listener(&mut poll, &mut events, &mut listener);
+                receiver.try_recv().ok()
+            })
+            .expect("connected not sent after version frame");
+        assert_eq!(connected.token, Listener::index2token(0));
+        assert_eq!(connected.listener, 0);
+        assert!(!connected.closed);
+    }
+
+    #[test]
+    fn udp_reorder() {
+        let (mut session, _peer) = udp_session();
+        session.input(&push(1, b"world"));
+        assert!(read_all(&mut session).is_empty());
+        session.input(&push(0, b"hello "));
+        assert_eq!(read_all(&mut session), b"hello world");
+        // 已经交付的重复数据段只回应确认
+        session.input(&push(0, b"hello "));
+        session.input(&push(1, b"world"));
+        assert!(session.pending.is_empty());
+        assert!(read_all(&mut session).is_empty());
+    }
+
+    #[test]
+    fn udp_receive_window() {
+        let (mut session, _peer) = udp_session();
+        session.input(&push(UDP_WINDOW, b"outside"));
+        assert!(session.pending.is_empty());
+        session.input(&push(UDP_WINDOW - 1, b"inside"));
+        assert_eq!(session.pending.len(), 1);
+
+        // 未读取的数据达到上限后不再缓存新的数据段
+        session.pending.clear();
+        session.received = vec![0u8; UDP_MAX_RECEIVED];
+        session.input(&push(0, b"full"));
+        assert!(session.pending.is_empty());
+        assert_eq!(session.recv_sn, 0);
+    }
+
+    #[test]
+    fn udp_send_window() {
+        let (mut session, _peer) = udp_session();
+        let data = vec![0u8; (UDP_WINDOW as usize + 1) * UDP_MSS];
+        assert_eq!(session.write(&data).unwrap(), UDP_WINDOW as usize * UDP_MSS);
+        assert_eq!(
+            session.write(&data).unwrap_err().kind(),
+            ErrorKind::WouldBlock
+        );
+        session.input(&UdpSession::datagram(CONV, UDP_ACK, 0, &[]));
+        assert_eq!(session.write(&data).unwrap(), UDP_MSS);
+        assert_eq!(session.send_sn, UDP_WINDOW + 1);
+    }
 }
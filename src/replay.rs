@@ -0,0 +1,244 @@
+use crate::{
+    network::RequestIdent, resource::FrameCounter, system::CloseSystem, BytesSender,
+    DynamicManager, GameDispatcherBuilder, Input, NetToken,
+};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use specs::{Component, Dispatcher, World, WorldExt};
+use std::{
+    fmt::Debug,
+    fs::File,
+    io::{Read, Write},
+    path::Path,
+};
+
+/// 输入日志，按帧记录每个实体收到的原始请求数据（cmd + body），用于回放
+#[derive(Default)]
+pub struct InputJournal {
+    records: Vec<(usize, u32, Vec<u8>)>,
+}
+
+impl InputJournal {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn record(&mut self, frame: usize, id: u32, data: Vec<u8>) {
+        self.records.push((frame, id, data));
+    }
+
+    /// 文件格式为连续的 frame(4) | entity id(4) | length(4) | data，均为大端
+    pub fn load(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        let mut buffer = Vec::new();
+        File::open(path)?.read_to_end(&mut buffer)?;
+        let mut records = Vec::new();
+        let mut data = buffer.as_slice();
+        while data.len() >= 12 {
+            let frame = BigEndian::read_u32(data) as usize;
+            let id = BigEndian::read_u32(&data[4..]);
+            let length = BigEndian::read_u32(&data[8..]) as usize;
+            data = &data[12..];
+            if data.len() < length {
+                return Err(std::io::ErrorKind::UnexpectedEof.into());
+            }
+            records.push((frame, id, data[..length].to_vec()));
+            data = &data[length..];
+        }
+        if !data.is_empty() {
+            return Err(std::io::ErrorKind::UnexpectedEof.into());
+        }
+        Ok(Self { records })
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let mut file = File::create(path)?;
+        let mut header = [0u8; 12];
+        for (frame, id, data) in &self.records {
+            BigEndian::write_u32(&mut header, *frame as u32);
+            BigEndian::write_u32(&mut header[4..], *id);
+            BigEndian::write_u32(&mut header[8..], data.len() as u32);
+            file.write_all(&header)?;
+            file.write_all(data.as_slice())?;
+        }
+        Ok(())
+    }
+
+    fn frame(&self, frame: usize) -> impl Iterator<Item = &(usize, u32, Vec<u8>)> {
+        self.records.iter().filter(move |(f, _, _)| *f == frame)
+    }
+}
+
+/// 回放测试工具，加载快照后按帧回放输入日志，再比对组件数据，可以直接在`cargo test`中使用
+pub struct ReplayRunner<'a, 'b, I> {
+    world: World,
+    dispatcher: Dispatcher<'a, 'b>,
+    request: I,
+    frame: usize,
+}
+
+impl<'a, 'b, I> ReplayRunner<'a, 'b, I>
+where
+    I: Input,
+{
+    /// `setup`与`Engine::run`的参数一致，`snapshot`用于在回放之前恢复世界状态
+    pub fn new<S, L>(library_path: &str, setup: S, snapshot: L) -> Self
+    where
+        S: Fn(&mut World, &mut GameDispatcherBuilder<'a, 'b>, &DynamicManager) -> I,
+        L: FnOnce(&mut World),
+    {
+        let mut builder = GameDispatcherBuilder::new(false);
+        let mut world = World::new();
+        let dm = DynamicManager::new(library_path.into());
+        let request = setup(&mut world, &mut builder, &dm);
+        world.insert(BytesSender::default());
+        world.insert(FrameCounter::default());
+        world.register::<NetToken>();
+        builder.add(CloseSystem, "close", &[]);
+        world.insert(dm);
+
+        let mut dispatcher = builder.build();
+        dispatcher.setup(&mut world);
+        snapshot(&mut world);
+        world.maintain();
+
+        Self {
+            world,
+            dispatcher,
+            request,
+            frame: 0,
+        }
+    }
+
+    /// 回放从当前帧开始的`frames`帧输入
+    pub fn replay(&mut self, journal: &InputJournal, frames: usize) {
+        for _ in 0..frames {
+            for (_, id, data) in journal.frame(self.frame) {
+                let entity = self.world.entities().entity(*id);
+                self.request
+                    .dispatch(RequestIdent::Entity(entity), Bytes::from(data.clone()));
+            }
+            self.world.write_resource::<FrameCounter>().next_frame();
+            self.dispatcher.dispatch(&self.world);
+            self.world.maintain();
+            let receiver = self.request.next_receiver();
+            receiver
+                .try_iter()
+                .flatten()
+                .for_each(|entity| self.request.do_next(entity));
+            self.frame += 1;
+        }
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    pub fn world_mut(&mut self) -> &mut World {
+        &mut self.world
+    }
+
+    /// 比对实体的组件数据与期望值，不一致时panic
+    pub fn assert_component<T>(&self, id: u32, expected: &T)
+    where
+        T: Component + PartialEq + Debug,
+    {
+        let entity = self.world.entities().entity(id);
+        let storage = self.world.read_storage::<T>();
+        assert_eq!(
+            storage.get(entity),
+            Some(expected),
+            "entity:{} {} mismatch at frame {}",
+            id,
+            std::any::type_name::<T>(),
+            self.frame
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::InputSystem;
+    use crossbeam::channel::{Receiver, Sender};
+    use specs::{Builder, Entity, VecStorage};
+
+    #[derive(Debug, PartialEq)]
+    struct Value(u32);
+
+    impl Component for Value {
+        type Storage = VecStorage<Self>;
+    }
+
+    /// 请求内容为 value(4)
+    struct ValueInput {
+        sender: Sender<(Entity, Value)>,
+        next: (Sender<Vec<Entity>>, Receiver<Vec<Entity>>),
+    }
+
+    impl Input for ValueInput {
+        fn dispatch(&mut self, ident: RequestIdent, data: Bytes) {
+            let value = Value(BigEndian::read_u32(&data));
+            self.sender.send((ident.entity(), value)).unwrap();
+        }
+
+        fn next_receiver(&self) -> Receiver<Vec<Entity>> {
+            self.next.1.clone()
+        }
+
+        fn do_next(&mut self, _entity: Entity) {}
+    }
+
+    fn journal() -> InputJournal {
+        let mut journal = InputJournal::new();
+        journal.record(0, 0, vec![0, 0, 0, 5]);
+        journal.record(1, 0, vec![0, 0, 0, 7]);
+        journal.record(1, 1, vec![0, 0, 0, 9]);
+        journal
+    }
+
+    #[test]
+    fn journal_save_load() {
+        let path = std::env::temp_dir().join(format!("journal-{}.bin", std::process::id()));
+        journal().save(&path).unwrap();
+        let loaded = InputJournal::load(&path).unwrap();
+        assert_eq!(loaded.records, journal().records);
+
+        // 截断的日志无法加载
+        let mut data = std::fs::read(&path).unwrap();
+        data.pop();
+        std::fs::write(&path, data).unwrap();
+        let err = InputJournal::load(&path).err().unwrap();
+        assert_eq!(err.kind(), std::io::ErrorKind::UnexpectedEof);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replay_frames() {
+        let mut runner = ReplayRunner::new(
+            "",
+            |_, builder, _| {
+                let (sender, receiver) = crossbeam::channel::unbounded();
+                builder.add(InputSystem::<Value>::new(receiver), "input", &[]);
+                ValueInput {
+                    sender,
+                    next: crossbeam::channel::unbounded(),
+                }
+            },
+            |world| {
+                world.register::<Value>();
+                world.create_entity().build();
+                world.create_entity().build();
+            },
+        );
+        let journal = journal();
+        runner.replay(&journal, 1);
+        runner.assert_component(0, &Value(5));
+        assert!(!runner
+            .world()
+            .read_storage::<Value>()
+            .contains(runner.world().entities().entity(1)));
+        runner.replay(&journal, 1);
+        runner.assert_component(0, &Value(7));
+        runner.assert_component(1, &Value(9));
+    }
+}
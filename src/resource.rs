@@ -1,29 +1,66 @@
 use crate::{
-    backend::DropEntity,
-    component::{AroundFullData, Position, SceneData, SceneMember, TeamMember},
-    events_to_bitsets, BytesSender, NetToken, SceneSyncBackend,
+    backend::{DropEntity, VisibilityFilter},
+    component::{
+        AroundFullData, EnteredAoi, LeftAoi, Member, Position, SceneData, SceneLifetime,
+        SceneMember, StaticEntity, SyncPriority,
+    },
+    dlog::trace_entity,
+    events_to_bitsets,
+    network::RESERVED_CMDS,
+    spatial::SpatialIndex,
+    BytesSender, NetToken, SceneSyncBackend, SyncDirection,
 };
+use mio::Token;
+use rayon::iter::{IntoParallelIterator, ParallelIterator};
 use specs::{
     hibitset::BitSetLike, prelude::ComponentEvent, storage::GenericWriteStorage, BitSet, Component,
-    Entities, Entity, Join, Read, ReadStorage, ReaderId, Tracked, WriteStorage,
+    Entities, Entity, Join, LazyUpdate, Read, ReadStorage, ReaderId, Tracked, World, WorldExt,
+    WriteStorage,
 };
 use specs_hierarchy::{Hierarchy, Parent};
 use std::{
-    collections::HashMap,
+    collections::{HashMap, VecDeque},
     fmt::Write,
     marker::PhantomData,
-    sync::Mutex,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering as AtomicOrdering},
+        Arc, Mutex,
+    },
     time::{Duration, Instant},
 };
 
+/// 生成的系统在bench特性下最近一次运行的耗时以及处理的实体数量，
+/// 系统在setup时从`TimeStatistic`注册后自己持有，记录时不需要访问共享资源
+#[derive(Default)]
+pub struct BenchRecord {
+    /// 耗时，单位为微秒
+    cost: AtomicUsize,
+    entities: AtomicUsize,
+    /// 上次输出统计之后是否运行过
+    updated: AtomicBool,
+}
+
+impl BenchRecord {
+    #[cfg(feature = "bench")]
+    pub(crate) fn record(&self, cost: Duration, entities: usize) {
+        self.cost
+            .store(cost.as_micros() as usize, AtomicOrdering::Relaxed);
+        self.entities.store(entities, AtomicOrdering::Relaxed);
+        self.updated.store(true, AtomicOrdering::Release);
+    }
+}
+
 pub struct TimeStatistic {
     times: Mutex<HashMap<String, (Duration, Duration)>>,
+    /// 系统名称 -> bench记录
+    benches: Mutex<HashMap<String, Arc<BenchRecord>>>,
 }
 
 impl TimeStatistic {
     pub fn new() -> Self {
         Self {
             times: Default::default(),
+            benches: Default::default(),
         }
     }
 
@@ -31,6 +68,16 @@ impl TimeStatistic {
         self.times.lock().unwrap().insert(name, (begin, end));
     }
 
+    /// 注册系统的bench记录，同名系统共用同一个记录
+    pub fn register_bench(&self, name: &str) -> Arc<BenchRecord> {
+        self.benches
+            .lock()
+            .unwrap()
+            .entry(name.into())
+            .or_default()
+            .clone()
+    }
+
     pub fn print(&self, frame: usize, fps: usize) {
         let mut buffer = bytes::BytesMut::new();
         write!(buffer, "frame:{}, fps:{},", frame, fps).unwrap();
@@ -45,6 +92,19 @@ impl TimeStatistic {
             )
             .unwrap();
         }
+        for (name, record) in self.benches.lock().unwrap().iter() {
+            if !record.updated.swap(false, AtomicOrdering::Acquire) {
+                continue;
+            }
+            write!(
+                buffer,
+                " system {} cost:{}, entities:{},",
+                name,
+                record.cost.load(AtomicOrdering::Relaxed),
+                record.entities.load(AtomicOrdering::Relaxed)
+            )
+            .unwrap();
+        }
         log::info!("{}", String::from_utf8(buffer.to_vec()).unwrap());
     }
 
@@ -80,6 +140,11 @@ impl FrameCounter {
         self.frame
     }
 
+    /// 从快照恢复时从快照的帧数继续计数
+    pub fn set_frame(&mut self, frame: usize) {
+        self.frame = frame;
+    }
+
     pub fn fps(&self) -> usize {
         let delta = self.delta.as_millis() as usize;
         if delta == 0 {
@@ -90,281 +155,2600 @@ impl FrameCounter {
     }
 }
 
-pub struct SceneManager<B>
-where
-    B: SceneSyncBackend,
-    <<B as SceneSyncBackend>::Position as Component>::Storage: Tracked + Default,
-    <<B as SceneSyncBackend>::SceneData as Component>::Storage: Tracked + Default,
-{
-    position_reader: ReaderId<ComponentEvent>,
-    scene_reader: ReaderId<ComponentEvent>,
-    _phantom: PhantomData<B>,
-    /// mapping from entity to grid index
-    user_grids: HashMap<u32, (Entity, usize)>,
-    /// mapping from scene to grids
-    scene_grids: HashMap<u32, HashMap<usize, BitSet>>,
-    scene_data: HashMap<u32, B::SceneData>,
-    scene_mapping: HashMap<u32, Entity>,
+#[derive(Default)]
+struct NetworkCounters {
+    accepted: AtomicUsize,
+    rejected: AtomicUsize,
+    closed: AtomicUsize,
+    bytes_in: AtomicUsize,
+    bytes_out: AtomicUsize,
+    parse_errors: AtomicUsize,
+    rate_limited: AtomicUsize,
+    pending_bytes: AtomicUsize,
+    queued_responses: AtomicUsize,
+    buffers_allocated: AtomicUsize,
+    buffers_reused: AtomicUsize,
+    pooled_buffers: AtomicUsize,
 }
 
-impl<B> SceneManager<B>
-where
-    B: SceneSyncBackend,
-    <<B as SceneSyncBackend>::Position as Component>::Storage: Tracked + Default,
-    <<B as SceneSyncBackend>::SceneData as Component>::Storage: Tracked + Default,
-{
-    pub fn new(
-        position_reader: ReaderId<ComponentEvent>,
-        scene_reader: ReaderId<ComponentEvent>,
-    ) -> Self {
-        Self {
-            position_reader,
-            scene_reader,
-            _phantom: Default::default(),
-            user_grids: Default::default(),
-            scene_grids: Default::default(),
-            scene_data: Default::default(),
-            scene_mapping: Default::default(),
-        }
+/// 网络统计信息，由网络线程直接更新原子计数，发送队列深度每秒刷新一次
+#[derive(Clone, Default)]
+pub struct NetworkMetrics {
+    counters: Arc<NetworkCounters>,
+}
+
+impl NetworkMetrics {
+    pub(crate) fn add_accepted(&self) {
+        self.counters.accepted.fetch_add(1, AtomicOrdering::Relaxed);
     }
 
-    fn drop_entities<'a>(
-        entity: u32,
-        set: BitSet,
-        storage: &ReadStorage<'a, NetToken>,
-        sender: &BytesSender,
-    ) {
-        if set.is_empty() {
-            return;
-        }
+    pub(crate) fn add_rejected(&self) {
+        self.counters.rejected.fetch_add(1, AtomicOrdering::Relaxed);
+    }
 
-        let tokens = NetToken::tokens(storage, &set);
-        let mut drop_entity = B::DropEntity::default();
-        drop_entity.add(entity);
-        sender.broadcast_data(tokens, entity, drop_entity);
+    pub(crate) fn add_closed(&self, count: usize) {
+        self.counters
+            .closed
+            .fetch_add(count, AtomicOrdering::Relaxed);
     }
 
-    fn add_full_data_commit<'a>(
-        entity: Entity,
-        set: BitSet,
-        storage: &mut WriteStorage<'a, AroundFullData>,
-        entities: &Entities<'a>,
-    ) {
-        let afdc = storage.get_mut_or_default(entity).unwrap();
-        afdc.add_mask(&set);
-        let id = entity.id();
-        for (entity, _) in (entities, &set).join() {
-            storage.get_mut_or_default(entity).unwrap().add(id);
-        }
+    pub(crate) fn add_bytes_in(&self, bytes: usize) {
+        self.counters
+            .bytes_in
+            .fetch_add(bytes, AtomicOrdering::Relaxed);
     }
 
-    pub(crate) fn maintain<'a>(
-        &mut self,
-        entities: Entities<'a>,
-        positions: ReadStorage<'a, B::Position>,
-        scene: ReadStorage<'a, SceneMember>,
-        scene_data: ReadStorage<'a, B::SceneData>,
-        mut new_scene_member: WriteStorage<'a, AroundFullData>,
-        tokens: ReadStorage<'a, NetToken>,
-        sender: Read<'a, BytesSender>,
-    ) {
-        let mut modified = BitSet::default();
-        let mut inserted = BitSet::default();
-        let mut removed = BitSet::default();
-        let events = scene_data.channel().read(&mut self.scene_reader);
-        events_to_bitsets(events, &mut inserted, &mut modified, &mut removed);
-        for id in &removed {
-            self.scene_data.remove(&id);
-            self.scene_mapping.remove(&id);
-        }
-        for (data, id) in (&scene_data, &inserted).join() {
-            self.scene_data.insert(id, data.clone());
-        }
-        inserted.clear();
-        removed.clear();
-        modified.clear();
+    pub(crate) fn add_bytes_out(&self, bytes: usize) {
+        self.counters
+            .bytes_out
+            .fetch_add(bytes, AtomicOrdering::Relaxed);
+    }
 
-        let events = positions.channel().read(&mut self.position_reader);
-        events_to_bitsets(events, &mut inserted, &mut modified, &mut removed);
+    pub(crate) fn add_parse_error(&self) {
+        self.counters
+            .parse_errors
+            .fetch_add(1, AtomicOrdering::Relaxed);
+    }
 
-        for id in &removed {
-            let around = self.get_user_around(id);
-            Self::drop_entities(id, around, &tokens, &sender);
-            self.remove_grid_entity(id);
-            log::info!("entity:{} removed from scene", id);
-        }
+    pub(crate) fn add_rate_limited(&self) {
+        self.counters
+            .rate_limited
+            .fetch_add(1, AtomicOrdering::Relaxed);
+    }
 
-        for (entity, pos, scene, _id) in (&entities, &positions, &scene, &inserted).join() {
-            let parent = scene.parent_entity();
-            if let Some(sd) = scene_data.get(parent) {
-                if let Some(index) = sd.grid_index(pos.x(), pos.y()) {
-                    self.insert_grid_entity(parent, entity, index);
-                    let around = self.get_user_around(entity.id());
-                    Self::add_full_data_commit(entity, around, &mut new_scene_member, &entities);
-                } else {
-                    log::error!(
-                        "invalid position:[{},{}] for scene:{}",
-                        pos.x(),
-                        pos.y(),
-                        parent.id()
-                    );
-                }
-            } else {
-                log::error!("scene:{} not found", parent.id());
-            }
-        }
+    pub(crate) fn set_queue(&self, pending_bytes: usize, queued_responses: usize) {
+        self.counters
+            .pending_bytes
+            .store(pending_bytes, AtomicOrdering::Relaxed);
+        self.counters
+            .queued_responses
+            .store(queued_responses, AtomicOrdering::Relaxed);
+    }
 
-        for (entity, pos, id) in (&entities, &positions, &modified).join() {
-            if let Some((parent, index)) = self
-                .user_grids
-                .get(&id)
-                .map(|(parent, index)| (*parent, *index))
-            {
-                if let Some(sd) = scene_data.get(parent) {
-                    if let Some(new_index) = sd.grid_index(pos.x(), pos.y()) {
-                        if index == new_index {
-                            continue;
-                        }
-                        let (removed, _, inserted) = sd.diff(index, new_index);
-                        let inserted = self.get_user_grids(&entity, inserted);
-                        Self::add_full_data_commit(
-                            entity,
-                            inserted,
-                            &mut new_scene_member,
-                            &entities,
-                        );
+    pub(crate) fn add_buffer_allocated(&self) {
+        self.counters
+            .buffers_allocated
+            .fetch_add(1, AtomicOrdering::Relaxed);
+    }
 
-                        let removed = self.get_user_grids(&entity, removed);
-                        Self::drop_entities(entity.id(), removed, &tokens, &sender);
-                        self.insert_grid_entity(parent, entity, new_index);
-                    } else {
-                        log::error!(
-                            "invalid position:[{}, {}] for scene:{}",
-                            pos.x(),
-                            pos.y(),
-                            parent.id()
-                        );
-                    }
-                } else {
-                    log::error!("scene data {} not found in manager", parent.id());
-                }
-            } else {
-                log::error!("entity:{} not found in user grid", entity.id());
-            }
-        }
+    pub(crate) fn add_buffer_reused(&self) {
+        self.counters
+            .buffers_reused
+            .fetch_add(1, AtomicOrdering::Relaxed);
+    }
 
-        let empty_scene: Vec<_> = self
-            .scene_grids
-            .iter()
-            .filter_map(|(entity, grids)| {
-                if grids.iter().any(|(_, grid)| !grid.is_empty()) {
-                    None
-                } else {
-                    Some(*entity)
-                }
-            })
-            .collect();
-        empty_scene.iter().for_each(|id| {
-            log::info!("scene:{} deleted", id);
-            let entity = entities.entity(*id);
-            if entities.is_alive(entity) {
-                if let Err(err) = entities.delete(entity) {
-                    log::error!("delete entity:{} failed:{}", entity.id(), err);
-                }
-                self.scene_grids.remove(id);
-            }
-        });
+    pub(crate) fn set_pooled_buffers(&self, pooled_buffers: usize) {
+        self.counters
+            .pooled_buffers
+            .store(pooled_buffers, AtomicOrdering::Relaxed);
+    }
 
-        //log::info!("grid system cost:{}us", begin.elapsed().as_micros());
+    /// 累计接受的连接数
+    pub fn accepted(&self) -> usize {
+        self.counters.accepted.load(AtomicOrdering::Relaxed)
     }
 
-    fn insert_grid_entity(&mut self, parent: Entity, entity: Entity, index: usize) {
-        self.remove_grid_entity(entity.id());
-        if !self.scene_grids.contains_key(&parent.id()) {
-            self.scene_grids.insert(parent.id(), Default::default());
-        }
-        let grids = self.scene_grids.get_mut(&parent.id()).unwrap();
-        if !grids.contains_key(&index) {
-            grids.insert(index, Default::default());
-        }
-        let grid = grids.get_mut(&index).unwrap();
-        if grid.add(entity.id()) {
-            log::error!("entity:{} already in grid", entity.id());
-        }
-        self.user_grids.insert(entity.id(), (parent, index));
+    /// 准入检查拒绝的连接数
+    pub fn rejected(&self) -> usize {
+        self.counters.rejected.load(AtomicOrdering::Relaxed)
+    }
+
+    /// 累计释放的连接数
+    pub fn closed(&self) -> usize {
+        self.counters.closed.load(AtomicOrdering::Relaxed)
+    }
+
+    /// 当前的连接数
+    pub fn connections(&self) -> usize {
+        self.accepted().saturating_sub(self.closed())
+    }
+
+    pub fn bytes_in(&self) -> usize {
+        self.counters.bytes_in.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn bytes_out(&self) -> usize {
+        self.counters.bytes_out.load(AtomicOrdering::Relaxed)
+    }
+
+    /// 非法请求的数量，包括长度超限、解密解压失败以及版本不一致等
+    pub fn parse_errors(&self) -> usize {
+        self.counters.parse_errors.load(AtomicOrdering::Relaxed)
+    }
+
+    /// 超过限流的请求数量
+    pub fn rate_limited(&self) -> usize {
+        self.counters.rate_limited.load(AtomicOrdering::Relaxed)
+    }
+
+    /// 所有连接写缓冲中未发出的字节数
+    pub fn pending_bytes(&self) -> usize {
+        self.counters.pending_bytes.load(AtomicOrdering::Relaxed)
+    }
+
+    /// ECS发往网络线程还未处理的响应数量
+    pub fn queued_responses(&self) -> usize {
+        self.counters.queued_responses.load(AtomicOrdering::Relaxed)
+    }
+
+    /// 缓冲池新分配的连接缓冲数量
+    pub fn buffers_allocated(&self) -> usize {
+        self.counters
+            .buffers_allocated
+            .load(AtomicOrdering::Relaxed)
+    }
+
+    /// 从缓冲池复用的连接缓冲数量
+    pub fn buffers_reused(&self) -> usize {
+        self.counters.buffers_reused.load(AtomicOrdering::Relaxed)
+    }
+
+    /// 缓冲池中空闲的缓冲数量
+    pub fn pooled_buffers(&self) -> usize {
+        self.counters.pooled_buffers.load(AtomicOrdering::Relaxed)
+    }
+
+    pub fn print(&self) {
         log::info!(
-            "entity:{} insert into scene:{} grid:{}",
-            entity.id(),
-            parent.id(),
-            index
+            "connections:{}, accepted:{}, rejected:{}, closed:{}, bytes in:{}, bytes out:{}, parse errors:{}, rate limited:{}, pending bytes:{}, queued responses:{}, buffers allocated:{}, buffers reused:{}, pooled buffers:{}",
+            self.connections(),
+            self.accepted(),
+            self.rejected(),
+            self.closed(),
+            self.bytes_in(),
+            self.bytes_out(),
+            self.parse_errors(),
+            self.rate_limited(),
+            self.pending_bytes(),
+            self.queued_responses(),
+            self.buffers_allocated(),
+            self.buffers_reused(),
+            self.pooled_buffers()
         );
     }
+}
 
-    pub fn get_scene_data(&self, entity: Entity) -> Option<&B::SceneData> {
-        self.scene_data.get(&entity.id())
-    }
+/// 世界统计信息，按照间隔在maintain之后刷新，用于发现只插入不删除的组件泄漏
+pub struct WorldStats {
+    entities: usize,
+    interval: Duration,
+    last_refresh: Option<Instant>,
+    watchers: Vec<(String, Box<dyn Fn(&World) -> usize + Send + Sync>, usize)>,
+    /// 组件名称 -> (数量，估算的内存大小)
+    components: HashMap<String, (usize, usize)>,
+    /// 存储整理累计释放的内存估算
+    reclaimed: usize,
+}
 
-    fn remove_grid_entity(&mut self, id: u32) {
-        if let Some((parent, index)) = self.user_grids.remove(&id) {
-            if let Some(scene_grid) = self.scene_grids.get_mut(&parent.id()) {
-                if let Some(grid) = scene_grid.get_mut(&index) {
-                    if !grid.remove(id) {
-                        log::warn!("entity {} not found in set", id);
-                    }
-                }
-            }
+impl Default for WorldStats {
+    fn default() -> Self {
+        Self {
+            entities: 0,
+            interval: Duration::from_secs(1),
+            last_refresh: None,
+            watchers: Default::default(),
+            components: Default::default(),
+            reclaimed: 0,
         }
     }
+}
 
-    fn get_scene_around(&self, parent: &Entity, index: usize) -> BitSet {
-        let mut set = BitSet::new();
-        if let Some(sd) = self.scene_data.get(&parent.id()) {
-            if let Some(grids) = self.scene_grids.get(&parent.id()) {
-                for index in sd.around(index) {
-                    if let Some(grid) = grids.get(&index) {
-                        set |= grid;
-                    }
-                }
-            }
+impl WorldStats {
+    /// 刷新间隔，刷新需要遍历所有实体以及统计的组件
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// 统计组件T的数量，内存按照组件大小乘以数量估算，不包括堆上分配
+    pub fn watch<T>(&mut self)
+    where
+        T: Component,
+    {
+        let name = std::any::type_name::<T>().to_string();
+        if self.watchers.iter().any(|(n, _, _)| *n == name) {
+            return;
         }
-        set
+        self.watchers.push((
+            name,
+            Box::new(|world| world.read_storage::<T>().join().count()),
+            std::mem::size_of::<T>(),
+        ));
     }
 
-    fn get_user_grids(&self, entity: &Entity, indexes: Vec<usize>) -> BitSet {
-        let mut set = BitSet::new();
-        if let Some((parent, _)) = self.user_grids.get(&entity.id()) {
-            if let Some(grids) = self.scene_grids.get(&parent.id()) {
-                for index in indexes {
-                    if let Some(grid) = grids.get(&index) {
-                        set |= grid;
-                    }
-                }
+    /// 距离上次刷新超过间隔时重新统计，返回是否刷新
+    pub(crate) fn refresh(&mut self, world: &World) -> bool {
+        if let Some(last) = self.last_refresh {
+            if last.elapsed() < self.interval {
+                return false;
             }
-            set.remove(entity.id());
         }
-        set
+        self.last_refresh = Some(Instant::now());
+        self.entities = world.entities().join().count();
+        for (name, counter, size) in &self.watchers {
+            let count = counter(world);
+            self.components.insert(name.clone(), (count, count * size));
+        }
+        true
     }
 
-    pub fn get_user_around(&self, entity: u32) -> BitSet {
-        if let Some((parent, index)) = self.user_grids.get(&entity) {
-            let mut bitset = self.get_scene_around(parent, *index);
-            bitset.remove(entity);
-            bitset
-        } else {
-            BitSet::new()
-        }
+    pub fn entities(&self) -> usize {
+        self.entities
     }
 
-    pub fn insert_scene(&mut self, id: u32, entity: Entity) {
-        if self.scene_mapping.insert(id, entity).is_some() {
-            log::error!("scene:{} already inserted", id);
-        }
+    pub fn components(&self) -> &HashMap<String, (usize, usize)> {
+        &self.components
     }
 
-    pub fn get_scene_entity(&self, id: u32) -> Option<Entity> {
-        self.scene_mapping.get(&id).map(|entity| *entity)
+    pub fn reclaimed(&self) -> usize {
+        self.reclaimed
+    }
+
+    pub(crate) fn add_reclaimed(&mut self, reclaimed: usize) {
+        self.reclaimed += reclaimed;
+    }
+
+    pub fn print(&self) {
+        let mut buffer = bytes::BytesMut::new();
+        write!(
+            buffer,
+            "entities:{}, reclaimed:{},",
+            self.entities, self.reclaimed
+        )
+        .unwrap();
+        for (name, (count, memory)) in &self.components {
+            write!(
+                buffer,
+                " component {} count:{}, memory:{},",
+                name, count, memory
+            )
+            .unwrap();
+        }
+        log::info!("{}", String::from_utf8(buffer.to_vec()).unwrap());
+    }
+}
+
+/// 实体数量软上限，生成实体之前需要先申请预算，0表示不限制
+pub struct SpawnBudget {
+    max_entities: usize,
+    max_scene_entities: usize,
+    /// 达到上限的比例时开始告警
+    warn_ratio: f32,
+    entities: AtomicUsize,
+    scenes: Mutex<HashMap<u32, usize>>,
+}
+
+impl Default for SpawnBudget {
+    fn default() -> Self {
+        Self::new(0, 0)
+    }
+}
+
+impl SpawnBudget {
+    pub fn new(max_entities: usize, max_scene_entities: usize) -> Self {
+        Self {
+            max_entities,
+            max_scene_entities,
+            warn_ratio: 0.9,
+            entities: Default::default(),
+            scenes: Default::default(),
+        }
+    }
+
+    pub fn with_warn_ratio(mut self, warn_ratio: f32) -> Self {
+        self.warn_ratio = warn_ratio;
+        self
+    }
+
+    fn acquire(count: usize, max: usize, warn_ratio: f32, name: &str) -> bool {
+        if max == 0 {
+            return true;
+        }
+        if count >= max {
+            log::error!("{} entity limit {} reached", name, max);
+            return false;
+        }
+        if count + 1 == (max as f32 * warn_ratio) as usize {
+            log::warn!("{} entity count is approaching limit {}", name, max);
+        }
+        true
+    }
+
+    /// 申请生成一个实体，超出上限时返回false，检查和计数是一次原子操作，多个系统并发申请也不会超出上限
+    pub fn try_spawn(&self) -> bool {
+        let max = self.max_entities;
+        let count = self
+            .entities
+            .fetch_update(AtomicOrdering::Relaxed, AtomicOrdering::Relaxed, |count| {
+                if max == 0 || count < max {
+                    Some(count + 1)
+                } else {
+                    None
+                }
+            })
+            .unwrap_or_else(|count| count);
+        Self::acquire(count, max, self.warn_ratio, "world")
+    }
+
+    /// 申请在场景中生成一个实体，同时受世界和场景上限约束
+    pub fn try_spawn_in_scene(&self, scene: u32) -> bool {
+        let mut scenes = self.scenes.lock().unwrap();
+        let count = scenes.entry(scene).or_default();
+        let name = format!("scene:{}", scene);
+        if !Self::acquire(*count, self.max_scene_entities, self.warn_ratio, &name) {
+            return false;
+        }
+        if !self.try_spawn() {
+            return false;
+        }
+        *count += 1;
+        true
+    }
+
+    pub fn is_approaching(&self) -> bool {
+        self.max_entities != 0
+            && self.entities.load(AtomicOrdering::Relaxed)
+                >= (self.max_entities as f32 * self.warn_ratio) as usize
+    }
+
+    pub fn entities(&self) -> usize {
+        self.entities.load(AtomicOrdering::Relaxed)
+    }
+
+    pub(crate) fn refresh(&mut self, entities: usize) {
+        *self.entities.get_mut() = entities;
+    }
+
+    pub(crate) fn set_scene_entities(&mut self, scenes: HashMap<u32, usize>) {
+        *self.scenes.get_mut().unwrap() = scenes;
+    }
+}
+
+/// 登录排队，在线玩家达到上限时新连接进入队列，有空位时按顺序放行，0表示不限制
+pub struct LoginQueue {
+    max_players: usize,
+    queue: VecDeque<Token>,
+    /// 排队位置通知间隔
+    notify_interval: Duration,
+    last_notify: Instant,
+}
+
+impl Default for LoginQueue {
+    fn default() -> Self {
+        Self::new(0)
+    }
+}
+
+impl LoginQueue {
+    pub fn new(max_players: usize) -> Self {
+        Self {
+            max_players,
+            queue: Default::default(),
+            notify_interval: Duration::from_secs(5),
+            last_notify: Instant::now(),
+        }
+    }
+
+    pub fn with_notify_interval(mut self, notify_interval: Duration) -> Self {
+        self.notify_interval = notify_interval;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.queue.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.queue.is_empty()
+    }
+
+    /// 排队位置，从1开始
+    pub fn position(&self, token: Token) -> Option<usize> {
+        self.queue
+            .iter()
+            .position(|t| *t == token)
+            .map(|index| index + 1)
+    }
+
+    pub(crate) fn push(&mut self, token: Token) {
+        self.queue.push_back(token);
+    }
+
+    /// 排队中的连接断开，返回是否还在队列中
+    pub(crate) fn remove(&mut self, token: Token) -> bool {
+        match self.queue.iter().position(|t| *t == token) {
+            Some(index) => {
+                self.queue.remove(index);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 按照当前在线人数放行排队的连接，每个放行的连接都要申请实体预算，预算不足时继续排队
+    pub(crate) fn admit(&mut self, players: usize, budget: &SpawnBudget) -> Vec<Token> {
+        let count = if self.max_players == 0 {
+            self.queue.len()
+        } else {
+            self.max_players
+                .saturating_sub(players)
+                .min(self.queue.len())
+        };
+        let mut admitted = Vec::with_capacity(count);
+        while admitted.len() < count && budget.try_spawn() {
+            admitted.extend(self.queue.pop_front());
+        }
+        admitted
+    }
+
+    /// 到达通知间隔或者队列有变化时，返回需要通知的连接及其位置
+    pub(crate) fn notify(&mut self, changed: bool) -> Vec<(Token, usize)> {
+        if !changed && self.last_notify.elapsed() < self.notify_interval {
+            return Vec::new();
+        }
+        self.last_notify = Instant::now();
+        self.queue
+            .iter()
+            .enumerate()
+            .map(|(index, token)| (*token, index + 1))
+            .collect()
+    }
+}
+
+/// 启动一致性检查发现的问题，由setup中的检查代码写入，调度器初始化后由引擎汇总
+#[derive(Default)]
+pub struct StartupCheck {
+    problems: Vec<String>,
+}
+
+impl StartupCheck {
+    pub fn report(&mut self, problem: String) {
+        self.problems.push(problem);
+    }
+
+    pub fn problems(&self) -> &Vec<String> {
+        &self.problems
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    /// 检查请求命令号是否重复或者与控制帧冲突
+    pub fn check_commands(&mut self, commands: Vec<(u32, &'static str)>) {
+        let mut registry: HashMap<u32, &'static str> = HashMap::new();
+        for (cmd, name) in commands {
+            if cmd < RESERVED_CMDS {
+                self.report(format!(
+                    "request {} cmd {} is reserved for control",
+                    name, cmd
+                ));
+            }
+            if let Some(old) = registry.insert(cmd, name) {
+                self.report(format!(
+                    "request {} and {} share the same cmd {}",
+                    old, name, cmd
+                ));
+            }
+        }
+    }
+}
+
+/// 按系统名暂停或恢复#[system]生成的系统，暂停期间系统直接跳过，
+/// 输入系统的请求仍然会被清理系统删除
+#[derive(Default)]
+pub struct SystemToggles {
+    toggles: HashMap<String, Arc<AtomicBool>>,
+}
+
+impl SystemToggles {
+    /// 由生成的setup代码调用，返回系统持有的开关
+    pub fn register(&mut self, name: &str) -> Arc<AtomicBool> {
+        self.toggles.entry(name.into()).or_default().clone()
+    }
+
+    /// 暂停系统，系统不存在时返回false
+    pub fn pause(&self, name: &str) -> bool {
+        self.set_paused(name, true)
+    }
+
+    /// 恢复系统，系统不存在时返回false
+    pub fn resume(&self, name: &str) -> bool {
+        self.set_paused(name, false)
+    }
+
+    pub fn is_paused(&self, name: &str) -> bool {
+        self.toggles
+            .get(name)
+            .map_or(false, |paused| paused.load(AtomicOrdering::Relaxed))
+    }
+
+    /// 所有已注册的系统名以及是否暂停
+    pub fn systems(&self) -> Vec<(String, bool)> {
+        let mut systems: Vec<_> = self
+            .toggles
+            .iter()
+            .map(|(name, paused)| (name.clone(), paused.load(AtomicOrdering::Relaxed)))
+            .collect();
+        systems.sort();
+        systems
+    }
+
+    fn set_paused(&self, name: &str, paused: bool) -> bool {
+        if let Some(toggle) = self.toggles.get(name) {
+            toggle.store(paused, AtomicOrdering::Relaxed);
+            log::warn!("system {} paused:{}", name, paused);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// 每帧各阶段的时间预算，阶段由`GameDispatcherBuilder::add_stage`划分，
+/// 第一个划分点之前的系统属于`FrameBudget::FIRST_STAGE`阶段
+#[derive(Default)]
+pub struct FrameBudget {
+    budgets: HashMap<String, Duration>,
+    /// 超出预算时延后同步阶段的非关键数据
+    enforce: bool,
+    current: Option<(String, Instant)>,
+    /// 上一帧各阶段的耗时
+    costs: HashMap<String, Duration>,
+    overruns: HashMap<String, usize>,
+    deferring: bool,
+}
+
+impl FrameBudget {
+    pub const FIRST_STAGE: &'static str = "input";
+
+    pub fn new(enforce: bool) -> Self {
+        Self {
+            enforce,
+            ..Default::default()
+        }
+    }
+
+    pub fn with_budget(mut self, stage: &str, budget: Duration) -> Self {
+        self.budgets.insert(stage.into(), budget);
+        self
+    }
+
+    pub fn budget(&self, stage: &str) -> Option<Duration> {
+        self.budgets.get(stage).cloned()
+    }
+
+    /// 阶段在上一帧的耗时
+    pub fn cost(&self, stage: &str) -> Option<Duration> {
+        self.costs.get(stage).cloned()
+    }
+
+    /// 阶段累计超出预算的帧数
+    pub fn overruns(&self, stage: &str) -> usize {
+        self.overruns.get(stage).cloned().unwrap_or_default()
+    }
+
+    /// 本帧已有阶段超出预算，非关键的同步需要延后到下一帧
+    pub fn is_deferring(&self) -> bool {
+        self.enforce && self.deferring
+    }
+
+    pub(crate) fn begin_frame(&mut self) {
+        self.deferring = false;
+        self.current
+            .replace((Self::FIRST_STAGE.into(), Instant::now()));
+    }
+
+    /// 结束当前阶段并开始新的阶段
+    pub(crate) fn begin_stage(&mut self, stage: &str) {
+        self.finish_stage();
+        self.current.replace((stage.into(), Instant::now()));
+    }
+
+    pub(crate) fn end_frame(&mut self) {
+        self.finish_stage();
+    }
+
+    fn finish_stage(&mut self) {
+        if let Some((stage, start)) = self.current.take() {
+            let cost = start.elapsed();
+            if let Some(budget) = self.budgets.get(&stage) {
+                if cost > *budget {
+                    log::warn!(
+                        "stage {} cost {:?} exceeds budget {:?}",
+                        stage,
+                        cost,
+                        budget
+                    );
+                    *self.overruns.entry(stage.clone()).or_default() += 1;
+                    self.deferring = true;
+                }
+            }
+            self.costs.insert(stage, cost);
+        }
+    }
+
+    pub fn print(&self) {
+        let mut buffer = String::new();
+        write!(buffer, "frame budget:").unwrap();
+        for (stage, cost) in &self.costs {
+            write!(
+                buffer,
+                " stage {} cost:{}, budget:{:?}, overruns:{},",
+                stage,
+                cost.as_micros(),
+                self.budget(stage),
+                self.overruns(stage)
+            )
+            .unwrap();
+        }
+        log::info!("{}", buffer);
+    }
+}
+
+/// 场景中周围同步的统计
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SceneSyncStat {
+    /// 周围广播的次数
+    pub broadcasts: usize,
+    /// 所有广播的接收者数量之和
+    pub viewers: usize,
+    /// 单次广播最多的接收者数量
+    pub max_fan_out: usize,
+    /// 完整数据发送的字节数，按接收者累计
+    pub full_data_bytes: usize,
+}
+
+impl SceneSyncStat {
+    pub(crate) fn add_broadcast(&mut self, viewers: usize) {
+        self.broadcasts += 1;
+        self.viewers += viewers;
+        self.max_fan_out = self.max_fan_out.max(viewers);
+    }
+
+    fn merge(&mut self, other: &SceneSyncStat) {
+        self.broadcasts += other.broadcasts;
+        self.viewers += other.viewers;
+        self.max_fan_out = self.max_fan_out.max(other.max_fan_out);
+        self.full_data_bytes += other.full_data_bytes;
+    }
+}
+
+/// 场景统计，实体数量以及最拥挤的格子由GridSystem每帧刷新，
+/// 同步统计由CommitChangeSystem累加，每次打印统计后清空
+pub struct SceneMetrics {
+    /// 场景实体id -> 实体数量
+    entities: HashMap<u32, usize>,
+    /// 实体最多的格子，(场景，格子，数量)
+    hot_grids: Vec<(u32, usize, usize)>,
+    hot_grid_count: usize,
+    sync: Mutex<HashMap<u32, SceneSyncStat>>,
+}
+
+impl Default for SceneMetrics {
+    fn default() -> Self {
+        Self::new(10)
+    }
+}
+
+impl SceneMetrics {
+    pub fn new(hot_grid_count: usize) -> Self {
+        Self {
+            entities: Default::default(),
+            hot_grids: Default::default(),
+            hot_grid_count,
+            sync: Default::default(),
+        }
+    }
+
+    pub(crate) fn refresh(
+        &mut self,
+        entities: HashMap<u32, usize>,
+        mut grids: Vec<(u32, usize, usize)>,
+    ) {
+        self.entities = entities;
+        grids.sort_unstable_by(|a, b| b.2.cmp(&a.2));
+        grids.truncate(self.hot_grid_count);
+        self.hot_grids = grids;
+    }
+
+    pub(crate) fn add_sync(&self, stats: HashMap<u32, SceneSyncStat>) {
+        if stats.is_empty() {
+            return;
+        }
+        let mut sync = self.sync.lock().unwrap();
+        for (scene, stat) in stats {
+            sync.entry(scene).or_default().merge(&stat);
+        }
+    }
+
+    pub(crate) fn clear_sync(&self) {
+        self.sync.lock().unwrap().clear();
+    }
+
+    pub fn scene_entities(&self, scene: u32) -> usize {
+        self.entities.get(&scene).cloned().unwrap_or_default()
+    }
+
+    pub fn hot_grids(&self) -> &[(u32, usize, usize)] {
+        self.hot_grids.as_slice()
+    }
+
+    /// 上次打印统计之后场景的同步统计
+    pub fn scene_sync(&self, scene: u32) -> SceneSyncStat {
+        self.sync
+            .lock()
+            .unwrap()
+            .get(&scene)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn print(&self) {
+        let mut buffer = String::new();
+        write!(buffer, "scene metrics:").unwrap();
+        let sync = self.sync.lock().unwrap();
+        for (scene, entities) in &self.entities {
+            let stat = sync.get(scene).cloned().unwrap_or_default();
+            write!(
+                buffer,
+                " scene {} entities:{}, broadcasts:{}, viewers:{}, max fan out:{}, full data bytes:{},",
+                scene,
+                entities,
+                stat.broadcasts,
+                stat.viewers,
+                stat.max_fan_out,
+                stat.full_data_bytes
+            )
+            .unwrap();
+        }
+        for (scene, grid, count) in &self.hot_grids {
+            write!(buffer, " hot grid {}:{} entities:{},", scene, grid, count).unwrap();
+        }
+        log::info!("{}", buffer);
+    }
+}
+
+/// 同步预算的计量方式
+#[derive(Debug, Clone, Copy)]
+pub enum SyncLimit {
+    /// 每个接收者每帧的字节数
+    Bytes(usize),
+    /// 每个接收者每帧的消息数
+    Messages(usize),
+}
+
+/// 每个接收者每帧的同步预算，目前只有周围方向会检查，
+/// 超出时低优先级实体的数据延后到之后的帧发送完整数据
+pub struct SyncBudget {
+    limits: Vec<(SyncDirection, SyncLimit)>,
+    /// 优先级不低于此值的实体总是立即同步
+    urgent: u8,
+    /// (方向, 接收者) -> (帧数, 已用预算)
+    usage: Mutex<HashMap<(usize, u32), (usize, usize)>>,
+}
+
+impl Default for SyncBudget {
+    fn default() -> Self {
+        Self {
+            limits: Vec::new(),
+            urgent: u8::MAX,
+            usage: Default::default(),
+        }
+    }
+}
+
+impl SyncBudget {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_limit(mut self, direction: SyncDirection, limit: SyncLimit) -> Self {
+        self.limits.retain(|(d, _)| *d != direction);
+        self.limits.push((direction, limit));
+        self
+    }
+
+    pub fn with_urgent(mut self, urgent: u8) -> Self {
+        self.urgent = urgent;
+        self
+    }
+
+    pub fn limit(&self, direction: SyncDirection) -> Option<SyncLimit> {
+        self.limits
+            .iter()
+            .find(|(d, _)| *d == direction)
+            .map(|(_, limit)| *limit)
+    }
+
+    /// 为接收者扣除一条消息的预算，把预算不足的接收者从`viewers`中移除并返回。
+    /// 每个接收者每帧的第一条消息总是允许发送，避免大消息永远发不出去
+    pub fn consume(
+        &self,
+        frame: usize,
+        direction: SyncDirection,
+        viewers: &mut BitSet,
+        priority: SyncPriority,
+        bytes: usize,
+    ) -> BitSet {
+        let mut denied = BitSet::new();
+        let (cap, cost) = match self.limit(direction) {
+            Some(SyncLimit::Bytes(cap)) => (cap, bytes),
+            Some(SyncLimit::Messages(cap)) => (cap, 1),
+            None => return denied,
+        };
+        let mut usage = self.usage.lock().unwrap();
+        for viewer in viewers.iter() {
+            let used = usage
+                .entry((direction as usize, viewer))
+                .or_insert((frame, 0));
+            if used.0 != frame {
+                *used = (frame, 0);
+            }
+            if priority.0 < self.urgent && used.1 != 0 && used.1 + cost > cap {
+                denied.add(viewer);
+            } else {
+                used.1 += cost;
+            }
+        }
+        *viewers &= &!&denied;
+        denied
+    }
+}
+
+pub struct SceneManager<B>
+where
+    B: SceneSyncBackend,
+    <<B as SceneSyncBackend>::Position as Component>::Storage: Tracked + Default,
+    <<B as SceneSyncBackend>::SceneData as Component>::Storage: Tracked + Default,
+{
+    position_reader: ReaderId<ComponentEvent>,
+    scene_reader: ReaderId<ComponentEvent>,
+    _phantom: PhantomData<B>,
+    /// mapping from entity to grid index
+    user_grids: HashMap<u32, (Entity, usize)>,
+    /// mapping from scene to spatial index
+    scene_grids: HashMap<u32, B::SpatialIndex>,
+    scene_data: HashMap<u32, B::SceneData>,
+    /// (场景id, 实例id) -> 场景实体，主实例的id为0
+    scene_mapping: HashMap<(u32, u32), Entity>,
+    /// 每个场景最后分配的实例id
+    instances: HashMap<u32, u32>,
+    /// 单独设置了视野半径的实体
+    view_radius: HashMap<u32, i32>,
+    /// 场景中单独设置了视野半径的实体，场景 -> 半径 -> 实体集合
+    radius_entities: HashMap<u32, HashMap<i32, BitSet>>,
+    /// 视野半径变化前周围的实体，在下一次maintain时补发以及删除
+    radius_changed: HashMap<u32, BitSet>,
+    /// 可见性变化的实体
+    visibility_changed: BitSet,
+    /// 静态实体，场景 -> 格子 -> 实体集合
+    static_grids: HashMap<u32, HashMap<usize, BitSet>>,
+    static_entities: BitSet,
+    /// 单独设置了保留策略的场景
+    scene_lifetimes: HashMap<u32, SceneLifetime>,
+    /// 场景开始为空的时间
+    empty_since: HashMap<u32, Instant>,
+    /// 本次maintain中进入以及离开视野的实体，观察者 -> 实体集合
+    entered: HashMap<u32, BitSet>,
+    left: HashMap<u32, BitSet>,
+    /// 等待传送的实体，目标场景以及位置
+    transfers: Vec<(Entity, Entity, f32, f32, f32)>,
+}
+
+impl<B> SceneManager<B>
+where
+    B: SceneSyncBackend,
+    <<B as SceneSyncBackend>::Position as Component>::Storage: Tracked + Default,
+    <<B as SceneSyncBackend>::SceneData as Component>::Storage: Tracked + Default,
+{
+    pub fn new(
+        position_reader: ReaderId<ComponentEvent>,
+        scene_reader: ReaderId<ComponentEvent>,
+    ) -> Self {
+        Self {
+            position_reader,
+            scene_reader,
+            _phantom: Default::default(),
+            user_grids: Default::default(),
+            scene_grids: Default::default(),
+            scene_data: Default::default(),
+            scene_mapping: Default::default(),
+            instances: Default::default(),
+            view_radius: Default::default(),
+            radius_entities: Default::default(),
+            radius_changed: Default::default(),
+            visibility_changed: Default::default(),
+            static_grids: Default::default(),
+            static_entities: Default::default(),
+            scene_lifetimes: Default::default(),
+            empty_since: Default::default(),
+            entered: Default::default(),
+            left: Default::default(),
+            transfers: Default::default(),
+        }
+    }
+
+    /// 观察者是否可以看到目标实体
+    fn can_see<'a>(
+        visibility: &ReadStorage<'a, B::Visibility>,
+        observer: u32,
+        target: u32,
+    ) -> bool {
+        let entities = visibility.fetched_entities();
+        match visibility.get(entities.entity(target)) {
+            Some(data) => data.can_see(visibility.get(entities.entity(observer))),
+            None => true,
+        }
+    }
+
+    /// 集合中可以看到目标实体的观察者
+    fn viewers_of<'a>(
+        visibility: &ReadStorage<'a, B::Visibility>,
+        target: u32,
+        mut set: BitSet,
+    ) -> BitSet {
+        if visibility.contains(visibility.fetched_entities().entity(target)) {
+            let hidden: BitSet = (&set)
+                .iter()
+                .filter(|observer| !Self::can_see(visibility, *observer, target))
+                .collect();
+            set &= &!&hidden;
+        }
+        set
+    }
+
+    fn drop_entities<'a>(
+        &mut self,
+        entity: u32,
+        set: BitSet,
+        storage: &ReadStorage<'a, NetToken>,
+        sender: &BytesSender,
+    ) {
+        if set.is_empty() {
+            return;
+        }
+        for observer in &set {
+            self.left.entry(observer).or_default().add(entity);
+        }
+
+        let tokens = NetToken::tokens(storage, &set);
+        let mut drop_entity = B::DropEntity::default();
+        drop_entity.add(entity);
+        sender.broadcast_data(tokens, entity, drop_entity);
+    }
+
+    /// 通知实体自身的客户端删除集合中的实体
+    fn drop_for<'a>(
+        &mut self,
+        entity: Entity,
+        set: impl IntoIterator<Item = u32>,
+        tokens: &ReadStorage<'a, NetToken>,
+        sender: &BytesSender,
+    ) {
+        let set: BitSet = set.into_iter().collect();
+        if set.is_empty() {
+            return;
+        }
+        if let Some(token) = tokens.get(entity) {
+            let mut drop_entity = B::DropEntity::default();
+            drop_entity.add_set(&set);
+            sender.send_data(token.token(), entity.id(), drop_entity);
+        }
+        *self.left.entry(entity.id()).or_default() |= &set;
+    }
+
+    fn add_full_data_commit<'a>(
+        &mut self,
+        entity: Entity,
+        set: BitSet,
+        storage: &mut WriteStorage<'a, AroundFullData>,
+        entities: &Entities<'a>,
+        visibility: &ReadStorage<'a, B::Visibility>,
+    ) {
+        let id = entity.id();
+        let viewers = Self::viewers_of(visibility, id, set.clone());
+        let afdc = storage.get_mut_or_default(entity).unwrap();
+        afdc.add_mask(&viewers);
+        for observer in &viewers {
+            self.entered.entry(observer).or_default().add(id);
+        }
+        for (entity, _) in (entities, &set).join() {
+            if Self::can_see(visibility, id, entity.id()) {
+                storage.get_mut_or_default(entity).unwrap().add(id);
+                self.entered.entry(id).or_default().add(entity.id());
+            }
+        }
+    }
+
+    /// 根据视野变化前后周围的实体，补发新增实体的完整数据，并通知离开的实体
+    fn update_around<'a>(
+        &mut self,
+        entity: Entity,
+        old: BitSet,
+        storage: &mut WriteStorage<'a, AroundFullData>,
+        entities: &Entities<'a>,
+        visibility: &ReadStorage<'a, B::Visibility>,
+        tokens: &ReadStorage<'a, NetToken>,
+        sender: &BytesSender,
+    ) {
+        let new = self.around_of(entity.id());
+        let mut inserted = new.clone();
+        inserted &= &!&old;
+        self.add_full_data_commit(entity, inserted, storage, entities, visibility);
+        let mut removed = old;
+        removed &= &!&new;
+        let id = entity.id();
+        let gone = (&removed)
+            .iter()
+            .filter(|other| Self::can_see(visibility, id, *other));
+        *self.left.entry(id).or_default() |= &gone.collect::<BitSet>();
+        let removed = Self::viewers_of(visibility, id, removed);
+        self.drop_entities(id, removed, tokens, sender);
+    }
+
+    pub(crate) fn maintain<'a>(
+        &mut self,
+        entities: Entities<'a>,
+        mut positions: WriteStorage<'a, B::Position>,
+        mut scene: WriteStorage<'a, SceneMember>,
+        scene_data: ReadStorage<'a, B::SceneData>,
+        mut new_scene_member: WriteStorage<'a, AroundFullData>,
+        visibility: ReadStorage<'a, B::Visibility>,
+        statics: ReadStorage<'a, StaticEntity>,
+        (mut entered, mut left): (WriteStorage<'a, EnteredAoi>, WriteStorage<'a, LeftAoi>),
+        tokens: ReadStorage<'a, NetToken>,
+        sender: Read<'a, BytesSender>,
+    ) {
+        entered.clear();
+        left.clear();
+        let mut modified = BitSet::default();
+        let mut inserted = BitSet::default();
+        let mut removed = BitSet::default();
+        let events = scene_data.channel().read(&mut self.scene_reader);
+        events_to_bitsets(events, &mut inserted, &mut modified, &mut removed);
+        for id in &removed {
+            self.scene_data.remove(&id);
+            self.scene_mapping.retain(|_, entity| entity.id() != id);
+        }
+        for (data, id) in (&scene_data, &inserted).join() {
+            self.scene_data.insert(id, data.clone());
+        }
+        inserted.clear();
+        removed.clear();
+        modified.clear();
+
+        for (id, old) in std::mem::take(&mut self.radius_changed) {
+            let entity = entities.entity(id);
+            if entities.is_alive(entity) {
+                self.update_around(
+                    entity,
+                    old,
+                    &mut new_scene_member,
+                    &entities,
+                    &visibility,
+                    &tokens,
+                    &sender,
+                );
+            }
+        }
+
+        for id in &std::mem::take(&mut self.visibility_changed) {
+            let entity = entities.entity(id);
+            if !entities.is_alive(entity) || !self.user_grids.contains_key(&id) {
+                continue;
+            }
+            let around = self.around_of(id);
+            let mut hidden = around.clone();
+            hidden &= &!&Self::viewers_of(&visibility, id, around.clone());
+            self.drop_entities(id, hidden, &tokens, &sender);
+            // 实体自身不再能看到的周围实体
+            self.drop_for(
+                entity,
+                (&around)
+                    .iter()
+                    .filter(|other| !Self::can_see(&visibility, id, *other)),
+                &tokens,
+                &sender,
+            );
+            self.add_full_data_commit(
+                entity,
+                around,
+                &mut new_scene_member,
+                &entities,
+                &visibility,
+            );
+        }
+
+        let events = positions.channel().read(&mut self.position_reader);
+        events_to_bitsets(events, &mut inserted, &mut modified, &mut removed);
+
+        for id in &removed {
+            let around = self.get_user_around(id, &visibility);
+            self.drop_entities(id, around, &tokens, &sender);
+            self.remove_grid_entity(id);
+            self.view_radius.remove(&id);
+            self.static_entities.remove(id);
+            log::info!("entity:{} removed from scene", id);
+        }
+
+        for (entity, pos, scene, _id) in (&entities, &positions, &scene, &inserted).join() {
+            let parent = scene.parent_entity();
+            if let Some(sd) = scene_data.get(parent) {
+                if let Some(index) = sd.position_index(pos.x(), pos.y(), pos.z()) {
+                    if statics.contains(entity) {
+                        self.static_entities.add(entity.id());
+                    }
+                    self.insert_grid_entity(parent, entity, index, pos.x(), pos.y());
+                    let around = self.around_of(entity.id());
+                    if self.static_entities.contains(entity.id()) {
+                        // 静态实体只需要把自身数据发送给周围
+                        let id = entity.id();
+                        let seen: BitSet = (&around)
+                            .iter()
+                            .filter(|other| Self::can_see(&visibility, id, *other))
+                            .collect();
+                        *self.entered.entry(id).or_default() |= &seen;
+                        let viewers = Self::viewers_of(&visibility, id, around);
+                        for observer in &viewers {
+                            self.entered.entry(observer).or_default().add(id);
+                        }
+                        new_scene_member
+                            .get_mut_or_default(entity)
+                            .unwrap()
+                            .add_mask(&viewers);
+                        continue;
+                    }
+                    self.add_full_data_commit(
+                        entity,
+                        around,
+                        &mut new_scene_member,
+                        &entities,
+                        &visibility,
+                    );
+                } else {
+                    log::error!(
+                        "invalid position:[{},{}] for scene:{}",
+                        pos.x(),
+                        pos.y(),
+                        parent.id()
+                    );
+                }
+            } else {
+                log::error!("scene:{} not found", parent.id());
+            }
+        }
+
+        // 静态实体不会移动，位置修改事件直接忽略
+        modified &= &!&self.static_entities;
+        for (entity, pos, id) in (&entities, &positions, &modified).join() {
+            if let Some((parent, index)) = self
+                .user_grids
+                .get(&id)
+                .map(|(parent, index)| (*parent, *index))
+            {
+                if let Some(sd) = scene_data.get(parent) {
+                    if let Some(new_index) = sd.position_index(pos.x(), pos.y(), pos.z()) {
+                        if index == new_index {
+                            continue;
+                        }
+                        let old = self.around_of(id);
+                        self.insert_grid_entity(parent, entity, new_index, pos.x(), pos.y());
+                        self.update_around(
+                            entity,
+                            old,
+                            &mut new_scene_member,
+                            &entities,
+                            &visibility,
+                            &tokens,
+                            &sender,
+                        );
+                    } else {
+                        log::error!(
+                            "invalid position:[{}, {}] for scene:{}",
+                            pos.x(),
+                            pos.y(),
+                            parent.id()
+                        );
+                    }
+                } else {
+                    log::error!("scene data {} not found in manager", parent.id());
+                }
+            } else {
+                log::error!("entity:{} not found in user grid", entity.id());
+            }
+        }
+
+        // 传送放在最后处理，覆盖本帧的移动，修改位置产生的事件在下一帧不会再改变格子
+        for (entity, target, x, y, z) in std::mem::take(&mut self.transfers) {
+            if !entities.is_alive(entity) || !self.user_grids.contains_key(&entity.id()) {
+                log::warn!("entity:{} not in scene, transfer ignored", entity.id());
+                continue;
+            }
+            let index = match self
+                .scene_data
+                .get(&target.id())
+                .and_then(|sd| sd.position_index(x, y, z))
+            {
+                Some(index) => index,
+                None => {
+                    log::error!(
+                        "invalid transfer of entity:{} to scene:{} position:[{}, {}, {}]",
+                        entity.id(),
+                        target.id(),
+                        x,
+                        y,
+                        z
+                    );
+                    continue;
+                }
+            };
+            let id = entity.id();
+            let old = self.around_of(id);
+            self.insert_grid_entity(target, entity, index, x, y);
+            self.update_around(
+                entity,
+                old.clone(),
+                &mut new_scene_member,
+                &entities,
+                &visibility,
+                &tokens,
+                &sender,
+            );
+            let mut gone = old;
+            gone &= &!&self.around_of(id);
+            self.drop_for(
+                entity,
+                (&gone)
+                    .iter()
+                    .filter(|other| Self::can_see(&visibility, id, *other)),
+                &tokens,
+                &sender,
+            );
+            if let Some(pos) = positions.get_mut(entity) {
+                pos.set_x(x);
+                pos.set_y(y);
+                pos.set_z(z);
+            }
+            if scene.get(entity).map(|member| member.parent_entity()) != Some(target) {
+                if let Err(err) = scene.insert(entity, SceneMember::new(target)) {
+                    log::error!("update scene of entity:{} failed:{}", id, err);
+                }
+            }
+        }
+
+        for (id, set) in std::mem::take(&mut self.entered) {
+            let entity = entities.entity(id);
+            if entities.is_alive(entity) {
+                let _ = entered.insert(entity, EnteredAoi(set));
+            }
+        }
+        for (id, set) in std::mem::take(&mut self.left) {
+            let entity = entities.entity(id);
+            if entities.is_alive(entity) {
+                let _ = left.insert(entity, LeftAoi(set));
+            }
+        }
+
+        let empty_scene: Vec<_> = self
+            .scene_grids
+            .iter()
+            .filter_map(|(entity, grids)| {
+                let statics = self
+                    .static_grids
+                    .get(entity)
+                    .map_or(true, |grids| grids.is_empty());
+                if grids.is_empty() && statics {
+                    Some(*entity)
+                } else {
+                    None
+                }
+            })
+            .collect();
+        // 重新有实体进入的场景取消计时
+        self.empty_since.retain(|id, _| empty_scene.contains(id));
+        let now = Instant::now();
+        for id in empty_scene {
+            let lifetime = self
+                .scene_lifetimes
+                .get(&id)
+                .cloned()
+                .or_else(|| self.scene_data.get(&id).map(|sd| sd.lifetime()))
+                .unwrap_or_default();
+            let expired = match lifetime {
+                SceneLifetime::Immediate => true,
+                SceneLifetime::Persistent => false,
+                SceneLifetime::Linger(duration) => {
+                    let since = self.empty_since.entry(id).or_insert(now);
+                    now.duration_since(*since) >= duration
+                }
+            };
+            if !expired {
+                continue;
+            }
+            log::info!("scene:{} deleted", id);
+            self.empty_since.remove(&id);
+            self.scene_lifetimes.remove(&id);
+            let entity = entities.entity(id);
+            if entities.is_alive(entity) {
+                if let Err(err) = entities.delete(entity) {
+                    log::error!("delete entity:{} failed:{}", entity.id(), err);
+                }
+                self.scene_grids.remove(&id);
+                self.static_grids.remove(&id);
+                self.radius_entities.remove(&id);
+            }
+        }
+
+        //log::info!("grid system cost:{}us", begin.elapsed().as_micros());
+    }
+
+    fn insert_grid_entity(&mut self, parent: Entity, entity: Entity, index: usize, x: f32, y: f32) {
+        self.remove_grid_entity(entity.id());
+        if self.static_entities.contains(entity.id()) {
+            self.static_grids
+                .entry(parent.id())
+                .or_default()
+                .entry(index)
+                .or_default()
+                .add(entity.id());
+            self.user_grids.insert(entity.id(), (parent, index));
+            self.index_radius(entity.id(), true);
+            log::info!(
+                "static entity:{} insert into scene:{} grid:{}",
+                entity.id(),
+                parent.id(),
+                index
+            );
+            return;
+        }
+        let sd = match self.scene_data.get(&parent.id()) {
+            Some(sd) => sd,
+            None => {
+                log::error!("scene data {} not found in manager", parent.id());
+                return;
+            }
+        };
+        self.scene_grids
+            .entry(parent.id())
+            .or_default()
+            .insert(sd, entity.id(), index, x, y);
+        self.user_grids.insert(entity.id(), (parent, index));
+        self.index_radius(entity.id(), true);
+        trace_entity(
+            entity.id(),
+            "grid",
+            format_args!("enter scene:{} grid:{}", parent.id(), index),
+        );
+        log::info!(
+            "entity:{} insert into scene:{} grid:{}",
+            entity.id(),
+            parent.id(),
+            index
+        );
+    }
+
+    pub fn get_scene_data(&self, entity: Entity) -> Option<&B::SceneData> {
+        self.scene_data.get(&entity.id())
+    }
+
+    fn remove_grid_entity(&mut self, id: u32) {
+        self.index_radius(id, false);
+        if let Some((parent, index)) = self.user_grids.remove(&id) {
+            trace_entity(
+                id,
+                "grid",
+                format_args!("leave scene:{} grid:{}", parent.id(), index),
+            );
+            if self.static_entities.contains(id) {
+                if let Some(grids) = self.static_grids.get_mut(&parent.id()) {
+                    if let Some(grid) = grids.get_mut(&index) {
+                        grid.remove(id);
+                        if grid.is_empty() {
+                            grids.remove(&index);
+                        }
+                    }
+                }
+            } else if let Some(scene_grid) = self.scene_grids.get_mut(&parent.id()) {
+                if !scene_grid.remove(id, index) {
+                    log::warn!("entity {} not found in set", id);
+                }
+            }
+        }
+    }
+
+    /// 两个实体之间的可见范围按照双方中较大的视野半径计算
+    fn get_scene_around(&self, parent: &Entity, index: usize, entity: u32) -> BitSet {
+        let mut set = BitSet::new();
+        if let Some(sd) = self.scene_data.get(&parent.id()) {
+            let radius = self
+                .view_radius
+                .get(&entity)
+                .map_or(sd.view_radius(), |radius| (*radius).max(sd.view_radius()));
+            // 静态实体之间互相不需要同步
+            let statics = !self.static_entities.contains(entity);
+            set |= &self.grid_around(parent.id(), sd, index, radius, statics);
+            // 视野半径更大的实体可能看到当前格子，只检查半径可以到达当前格子的实体
+            if let Some(groups) = self.radius_entities.get(&parent.id()) {
+                for (other_radius, group) in groups {
+                    // 均匀的格子视野是对称的，半径不超过当前半径的实体已经在集合中
+                    if sd.regions().is_empty() && *other_radius <= radius {
+                        continue;
+                    }
+                    let reach = Self::reach_radius(sd, index, *other_radius);
+                    let mut candidates = self.grid_around(parent.id(), sd, index, reach, true);
+                    candidates &= group;
+                    candidates &= &!&set;
+                    for id in &candidates {
+                        if let Some((_, other_index)) = self.user_grids.get(&id) {
+                            if sd
+                                .around_radius(*other_index, *other_radius)
+                                .contains(&index)
+                            {
+                                set.add(id);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        set
+    }
+
+    /// 场景中半径内格子的实体，statics为false时不包括静态实体
+    fn grid_around(
+        &self,
+        scene: u32,
+        sd: &B::SceneData,
+        index: usize,
+        radius: i32,
+        statics: bool,
+    ) -> BitSet {
+        let mut set = BitSet::new();
+        if let Some(grids) = self.scene_grids.get(&scene) {
+            set |= &grids.around(sd, index, radius);
+        }
+        if statics {
+            if let Some(grids) = self.static_grids.get(&scene) {
+                for index in sd.around_radius(index, radius) {
+                    if let Some(grid) = grids.get(&index) {
+                        set |= grid;
+                    }
+                }
+            }
+        }
+        set
+    }
+
+    /// 从当前格子查找半径为radius并且可以看到当前格子的实体时需要的查询半径，
+    /// 非均匀区域中格子尺寸不同，按照最大的格子尺寸放大
+    fn reach_radius(sd: &B::SceneData, index: usize, radius: i32) -> i32 {
+        if sd.regions().is_empty() {
+            return radius;
+        }
+        let (x0, _, x1, _) = sd.grid_rect(index);
+        let max = sd
+            .regions()
+            .iter()
+            .fold(sd.grid_size(), |max, region| max.max(region.grid_size));
+        (radius as f32 * max / (x1 - x0)).ceil() as i32 + 1
+    }
+
+    /// 更新场景中单独设置了视野半径的实体，实体需要已经在格子中
+    fn index_radius(&mut self, id: u32, add: bool) {
+        let (scene, radius) = match (self.user_grids.get(&id), self.view_radius.get(&id)) {
+            (Some((parent, _)), Some(radius)) => (parent.id(), *radius),
+            _ => return,
+        };
+        if add {
+            self.radius_entities
+                .entry(scene)
+                .or_default()
+                .entry(radius)
+                .or_default()
+                .add(id);
+        } else if let Some(groups) = self.radius_entities.get_mut(&scene) {
+            if let Some(group) = groups.get_mut(&radius) {
+                group.remove(id);
+                if group.is_empty() {
+                    groups.remove(&radius);
+                }
+            }
+            if groups.is_empty() {
+                self.radius_entities.remove(&scene);
+            }
+        }
+    }
+
+    /// 设置实体的视野半径，小于场景默认半径时按默认半径处理，None恢复默认，
+    /// 周围实体的变化在下一次maintain时同步
+    pub fn set_view_radius(&mut self, entity: u32, radius: Option<i32>) {
+        if self.user_grids.contains_key(&entity) && !self.radius_changed.contains_key(&entity) {
+            let old = self.around_of(entity);
+            self.radius_changed.insert(entity, old);
+        }
+        self.index_radius(entity, false);
+        match radius {
+            Some(radius) => self.view_radius.insert(entity, radius),
+            None => self.view_radius.remove(&entity),
+        };
+        self.index_radius(entity, true);
+    }
+
+    /// 把实体传送到目标场景的指定位置，在下一次maintain中一次完成：
+    /// 旧的周围实体删除该实体，进入新格子后双方互相补发完整数据，同时更新位置以及SceneMember
+    pub fn transfer(&mut self, entity: Entity, target_scene: Entity, x: f32, y: f32, z: f32) {
+        self.transfers.push((entity, target_scene, x, y, z));
+    }
+
+    /// 实体的可见性组件变化后调用，下一次maintain时按照新的可见性补发完整数据以及删除实体
+    pub fn refresh_visibility(&mut self, entity: u32) {
+        self.visibility_changed.add(entity);
+    }
+
+    fn around_of(&self, entity: u32) -> BitSet {
+        if let Some((parent, index)) = self.user_grids.get(&entity) {
+            let mut bitset = self.get_scene_around(parent, *index, entity);
+            bitset.remove(entity);
+            bitset
+        } else {
+            BitSet::new()
+        }
+    }
+
+    /// 周围可以看到实体的其他实体
+    pub fn get_user_around<'a>(
+        &self,
+        entity: u32,
+        visibility: &ReadStorage<'a, B::Visibility>,
+    ) -> BitSet {
+        Self::viewers_of(visibility, entity, self.around_of(entity))
+    }
+
+    /// 与实体在同一个格子中的其他实体
+    pub fn get_user_grid(&self, entity: u32) -> BitSet {
+        let mut set = BitSet::new();
+        if let Some((parent, index)) = self.user_grids.get(&entity) {
+            if let (Some(sd), Some(grids)) = (
+                self.scene_data.get(&parent.id()),
+                self.scene_grids.get(&parent.id()),
+            ) {
+                set |= &grids.grid(sd, *index);
+                set.remove(entity);
+            }
+        }
+        set
+    }
+
+    /// 注册场景的主实例
+    pub fn insert_scene(&mut self, id: u32, entity: Entity) {
+        self.insert_instance(id, 0, entity);
+    }
+
+    pub fn insert_instance(&mut self, id: u32, instance: u32, entity: Entity) {
+        if self.scene_mapping.insert((id, instance), entity).is_some() {
+            log::error!("scene:{} instance:{} already inserted", id, instance);
+        }
+    }
+
+    /// 创建场景的新实例，例如副本，场景数据在下一帧生效。
+    /// 实体的SceneMember指向返回的场景实体即进入该实例，实体数量达到上限时返回None
+    pub fn create_instance<'a>(
+        &mut self,
+        entities: &Entities<'a>,
+        lazy: &LazyUpdate,
+        budget: &SpawnBudget,
+        data: B::SceneData,
+    ) -> Option<(u32, Entity)> {
+        if !budget.try_spawn() {
+            return None;
+        }
+        let id = data.id();
+        let instance = self.instances.entry(id).or_default();
+        *instance += 1;
+        let instance = *instance;
+        let entity = entities.create();
+        lazy.insert(entity, data);
+        self.insert_instance(id, instance, entity);
+        log::info!("scene:{} instance:{} created", id, instance);
+        Some((instance, entity))
+    }
+
+    /// 销毁场景实例，实例中还有实体时失败，
+    /// 实例中的实体全部离开后也会被自动删除
+    pub fn destroy_instance<'a>(
+        &mut self,
+        id: u32,
+        instance: u32,
+        entities: &Entities<'a>,
+    ) -> bool {
+        let entity = match self.scene_mapping.get(&(id, instance)) {
+            Some(entity) => *entity,
+            None => return false,
+        };
+        // 只有静态实体的场景同样不为空
+        let empty = self
+            .scene_grids
+            .get(&entity.id())
+            .map_or(true, |grids| grids.is_empty())
+            && self
+                .static_grids
+                .get(&entity.id())
+                .map_or(true, |grids| grids.is_empty());
+        if !empty {
+            log::warn!("scene:{} instance:{} is not empty", id, instance);
+            return false;
+        }
+        self.scene_mapping.remove(&(id, instance));
+        self.scene_grids.remove(&entity.id());
+        self.static_grids.remove(&entity.id());
+        self.radius_entities.remove(&entity.id());
+        self.scene_lifetimes.remove(&entity.id());
+        self.empty_since.remove(&entity.id());
+        if let Err(err) = entities.delete(entity) {
+            log::error!("delete scene:{} instance:{} failed:{}", id, instance, err);
+        }
+        log::info!("scene:{} instance:{} destroyed", id, instance);
+        true
+    }
+
+    /// 设置场景为空后的保留策略，覆盖SceneData中的配置
+    pub fn set_scene_lifetime(&mut self, scene: Entity, lifetime: SceneLifetime) {
+        self.scene_lifetimes.insert(scene.id(), lifetime);
+    }
+
+    /// 场景所有实例的id以及实体
+    pub fn get_instances(&self, id: u32) -> Vec<(u32, Entity)> {
+        self.scene_mapping
+            .iter()
+            .filter(|((scene, _), _)| *scene == id)
+            .map(|((_, instance), entity)| (*instance, *entity))
+            .collect()
+    }
+
+    /// 按场景实例并行处理，回调的第一个参数是场景实例的实体(即SceneMember指向的实体)而不是场景id，
+    /// 同一场景的不同实例分别处理，同一实例中的实体在同一个任务中顺序处理，不在场景中的实体会被忽略
+    pub fn par_scene_join<'e, J, F>(&self, entities: &Entities<'e>, data: J, f: F)
+    where
+        J: Join,
+        J::Type: Send,
+        F: Fn(Entity, Vec<(Entity, J::Type)>) + Send + Sync,
+    {
+        let mut instances: HashMap<Entity, Vec<(Entity, J::Type)>> = HashMap::new();
+        for (entity, item) in (&**entities, data).join() {
+            if let Some((parent, _)) = self.user_grids.get(&entity.id()) {
+                instances.entry(*parent).or_default().push((entity, item));
+            }
+        }
+        instances
+            .into_par_iter()
+            .for_each(|(scene_entity, items)| f(scene_entity, items));
+    }
+
+    /// 每个场景中的实体数量
+    pub fn scene_entities(&self) -> HashMap<u32, usize> {
+        self.scene_grids
+            .iter()
+            .map(|(id, grids)| {
+                let statics: usize = self.static_grids.get(id).map_or(0, |grids| {
+                    grids.values().map(|grid| grid.iter().count()).sum()
+                });
+                (*id, grids.len() + statics)
+            })
+            .collect()
+    }
+
+    /// 实体所在的场景
+    pub fn get_user_scene(&self, entity: u32) -> Option<Entity> {
+        self.user_grids.get(&entity).map(|(parent, _)| *parent)
+    }
+
+    /// 每个格子中的实体数量，(场景，格子，数量)
+    pub fn grid_entities(&self) -> Vec<(u32, usize, usize)> {
+        let mut counts: HashMap<(u32, usize), usize> = HashMap::new();
+        for (parent, index) in self.user_grids.values() {
+            *counts.entry((parent.id(), *index)).or_default() += 1;
+        }
+        counts
+            .into_iter()
+            .map(|((scene, index), count)| (scene, index, count))
+            .collect()
+    }
+
+    /// 场景主实例的实体
+    pub fn get_scene_entity(&self, id: u32) -> Option<Entity> {
+        self.get_instance_entity(id, 0)
+    }
+
+    pub fn get_instance_entity(&self, id: u32, instance: u32) -> Option<Entity> {
+        self.scene_mapping.get(&(id, instance)).copied()
+    }
+}
+pub type GroupHierarchy<const G: usize> = Hierarchy<Member<G>>;
+pub type TeamHierarchy = GroupHierarchy<0>;
+#[allow(dead_code)]
+pub type SceneHierarchy = Hierarchy<SceneMember>;
+
+/// 队伍中的职位，每个队伍只有一个队长
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum TeamRole {
+    Member,
+    /// 可以邀请新成员以及踢出普通成员
+    Officer,
+    Leader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeamError {
+    /// 队伍不存在
+    NoTeam,
+    /// 已经在其他队伍中
+    AlreadyInTeam,
+    /// 不是队伍成员
+    NotMember,
+    /// 队伍人数已满
+    Full,
+    /// 职位不足
+    Permission,
+    /// 邀请不存在或者已经过期
+    NoInvite,
+    /// 实体已经删除
+    InvalidEntity,
+}
+
+/// 队伍变化，成员的加入以及离开已经由`TeamManagerSystem`同步，
+/// 其他变化需要游戏逻辑写入组队方向的数据集通知客户端
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TeamEvent {
+    Created {
+        team: Entity,
+        leader: Entity,
+    },
+    Invited {
+        team: Entity,
+        inviter: Entity,
+        invitee: Entity,
+    },
+    Joined {
+        team: Entity,
+        member: Entity,
+    },
+    Left {
+        team: Entity,
+        member: Entity,
+        kicked: bool,
+    },
+    RoleChanged {
+        team: Entity,
+        member: Entity,
+        role: TeamRole,
+    },
+    Disbanded {
+        team: Entity,
+    },
+}
+
+struct TeamInfo {
+    entity: Entity,
+    capacity: usize,
+    roles: HashMap<u32, (Entity, TeamRole)>,
+}
+
+/// 队伍的创建、邀请、踢人以及转让队长，成员关系通过`Member<G>`组件维护，
+/// 需要通过此资源修改，直接插入的`Member<G>`不受人数以及职位限制。
+/// 每个组一个实例，例如公会可以使用`TeamManager<2>`
+pub struct TeamManager<const G: usize = 0> {
+    capacity: usize,
+    invite_timeout: Duration,
+    teams: HashMap<u32, TeamInfo>,
+    /// 成员id -> 队伍id
+    member_team: HashMap<u32, u32>,
+    /// 被邀请者id -> (队伍id, 邀请者, 过期时间)
+    invites: HashMap<u32, Vec<(u32, Entity, Instant)>>,
+    events: Vec<TeamEvent>,
+}
+
+impl<const G: usize> Default for TeamManager<G> {
+    fn default() -> Self {
+        Self {
+            capacity: 5,
+            invite_timeout: Duration::from_secs(60),
+            teams: Default::default(),
+            member_team: Default::default(),
+            invites: Default::default(),
+            events: Default::default(),
+        }
+    }
+}
+
+impl<const G: usize> TeamManager<G> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// 默认的队伍人数上限
+    pub fn with_capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity;
+        self
+    }
+
+    pub fn with_invite_timeout(mut self, timeout: Duration) -> Self {
+        self.invite_timeout = timeout;
+        self
+    }
+
+    /// 创建队伍，创建者成为队长，capacity为None时使用默认人数上限
+    pub fn create(
+        &mut self,
+        entities: &Entities,
+        members: &mut WriteStorage<Member<G>>,
+        leader: Entity,
+        capacity: Option<usize>,
+    ) -> Result<Entity, TeamError> {
+        if self.member_team.contains_key(&leader.id()) {
+            return Err(TeamError::AlreadyInTeam);
+        }
+        let team = entities.create();
+        if let Err(err) = members.insert(leader, Member::new(team)) {
+            log::error!("create team for entity:{} failed:{}", leader.id(), err);
+            let _ = entities.delete(team);
+            return Err(TeamError::InvalidEntity);
+        }
+        let mut roles = HashMap::new();
+        roles.insert(leader.id(), (leader, TeamRole::Leader));
+        self.teams.insert(
+            team.id(),
+            TeamInfo {
+                entity: team,
+                capacity: capacity.unwrap_or(self.capacity),
+                roles,
+            },
+        );
+        self.member_team.insert(leader.id(), team.id());
+        self.invites.remove(&leader.id());
+        self.events.push(TeamEvent::Created { team, leader });
+        Ok(team)
+    }
+
+    /// 邀请者需要是队长或者干部，同一队伍的重复邀请会刷新过期时间
+    pub fn invite(&mut self, inviter: Entity, invitee: Entity) -> Result<(), TeamError> {
+        let team = self.role_of(inviter).and_then(|(team, role)| {
+            if role < TeamRole::Officer {
+                Err(TeamError::Permission)
+            } else {
+                Ok(team)
+            }
+        })?;
+        if self.member_team.contains_key(&invitee.id()) {
+            return Err(TeamError::AlreadyInTeam);
+        }
+        let info = &self.teams[&team];
+        if info.roles.len() >= info.capacity {
+            return Err(TeamError::Full);
+        }
+        let expire = Instant::now() + self.invite_timeout;
+        let invites = self.invites.entry(invitee.id()).or_default();
+        invites.retain(|(id, _, _)| *id != team);
+        invites.push((team, inviter, expire));
+        self.events.push(TeamEvent::Invited {
+            team: info.entity,
+            inviter,
+            invitee,
+        });
+        Ok(())
+    }
+
+    /// 没有过期的邀请，(队伍, 邀请者)
+    pub fn get_invites(&mut self, invitee: Entity) -> Vec<(Entity, Entity)> {
+        self.prune_invites(invitee.id());
+        self.invites
+            .get(&invitee.id())
+            .map(|invites| {
+                invites
+                    .iter()
+                    .filter_map(|(team, inviter, _)| {
+                        self.teams.get(team).map(|info| (info.entity, *inviter))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// 接受邀请加入队伍，同时清除其他邀请
+    pub fn accept(
+        &mut self,
+        members: &mut WriteStorage<Member<G>>,
+        invitee: Entity,
+        team: Entity,
+    ) -> Result<(), TeamError> {
+        self.prune_invites(invitee.id());
+        let invited = self.invites.get(&invitee.id()).map_or(false, |invites| {
+            invites.iter().any(|(id, _, _)| *id == team.id())
+        });
+        if !invited {
+            return Err(TeamError::NoInvite);
+        }
+        if self.member_team.contains_key(&invitee.id()) {
+            return Err(TeamError::AlreadyInTeam);
+        }
+        let info = match self.teams.get_mut(&team.id()) {
+            Some(info) if info.entity == team => info,
+            _ => return Err(TeamError::NoTeam),
+        };
+        if info.roles.len() >= info.capacity {
+            return Err(TeamError::Full);
+        }
+        if let Err(err) = members.insert(invitee, Member::new(team)) {
+            log::error!("entity:{} join team failed:{}", invitee.id(), err);
+            return Err(TeamError::InvalidEntity);
+        }
+        info.roles.insert(invitee.id(), (invitee, TeamRole::Member));
+        self.member_team.insert(invitee.id(), team.id());
+        self.invites.remove(&invitee.id());
+        self.events.push(TeamEvent::Joined {
+            team,
+            member: invitee,
+        });
+        Ok(())
+    }
+
+    /// 拒绝邀请，返回邀请是否存在
+    pub fn decline(&mut self, invitee: Entity, team: Entity) -> bool {
+        match self.invites.get_mut(&invitee.id()) {
+            Some(invites) => {
+                let count = invites.len();
+                invites.retain(|(id, _, _)| *id != team.id());
+                let declined = invites.len() != count;
+                if invites.is_empty() {
+                    self.invites.remove(&invitee.id());
+                }
+                declined
+            }
+            None => false,
+        }
+    }
+
+    /// 踢出职位比自己低的成员，需要是队长或者干部
+    pub fn kick(
+        &mut self,
+        entities: &Entities,
+        members: &mut WriteStorage<Member<G>>,
+        operator: Entity,
+        target: Entity,
+    ) -> Result<(), TeamError> {
+        let (team, role) = self.role_of(operator)?;
+        let (target_team, target_role) = self.role_of(target)?;
+        if team != target_team {
+            return Err(TeamError::NotMember);
+        }
+        if role < TeamRole::Officer || role <= target_role {
+            return Err(TeamError::Permission);
+        }
+        members.remove(target);
+        self.remove_member(entities, target.id(), true);
+        Ok(())
+    }
+
+    /// 离开队伍，队长离开时职位最高的成员成为队长，最后一人离开时解散队伍
+    pub fn leave(
+        &mut self,
+        entities: &Entities,
+        members: &mut WriteStorage<Member<G>>,
+        member: Entity,
+    ) -> Result<(), TeamError> {
+        self.role_of(member)?;
+        members.remove(member);
+        self.remove_member(entities, member.id(), false);
+        Ok(())
+    }
+
+    /// 队长转让给同队的其他成员，原队长成为普通成员
+    pub fn transfer_leader(&mut self, leader: Entity, target: Entity) -> Result<(), TeamError> {
+        let (team, role) = self.role_of(leader)?;
+        if role != TeamRole::Leader {
+            return Err(TeamError::Permission);
+        }
+        if self.role_of(target)?.0 != team || leader == target {
+            return Err(TeamError::NotMember);
+        }
+        self.change_role(team, leader.id(), TeamRole::Member);
+        self.change_role(team, target.id(), TeamRole::Leader);
+        Ok(())
+    }
+
+    /// 队长设置成员的职位，转让队长需要使用`transfer_leader`
+    pub fn set_role(
+        &mut self,
+        leader: Entity,
+        target: Entity,
+        role: TeamRole,
+    ) -> Result<(), TeamError> {
+        let (team, leader_role) = self.role_of(leader)?;
+        if leader_role != TeamRole::Leader || role == TeamRole::Leader || leader == target {
+            return Err(TeamError::Permission);
+        }
+        if self.role_of(target)?.0 != team {
+            return Err(TeamError::NotMember);
+        }
+        self.change_role(team, target.id(), role);
+        Ok(())
+    }
+
+    /// `Member<G>`组件被删除后清理成员记录，例如实体被删除，已经清理过的成员忽略
+    pub fn member_removed(&mut self, entities: &Entities, id: u32) {
+        if self.member_team.contains_key(&id) {
+            self.remove_member(entities, id, false);
+        }
+        self.invites.remove(&id);
+    }
+
+    pub fn get_team(&self, member: Entity) -> Option<Entity> {
+        self.member_team
+            .get(&member.id())
+            .map(|team| self.teams[team].entity)
+    }
+
+    pub fn get_role(&self, member: Entity) -> Option<TeamRole> {
+        self.role_of(member).ok().map(|(_, role)| role)
+    }
+
+    pub fn get_leader(&self, team: Entity) -> Option<Entity> {
+        self.teams
+            .get(&team.id())?
+            .roles
+            .values()
+            .find_map(|(entity, role)| {
+                if *role == TeamRole::Leader {
+                    Some(*entity)
+                } else {
+                    None
+                }
+            })
+    }
+
+    pub fn get_members(&self, team: Entity) -> BitSet {
+        let mut set = BitSet::new();
+        if let Some(info) = self.teams.get(&team.id()) {
+            info.roles.keys().for_each(|id| {
+                set.add(*id);
+            });
+        }
+        set
+    }
+
+    /// 取出上次调用以来的队伍变化
+    pub fn take_events(&mut self) -> Vec<TeamEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn role_of(&self, member: Entity) -> Result<(u32, TeamRole), TeamError> {
+        let team = *self
+            .member_team
+            .get(&member.id())
+            .ok_or(TeamError::NotMember)?;
+        match self.teams[&team].roles.get(&member.id()) {
+            Some((entity, role)) if *entity == member => Ok((team, *role)),
+            _ => Err(TeamError::NotMember),
+        }
+    }
+
+    fn change_role(&mut self, team: u32, id: u32, role: TeamRole) {
+        let info = self.teams.get_mut(&team).unwrap();
+        let (member, current) = info.roles.get_mut(&id).unwrap();
+        *current = role;
+        self.events.push(TeamEvent::RoleChanged {
+            team: info.entity,
+            member: *member,
+            role,
+        });
+    }
+
+    fn remove_member(&mut self, entities: &Entities, id: u32, kicked: bool) {
+        let team = match self.member_team.remove(&id) {
+            Some(team) => team,
+            None => return,
+        };
+        let info = self.teams.get_mut(&team).unwrap();
+        let (member, role) = info.roles.remove(&id).unwrap();
+        self.events.push(TeamEvent::Left {
+            team: info.entity,
+            member,
+            kicked,
+        });
+        if info.roles.is_empty() {
+            let entity = info.entity;
+            self.teams.remove(&team);
+            let _ = entities.delete(entity);
+            self.events.push(TeamEvent::Disbanded { team: entity });
+            return;
+        }
+        if role == TeamRole::Leader {
+            let next = info
+                .roles
+                .iter()
+                .max_by_key(|(id, (_, role))| (*role, std::cmp::Reverse(**id)))
+                .map(|(id, _)| *id)
+                .unwrap();
+            self.change_role(team, next, TeamRole::Leader);
+        }
+    }
+
+    fn prune_invites(&mut self, invitee: u32) {
+        let now = Instant::now();
+        if let Some(invites) = self.invites.get_mut(&invitee) {
+            invites.retain(|(_, _, expire)| *expire > now);
+            if invites.is_empty() {
+                self.invites.remove(&invitee);
+            }
+        }
+    }
+}
+
+/// 观察者订阅，目标实体id -> (观察者连接, 订阅序号)，观察者不需要是实体，
+/// 新订阅的观察者会先收到一次完整数据
+#[derive(Default)]
+pub struct ObserverManager {
+    observers: HashMap<u32, HashMap<Token, usize>>,
+    sequence: usize,
+}
+
+impl ObserverManager {
+    pub fn subscribe(&mut self, target: Entity, token: Token) {
+        self.sequence += 1;
+        self.observers
+            .entry(target.id())
+            .or_default()
+            .insert(token, self.sequence);
+    }
+
+    pub fn unsubscribe(&mut self, target: Entity, token: Token) -> bool {
+        match self.observers.get_mut(&target.id()) {
+            Some(tokens) => {
+                let removed = tokens.remove(&token).is_some();
+                if tokens.is_empty() {
+                    self.observers.remove(&target.id());
+                }
+                removed
+            }
+            None => false,
+        }
+    }
+
+    /// 连接断开时取消所有订阅
+    pub fn unsubscribe_all(&mut self, token: Token) {
+        self.observers.retain(|_, tokens| {
+            tokens.remove(&token);
+            !tokens.is_empty()
+        });
+    }
+
+    /// 目标实体删除后取消所有订阅
+    pub fn remove_target(&mut self, target: u32) {
+        self.observers.remove(&target);
+    }
+
+    pub fn get_observers(&self, target: u32) -> Vec<Token> {
+        self.observers
+            .get(&target)
+            .map(|tokens| tokens.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    pub fn is_observed(&self, target: u32) -> bool {
+        self.observers.contains_key(&target)
+    }
+
+    /// 最新的订阅序号
+    pub fn sequence(&self) -> usize {
+        self.sequence
+    }
+
+    /// 序号大于sequence的订阅，(目标实体id, 观察者连接)
+    pub fn subscribed_since(&self, sequence: usize) -> Vec<(u32, Token)> {
+        self.observers
+            .iter()
+            .flat_map(|(target, tokens)| {
+                tokens
+                    .iter()
+                    .filter(move |(_, seq)| **seq > sequence)
+                    .map(move |(token, _)| (*target, *token))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{AlwaysVisible, DummyDropEntity},
+        component::LeftAoi,
+        GridSystem, SpawnBudget,
+    };
+    use specs::{Builder, FlaggedStorage, HashMapStorage, RunNow, System, SystemData, VecStorage};
+
+    struct TestPosition {
+        x: f32,
+        y: f32,
+    }
+
+    impl Position for TestPosition {
+        fn x(&self) -> f32 {
+            self.x
+        }
+
+        fn y(&self) -> f32 {
+            self.y
+        }
+
+        fn set_x(&mut self, x: f32) {
+            self.x = x;
+        }
+
+        fn set_y(&mut self, y: f32) {
+            self.y = y;
+        }
+    }
+
+    impl Component for TestPosition {
+        type Storage = FlaggedStorage<Self, VecStorage<Self>>;
+    }
+
+    #[derive(Clone)]
+    struct TestScene;
+
+    impl SceneData for TestScene {
+        fn id(&self) -> u32 {
+            1
+        }
+
+        fn get_min_x(&self) -> f32 {
+            0.0
+        }
+
+        fn get_min_y(&self) -> f32 {
+            0.0
+        }
+
+        fn get_column(&self) -> i32 {
+            10
+        }
+
+        fn get_row(&self) -> i32 {
+            10
+        }
+
+        fn grid_size(&self) -> f32 {
+            10.0
+        }
+
+        fn lifetime(&self) -> SceneLifetime {
+            SceneLifetime::Persistent
+        }
+    }
+
+    impl Component for TestScene {
+        type Storage = FlaggedStorage<Self, HashMapStorage<Self>>;
+    }
+
+    struct TestBackend;
+
+    impl SceneSyncBackend for TestBackend {
+        type Position = TestPosition;
+        type SceneData = TestScene;
+        type DropEntity = DummyDropEntity;
+    }
+
+    /// 测试中周围实体只增不减，不会发送删除实体的消息
+    struct Scene {
+        world: World,
+        grid: GridSystem<TestBackend>,
+        scene: Entity,
+    }
+
+    impl Scene {
+        fn new() -> Self {
+            let mut world = World::new();
+            TestBackend::setup(&mut world);
+            world.register::<StaticEntity>();
+            world.register::<LeftAoi>();
+            let mut grid = GridSystem::new(&mut world);
+            System::setup(&mut grid, &mut world);
+            world.insert(SpawnBudget::default());
+            let scene = world.create_entity().with(TestScene).build();
+            world
+                .write_resource::<SceneManager<TestBackend>>()
+                .insert_scene(1, scene);
+            Self { world, grid, scene }
+        }
+
+        fn spawn(&mut self, x: f32, y: f32, is_static: bool) -> Entity {
+            let mut builder = self
+                .world
+                .create_entity()
+                .with(SceneMember::new(self.scene))
+                .with(TestPosition { x, y });
+            if is_static {
+                builder = builder.with(StaticEntity);
+            }
+            builder.build()
+        }
+
+        fn frame(&mut self) {
+            self.grid.run_now(&self.world);
+            self.world.maintain();
+        }
+
+        fn manager(&self) -> specs::shred::FetchMut<SceneManager<TestBackend>> {
+            self.world.write_resource()
+        }
+    }
+
+    #[test]
+    fn destroy_instance_with_static() {
+        let mut s = Scene::new();
+        let entity = s.spawn(5.0, 5.0, true);
+        s.frame();
+        let scene = s.scene.id();
+        assert!(s.manager().static_grids.contains_key(&scene));
+        assert!(!s.manager().destroy_instance(1, 0, &s.world.entities()));
+
+        s.world.delete_entity(entity).unwrap();
+        s.frame();
+        assert!(s.manager().destroy_instance(1, 0, &s.world.entities()));
+        assert!(!s.manager().static_grids.contains_key(&scene));
+        assert_eq!(s.manager().get_scene_entity(1), None);
+    }
+
+    #[test]
+    fn custom_view_radius() {
+        let mut s = Scene::new();
+        let far = s.spawn(5.0, 5.0, false);
+        let near = s.spawn(35.0, 5.0, false);
+        let out = s.spawn(45.0, 5.0, false);
+        // 进入格子之前设置的半径在进入时建立索引
+        s.manager().set_view_radius(far.id(), Some(3));
+        s.frame();
+        let around = |s: &Scene, entity: Entity| -> Vec<u32> {
+            let visibility = s.world.read_storage::<AlwaysVisible>();
+            (&s.manager().get_user_around(entity.id(), &visibility))
+                .iter()
+                .collect()
+        };
+        assert_eq!(around(&s, far), vec![near.id()]);
+        assert_eq!(around(&s, near), vec![far.id(), out.id()]);
+        assert_eq!(around(&s, out), vec![near.id()]);
+
+        // 周围实体立即按照新的半径计算，不运行maintain避免发送删除消息
+        s.manager().set_view_radius(far.id(), Some(4));
+        assert_eq!(around(&s, far), vec![near.id(), out.id()]);
+        assert_eq!(around(&s, out), vec![far.id(), near.id()]);
+        let scene = s.scene.id();
+        let groups = s.manager().radius_entities[&scene].clone();
+        assert_eq!(groups.len(), 1);
+        assert!(groups[&4].contains(far.id()));
+
+        s.manager().set_view_radius(far.id(), None);
+        assert!(s.manager().radius_entities.is_empty());
+        assert_eq!(around(&s, out), vec![near.id()]);
+    }
+
+    struct Team {
+        world: World,
+        manager: TeamManager,
+    }
+
+    impl Team {
+        fn new(manager: TeamManager) -> (Self, Vec<Entity>) {
+            let mut world = World::new();
+            world.register::<Member<0>>();
+            let players = (0..4).map(|_| world.create_entity().build()).collect();
+            (Self { world, manager }, players)
+        }
+
+        fn create(&mut self, leader: Entity) -> Result<Entity, TeamError> {
+            let (entities, mut members) = <(Entities, WriteStorage<Member<0>>)>::fetch(&self.world);
+            self.manager.create(&entities, &mut members, leader, None)
+        }
+
+        fn join(&mut self, inviter: Entity, invitee: Entity) -> Result<(), TeamError> {
+            let team = self.manager.get_team(inviter).ok_or(TeamError::NoTeam)?;
+            self.manager.invite(inviter, invitee)?;
+            let mut members = self.world.write_storage::<Member<0>>();
+            self.manager.accept(&mut members, invitee, team)
+        }
+
+        fn leave(&mut self, member: Entity) -> Result<(), TeamError> {
+            let (entities, mut members) = <(Entities, WriteStorage<Member<0>>)>::fetch(&self.world);
+            self.manager.leave(&entities, &mut members, member)
+        }
+
+        fn kick(&mut self, operator: Entity, target: Entity) -> Result<(), TeamError> {
+            let (entities, mut members) = <(Entities, WriteStorage<Member<0>>)>::fetch(&self.world);
+            self.manager.kick(&entities, &mut members, operator, target)
+        }
+
+        fn parent(&self, member: Entity) -> Option<Entity> {
+            self.world
+                .read_storage::<Member<0>>()
+                .get(member)
+                .map(|m| m.parent_entity())
+        }
+    }
+
+    #[test]
+    fn bench_register_by_name() {
+        let ts = TimeStatistic::new();
+        let record = ts.register_bench("move");
+        assert!(Arc::ptr_eq(&record, &ts.register_bench("move")));
+        assert!(!Arc::ptr_eq(&record, &ts.register_bench("attack")));
+        assert_eq!(ts.benches.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn world_stats_interval() {
+        let mut world = World::new();
+        world.register::<Member<0>>();
+        let mut stats = WorldStats::default().with_interval(Duration::from_secs(60));
+        stats.watch::<Member<0>>();
+        world.create_entity().build();
+        assert!(stats.refresh(&world));
+        assert_eq!(stats.entities(), 1);
+
+        // 间隔内不重新统计
+        let team = world.create_entity().build();
+        world.create_entity().with(Member::<0>::new(team)).build();
+        assert!(!stats.refresh(&world));
+        assert_eq!(stats.entities(), 1);
+
+        let mut stats = stats.with_interval(Duration::from_secs(0));
+        assert!(stats.refresh(&world));
+        assert_eq!(stats.entities(), 3);
+        assert_eq!(stats.components()[std::any::type_name::<Member<0>>()].0, 1);
+    }
+
+    #[test]
+    fn invite_and_accept() {
+        let (mut t, p) = Team::new(TeamManager::new());
+        let team = t.create(p[0]).unwrap();
+        assert_eq!(t.create(p[0]), Err(TeamError::AlreadyInTeam));
+        t.join(p[0], p[1]).unwrap();
+        assert_eq!(t.manager.get_team(p[1]), Some(team));
+        assert_eq!(t.manager.get_role(p[1]), Some(TeamRole::Member));
+        assert_eq!(t.manager.get_leader(team), Some(p[0]));
+        assert_eq!(t.parent(p[1]), Some(team));
+        let members: Vec<_> = (&t.manager.get_members(team)).iter().collect();
+        assert_eq!(members, vec![p[0].id(), p[1].id()]);
+        assert_eq!(
+            t.manager.take_events(),
+            vec![
+                TeamEvent::Created { team, leader: p[0] },
+                TeamEvent::Invited {
+                    team,
+                    inviter: p[0],
+                    invitee: p[1]
+                },
+                TeamEvent::Joined { team, member: p[1] },
+            ]
+        );
+
+        // 已经在队伍中，或者邀请被拒绝
+        assert_eq!(t.manager.invite(p[0], p[1]), Err(TeamError::AlreadyInTeam));
+        t.manager.invite(p[0], p[2]).unwrap();
+        assert_eq!(t.manager.get_invites(p[2]), vec![(team, p[0])]);
+        assert!(t.manager.decline(p[2], team));
+        assert!(!t.manager.decline(p[2], team));
+        let mut members = t.world.write_storage::<Member<0>>();
+        assert_eq!(
+            t.manager.accept(&mut members, p[2], team),
+            Err(TeamError::NoInvite)
+        );
+    }
+
+    #[test]
+    fn invite_permission_and_capacity() {
+        let (mut t, p) = Team::new(TeamManager::new().with_capacity(3));
+        t.create(p[0]).unwrap();
+        t.join(p[0], p[1]).unwrap();
+        assert_eq!(t.manager.invite(p[1], p[2]), Err(TeamError::Permission));
+        assert_eq!(t.manager.invite(p[2], p[3]), Err(TeamError::NotMember));
+        t.manager.set_role(p[0], p[1], TeamRole::Officer).unwrap();
+        t.join(p[1], p[2]).unwrap();
+        assert_eq!(t.manager.invite(p[0], p[3]), Err(TeamError::Full));
+    }
+
+    #[test]
+    fn invite_expired() {
+        let (mut t, p) = Team::new(TeamManager::new().with_invite_timeout(Duration::from_secs(0)));
+        let team = t.create(p[0]).unwrap();
+        t.manager.invite(p[0], p[1]).unwrap();
+        assert!(t.manager.get_invites(p[1]).is_empty());
+        let mut members = t.world.write_storage::<Member<0>>();
+        assert_eq!(
+            t.manager.accept(&mut members, p[1], team),
+            Err(TeamError::NoInvite)
+        );
+    }
+
+    #[test]
+    fn kick_and_roles() {
+        let (mut t, p) = Team::new(TeamManager::new());
+        let team = t.create(p[0]).unwrap();
+        t.join(p[0], p[1]).unwrap();
+        t.join(p[0], p[2]).unwrap();
+        assert_eq!(
+            t.manager.set_role(p[1], p[2], TeamRole::Officer),
+            Err(TeamError::Permission)
+        );
+        assert_eq!(
+            t.manager.set_role(p[0], p[1], TeamRole::Leader),
+            Err(TeamError::Permission)
+        );
+        t.manager.set_role(p[0], p[1], TeamRole::Officer).unwrap();
+        // 干部不能踢出队长以及同级干部
+        assert_eq!(t.kick(p[1], p[0]), Err(TeamError::Permission));
+        t.kick(p[1], p[2]).unwrap();
+        assert_eq!(t.manager.get_team(p[2]), None);
+        assert_eq!(t.parent(p[2]), None);
+        assert_eq!(t.kick(p[1], p[2]), Err(TeamError::NotMember));
+
+        t.manager.transfer_leader(p[0], p[1]).unwrap();
+        assert_eq!(t.manager.get_leader(team), Some(p[1]));
+        assert_eq!(t.manager.get_role(p[0]), Some(TeamRole::Member));
+        assert_eq!(
+            t.manager.transfer_leader(p[0], p[1]),
+            Err(TeamError::Permission)
+        );
+    }
+
+    #[test]
+    fn leader_leave_and_disband() {
+        let (mut t, p) = Team::new(TeamManager::new());
+        let team = t.create(p[0]).unwrap();
+        t.join(p[0], p[1]).unwrap();
+        t.join(p[0], p[2]).unwrap();
+        t.manager.set_role(p[0], p[2], TeamRole::Officer).unwrap();
+        t.manager.take_events();
+
+        // 职位最高的成员成为队长
+        t.leave(p[0]).unwrap();
+        assert_eq!(t.manager.get_leader(team), Some(p[2]));
+        assert_eq!(t.leave(p[0]), Err(TeamError::NotMember));
+
+        // 实体删除后通过member_removed清理
+        {
+            let entities = Entities::fetch(&t.world);
+            t.world.write_storage::<Member<0>>().remove(p[2]);
+            t.manager.member_removed(&entities, p[2].id());
+        }
+        assert_eq!(t.manager.get_leader(team), Some(p[1]));
+        t.leave(p[1]).unwrap();
+        assert_eq!(
+            t.manager.take_events().last(),
+            Some(&TeamEvent::Disbanded { team })
+        );
+        t.world.maintain();
+        assert!(!t.world.is_alive(team));
+        assert_eq!(t.manager.get_leader(team), None);
     }
 }
-pub type TeamHierarchy = Hierarchy<TeamMember>;
-#[allow(dead_code)]
-pub type SceneHierarchy = Hierarchy<SceneMember>;
@@ -0,0 +1,295 @@
+use crate::{
+    component::{AccountId, NetToken},
+    resource::FrameCounter,
+    sync::Persist,
+};
+use byteorder::{BigEndian, ByteOrder};
+use specs::{storage::MaskedStorage, BitSet, Component, Join, RunNow, World, WorldExt};
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{Error, ErrorKind, Read, Result, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+const SNAPSHOT_MAGIC: &[u8; 4] = b"ECSS";
+const SNAPSHOT_VERSION: u32 = 1;
+/// 快照中允许的最大实体id，避免损坏的文件分配过多实体
+const SNAPSHOT_MAX_ENTITIES: u32 = 1 << 24;
+
+type SaveFn = fn(&World, &BitSet) -> Vec<(u32, Vec<u8>)>;
+/// 解码出的组件在所有数据校验通过后再插入World
+type Apply = Box<dyn FnOnce(&mut World) -> Result<()>>;
+type LoadFn = fn(Vec<(u32, Vec<u8>)>) -> Result<Apply>;
+
+fn save<T: Component + Persist>(world: &World, skipped: &BitSet) -> Vec<(u32, Vec<u8>)> {
+    (&world.entities(), &world.read_storage::<T>(), !skipped)
+        .join()
+        .filter_map(|(entity, t, _)| t.to_bytes().map(|data| (entity.id(), data)))
+        .collect()
+}
+
+fn load<T>(records: Vec<(u32, Vec<u8>)>) -> Result<Apply>
+where
+    T: Component + Persist,
+    T::Storage: Default,
+{
+    let mut components = Vec::with_capacity(records.len());
+    for (id, data) in records {
+        match T::from_bytes(data.as_slice()) {
+            Some(t) => components.push((id, t)),
+            None => {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "decode {} of entity:{} failed",
+                        std::any::type_name::<T>(),
+                        id
+                    ),
+                ))
+            }
+        }
+    }
+    Ok(Box::new(move |world: &mut World| {
+        world.register::<T>();
+        let entities = world.entities();
+        let mut storage = world.write_storage::<T>();
+        for (id, t) in components {
+            storage
+                .insert(entities.entity(id), t)
+                .map_err(|err| Error::new(ErrorKind::Other, err.to_string()))?;
+        }
+        Ok(())
+    }))
+}
+
+/// 属于网络连接的实体，连接不能跨进程恢复，不保存到快照中
+fn connected(world: &World) -> BitSet {
+    let mut skipped = BitSet::new();
+    if world.has_value::<MaskedStorage<NetToken>>() {
+        skipped |= world.read_storage::<NetToken>().mask();
+    }
+    if world.has_value::<MaskedStorage<AccountId>>() {
+        skipped |= world.read_storage::<AccountId>().mask();
+    }
+    skipped
+}
+
+/// 需要保存到快照中的组件，生成的`setup`会注册所有数据集
+#[derive(Default)]
+pub struct SnapshotRegistry {
+    entries: Vec<(&'static str, SaveFn, LoadFn)>,
+}
+
+impl SnapshotRegistry {
+    pub fn register<T>(&mut self, name: &'static str)
+    where
+        T: Component + Persist,
+        T::Storage: Default,
+    {
+        if self.entries.iter().any(|(n, _, _)| *n == name) {
+            log::warn!("snapshot component:{} already registered", name);
+            return;
+        }
+        self.entries.push((name, save::<T>, load::<T>));
+    }
+}
+
+/// 属于网络连接的实体不保存，文件格式均为大端：
+/// magic(4) | version(4) | frame(8) | allocated(4) | alive count(4) | alive ids |
+/// component count(4) | (name length(2) | name | record count(4) | (id(4) | length(4) | data)*)*
+fn encode(world: &World) -> Vec<u8> {
+    let put_u32 = |buffer: &mut Vec<u8>, value: u32| buffer.extend_from_slice(&value.to_be_bytes());
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(SNAPSHOT_MAGIC);
+    put_u32(&mut buffer, SNAPSHOT_VERSION);
+    let frame = world.read_resource::<FrameCounter>().frame() as u64;
+    buffer.extend_from_slice(&frame.to_be_bytes());
+    let skipped = connected(world);
+    let alive: Vec<_> = (&world.entities(), !&skipped)
+        .join()
+        .map(|(entity, _)| entity.id())
+        .collect();
+    let allocated = alive.iter().max().map_or(0, |id| id + 1);
+    put_u32(&mut buffer, allocated);
+    put_u32(&mut buffer, alive.len() as u32);
+    alive.iter().for_each(|id| put_u32(&mut buffer, *id));
+
+    let registry = world.read_resource::<SnapshotRegistry>();
+    put_u32(&mut buffer, registry.entries.len() as u32);
+    for (name, save, _) in &registry.entries {
+        buffer.extend_from_slice(&(name.len() as u16).to_be_bytes());
+        buffer.extend_from_slice(name.as_bytes());
+        let records = save(world, &skipped);
+        put_u32(&mut buffer, records.len() as u32);
+        for (id, data) in records {
+            put_u32(&mut buffer, id);
+            put_u32(&mut buffer, data.len() as u32);
+            buffer.extend_from_slice(data.as_slice());
+        }
+    }
+    buffer
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, size: usize) -> Result<&'a [u8]> {
+        if self.0.len() < size {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        let (data, rest) = self.0.split_at(size);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn u16(&mut self) -> Result<u16> {
+        self.take(2).map(BigEndian::read_u16)
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        self.take(4).map(BigEndian::read_u32)
+    }
+
+    fn u64(&mut self) -> Result<u64> {
+        self.take(8).map(BigEndian::read_u64)
+    }
+}
+
+fn invalid(message: String) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+/// 从快照文件恢复实体以及组件，并从快照的帧数继续计数，需要在还没有创建任何实体的World上、
+/// 网络启动之前调用。整个文件校验并解码成功后才修改World，失败时World保持不变。
+/// 只保证实体id不变，世代会被重置，返回快照的帧数
+pub fn restore_snapshot(world: &mut World, path: impl AsRef<Path>) -> Result<usize> {
+    let mut buffer = Vec::new();
+    File::open(path)?.read_to_end(&mut buffer)?;
+    let mut reader = Reader(buffer.as_slice());
+    if reader.take(4)? != SNAPSHOT_MAGIC || reader.u32()? != SNAPSHOT_VERSION {
+        return Err(invalid("invalid snapshot file".into()));
+    }
+    if world.entities().join().next().is_some() {
+        return Err(Error::new(ErrorKind::Other, "world is not empty"));
+    }
+    let frame = reader.u64()? as usize;
+    let allocated = reader.u32()?;
+    let count = reader.u32()?;
+    let mut alive = HashSet::new();
+    for _ in 0..count {
+        alive.insert(reader.u32()?);
+    }
+    if allocated > SNAPSHOT_MAX_ENTITIES || alive.iter().max().map_or(0, |id| id + 1) != allocated {
+        return Err(invalid(format!("invalid allocated entities:{}", allocated)));
+    }
+
+    let count = reader.u32()?;
+    let mut applies = Vec::new();
+    for _ in 0..count {
+        let length = reader.u16()? as usize;
+        let name = String::from_utf8_lossy(reader.take(length)?).to_string();
+        let records = reader.u32()?;
+        let mut data = Vec::new();
+        for _ in 0..records {
+            let id = reader.u32()?;
+            if !alive.contains(&id) {
+                return Err(invalid(format!("component:{} of dead entity:{}", name, id)));
+            }
+            let length = reader.u32()? as usize;
+            data.push((id, reader.take(length)?.to_vec()));
+        }
+        let load = world
+            .read_resource::<SnapshotRegistry>()
+            .entries
+            .iter()
+            .find(|(n, _, _)| *n == name)
+            .map(|(_, _, load)| *load)
+            .ok_or_else(|| invalid(format!("component:{} not registered", name)))?;
+        applies.push(load(data)?);
+    }
+
+    // 按顺序分配id，再删除已经不存在的实体，使存活实体的id保持不变
+    let entities: Vec<_> = (0..allocated).map(|_| world.entities().create()).collect();
+    for entity in entities {
+        if !alive.contains(&entity.id()) {
+            let _ = world.entities().delete(entity);
+        }
+    }
+    world.maintain();
+    for apply in applies {
+        apply(world)?;
+    }
+    world.maintain();
+    world
+        .entry::<FrameCounter>()
+        .or_insert_with(Default::default)
+        .set_frame(frame);
+    log::info!(
+        "restored {} entities from snapshot of frame {}",
+        alive.len(),
+        frame
+    );
+    Ok(frame)
+}
+
+/// 定期把所有注册的组件保存到快照文件，序列化在帧内完成，写文件在后台线程，
+/// 先写临时文件再改名，保证文件总是完整的，需要以thread local方式添加
+pub struct SnapshotSystem {
+    path: PathBuf,
+    interval: Duration,
+    last: Instant,
+    writing: Arc<AtomicBool>,
+}
+
+impl SnapshotSystem {
+    pub fn new(path: impl Into<PathBuf>, interval: Duration) -> Self {
+        Self {
+            path: path.into(),
+            interval,
+            last: Instant::now(),
+            writing: Default::default(),
+        }
+    }
+
+    fn write(path: &Path, data: &[u8]) -> Result<()> {
+        let temp = path.with_extension("tmp");
+        let mut file = File::create(&temp)?;
+        file.write_all(data)?;
+        file.sync_all()?;
+        std::fs::rename(temp, path)
+    }
+}
+
+impl<'a> RunNow<'a> for SnapshotSystem {
+    fn run_now(&mut self, world: &'a World) {
+        if self.last.elapsed() < self.interval {
+            return;
+        }
+        if self.writing.swap(true, Ordering::AcqRel) {
+            log::warn!("last snapshot still writing, skipped");
+            return;
+        }
+        self.last = Instant::now();
+        let data = encode(world);
+        let path = self.path.clone();
+        let writing = self.writing.clone();
+        std::thread::spawn(move || {
+            if let Err(err) = Self::write(path.as_path(), data.as_slice()) {
+                log::error!("write snapshot to {:?} failed:{}", path, err);
+            }
+            writing.store(false, Ordering::Release);
+        });
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        world
+            .entry::<SnapshotRegistry>()
+            .or_insert_with(Default::default);
+    }
+}
@@ -0,0 +1,405 @@
+use crate::SceneData;
+use specs::{hibitset::BitSetLike, BitSet};
+use std::collections::{HashMap, HashSet};
+
+/// 场景中实体的空间索引，每个场景一个实例。
+/// 实体按照所在格子的索引管理，查询结果与均匀格子保持一致，只是存储方式不同
+pub trait SpatialIndex: Default + Send + Sync + 'static {
+    /// 实体进入格子，坐标为进入时的位置
+    fn insert<S: SceneData>(&mut self, scene: &S, id: u32, index: usize, x: f32, y: f32);
+    /// 实体离开格子，返回实体是否存在
+    fn remove(&mut self, id: u32, index: usize) -> bool;
+    /// 以格子为中心，半径内所有格子中的实体
+    fn around<S: SceneData>(&self, scene: &S, index: usize, radius: i32) -> BitSet;
+    /// 格子中的实体
+    fn grid<S: SceneData>(&self, scene: &S, index: usize) -> BitSet;
+    /// 实体数量
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// 均匀格子，每个有实体的格子一个集合
+#[derive(Default)]
+pub struct GridIndex {
+    grids: HashMap<usize, BitSet>,
+}
+
+impl SpatialIndex for GridIndex {
+    fn insert<S: SceneData>(&mut self, _scene: &S, id: u32, index: usize, _x: f32, _y: f32) {
+        if self.grids.entry(index).or_default().add(id) {
+            log::error!("entity:{} already in grid", id);
+        }
+    }
+
+    fn remove(&mut self, id: u32, index: usize) -> bool {
+        let removed = self
+            .grids
+            .get_mut(&index)
+            .map_or(false, |grid| grid.remove(id));
+        if removed && self.grids[&index].is_empty() {
+            self.grids.remove(&index);
+        }
+        removed
+    }
+
+    fn around<S: SceneData>(&self, scene: &S, index: usize, radius: i32) -> BitSet {
+        let mut set = BitSet::new();
+        for index in scene.around_radius(index, radius) {
+            if let Some(grid) = self.grids.get(&index) {
+                set |= grid;
+            }
+        }
+        set
+    }
+
+    fn grid<S: SceneData>(&self, _scene: &S, index: usize) -> BitSet {
+        self.grids.get(&index).cloned().unwrap_or_default()
+    }
+
+    fn len(&self) -> usize {
+        self.grids.values().map(|grid| grid.iter().count()).sum()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.grids.is_empty()
+    }
+}
+
+/// 左下角以及右上角坐标，左闭右开
+type Rect = (f32, f32, f32, f32);
+
+fn rect_contains((x0, y0, x1, y1): Rect, x: f32, y: f32) -> bool {
+    x >= x0 && y >= y0 && x < x1 && y < y1
+}
+
+fn rect_intersects(a: Rect, b: Rect) -> bool {
+    a.0 < b.2 && b.0 < a.2 && a.1 < b.3 && b.1 < a.3
+}
+
+const NODE_CAPACITY: usize = 16;
+const MAX_DEPTH: usize = 10;
+
+struct QuadNode {
+    rect: Rect,
+    depth: usize,
+    items: Vec<(u32, f32, f32)>,
+    children: Option<Box<[QuadNode; 4]>>,
+}
+
+impl QuadNode {
+    fn new(rect: Rect, depth: usize) -> Self {
+        Self {
+            rect,
+            depth,
+            items: Vec::new(),
+            children: None,
+        }
+    }
+
+    fn child(&mut self, x: f32, y: f32) -> &mut QuadNode {
+        let (x0, y0, x1, y1) = self.rect;
+        let (cx, cy) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+        let i = (x >= cx) as usize + 2 * (y >= cy) as usize;
+        &mut self.children.as_mut().unwrap()[i]
+    }
+
+    fn insert(&mut self, id: u32, x: f32, y: f32) {
+        if self.children.is_some() {
+            self.child(x, y).insert(id, x, y);
+            return;
+        }
+        self.items.push((id, x, y));
+        if self.items.len() > NODE_CAPACITY && self.depth < MAX_DEPTH {
+            let (x0, y0, x1, y1) = self.rect;
+            let (cx, cy) = ((x0 + x1) / 2.0, (y0 + y1) / 2.0);
+            let depth = self.depth + 1;
+            self.children = Some(Box::new([
+                QuadNode::new((x0, y0, cx, cy), depth),
+                QuadNode::new((cx, y0, x1, cy), depth),
+                QuadNode::new((x0, cy, cx, y1), depth),
+                QuadNode::new((cx, cy, x1, y1), depth),
+            ]));
+            for (id, x, y) in std::mem::take(&mut self.items) {
+                self.child(x, y).insert(id, x, y);
+            }
+        }
+    }
+
+    fn remove(&mut self, id: u32, x: f32, y: f32) -> bool {
+        if self.children.is_none() {
+            return match self.items.iter().position(|(i, _, _)| *i == id) {
+                Some(pos) => {
+                    self.items.swap_remove(pos);
+                    true
+                }
+                None => false,
+            };
+        }
+        let removed = self.child(x, y).remove(id, x, y);
+        // 子节点都是叶子并且数量足够少时合并回当前节点
+        let children = self.children.as_mut().unwrap();
+        if removed
+            && children.iter().all(|child| child.children.is_none())
+            && children
+                .iter()
+                .map(|child| child.items.len())
+                .sum::<usize>()
+                <= NODE_CAPACITY
+        {
+            for child in children.iter_mut() {
+                self.items.append(&mut child.items);
+            }
+            self.children = None;
+        }
+        removed
+    }
+
+    fn query(&self, rect: Rect, set: &mut BitSet) {
+        if !rect_intersects(self.rect, rect) {
+            return;
+        }
+        for (id, x, y) in &self.items {
+            if rect_contains(rect, *x, *y) {
+                set.add(*id);
+            }
+        }
+        if let Some(children) = &self.children {
+            children.iter().for_each(|child| child.query(rect, set));
+        }
+    }
+}
+
+/// 四叉树，只保存实体本身，适用于很大但是实体稀疏的场景。
+/// 按照格子的矩形范围查询，实体在同一格子中移动时不需要更新，分层的场景每层一棵树
+#[derive(Default)]
+pub struct QuadTreeIndex {
+    roots: HashMap<usize, QuadNode>,
+    /// 实体所在的层、格子索引以及位置
+    positions: HashMap<u32, (usize, usize, f32, f32)>,
+}
+
+impl QuadTreeIndex {
+    /// 场景以及所有非均匀区域的范围
+    fn bounds<S: SceneData>(scene: &S) -> Rect {
+        let (min_x, min_y) = (scene.get_min_x(), scene.get_min_y());
+        let mut rect = (
+            min_x,
+            min_y,
+            min_x + scene.get_column() as f32 * scene.grid_size(),
+            min_y + scene.get_row() as f32 * scene.grid_size(),
+        );
+        for region in scene.regions() {
+            rect.0 = rect.0.min(region.min_x);
+            rect.1 = rect.1.min(region.min_y);
+            rect.2 = rect
+                .2
+                .max(region.min_x + region.column as f32 * region.grid_size);
+            rect.3 = rect
+                .3
+                .max(region.min_y + region.row as f32 * region.grid_size);
+        }
+        rect
+    }
+}
+
+impl SpatialIndex for QuadTreeIndex {
+    fn insert<S: SceneData>(&mut self, scene: &S, id: u32, index: usize, x: f32, y: f32) {
+        // 位置不在格子中时以格子中心代替，保证与格子索引一致
+        let (x0, y0, x1, y1) = scene.grid_rect(index);
+        let (x, y) = if rect_contains((x0, y0, x1, y1), x, y) {
+            (x, y)
+        } else {
+            ((x0 + x1) / 2.0, (y0 + y1) / 2.0)
+        };
+        if self.positions.contains_key(&id) {
+            log::error!("entity:{} already in quadtree", id);
+            return;
+        }
+        let layer = index / scene.layer_grids();
+        self.roots
+            .entry(layer)
+            .or_insert_with(|| QuadNode::new(Self::bounds(scene), 0))
+            .insert(id, x, y);
+        self.positions.insert(id, (layer, index, x, y));
+    }
+
+    fn remove(&mut self, id: u32, _index: usize) -> bool {
+        match self.positions.remove(&id) {
+            Some((layer, _, x, y)) => self
+                .roots
+                .get_mut(&layer)
+                .map_or(false, |root| root.remove(id, x, y)),
+            None => false,
+        }
+    }
+
+    fn around<S: SceneData>(&self, scene: &S, index: usize, radius: i32) -> BitSet {
+        let mut set = BitSet::new();
+        if !scene.regions().is_empty() {
+            // 格子尺寸不同时，周围格子不一定正好拼成一个矩形，按照外接矩形查询后再按格子索引过滤
+            let indexes: HashSet<_> = scene.around_radius(index, radius).into_iter().collect();
+            let rect = indexes
+                .iter()
+                .map(|index| scene.grid_rect(*index))
+                .fold(scene.grid_rect(index), |a, b| {
+                    (a.0.min(b.0), a.1.min(b.1), a.2.max(b.2), a.3.max(b.3))
+                });
+            let mut found = BitSet::new();
+            for layer in scene.around_layers(index) {
+                if let Some(root) = self.roots.get(&layer) {
+                    root.query(rect, &mut found);
+                }
+            }
+            for id in found.iter() {
+                if indexes.contains(&self.positions[&id].1) {
+                    set.add(id);
+                }
+            }
+            return set;
+        }
+        let (x0, y0, x1, y1) = scene.grid_rect(index);
+        let size = (x1 - x0) * radius.max(0) as f32;
+        for layer in scene.around_layers(index) {
+            if let Some(root) = self.roots.get(&layer) {
+                root.query((x0 - size, y0 - size, x1 + size, y1 + size), &mut set);
+            }
+        }
+        set
+    }
+
+    fn grid<S: SceneData>(&self, scene: &S, index: usize) -> BitSet {
+        let mut set = BitSet::new();
+        if let Some(root) = self.roots.get(&(index / scene.layer_grids())) {
+            root.query(scene.grid_rect(index), &mut set);
+        }
+        // 非均匀区域可能覆盖在场景格子上，只保留确实在该格子中的实体
+        let other: Vec<_> = (&set)
+            .iter()
+            .filter(|id| self.positions[id].1 != index)
+            .collect();
+        for id in other {
+            set.remove(id);
+        }
+        set
+    }
+
+    fn len(&self) -> usize {
+        self.positions.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Region;
+
+    #[derive(Clone)]
+    struct TestScene {
+        regions: Vec<Region>,
+    }
+
+    impl SceneData for TestScene {
+        fn id(&self) -> u32 {
+            1
+        }
+
+        fn get_min_x(&self) -> f32 {
+            0.0
+        }
+
+        fn get_min_y(&self) -> f32 {
+            0.0
+        }
+
+        fn get_column(&self) -> i32 {
+            10
+        }
+
+        fn get_row(&self) -> i32 {
+            10
+        }
+
+        fn grid_size(&self) -> f32 {
+            10.0
+        }
+
+        fn regions(&self) -> &[Region] {
+            &self.regions
+        }
+    }
+
+    fn ids(set: BitSet) -> Vec<u32> {
+        set.iter().collect()
+    }
+
+    /// 两种索引插入相同的实体后，所有格子的查询结果一致
+    fn check_same(scene: &TestScene) {
+        let mut grid = GridIndex::default();
+        let mut tree = QuadTreeIndex::default();
+        let mut entities = Vec::new();
+        for id in 0..300u32 {
+            let x = (id * 37 % 997) as f32 / 10.0;
+            let y = (id * 53 % 991) as f32 / 10.0;
+            let index = scene.grid_index(x, y).unwrap();
+            grid.insert(scene, id, index, x, y);
+            tree.insert(scene, id, index, x, y);
+            entities.push((id, index));
+        }
+        let compare = |grid: &GridIndex, tree: &QuadTreeIndex| {
+            assert_eq!(grid.len(), tree.len());
+            for index in 0..scene.layer_grids() {
+                for radius in 0..3 {
+                    assert_eq!(
+                        ids(grid.around(scene, index, radius)),
+                        ids(tree.around(scene, index, radius)),
+                        "around index:{} radius:{}",
+                        index,
+                        radius
+                    );
+                }
+            }
+            for (_, index) in &entities {
+                assert_eq!(ids(grid.grid(scene, *index)), ids(tree.grid(scene, *index)));
+            }
+        };
+        compare(&grid, &tree);
+
+        for (id, index) in entities.iter().step_by(2) {
+            assert!(grid.remove(*id, *index));
+            assert!(tree.remove(*id, *index));
+        }
+        assert!(!tree.remove(0, entities[0].1));
+        compare(&grid, &tree);
+    }
+
+    #[test]
+    fn quadtree_uniform() {
+        check_same(&TestScene {
+            regions: Vec::new(),
+        });
+    }
+
+    #[test]
+    fn quadtree_regions() {
+        check_same(&TestScene {
+            regions: vec![
+                Region {
+                    min_x: 20.0,
+                    min_y: 20.0,
+                    column: 4,
+                    row: 4,
+                    grid_size: 2.5,
+                },
+                Region {
+                    min_x: 50.0,
+                    min_y: 0.0,
+                    column: 2,
+                    row: 3,
+                    grid_size: 15.0,
+                },
+            ],
+        });
+    }
+}
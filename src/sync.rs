@@ -1,31 +1,107 @@
 use crate::SyncDirection;
+use specs::Entity;
+use std::{
+    collections::hash_map::DefaultHasher,
+    fmt::{self, Debug, Write},
+    hash::Hasher,
+};
 
 pub trait DataSet: Clone {
     fn commit(&mut self);
 
     fn encode(&mut self, id: u32, dir: SyncDirection) -> Option<Vec<u8>>;
 
+    /// 去掉客户端权威的字段，避免回传给所属客户端
+    fn mask_owner_fields(&mut self);
+
+    /// 只编码外圈格子需要的字段，需要在encode(Around)之前调用，不会清除修改记录
+    fn encode_lod(&mut self, id: u32) -> Option<Vec<u8>>;
+
+    /// 是否配置了外圈格子的同步字段
+    fn has_lod() -> bool;
+
+    /// 周围方向每秒同步的次数，0表示每次提交都同步
+    fn sync_rate() -> u32;
+
     fn is_data_dirty(&self) -> bool;
 
     fn is_direction_enabled(dir: SyncDirection) -> bool;
 }
 
+/// 增量压缩时map键以及值的摘要，基于Debug输出计算，不要求类型实现Hash
+pub fn delta_digest<T: Debug + ?Sized>(value: &T) -> u64 {
+    struct DigestWriter(DefaultHasher);
+
+    impl Write for DigestWriter {
+        fn write_str(&mut self, s: &str) -> fmt::Result {
+            self.0.write(s.as_bytes());
+            Ok(())
+        }
+    }
+
+    let mut writer = DigestWriter(DefaultHasher::new());
+    let _ = write!(writer, "{:?}", value);
+    writer.0.finish()
+}
+
+/// 可以保存到快照中的数据，恢复后不带修改记录
+pub trait Persist: Sized {
+    fn to_bytes(&self) -> Option<Vec<u8>>;
+
+    fn from_bytes(data: &[u8]) -> Option<Self>;
+}
+
+/// 带有过期条目的数据集，时间均为unix毫秒时间戳，0表示永不过期
+pub trait Expire {
+    fn has_expired(&self, now: u64) -> bool;
+
+    /// 删除过期的条目，修改后的字段会通过正常的同步流程通知客户端
+    fn expire(&mut self, now: u64);
+}
+
 pub trait DataBackend {
     type Connection;
 
     type Error;
 
+    /// 异步保存请求，由`DatabaseSystem`发送给写线程
+    type Request;
+
+    /// 写线程返回的保存结果，由写线程发布到`EventChannel<Self::Report>`
+    type Report: Send + Sync + 'static;
+
+    /// 数据库分片数量，没有配置分片时为1
+    const SHARDS: usize;
+
     fn patch_table(
         conn: &mut Self::Connection,
         exec: bool,
         database: Option<&str>,
     ) -> Result<Vec<String>, Self::Error>;
 
+    /// 保存以及加载时使用的分片序号，由分片字段取模得到
+    fn shard(&self) -> usize;
+
     fn select(&mut self, conn: &mut Self::Connection) -> Result<bool, Self::Error>;
 
     fn insert(&mut self, conn: &mut Self::Connection) -> Result<bool, Self::Error>;
 
     fn update(&mut self, conn: &mut Self::Connection) -> Result<bool, Self::Error>;
 
+    /// 生成只包含修改字段的保存请求，清除的修改记录保留到写入结果返回，没有修改时返回None
+    fn dirty_request(&mut self, entity: Entity) -> Result<Option<Self::Request>, Self::Error>;
+
+    /// 保存结果对应的实体，不是本数据集的结果时返回None
+    fn report_entity(report: &Self::Report) -> Option<Entity>;
+
+    /// 处理本数据集的保存结果，写入失败时恢复请求生成时清除的修改记录，返回是否需要重新保存
+    fn confirm_save(&mut self, report: &Self::Report) -> bool;
+
+    /// 只更新数据库方向上修改过的字段，没有修改时不访问数据库
+    fn update_dirty(&mut self, conn: &mut Self::Connection) -> Result<bool, Self::Error>;
+
+    /// 不存在时插入，存在时更新非主键字段
+    fn upsert(&mut self, conn: &mut Self::Connection) -> Result<bool, Self::Error>;
+
     fn delete(self, conn: &mut Self::Connection) -> Result<bool, Self::Error>;
 }
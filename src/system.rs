@@ -1,20 +1,42 @@
 use crate::{
-    backend::{DropEntity, DummySceneSyncBackend},
-    component::{AroundFullData, Closing, SceneMember, TeamFullData, TeamMember},
-    dynamic::{get_library_name, Library},
+    backend::{DropEntity, DummySceneSyncBackend, Output},
+    component::{
+        AccountId, AroundFullData, Authority, Bandwidth, Closing, Cooldown, Disconnected,
+        EnteredAoi, GroupFullData, InputSequence, InvalidMove, Kicked, LeftAoi, Loading, Member,
+        Origin, ReconnectEvent, RemoteMember, SceneMember, SessionKey, StaticEntity, SyncPriority,
+        Throttled,
+    },
+    dlog::trace_entity,
+    dynamic::get_library_name,
     events_to_bitsets,
-    network::BytesSender,
-    resource::{FrameCounter, SceneManager, TeamHierarchy, TimeStatistic},
-    DataSet, DynamicManager, NetToken, SceneSyncBackend, SelfSender, SyncDirection,
+    network::{
+        session_frame, BytesSender, Connected, RelaySender, DUPLICATE_LOGIN_CMD, LOGIN_QUEUE_CMD,
+        RESUME_CMD, SEQUENCE_CMD,
+    },
+    resource::{
+        BenchRecord, FrameBudget, FrameCounter, GroupHierarchy, LoginQueue, NetworkMetrics,
+        ObserverManager, SceneManager, SceneMetrics, SceneSyncStat, SpawnBudget, SyncBudget,
+        TeamManager, TimeStatistic, WorldStats,
+    },
+    DataBackend, DataSet, DynamicManager, Expire, NetToken, Position, SceneData, SceneSyncBackend,
+    SelfSender, ShutdownToken, SyncDirection,
 };
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
 use crossbeam::channel::{Receiver, Sender};
 use mio::Token;
+use mysql::{Pool, PooledConn};
 use notify::{DebouncedEvent, RecommendedWatcher, RecursiveMode, Watcher};
 use protobuf::Mask;
+use rand_core::{OsRng, RngCore};
 use specs::{
-    hibitset::BitSetLike, prelude::ComponentEvent, shred::SystemData, storage::GenericWriteStorage,
+    hibitset::BitSetLike,
+    prelude::ComponentEvent,
+    shred::SystemData,
+    shrev::EventChannel,
+    storage::{GenericWriteStorage, MaskedStorage},
     BitSet, Component, Entities, Entity, Join, LazyUpdate, Read, ReadExpect, ReadStorage, ReaderId,
-    RunNow, System, Tracked, World, WorldExt, WriteExpect, WriteStorage,
+    RunNow, System, Tracked, World, WorldExt, Write, WriteExpect, WriteStorage,
 };
 use specs_hierarchy::{HierarchySystem, Parent};
 use std::{
@@ -22,32 +44,100 @@ use std::{
     fmt::Debug,
     marker::PhantomData,
     ops::{Deref, DerefMut},
-    time::{Duration, UNIX_EPOCH},
+    sync::Arc,
+    time::{Duration, Instant, UNIX_EPOCH},
 };
 
+/// 把中转服务转发来的数据发给本服实体的连接
+pub struct RelaySystem {
+    receiver: Receiver<(u32, Vec<u8>)>,
+}
+
+impl RelaySystem {
+    pub fn new(receiver: Receiver<(u32, Vec<u8>)>) -> Self {
+        Self { receiver }
+    }
+}
+
+impl<'a> System<'a> for RelaySystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, NetToken>,
+        Read<'a, BytesSender>,
+    );
+
+    fn run(&mut self, (entities, tokens, sender): Self::SystemData) {
+        for (id, bytes) in self.receiver.try_iter() {
+            match tokens.get(entities.entity(id)) {
+                Some(token) => sender.send_bytes(token.token(), bytes),
+                None => log::debug!("relay target entity:{} not connected", id),
+            }
+        }
+    }
+}
+
 pub struct HandshakeSystem {
-    receiver: Receiver<Token>,
+    receiver: Receiver<Connected>,
+    /// 排队中的连接所属的监听序号
+    origins: HashMap<Token, usize>,
 }
 
 impl HandshakeSystem {
-    pub fn new(receiver: Receiver<Token>) -> Self {
-        Self { receiver }
+    pub fn new(receiver: Receiver<Connected>) -> Self {
+        Self {
+            receiver,
+            origins: Default::default(),
+        }
     }
 }
 
 impl<'a> System<'a> for HandshakeSystem {
     type SystemData = (
         WriteStorage<'a, NetToken>,
+        WriteStorage<'a, Origin>,
         Entities<'a>,
         ReadExpect<'a, BytesSender>,
         WriteStorage<'a, SelfSender>,
+        Write<'a, LoginQueue>,
+        Read<'a, SpawnBudget>,
     );
 
-    fn run(&mut self, (mut net_token, entities, sender, mut ss): Self::SystemData) {
-        self.receiver.try_iter().for_each(|token| {
+    fn run(
+        &mut self,
+        (mut net_token, mut origin, entities, sender, mut ss, mut queue, budget): Self::SystemData,
+    ) {
+        let mut changed = false;
+        for connected in self.receiver.try_iter() {
+            if connected.closed {
+                // 已经放行的连接在收到实体后按正常流程关闭
+                if queue.remove(connected.token) {
+                    self.origins.remove(&connected.token);
+                    sender.send_close(connected.token, true);
+                    changed = true;
+                }
+                continue;
+            }
+            self.origins.insert(connected.token, connected.listener);
+            queue.push(connected.token);
+            changed = true;
+        }
+        if queue.is_empty() {
+            return;
+        }
+        let players = net_token.join().count();
+        let admitted = queue.admit(players, &budget);
+        if !admitted.is_empty() {
+            changed = true;
+        }
+        for (token, position) in queue.notify(changed) {
+            sender.send_control(token, LOGIN_QUEUE_CMD, position as u32);
+        }
+        admitted.into_iter().for_each(|token| {
+            let listener = self.origins.remove(&token).unwrap_or_default();
             let entity = entities
                 .build_entity()
                 .with(NetToken::new(token.0), &mut net_token)
+                .with(Origin(listener), &mut origin)
                 .build();
             sender.send_entity(token, entity);
             if let Err(err) = ss.insert(entity, SelfSender::new(entity.id(), token, sender.clone()))
@@ -88,48 +178,806 @@ where
                 }
             });
     }
-}
+}
+
+/// 记录请求携带的客户端序号，需要以thread local方式添加。
+/// 序号在请求转发给ECS之后才发送，本帧收到的序号对应的请求最迟在下一帧处理，
+/// 因此确认帧延后一帧在所有系统执行完后返回，保证客户端收到确认时请求已经处理
+pub struct AckSystem {
+    receiver: Receiver<(Entity, u32)>,
+    pending: HashMap<Entity, u32>,
+}
+
+impl AckSystem {
+    pub fn new(receiver: Receiver<(Entity, u32)>) -> Self {
+        Self {
+            receiver,
+            pending: Default::default(),
+        }
+    }
+}
+
+impl<'a> System<'a> for AckSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, NetToken>,
+        WriteStorage<'a, InputSequence>,
+        Read<'a, BytesSender>,
+    );
+
+    fn run(&mut self, (entities, tokens, mut sequences, sender): Self::SystemData) {
+        for (entity, seq) in self.pending.drain() {
+            if let Some(token) = tokens.get(entity) {
+                sender.send_control(token.token(), SEQUENCE_CMD, seq);
+            }
+        }
+        for (entity, seq) in self.receiver.try_iter() {
+            if !entities.is_alive(entity) {
+                continue;
+            }
+            let sequence = match sequences.entry(entity) {
+                Ok(entry) => entry.or_insert_with(Default::default),
+                Err(err) => {
+                    log::error!("get InputSequence failed:{}", err);
+                    continue;
+                }
+            };
+            match sequence.update(seq) {
+                Some(seq) => {
+                    self.pending.insert(entity, seq);
+                }
+                None => log::warn!(
+                    "entity:{} request seq:{} reordered, last:{}",
+                    entity.id(),
+                    seq,
+                    sequence.last()
+                ),
+            }
+        }
+    }
+}
+
+/// 每秒统计一次连接的发送字节数并更新Bandwidth组件，新连接使用默认的带宽上限
+pub struct BandwidthSystem {
+    default_cap: usize,
+    last: Instant,
+}
+
+impl BandwidthSystem {
+    pub fn new(default_cap: usize) -> Self {
+        Self {
+            default_cap,
+            last: Instant::now(),
+        }
+    }
+}
+
+impl<'a> System<'a> for BandwidthSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, NetToken>,
+        WriteStorage<'a, Bandwidth>,
+        Read<'a, BytesSender>,
+    );
+
+    fn run(&mut self, (entities, tokens, mut bandwidth, sender): Self::SystemData) {
+        let elapsed = self.last.elapsed();
+        if elapsed < Duration::from_secs(1) {
+            return;
+        }
+        self.last = Instant::now();
+        let traffic = sender.take_traffic();
+        for (entity, token) in (&entities, &tokens).join() {
+            let bytes = traffic.get(&token.token()).cloned().unwrap_or_default();
+            let rate = (bytes as f64 / elapsed.as_secs_f64()) as usize;
+            match bandwidth.entry(entity) {
+                Ok(entry) => {
+                    let default_cap = self.default_cap;
+                    let bandwidth = entry.or_insert_with(|| Bandwidth::new(default_cap));
+                    bandwidth.set_rate(rate);
+                    if bandwidth.is_over_cap() {
+                        log::warn!(
+                            "entity:{} bandwidth:{} is over cap:{}",
+                            entity.id(),
+                            rate,
+                            bandwidth.cap()
+                        );
+                    }
+                }
+                Err(err) => log::error!("get Bandwidth failed:{}", err),
+            }
+        }
+    }
+}
+
+/// 定期清理数据集中过期的条目，需要在对应的CommitChangeSystem之前运行
+pub struct ExpireSystem<T> {
+    interval: Duration,
+    last: Instant,
+    _phantom: PhantomData<T>,
+}
+
+impl<T> Default for ExpireSystem<T> {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(1))
+    }
+}
+
+impl<T> ExpireSystem<T> {
+    pub fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: Instant::now(),
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<'a, T> System<'a> for ExpireSystem<T>
+where
+    T: Component + DerefMut,
+    <T as Deref>::Target: Expire,
+{
+    type SystemData = (Entities<'a>, WriteStorage<'a, T>);
+
+    fn run(&mut self, (entities, mut data): Self::SystemData) {
+        if self.last.elapsed() < self.interval {
+            return;
+        }
+        self.last = Instant::now();
+        let now = crate::unix_timestamp().as_millis() as u64;
+        let expired: Vec<_> = (&entities, &data)
+            .join()
+            .filter(|(_, data)| data.has_expired(now))
+            .map(|(entity, _)| entity)
+            .collect();
+        for entity in expired {
+            if let Some(data) = data.get_mut(entity) {
+                data.expire(now);
+            }
+        }
+    }
+}
+
+/// 在输入系统之后、逻辑系统之前运行，冷却中的请求被标记为Throttled而不会被处理
+pub struct CooldownSystem<T, const ID: u32> {
+    duration: Duration,
+    _phantom: PhantomData<T>,
+}
+
+impl<T, const ID: u32> CooldownSystem<T, ID> {
+    pub fn new(duration: Duration) -> Self {
+        Self {
+            duration,
+            _phantom: Default::default(),
+        }
+    }
+}
+
+impl<'a, T, const ID: u32> System<'a> for CooldownSystem<T, ID>
+where
+    T: Component,
+{
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, T>,
+        WriteStorage<'a, Cooldown<ID>>,
+        WriteStorage<'a, Throttled<ID>>,
+    );
+
+    fn run(&mut self, (entities, input, mut cooldowns, mut throttled): Self::SystemData) {
+        throttled.clear();
+        for (entity, _) in (&entities, &input).join() {
+            let ready = if let Some(cooldown) = cooldowns.get_mut(entity) {
+                cooldown.try_reset(self.duration)
+            } else {
+                if let Err(err) = cooldowns.insert(entity, Cooldown::new()) {
+                    log::error!("insert Cooldown failed:{}", err);
+                }
+                true
+            };
+            if !ready {
+                log::debug!(
+                    "entity:{} request {} dropped for cooldown",
+                    entity.id(),
+                    std::any::type_name::<T>()
+                );
+                if let Err(err) = throttled.insert(entity, Throttled) {
+                    log::error!("insert Throttled failed:{}", err);
+                }
+            }
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        world.register::<Cooldown<ID>>();
+        world.register::<Throttled<ID>>();
+    }
+}
+
+/// 处理连接关闭，网络断开且持有会话密钥的实体不会删除，而是等待客户端重连，
+/// 服务器主动关闭的连接会标记Kicked，断开后直接删除实体
+pub struct CloseSystem;
+
+impl<'a> System<'a> for CloseSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, Closing>,
+        ReadStorage<'a, NetToken>,
+        ReadStorage<'a, SessionKey>,
+        WriteStorage<'a, Kicked>,
+        Read<'a, LazyUpdate>,
+        Read<'a, BytesSender>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut closing, tokens, keys, mut kicked, lazy_update, sender): Self::SystemData,
+    ) {
+        let (entities, tokens): (Vec<_>, Vec<_>) = (&entities, &tokens, closing.drain())
+            .join()
+            .filter_map(|(entity, token, closing)| {
+                if closing.0 {
+                    log::debug!("entity:{} has shutdown network", entity.id());
+                    Some((entity, token.token()))
+                } else {
+                    log::debug!("entity:{} has invalid data", entity.id());
+                    if let Err(err) = kicked.insert(entity, Kicked) {
+                        log::error!("insert Kicked failed:{}", err);
+                    }
+                    sender.send_close(token.token(), false);
+                    None
+                }
+            })
+            .unzip();
+        if entities.is_empty() {
+            return;
+        }
+        let (kept, entities): (Vec<_>, Vec<_>) = entities
+            .into_iter()
+            .partition(|entity| keys.contains(*entity) && !kicked.contains(*entity));
+
+        lazy_update.exec_mut(move |world| {
+            if let Err(err) = world.delete_entities(entities.as_slice()) {
+                log::error!("delete entities failed:{}", err);
+            }
+            log::debug!("{} entities deleted", entities.len());
+            {
+                let mut observers = world
+                    .entry::<ObserverManager>()
+                    .or_insert_with(Default::default);
+                entities
+                    .iter()
+                    .for_each(|entity| observers.remove_target(entity.id()));
+                tokens
+                    .iter()
+                    .for_each(|token| observers.unsubscribe_all(*token));
+            }
+            if !kept.is_empty() {
+                let now = Instant::now();
+                let mut tokens = world.write_storage::<NetToken>();
+                let mut senders = world.write_storage::<SelfSender>();
+                let mut disconnected = world.write_storage::<Disconnected>();
+                for entity in &kept {
+                    tokens.remove(*entity);
+                    senders.remove(*entity);
+                    if let Err(err) = disconnected.insert(*entity, Disconnected(now)) {
+                        log::error!("insert Disconnected failed:{}", err);
+                    }
+                }
+                log::debug!("{} entities wait for reconnect", kept.len());
+            }
+            world.read_resource::<BytesSender>().broadcast_close(tokens);
+        });
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        world.register::<Closing>();
+        world.register::<Kicked>();
+        // 没有开启断线重连时没有SessionSystem注册会话密钥
+        world.register::<SessionKey>();
+    }
+}
+
+/// 断线重连，实体建立时下发会话密钥，连接断开后实体保留grace时间，
+/// 客户端在新连接上发送RESUME_CMD请求后重新绑定到原来的实体，并插入ReconnectEvent
+pub struct SessionSystem {
+    receiver: Receiver<(Entity, Bytes)>,
+    grace: Duration,
+}
+
+impl SessionSystem {
+    pub fn new(receiver: Receiver<(Entity, Bytes)>, grace: Duration) -> Self {
+        Self { receiver, grace }
+    }
+
+    /// 会话密钥从系统随机源读取，客户端无法根据实体id和时间推算
+    fn generate_key() -> Result<u64, rand_core::Error> {
+        let mut data = [0u8; 8];
+        OsRng.try_fill_bytes(&mut data)?;
+        Ok(BigEndian::read_u64(&data))
+    }
+
+    /// 按字节比较所有位，比较耗时与密钥在哪一位不同无关
+    fn key_equal(left: u64, right: u64) -> bool {
+        left.to_be_bytes()
+            .iter()
+            .zip(right.to_be_bytes().iter())
+            .fold(0u8, |diff, (l, r)| diff | (l ^ r))
+            == 0
+    }
+
+    /// 请求内容为 cmd(4) | id(4) | key(8)，返回可以恢复的实体
+    fn check_resume(
+        data: &[u8],
+        entities: &Entities,
+        keys: &WriteStorage<SessionKey>,
+        disconnected: &WriteStorage<Disconnected>,
+    ) -> Option<Entity> {
+        if data.len() != 16 {
+            return None;
+        }
+        let entity = entities.entity(BigEndian::read_u32(&data[4..]));
+        let key = BigEndian::read_u64(&data[8..]);
+        if entities.is_alive(entity)
+            && disconnected.contains(entity)
+            && keys
+                .get(entity)
+                .map_or(false, |k| Self::key_equal(k.0, key))
+        {
+            Some(entity)
+        } else {
+            None
+        }
+    }
+}
+
+impl<'a> System<'a> for SessionSystem {
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, NetToken>,
+        WriteStorage<'a, SessionKey>,
+        WriteStorage<'a, Disconnected>,
+        WriteStorage<'a, ReconnectEvent>,
+        WriteStorage<'a, SelfSender>,
+        ReadExpect<'a, BytesSender>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut tokens, mut keys, mut disconnected, mut events, mut ss, sender): Self::SystemData,
+    ) {
+        events.clear();
+
+        let created: Vec<_> = (&entities, &tokens, !&keys)
+            .join()
+            .map(|(entity, token, _)| (entity, token.token()))
+            .collect();
+        for (entity, token) in created {
+            let key = match Self::generate_key() {
+                Ok(key) => key,
+                Err(err) => {
+                    log::error!("generate session key failed:{}", err);
+                    continue;
+                }
+            };
+            if let Err(err) = keys.insert(entity, SessionKey(key)) {
+                log::error!("insert SessionKey failed:{}", err);
+                continue;
+            }
+            sender.send_bytes(token, session_frame(entity.id(), key));
+        }
+
+        for (entity, data) in self.receiver.try_iter() {
+            let token = match tokens.get(entity) {
+                Some(token) => token.token(),
+                None => continue,
+            };
+            let old = match Self::check_resume(&data, &entities, &keys, &disconnected) {
+                Some(old) => old,
+                None => {
+                    log::warn!("entity:{} resume failed", entity.id());
+                    sender.send_control(token, RESUME_CMD, 0);
+                    continue;
+                }
+            };
+            // 新连接建立的实体已经没有用处，删除前先去掉NetToken避免关闭连接
+            tokens.remove(entity);
+            ss.remove(entity);
+            if let Err(err) = entities.delete(entity) {
+                log::error!("delete entity failed:{}", err);
+            }
+            disconnected.remove(old);
+            if let Err(err) = tokens.insert(old, NetToken::new(token.0)) {
+                log::error!("insert NetToken failed:{}", err);
+            }
+            if let Err(err) = ss.insert(old, SelfSender::new(old.id(), token, sender.clone())) {
+                log::error!("insert SelfSender failed:{}", err);
+            }
+            if let Err(err) = events.insert(old, ReconnectEvent) {
+                log::error!("insert ReconnectEvent failed:{}", err);
+            }
+            sender.send_entity(token, old);
+            sender.send_control(token, RESUME_CMD, 1);
+            log::info!("entity:{} resumed by entity:{}", old.id(), entity.id());
+        }
+
+        let expired: Vec<_> = (&entities, &disconnected)
+            .join()
+            .filter(|(_, disconnected)| disconnected.0.elapsed() > self.grace)
+            .map(|(entity, _)| entity)
+            .collect();
+        for entity in expired {
+            log::debug!("entity:{} reconnect timeout", entity.id());
+            if let Err(err) = entities.delete(entity) {
+                log::error!("delete entity failed:{}", err);
+            }
+        }
+    }
+}
+
+/// 同一账号重复登录时的处理策略
+#[derive(Debug, Clone, Copy)]
+pub enum LoginPolicy {
+    /// 踢掉旧的连接
+    KickOld,
+    /// 拒绝新的连接
+    RejectNew,
+    /// 最多允许N个连接同时在线，超出时拒绝新的连接
+    Allow(usize),
+}
+
+impl Default for LoginPolicy {
+    fn default() -> Self {
+        LoginPolicy::KickOld
+    }
+}
+
+pub struct DuplicateLoginSystem {
+    reader: ReaderId<ComponentEvent>,
+    policy: LoginPolicy,
+    /// 账号对应的在线实体，按照登录顺序排列
+    sessions: HashMap<AccountId, Vec<Entity>>,
+    accounts: HashMap<u32, AccountId>,
+}
+
+impl DuplicateLoginSystem {
+    pub fn new(world: &mut World, policy: LoginPolicy) -> Self {
+        let reader = world.write_storage::<AccountId>().register_reader();
+        Self {
+            reader,
+            policy,
+            sessions: Default::default(),
+            accounts: Default::default(),
+        }
+    }
+}
+
+impl<'a> System<'a> for DuplicateLoginSystem {
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, AccountId>,
+        ReadStorage<'a, NetToken>,
+        WriteStorage<'a, Closing>,
+        Read<'a, BytesSender>,
+    );
+
+    fn run(&mut self, (entities, accounts, tokens, mut closing, sender): Self::SystemData) {
+        let mut inserted = BitSet::new();
+        let mut modified = BitSet::new();
+        let mut removed = BitSet::new();
+        let events = accounts.channel().read(&mut self.reader);
+        events_to_bitsets(events, &mut inserted, &mut modified, &mut removed);
+
+        for id in &removed {
+            if let Some(account) = self.accounts.remove(&id) {
+                if let Some(sessions) = self.sessions.get_mut(&account) {
+                    sessions.retain(|entity| entity.id() != id);
+                    if sessions.is_empty() {
+                        self.sessions.remove(&account);
+                    }
+                }
+            }
+        }
+
+        for (entity, account, _) in (&entities, &accounts, &inserted).join() {
+            self.accounts.insert(entity.id(), *account);
+            let sessions = self.sessions.entry(*account).or_default();
+            let kicked = match self.policy {
+                LoginPolicy::KickOld => std::mem::take(sessions),
+                LoginPolicy::RejectNew if !sessions.is_empty() => vec![entity],
+                LoginPolicy::Allow(n) if sessions.len() >= n => vec![entity],
+                _ => Vec::new(),
+            };
+            if !kicked.contains(&entity) {
+                sessions.push(entity);
+            }
+            for entity in kicked {
+                log::warn!(
+                    "account:{} entity:{} closed for duplicate login",
+                    account.0,
+                    entity.id()
+                );
+                if let Some(token) = tokens.get(entity) {
+                    sender.send_control(token.token(), DUPLICATE_LOGIN_CMD, 0);
+                }
+                if let Err(err) = closing.insert(entity, Closing(false)) {
+                    log::error!("insert Closing failed:{}", err);
+                }
+            }
+        }
+    }
+}
+
+/// 登录时从数据库加载数据集，账号插入后在工作线程中执行生成的SELECT，
+/// 等待期间实体带有`Loading`标记，查询返回后插入数据集组件
+pub struct LoadEntitySystem<B> {
+    reader: ReaderId<ComponentEvent>,
+    sender: Sender<(Entity, B)>,
+    receiver: Receiver<(Entity, Result<B, String>)>,
+    /// 根据账号生成只设置了主键的数据集
+    key: fn(&AccountId) -> B,
+}
+
+impl<B> LoadEntitySystem<B>
+where
+    B: DataBackend<Connection = PooledConn> + Send + 'static,
+    B::Error: Debug,
+{
+    /// 每个分片一个连接池，与`dataproxy::Writer::with_shards`一致，加载请求按`DataBackend::shard`路由
+    pub fn new(
+        world: &mut World,
+        pools: Vec<Pool>,
+        workers: usize,
+        key: fn(&AccountId) -> B,
+    ) -> Self {
+        assert_eq!(
+            pools.len(),
+            B::SHARDS,
+            "database pool count does not match shard count"
+        );
+        let reader = world.write_storage::<AccountId>().register_reader();
+        let (sender, requests) = crossbeam::channel::unbounded::<(Entity, B)>();
+        let (results, receiver) = crossbeam::channel::unbounded();
+        let shards: Vec<_> = pools
+            .into_iter()
+            .enumerate()
+            .map(|(shard, pool)| {
+                let (sender, requests) = crossbeam::channel::unbounded::<(Entity, B)>();
+                Self::spawn_loaders(shard, pool, workers, requests, results.clone());
+                sender
+            })
+            .collect();
+        std::thread::Builder::new()
+            .name("db_loader".into())
+            .spawn(move || {
+                for (entity, data) in requests {
+                    let shard = data.shard();
+                    match shards.get(shard) {
+                        Some(sender) => {
+                            if sender.send((entity, data)).is_err() {
+                                break;
+                            }
+                        }
+                        None => {
+                            let err = format!("invalid shard:{}", shard);
+                            if results.send((entity, Err(err))).is_err() {
+                                break;
+                            }
+                        }
+                    }
+                }
+            })
+            .unwrap();
+        Self {
+            reader,
+            sender,
+            receiver,
+            key,
+        }
+    }
+
+    fn spawn_loaders(
+        shard: usize,
+        pool: Pool,
+        workers: usize,
+        requests: Receiver<(Entity, B)>,
+        results: Sender<(Entity, Result<B, String>)>,
+    ) {
+        for index in 0..workers.max(1) {
+            let pool = pool.clone();
+            let requests = requests.clone();
+            let results = results.clone();
+            std::thread::Builder::new()
+                .name(format!("db_loader_{}_{}", shard, index))
+                .spawn(move || {
+                    for (entity, mut data) in requests {
+                        let result = pool
+                            .get_conn()
+                            .map_err(|err| err.to_string())
+                            .and_then(|mut conn| {
+                                data.select(&mut conn).map_err(|err| format!("{:?}", err))
+                            })
+                            .map(|found| {
+                                if !found {
+                                    log::info!("entity:{} not found in database", entity.id());
+                                }
+                                data
+                            });
+                        if results.send((entity, result)).is_err() {
+                            break;
+                        }
+                    }
+                })
+                .unwrap();
+        }
+    }
+}
+
+impl<'a, B> System<'a> for LoadEntitySystem<B>
+where
+    B: Component + DataBackend + Send + 'static,
+{
+    type SystemData = (
+        Entities<'a>,
+        ReadStorage<'a, AccountId>,
+        WriteStorage<'a, Loading>,
+        WriteStorage<'a, B>,
+        WriteStorage<'a, Closing>,
+    );
+
+    fn run(&mut self, (entities, accounts, mut loading, mut data, mut closing): Self::SystemData) {
+        let mut inserted = BitSet::new();
+        let mut modified = BitSet::new();
+        let mut removed = BitSet::new();
+        let events = accounts.channel().read(&mut self.reader);
+        events_to_bitsets(events, &mut inserted, &mut modified, &mut removed);
+
+        for (entity, account, _) in (&entities, &accounts, &inserted).join() {
+            if let Err(err) = loading.insert(entity, Loading) {
+                log::error!("insert Loading failed:{}", err);
+                continue;
+            }
+            if let Err(err) = self.sender.send((entity, (self.key)(account))) {
+                log::error!("send load request failed:{}", err);
+            }
+        }
+
+        for (entity, result) in self.receiver.try_iter() {
+            // 加载期间实体已经被删除
+            if !entities.is_alive(entity) || loading.remove(entity).is_none() {
+                log::warn!("entity:{} gone before loaded", entity.id());
+                continue;
+            }
+            match result {
+                Ok(result) => {
+                    if let Err(err) = data.insert(entity, result) {
+                        log::error!("insert loaded data failed:{}", err);
+                    }
+                }
+                Err(err) => {
+                    log::error!("load entity:{} failed:{}", entity.id(), err);
+                    if let Err(err) = closing.insert(entity, Closing(false)) {
+                        log::error!("insert Closing failed:{}", err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// 定期把修改过的数据集生成保存请求发给写线程，实体关闭以及停服时立即保存，
+/// 写入失败的数据集恢复修改记录后在下一次保存时重试，
+/// 需要在World中插入`Sender<B::Request>`，并且在数据集的CommitChangeSystem之后执行
+pub struct DatabaseSystem<T: DataBackend> {
+    reader: ReaderId<ComponentEvent>,
+    reports: ReaderId<T::Report>,
+    sender: Sender<T::Request>,
+    dirty: BitSet,
+    interval: Duration,
+    last: Instant,
+}
+
+impl<T> DatabaseSystem<T>
+where
+    T: Component + DataBackend,
+    <T as Component>::Storage: Tracked + Default,
+    T::Request: Send + Sync + 'static,
+{
+    /// World中没有保存请求的发送端时返回None
+    pub fn try_new(world: &mut World) -> Option<Self> {
+        let sender = world
+            .try_fetch::<Sender<T::Request>>()
+            .map(|sender| sender.deref().clone())?;
+        let reader = world.write_storage::<T>().register_reader();
+        let reports = world
+            .entry::<EventChannel<T::Report>>()
+            .or_insert_with(Default::default)
+            .register_reader();
+        Some(Self {
+            reader,
+            reports,
+            sender,
+            dirty: BitSet::new(),
+            interval: Duration::from_secs(5),
+            last: Instant::now(),
+        })
+    }
 
-pub struct CloseSystem;
+    /// 定期保存的间隔，默认5秒
+    pub fn with_interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
 
-impl<'a> System<'a> for CloseSystem {
+impl<'a, T> System<'a> for DatabaseSystem<T>
+where
+    T: Component + DataBackend,
+    <T as Component>::Storage: Tracked,
+    T::Error: Debug,
+    T::Request: Send,
+{
     type SystemData = (
         Entities<'a>,
-        WriteStorage<'a, Closing>,
-        ReadStorage<'a, NetToken>,
-        Read<'a, LazyUpdate>,
-        Read<'a, BytesSender>,
+        WriteStorage<'a, T>,
+        ReadStorage<'a, Closing>,
+        Read<'a, ShutdownToken>,
+        Read<'a, EventChannel<T::Report>>,
     );
 
-    fn run(&mut self, (entities, mut closing, tokens, lazy_update, sender): Self::SystemData) {
-        let (entities, tokens): (Vec<_>, Vec<_>) = (&entities, &tokens, closing.drain())
-            .join()
-            .filter_map(|(entity, token, closing)| {
-                if closing.0 {
-                    log::debug!("entity:{} has shutdown network", entity.id());
-                    Some((entity, token.token()))
-                } else {
-                    log::debug!("entity:{} has invalid data", entity.id());
-                    sender.send_close(token.token(), false);
-                    None
+    fn run(&mut self, (entities, data, closing, shutdown, reports): Self::SystemData) {
+        // 写入结果只修改数据库方向的修改记录，不需要触发修改事件
+        for report in reports.read(&mut self.reports) {
+            let entity = match T::report_entity(report) {
+                Some(entity) if entities.is_alive(entity) => entity,
+                _ => continue,
+            };
+            if let Some(data) = data.get(entity) {
+                let data = unsafe { &mut *(data as *const T as *mut T) };
+                if data.confirm_save(report) {
+                    log::warn!("save entity:{} failed, retry later", entity.id());
+                    self.dirty.add(entity.id());
                 }
-            })
-            .unzip();
-        if entities.is_empty() {
-            return;
+            }
         }
 
-        lazy_update.exec_mut(move |world| {
-            if let Err(err) = world.delete_entities(entities.as_slice()) {
-                log::error!("delete entities failed:{}", err);
-            }
-            log::debug!("{} entities deleted", entities.len());
-            world.read_resource::<BytesSender>().broadcast_close(tokens);
-        });
-    }
+        let mut inserted = BitSet::new();
+        let mut modified = BitSet::new();
+        let mut removed = BitSet::new();
+        let events = data.channel().read(&mut self.reader);
+        events_to_bitsets(events, &mut inserted, &mut modified, &mut removed);
+        self.dirty |= &inserted;
+        self.dirty |= &modified;
+        self.dirty &= &!&removed;
 
-    fn setup(&mut self, world: &mut World) {
-        world.register::<Closing>();
+        let flush = if shutdown.is_shutdown() || self.last.elapsed() >= self.interval {
+            self.last = Instant::now();
+            std::mem::take(&mut self.dirty)
+        } else {
+            // 即将删除的实体立即保存
+            let mut closed = closing.mask().clone();
+            closed &= &self.dirty;
+            self.dirty &= &!&closed;
+            closed
+        };
+        // 只清除数据库方向的修改记录，不需要触发修改事件
+        for (entity, data, _) in (&entities, &data, &flush).join() {
+            let data = unsafe { &mut *(data as *const T as *mut T) };
+            match data.dirty_request(entity) {
+                Ok(Some(request)) => {
+                    if let Err(err) = self.sender.send(request) {
+                        log::error!("send save request failed:{}", err);
+                    }
+                }
+                Ok(None) => {}
+                Err(err) => log::error!(
+                    "generate save request of entity:{} failed:{:?}",
+                    entity.id(),
+                    err
+                ),
+            }
+        }
     }
 }
 
@@ -169,9 +1017,7 @@ impl<'a> RunNow<'a> for FsNotifySystem {
                 log::debug!("path:{:?} changed", path);
                 if let Some(lname) = get_library_name(path) {
                     log::warn!("library {} updated", lname);
-                    let lib = dm.get(&lname);
-                    let lib = unsafe { &mut *(lib.as_ref() as *const Library as *mut Library) };
-                    lib.reload();
+                    dm.reload(&lname);
                 }
             }
             DebouncedEvent::Error(err, path) => {
@@ -184,12 +1030,48 @@ impl<'a> RunNow<'a> for FsNotifySystem {
     fn setup(&mut self, _world: &mut World) {}
 }
 
-pub struct CommitChangeSystem<T, B = DummySceneSyncBackend> {
+/// 组内其他服务器上的成员通过中转连接发送
+fn relay_bytes(
+    remote: &ReadStorage<RemoteMember>,
+    relay: &RelaySender,
+    members: &BitSet,
+    bytes: &[u8],
+) {
+    for (remote, _) in (remote, members).join() {
+        relay.send_bytes(remote.server, remote.entity, bytes.to_vec());
+    }
+}
+
+/// 在数据头之后插入8字节的时间戳，并修正长度
+fn stamp_bytes(mut bytes: Vec<u8>, timestamp: u64) -> Vec<u8> {
+    bytes.splice(12..12, timestamp.to_be_bytes().iter().cloned());
+    let length = (bytes.len() - 4) as u32;
+    BigEndian::write_u32(bytes.as_mut_slice(), length);
+    bytes
+}
+
+/// 每帧最多给多少个观察者补发延后的完整数据，
+/// 帧预算恢复后所有连接都有延后的数据，分散到多帧补发避免同一帧集中发送
+const MAX_DEFERRED_VIEWERS: usize = 64;
+
+/// G为组队方向同步到的组，对应`Member<G>`
+pub struct CommitChangeSystem<T, B = DummySceneSyncBackend, const G: usize = 0> {
     reader: ReaderId<ComponentEvent>,
+    /// 带宽超限而延后同步的数据，观察者id -> 实体集合
+    deferred: HashMap<u32, BitSet>,
+    /// 只收到外圈字段的观察者，实体id -> 观察者集合
+    lod_stale: HashMap<u32, BitSet>,
+    /// 移动数据等待批量同步的实体
+    moving: BitSet,
+    last_flush: Instant,
+    /// 已经补发过完整数据的订阅序号
+    observer_seen: usize,
+    /// 已经收到过全服数据的玩家
+    world_seen: BitSet,
     _phantom: PhantomData<(T, B)>,
 }
 
-impl<T, B> CommitChangeSystem<T, B>
+impl<T, B, const G: usize> CommitChangeSystem<T, B, G>
 where
     T: Component + Send + Sync + 'static,
     <T as Component>::Storage: Tracked + Default,
@@ -198,12 +1080,18 @@ where
         let reader = world.write_storage::<T>().register_reader();
         Self {
             reader,
+            deferred: Default::default(),
+            lod_stale: Default::default(),
+            moving: Default::default(),
+            last_flush: Instant::now(),
+            observer_seen: 0,
+            world_seen: Default::default(),
             _phantom: Default::default(),
         }
     }
 }
 
-impl<'a, T, B> System<'a> for CommitChangeSystem<T, B>
+impl<'a, T, B, const G: usize> System<'a> for CommitChangeSystem<T, B, G>
 where
     T: Component + DataSet,
     <T as Component>::Storage: Tracked,
@@ -216,20 +1104,62 @@ where
     type SystemData = (
         WriteStorage<'a, T>,
         ReadStorage<'a, NetToken>,
-        ReadStorage<'a, TeamMember>,
-        ReadExpect<'a, TeamHierarchy>,
+        ReadStorage<'a, Member<G>>,
+        ReadExpect<'a, GroupHierarchy<G>>,
         Read<'a, BytesSender>,
         Entities<'a>,
         ReadExpect<'a, SceneManager<B>>,
         ReadStorage<'a, AroundFullData>,
-        ReadStorage<'a, TeamFullData>,
+        ReadStorage<'a, GroupFullData<G>>,
+        ReadStorage<'a, Authority>,
+        ReadStorage<'a, Bandwidth>,
+        Read<'a, FrameBudget>,
+        ReadStorage<'a, SyncPriority>,
+        Read<'a, SyncBudget>,
+        Read<'a, FrameCounter>,
+        ReadStorage<'a, B::Visibility>,
+        Read<'a, SceneMetrics>,
+        Read<'a, ObserverManager>,
+        ReadStorage<'a, RemoteMember>,
+        Read<'a, RelaySender>,
     );
 
     fn run(
         &mut self,
-        (data, token, teams, hteams, sender, entities, gm, new_scene_member, new_team_member): Self::SystemData,
+        (
+            data,
+            token,
+            teams,
+            hteams,
+            sender,
+            entities,
+            gm,
+            new_scene_member,
+            new_team_member,
+            authority,
+            bandwidth,
+            budget,
+            priority,
+            sync_budget,
+            frame,
+            visibility,
+            metrics,
+            observers,
+            remote,
+            relay,
+        ): Self::SystemData,
     ) {
         //log::info!("CommitChangeSystem:{}", std::any::type_name::<T>());
+        let mut stats: HashMap<u32, SceneSyncStat> = HashMap::new();
+        // 移动数据周围方向的消息都带上时间戳，客户端据此插值
+        let timestamp = crate::unix_timestamp().as_millis() as u64;
+        let stamp = |bytes| {
+            if T::sync_rate() > 0 {
+                stamp_bytes(bytes, timestamp)
+            } else {
+                bytes
+            }
+        };
         // 处理有新玩家进入时需要完整数据集的情况
         if T::is_direction_enabled(SyncDirection::Around) {
             for (data, member, entity) in (&data, &new_scene_member, &entities).join() {
@@ -245,8 +1175,13 @@ where
                 data.mask_all(true);
                 data.commit();
                 if let Some(bytes) = data.encode(entity.id(), SyncDirection::Around) {
+                    let bytes = stamp(bytes);
                     let tokens = NetToken::tokens(&token, member.mask());
-                    sender.broadcast_bytes(tokens, bytes)
+                    if let Some(scene) = gm.get_user_scene(entity.id()) {
+                        stats.entry(scene.id()).or_default().full_data_bytes +=
+                            bytes.len() * tokens.len();
+                    }
+                    sender.sync_bytes(tokens, bytes)
                 } else {
                     log::warn!("full data synchronization required, but nothing to send");
                 }
@@ -267,8 +1202,9 @@ where
                 data.mask_all(true);
                 data.commit();
                 if let Some(bytes) = data.encode(entity.id(), SyncDirection::Team) {
+                    relay_bytes(&remote, &relay, member.mask(), bytes.as_slice());
                     let tokens = NetToken::tokens(&token, member.mask());
-                    sender.broadcast_bytes(tokens, bytes)
+                    sender.sync_bytes(tokens, bytes)
                 } else {
                     log::warn!("full data synchronization required, but nothing to send");
                 }
@@ -297,8 +1233,18 @@ where
         if T::is_direction_enabled(SyncDirection::Client) {
             for (data, id, token) in (&data, &(&modified | &inserted), &token).join() {
                 let data = unsafe { &mut *(data as *const T as *mut T) };
+                if modified.contains(id)
+                    && authority.get(entities.entity(id)) == Some(&Authority::Client)
+                {
+                    data.mask_owner_fields();
+                }
                 let bytes = data.encode(id, SyncDirection::Client);
                 if let Some(bytes) = bytes {
+                    trace_entity(
+                        id,
+                        "sync",
+                        format_args!("{} client size:{}", std::any::type_name::<T>(), bytes.len()),
+                    );
                     sender.send_bytes(token.token(), bytes);
                 }
             }
@@ -309,21 +1255,257 @@ where
             for (data, id, team) in (&data, &modified, &teams).join() {
                 let data = unsafe { &mut *(data as *const T as *mut T) };
                 if let Some(bytes) = data.encode(id, SyncDirection::Team) {
+                    trace_entity(
+                        id,
+                        "sync",
+                        format_args!("{} team size:{}", std::any::type_name::<T>(), bytes.len()),
+                    );
                     let members = hteams.all_children(team.parent_entity());
+                    relay_bytes(&remote, &relay, &members, bytes.as_slice());
                     let tokens = NetToken::tokens(&token, &members);
-                    sender.broadcast_bytes(tokens, bytes);
+                    sender.sync_bytes(tokens, bytes);
                 }
             }
         }
 
         // 处理针对场景的数据集
         if T::is_direction_enabled(SyncDirection::Around) {
-            for (data, id, entity, _) in (&data, &modified, &entities, !&new_scene_member).join() {
+            let mut over_cap = BitSet::new();
+            for (bandwidth, entity) in (&bandwidth, &entities).join() {
+                if bandwidth.is_over_cap() {
+                    over_cap.add(entity.id());
+                }
+            }
+            // 帧预算超出时周围实体的同步全部延后到下一帧
+            if budget.is_deferring() {
+                for (_, entity) in (&token, &entities).join() {
+                    over_cap.add(entity.id());
+                }
+            }
+
+            // 带宽恢复后补发延后的完整数据，每帧只处理一部分观察者
+            let deferred: Vec<_> = self
+                .deferred
+                .keys()
+                .filter(|viewer| !over_cap.contains(**viewer))
+                .take(MAX_DEFERRED_VIEWERS)
+                .cloned()
+                .collect();
+            for viewer in deferred {
+                let ids = self.deferred.remove(&viewer).unwrap();
+                let viewer_token = match token.get(entities.entity(viewer)) {
+                    Some(viewer_token) => viewer_token.token(),
+                    None => continue,
+                };
+                for (data, id) in (&data, &ids).join() {
+                    if !gm.get_user_around(id, &visibility).contains(viewer) {
+                        continue;
+                    }
+                    let mut data = data.clone();
+                    data.mask_all(true);
+                    data.commit();
+                    if let Some(bytes) = data.encode(id, SyncDirection::Around) {
+                        let bytes = stamp(bytes);
+                        let mut viewers = BitSet::new();
+                        viewers.add(viewer);
+                        let priority = priority
+                            .get(entities.entity(id))
+                            .cloned()
+                            .unwrap_or_default();
+                        let denied = sync_budget.consume(
+                            frame.frame(),
+                            SyncDirection::Around,
+                            &mut viewers,
+                            priority,
+                            bytes.len(),
+                        );
+                        if !denied.is_empty() {
+                            self.deferred.entry(viewer).or_default().add(id);
+                            continue;
+                        }
+                        sender.send_bytes(viewer_token, bytes);
+                    }
+                }
+            }
+
+            // 还在等待补发的观察者本帧的修改也合并到延后的完整数据中，避免先收到增量
+            for viewer in self.deferred.keys() {
+                over_cap.add(*viewer);
+            }
+
+            // 外圈观察者进入同一格子后补发完整数据
+            let stale: Vec<_> = self.lod_stale.keys().cloned().collect();
+            for id in stale {
+                let viewers = self.lod_stale.get_mut(&id).unwrap();
+                let data = match data.get(entities.entity(id)) {
+                    Some(data) => data,
+                    None => {
+                        self.lod_stale.remove(&id);
+                        continue;
+                    }
+                };
+                let mut promoted = gm.get_user_grid(id);
+                promoted &= &*viewers;
+                if !promoted.is_empty() {
+                    let mut data = data.clone();
+                    data.mask_all(true);
+                    data.commit();
+                    if let Some(bytes) = data.encode(id, SyncDirection::Around) {
+                        sender.sync_bytes(NetToken::tokens(&token, &promoted), stamp(bytes));
+                    }
+                    *viewers &= &!&promoted;
+                }
+                // 离开视野的观察者重新进入时会收到完整数据
+                *viewers &= &gm.get_user_around(id, &visibility);
+                if viewers.is_empty() {
+                    self.lod_stale.remove(&id);
+                }
+            }
+
+            // 移动数据的修改在周围方向的记录中累积，按照固定频率一起同步
+            let mut flushed = BitSet::new();
+            let around_modified = if T::sync_rate() > 0 {
+                self.moving |= &modified;
+                let interval = Duration::from_secs(1) / T::sync_rate();
+                if self.last_flush.elapsed() >= interval {
+                    self.last_flush = Instant::now();
+                    flushed = std::mem::take(&mut self.moving);
+                }
+                &flushed
+            } else {
+                &modified
+            };
+
+            let mut changed: Vec<_> = (&data, around_modified, &entities, !&new_scene_member)
+                .join()
+                .map(|(data, id, entity, _)| {
+                    let priority = priority.get(entity).cloned().unwrap_or_default();
+                    (priority, data, id, entity)
+                })
+                .collect();
+            // 优先级高的实体先消耗预算
+            changed.sort_by(|a, b| b.0.cmp(&a.0));
+            for (priority, data, id, entity) in changed {
                 let data = unsafe { &mut *(data as *const T as *mut T) };
+                let mut around = gm.get_user_around(entity.id(), &visibility);
+                for viewer in (&around & &over_cap).iter() {
+                    self.deferred.entry(viewer).or_default().add(id);
+                }
+                around &= &!&over_cap;
+                if T::has_lod() {
+                    let mut outer = around.clone();
+                    outer &= &!&gm.get_user_grid(id);
+                    if !outer.is_empty() {
+                        if let Some(bytes) = data.encode_lod(id) {
+                            sender.sync_bytes(NetToken::tokens(&token, &outer), stamp(bytes));
+                        }
+                        *self.lod_stale.entry(id).or_default() |= &outer;
+                        around &= &!&outer;
+                    }
+                }
                 if let Some(bytes) = data.encode(id, SyncDirection::Around) {
-                    let around = gm.get_user_around(entity.id());
+                    let bytes = stamp(bytes);
+                    let denied = sync_budget.consume(
+                        frame.frame(),
+                        SyncDirection::Around,
+                        &mut around,
+                        priority,
+                        bytes.len(),
+                    );
+                    for viewer in denied.iter() {
+                        self.deferred.entry(viewer).or_default().add(id);
+                    }
                     let tokens = NetToken::tokens(&token, &around);
-                    sender.broadcast_bytes(tokens, bytes)
+                    if let Some(scene) = gm.get_user_scene(id) {
+                        stats
+                            .entry(scene.id())
+                            .or_default()
+                            .add_broadcast(tokens.len());
+                    }
+                    trace_entity(
+                        id,
+                        "sync",
+                        format_args!(
+                            "{} around size:{} viewers:{}",
+                            std::any::type_name::<T>(),
+                            bytes.len(),
+                            tokens.len()
+                        ),
+                    );
+                    sender.sync_bytes(tokens, bytes)
+                }
+            }
+        }
+
+        metrics.add_sync(stats);
+
+        // 处理针对观察者的数据集
+        if T::is_direction_enabled(SyncDirection::Observer) {
+            for (target, observer) in observers.subscribed_since(self.observer_seen) {
+                if let Some(data) = data.get(entities.entity(target)) {
+                    let mut data = data.clone();
+                    data.mask_all(true);
+                    data.commit();
+                    if let Some(bytes) = data.encode(target, SyncDirection::Observer) {
+                        sender.send_bytes(observer, bytes);
+                    }
+                }
+            }
+            self.observer_seen = observers.sequence();
+            for (data, id) in (&data, &modified).join() {
+                if !observers.is_observed(id) {
+                    continue;
+                }
+                let data = unsafe { &mut *(data as *const T as *mut T) };
+                if let Some(bytes) = data.encode(id, SyncDirection::Observer) {
+                    trace_entity(
+                        id,
+                        "sync",
+                        format_args!(
+                            "{} observer size:{}",
+                            std::any::type_name::<T>(),
+                            bytes.len()
+                        ),
+                    );
+                    sender.sync_bytes(observers.get_observers(id), bytes);
+                }
+            }
+        }
+
+        // 处理针对全服的数据集
+        if T::is_direction_enabled(SyncDirection::World) {
+            // 新进入游戏的玩家补发完整数据
+            let mut joined = token.mask().clone();
+            joined &= &!&self.world_seen;
+            self.world_seen = token.mask().clone();
+            // 新玩家已经收到包含本帧修改的完整数据，修改只发给其他玩家
+            let mut others = None;
+            if !joined.is_empty() {
+                let mut rest = token.mask().clone();
+                rest &= &!&joined;
+                others = Some(NetToken::tokens(&token, &rest));
+                let tokens = NetToken::tokens(&token, &joined);
+                for (data, entity) in (&data, &entities).join() {
+                    let mut data = data.clone();
+                    data.mask_all(true);
+                    data.commit();
+                    if let Some(bytes) = data.encode(entity.id(), SyncDirection::World) {
+                        sender.sync_bytes(tokens.clone(), bytes);
+                    }
+                }
+            }
+            for (data, id) in (&data, &modified).join() {
+                let data = unsafe { &mut *(data as *const T as *mut T) };
+                if let Some(bytes) = data.encode(id, SyncDirection::World) {
+                    trace_entity(
+                        id,
+                        "sync",
+                        format_args!("{} world size:{}", std::any::type_name::<T>(), bytes.len()),
+                    );
+                    match &others {
+                        Some(tokens) => sender.sync_bytes(tokens.clone(), bytes),
+                        None => sender.broadcast_all(bytes),
+                    }
                 }
             }
         }
@@ -334,18 +1516,23 @@ where
     }
 }
 
-pub type TeamSystem = HierarchySystem<TeamMember>;
+pub type GroupSystem<const G: usize> = HierarchySystem<Member<G>>;
+pub type TeamSystem = GroupSystem<0>;
 pub type SceneSystem = HierarchySystem<SceneMember>;
 
-pub struct TeamManagerSystem<B> {
+/// 维护组`Member<G>`的成员变化，每个组一个
+pub struct TeamManagerSystem<B, const G: usize = 0> {
     reader: ReaderId<ComponentEvent>,
     mapping: HashMap<u32, Entity>,
     _phantom: PhantomData<B>,
 }
 
-impl<B> TeamManagerSystem<B> {
+impl<B, const G: usize> TeamManagerSystem<B, G> {
     pub fn new(world: &mut World) -> Self {
-        let mut storage = world.write_storage::<TeamMember>();
+        world
+            .entry::<TeamManager<G>>()
+            .or_insert_with(Default::default);
+        let mut storage = world.write_storage::<Member<G>>();
         let reader = storage.register_reader();
         Self {
             reader,
@@ -355,7 +1542,7 @@ impl<B> TeamManagerSystem<B> {
     }
 }
 
-impl<'a, B> System<'a> for TeamManagerSystem<B>
+impl<'a, B, const G: usize> System<'a> for TeamManagerSystem<B, G>
 where
     B: SceneSyncBackend + Send + Sync + 'static,
     <<B as SceneSyncBackend>::Position as Component>::Storage: Tracked + Default,
@@ -363,14 +1550,20 @@ where
 {
     type SystemData = (
         Entities<'a>,
-        ReadStorage<'a, TeamMember>,
-        ReadExpect<'a, TeamHierarchy>,
-        WriteStorage<'a, TeamFullData>,
+        ReadStorage<'a, Member<G>>,
+        ReadExpect<'a, GroupHierarchy<G>>,
+        WriteStorage<'a, GroupFullData<G>>,
         ReadStorage<'a, NetToken>,
         ReadExpect<'a, BytesSender>,
+        Write<'a, TeamManager<G>>,
+        ReadStorage<'a, RemoteMember>,
+        Read<'a, RelaySender>,
     );
 
-    fn run(&mut self, (entities, tm, th, mut tfd, tokens, sender): Self::SystemData) {
+    fn run(
+        &mut self,
+        (entities, tm, th, mut tfd, tokens, sender, mut manager, remote, relay): Self::SystemData,
+    ) {
         let events = tm.channel().read(&mut self.reader);
         let mut inserted = BitSet::new();
         let mut modified = BitSet::new();
@@ -387,12 +1580,18 @@ where
             }
         }
         for id in removed {
+            // 同一帧内重新加入队伍的成员不清理
+            if tm.get(entities.entity(id)).is_none() {
+                manager.member_removed(&entities, id);
+            }
             if let Some(parent) = self.mapping.remove(&id) {
                 let members = th.all_children(parent);
                 let mut drop_entity = B::DropEntity::default();
                 drop_entity.add_set(&members);
+                let bytes = drop_entity.encode(0);
+                relay_bytes(&remote, &relay, &members, bytes.as_slice());
                 let tokens = NetToken::tokens(&tokens, &members);
-                sender.broadcast_data(tokens, 0, drop_entity);
+                sender.broadcast_bytes(tokens, bytes);
             }
         }
     }
@@ -431,13 +1630,19 @@ where
 {
     type SystemData = (
         Entities<'a>,
-        ReadStorage<'a, B::Position>,
-        ReadStorage<'a, SceneMember>,
+        WriteStorage<'a, B::Position>,
+        WriteStorage<'a, SceneMember>,
         ReadStorage<'a, B::SceneData>,
         WriteExpect<'a, SceneManager<B>>,
         WriteStorage<'a, AroundFullData>,
+        ReadStorage<'a, B::Visibility>,
+        ReadStorage<'a, StaticEntity>,
+        WriteStorage<'a, EnteredAoi>,
+        WriteStorage<'a, LeftAoi>,
         ReadStorage<'a, NetToken>,
         Read<'a, BytesSender>,
+        Write<'a, SpawnBudget>,
+        Write<'a, SceneMetrics>,
     );
 
     fn run(
@@ -449,8 +1654,14 @@ where
             scene_data,
             mut sm,
             new_scene_member,
+            visibility,
+            statics,
+            entered,
+            left,
             tokens,
             sender,
+            mut budget,
+            mut metrics,
         ): Self::SystemData,
     ) {
         //log::info!("GridSystem");
@@ -460,9 +1671,189 @@ where
             scene,
             scene_data,
             new_scene_member,
+            visibility,
+            statics,
+            (entered, left),
             tokens,
             sender,
         );
+        let scenes = sm.scene_entities();
+        metrics.refresh(scenes.clone(), sm.grid_entities());
+        budget.set_scene_entities(scenes);
+    }
+}
+
+/// 移动校验系统，需要在GridSystem之前运行，否则非法的位置会被同步给周围玩家
+pub struct MovementValidatorSystem<B> {
+    reader: ReaderId<ComponentEvent>,
+    /// 每秒允许移动的最大距离
+    max_speed: f32,
+    /// 单次移动超过此距离即视为瞬移
+    teleport_distance: f32,
+    /// 最后一次校验通过的位置以及时间
+    positions: HashMap<u32, (f32, f32, Instant)>,
+    _phantom: PhantomData<B>,
+}
+
+impl<B> MovementValidatorSystem<B>
+where
+    B: SceneSyncBackend + Send + Sync + 'static,
+    <<B as SceneSyncBackend>::Position as Component>::Storage: Tracked + Default,
+    <<B as SceneSyncBackend>::SceneData as Component>::Storage: Tracked + Default,
+{
+    pub fn new(world: &mut World, max_speed: f32, teleport_distance: f32) -> Self {
+        let reader = world.write_storage::<B::Position>().register_reader();
+        Self {
+            reader,
+            max_speed,
+            teleport_distance,
+            positions: Default::default(),
+            _phantom: Default::default(),
+        }
+    }
+
+    fn is_valid(
+        &self,
+        (x, y): (f32, f32),
+        (old_x, old_y, time): (f32, f32, Instant),
+        sd: Option<&B::SceneData>,
+    ) -> bool {
+        if let Some(sd) = sd {
+            if sd.grid_index(x, y).is_none() {
+                return false;
+            }
+        }
+        let distance = ((x - old_x).powi(2) + (y - old_y).powi(2)).sqrt();
+        if distance > self.teleport_distance {
+            return false;
+        }
+        distance <= self.max_speed * time.elapsed().as_secs_f32()
+    }
+}
+
+impl<'a, B> System<'a> for MovementValidatorSystem<B>
+where
+    B: SceneSyncBackend + Send + Sync + 'static,
+    <<B as SceneSyncBackend>::Position as Component>::Storage: Tracked + Default,
+    <<B as SceneSyncBackend>::SceneData as Component>::Storage: Tracked + Default,
+{
+    type SystemData = (
+        Entities<'a>,
+        WriteStorage<'a, B::Position>,
+        ReadStorage<'a, SceneMember>,
+        ReadStorage<'a, B::SceneData>,
+        ReadStorage<'a, NetToken>,
+        WriteStorage<'a, InvalidMove>,
+    );
+
+    fn run(
+        &mut self,
+        (entities, mut positions, scene, scene_data, tokens, mut invalid): Self::SystemData,
+    ) {
+        let mut inserted = BitSet::new();
+        let mut modified = BitSet::new();
+        let mut removed = BitSet::new();
+        let events = positions.channel().read(&mut self.reader);
+        events_to_bitsets(events, &mut inserted, &mut modified, &mut removed);
+
+        for id in &removed {
+            self.positions.remove(&id);
+        }
+
+        let now = Instant::now();
+        for (pos, id) in (&positions, &inserted).join() {
+            self.positions.insert(id, (pos.x(), pos.y(), now));
+        }
+
+        // 只校验客户端驱动的实体，只读遍历，避免给通过校验的位置产生修改事件
+        let mut rejected = Vec::new();
+        for (entity, pos, _, _) in (&entities, &positions, &tokens, &modified).join() {
+            let last = if let Some(last) = self.positions.get(&entity.id()) {
+                *last
+            } else {
+                continue;
+            };
+            let sd = scene
+                .get(entity)
+                .and_then(|member| scene_data.get(member.parent_entity()));
+            if self.is_valid((pos.x(), pos.y()), last, sd) {
+                self.positions.insert(entity.id(), (pos.x(), pos.y(), now));
+            } else {
+                log::warn!(
+                    "entity:{} invalid move from [{}, {}] to [{}, {}]",
+                    entity.id(),
+                    last.0,
+                    last.1,
+                    pos.x(),
+                    pos.y()
+                );
+                rejected.push((entity, last));
+            }
+        }
+        // 只有被拒绝的移动才写回位置
+        for (entity, (x, y, _)) in rejected {
+            if let Some(pos) = positions.get_mut(entity) {
+                pos.set_x(x);
+                pos.set_y(y);
+            }
+            invalid.get_mut_or_default(entity).unwrap().0 += 1;
+        }
+        // 回滚产生的修改事件不需要再次校验
+        positions.channel().read(&mut self.reader).for_each(|_| {});
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        world.register::<InvalidMove>();
+    }
+}
+
+/// 分片处理，用于需要遍历大量实体的系统，单帧超出预算后记录处理位置，下一帧继续
+#[derive(Default)]
+pub struct TimeSlice {
+    budget: Duration,
+    /// 本轮已经处理到的实体id
+    cursor: u32,
+    begin: Option<Instant>,
+    processed: usize,
+}
+
+impl TimeSlice {
+    pub fn new(budget: Duration) -> Self {
+        Self {
+            budget,
+            ..Default::default()
+        }
+    }
+
+    /// 每帧开始遍历之前调用
+    pub fn begin(&mut self) {
+        self.begin = Some(Instant::now());
+        self.processed = 0;
+    }
+
+    /// 本轮已经处理过的实体需要跳过
+    pub fn skip(&self, id: u32) -> bool {
+        id < self.cursor
+    }
+
+    /// 超出预算时记录当前位置并返回true，每帧至少处理一个实体
+    pub fn is_exceeded(&mut self, id: u32) -> bool {
+        let exceeded = self.processed > 0
+            && self
+                .begin
+                .map(|begin| begin.elapsed() > self.budget)
+                .unwrap_or_default();
+        if exceeded {
+            self.cursor = id;
+        } else {
+            self.processed += 1;
+        }
+        exceeded
+    }
+
+    /// 全部实体遍历完成，下一帧重新开始
+    pub fn finish(&mut self) {
+        self.cursor = 0;
     }
 }
 
@@ -484,6 +1875,15 @@ where
     }
 }
 
+/// 空系统，多个输入共用一个执行系统时为其它输入提供`{input}_exec`名称
+pub struct AliasSystem;
+
+impl<'a> System<'a> for AliasSystem {
+    type SystemData = ();
+
+    fn run(&mut self, _: Self::SystemData) {}
+}
+
 pub struct StatisticSystem<T>(pub String, pub T);
 
 impl<'a, T> System<'a> for StatisticSystem<T>
@@ -503,6 +1903,40 @@ where
     }
 }
 
+/// 生成的系统在bench特性下记录每次运行的耗时以及处理的实体数量，由`TimeStatistic`输出，需要同时开启profile
+pub const BENCH: bool = cfg!(feature = "bench");
+
+#[inline]
+pub fn bench_begin() -> Option<Instant> {
+    if BENCH {
+        Some(Instant::now())
+    } else {
+        None
+    }
+}
+
+/// 没有开启profile时没有`TimeStatistic`，返回None不做记录
+pub fn bench_register(world: &World, name: &str) -> Option<Arc<BenchRecord>> {
+    if BENCH {
+        world
+            .try_fetch::<TimeStatistic>()
+            .map(|ts| ts.register_bench(name))
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "bench")]
+pub fn bench_end(record: &Option<Arc<BenchRecord>>, begin: Option<Instant>, entities: usize) {
+    if let (Some(record), Some(begin)) = (record, begin) {
+        record.record(begin.elapsed(), entities);
+    }
+}
+
+#[cfg(not(feature = "bench"))]
+#[inline]
+pub fn bench_end(_record: &Option<Arc<BenchRecord>>, _begin: Option<Instant>, _entities: usize) {}
+
 pub struct StatisticRunNow<T>(pub String, pub T);
 
 impl<'a, T> RunNow<'a> for StatisticRunNow<T>
@@ -525,11 +1959,171 @@ where
 pub struct PrintStatisticSystem;
 
 impl<'a> System<'a> for PrintStatisticSystem {
-    type SystemData = (Read<'a, FrameCounter>, ReadExpect<'a, TimeStatistic>);
+    type SystemData = (
+        Read<'a, FrameCounter>,
+        ReadExpect<'a, TimeStatistic>,
+        Read<'a, WorldStats>,
+        Read<'a, FrameBudget>,
+        Read<'a, NetworkMetrics>,
+        Read<'a, SceneMetrics>,
+    );
 
-    fn run(&mut self, (frame, data): Self::SystemData) {
+    fn run(&mut self, (frame, data, stats, budget, metrics, scene_metrics): Self::SystemData) {
         data.print(frame.frame(), frame.fps());
         data.clear();
+        stats.print();
+        budget.print();
+        metrics.print();
+        scene_metrics.print();
+        scene_metrics.clear_sync();
+    }
+}
+
+/// 阶段划分点，前后各有一个屏障，开始新阶段的计时
+pub struct StageSystem(pub String);
+
+impl<'a> System<'a> for StageSystem {
+    type SystemData = Write<'a, FrameBudget>;
+
+    fn run(&mut self, mut budget: Self::SystemData) {
+        budget.begin_stage(&self.0);
+    }
+}
+
+struct StorageCompactor {
+    name: String,
+    size: usize,
+    /// 上次整理后的最大数量
+    peak: usize,
+    count: Box<dyn Fn(&World) -> usize>,
+    /// 重建存储，返回整理后的组件数量
+    compact: Box<dyn Fn(&World) -> usize>,
+}
+
+/// 定期重建注册的组件存储以释放大量实体销毁后残留的内存，只在连接数较少时进行，
+/// 需要以thread local方式添加。Tracked存储的组件需要通过`register_tracked`注册以保留事件通道
+pub struct CompactStorageSystem {
+    interval: Duration,
+    last: Instant,
+    /// 连接数不超过此值时才进行整理，0表示不限制
+    max_connections: usize,
+    compactors: Vec<StorageCompactor>,
+}
+
+impl CompactStorageSystem {
+    pub fn new(interval: Duration, max_connections: usize) -> Self {
+        Self {
+            interval,
+            last: Instant::now(),
+            max_connections,
+            compactors: Vec::new(),
+        }
+    }
+
+    /// 注册普通存储的组件，重建时会替换整个存储，Tracked存储的组件使用`register_tracked`
+    pub fn register<T>(self) -> Self
+    where
+        T: Component,
+        T::Storage: Default,
+    {
+        let compact = Box::new(|world: &World| {
+            let components: Vec<(Entity, T)> = {
+                let entities = world.entities();
+                let mut storage = world.write_storage::<T>();
+                (&entities, storage.drain()).join().collect()
+            };
+            *world.write_resource::<MaskedStorage<T>>() = MaskedStorage::new(Default::default());
+            let count = components.len();
+            let mut storage = world.write_storage::<T>();
+            for (entity, component) in components {
+                if let Err(err) = storage.insert(entity, component) {
+                    log::error!("insert component failed:{}", err);
+                }
+            }
+            count
+        });
+        self.push::<T>(compact)
+    }
+
+    /// 注册Tracked存储的组件，重建前先取出原来的事件通道，重建完成后放回新存储，
+    /// 已经注册的ReaderId继续有效，也不会收到重建产生的删除和插入事件
+    pub fn register_tracked<T>(self) -> Self
+    where
+        T: Component,
+        T::Storage: Tracked + Default,
+    {
+        let compact = Box::new(|world: &World| {
+            let (components, channel) = {
+                let entities = world.entities();
+                let mut storage = world.write_storage::<T>();
+                let channel = std::mem::take(storage.channel_mut());
+                let components: Vec<(Entity, T)> = (&entities, storage.drain()).join().collect();
+                (components, channel)
+            };
+            *world.write_resource::<MaskedStorage<T>>() = MaskedStorage::new(Default::default());
+            let count = components.len();
+            let mut storage = world.write_storage::<T>();
+            for (entity, component) in components {
+                if let Err(err) = storage.insert(entity, component) {
+                    log::error!("insert component failed:{}", err);
+                }
+            }
+            *storage.channel_mut() = channel;
+            count
+        });
+        self.push::<T>(compact)
+    }
+
+    fn push<T: Component>(mut self, compact: Box<dyn Fn(&World) -> usize>) -> Self {
+        self.compactors.push(StorageCompactor {
+            name: std::any::type_name::<T>().to_string(),
+            size: std::mem::size_of::<T>(),
+            peak: 0,
+            count: Box::new(|world| world.read_storage::<T>().join().count()),
+            compact,
+        });
+        self
+    }
+}
+
+impl<'a> RunNow<'a> for CompactStorageSystem {
+    fn run_now(&mut self, world: &'a World) {
+        if self.last.elapsed() < self.interval {
+            return;
+        }
+        self.last = Instant::now();
+        if self.max_connections != 0
+            && world.read_storage::<NetToken>().join().count() > self.max_connections
+        {
+            return;
+        }
+        let mut reclaimed = 0;
+        for compactor in &mut self.compactors {
+            let count = (compactor.count)(world);
+            // 数量降到峰值的一半以下时才重建
+            if compactor.peak == 0 || count * 2 > compactor.peak {
+                compactor.peak = compactor.peak.max(count);
+                continue;
+            }
+            let count = (compactor.compact)(world);
+            reclaimed += (compactor.peak - count) * compactor.size;
+            log::info!(
+                "component {} compacted, count:{}, peak:{}",
+                compactor.name,
+                count,
+                compactor.peak
+            );
+            compactor.peak = count;
+        }
+        if reclaimed > 0 {
+            world
+                .write_resource::<WorldStats>()
+                .add_reclaimed(reclaimed);
+        }
+    }
+
+    fn setup(&mut self, world: &mut World) {
+        world.entry::<WorldStats>().or_insert_with(Default::default);
     }
 }
 
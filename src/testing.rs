@@ -0,0 +1,611 @@
+use crate::{
+    backend::Input,
+    network::{channel, BytesSender, Connected, NetworkOutputData, RequestIdent, Response},
+};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::Bytes;
+use crossbeam::channel::{Receiver, Sender};
+use mio::Token;
+use specs::Entity;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+/// 故障注入配置，时间单位为`FakeNetwork::step`的步数
+#[derive(Clone, Debug)]
+pub struct FaultInjection {
+    latency: usize,
+    jitter: usize,
+    drop_rate: f64,
+    seed: u64,
+}
+
+impl Default for FaultInjection {
+    fn default() -> Self {
+        Self {
+            latency: 0,
+            jitter: 0,
+            drop_rate: 0.0,
+            seed: 0x2545_f491_4f6c_dd1d,
+        }
+    }
+}
+
+impl FaultInjection {
+    /// 固定延迟
+    pub fn with_latency(mut self, latency: usize) -> Self {
+        self.latency = latency;
+        self
+    }
+
+    /// 在固定延迟上随机增加0到jitter步
+    pub fn with_jitter(mut self, jitter: usize) -> Self {
+        self.jitter = jitter;
+        self
+    }
+
+    /// 数据包的丢弃概率，取值0到1，只影响请求和数据响应，连接事件不会丢失
+    pub fn with_drop_rate(mut self, drop_rate: f64) -> Self {
+        self.drop_rate = drop_rate;
+        self
+    }
+
+    /// 随机数种子，相同的种子得到相同的延迟和丢包序列
+    pub fn with_seed(mut self, seed: u64) -> Self {
+        self.seed = seed.max(1);
+        self
+    }
+}
+
+/// xorshift随机数，保证测试可以复现
+struct Random(u64);
+
+impl Random {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn chance(&mut self, rate: f64) -> bool {
+        rate > 0.0 && ((self.next() >> 11) as f64 / (1u64 << 53) as f64) < rate
+    }
+}
+
+#[derive(Default)]
+struct FakeClient {
+    entity: Option<Entity>,
+    /// 已经送达客户端的响应
+    received: Vec<Vec<u8>>,
+    closed: bool,
+}
+
+enum Packet {
+    Request(Token, Bytes),
+    Response(Token, Vec<u8>),
+}
+
+/// 进程内的假网络，代替`async_run`的网络线程和解码线程，不打开任何socket。
+/// 请求直接调用`Input::dispatch`，响应通过`BytesSender`的通道取回，
+/// 每次`step`推进一步，按故障注入配置延迟或丢弃数据包，可以在测试中确定性地驱动ECS
+pub struct FakeNetwork<T> {
+    t: T,
+    config: FaultInjection,
+    random: Random,
+    connected: Sender<Connected>,
+    receiver: Receiver<NetworkOutputData>,
+    sender: BytesSender,
+    next_receiver: Receiver<Vec<Entity>>,
+    clients: HashMap<Token, FakeClient>,
+    next_token: usize,
+    /// 送达步数 -> 数据包
+    pending: BTreeMap<usize, VecDeque<Packet>>,
+    now: usize,
+}
+
+impl<T> FakeNetwork<T>
+where
+    T: Input,
+{
+    /// `connected`为`HandshakeSystem`的发送端，返回的`BytesSender`需要作为资源插入World
+    pub fn new(t: T, connected: Sender<Connected>, config: FaultInjection) -> (Self, BytesSender) {
+        let (sender, receiver) = channel(0);
+        let next_receiver = t.next_receiver();
+        let sender = BytesSender::detached(sender);
+        let network = Self {
+            t,
+            random: Random(config.seed),
+            config,
+            connected,
+            receiver,
+            sender: sender.clone(),
+            next_receiver,
+            clients: HashMap::new(),
+            next_token: 0,
+            pending: BTreeMap::new(),
+            now: 0,
+        };
+        (network, sender)
+    }
+
+    /// 建立新连接，与真实网络一样立即通知`HandshakeSystem`
+    pub fn connect(&mut self, listener: usize) -> Token {
+        let token = Token(self.next_token);
+        self.next_token += 1;
+        self.clients.insert(token, FakeClient::default());
+        let connected = Connected {
+            token,
+            listener,
+            closed: false,
+        };
+        if let Err(err) = self.connected.send(connected) {
+            log::error!("send connected to ecs failed:{}", err);
+        }
+        token
+    }
+
+    /// 客户端发送请求，data为 cmd(4) | body
+    pub fn send(&mut self, token: Token, cmd: u32, body: &[u8]) {
+        let mut data = vec![0u8; 4];
+        BigEndian::write_u32(&mut data, cmd);
+        data.extend_from_slice(body);
+        self.schedule(Packet::Request(token, Bytes::from(data)));
+    }
+
+    /// 客户端断开连接
+    pub fn disconnect(&mut self, token: Token) {
+        if let Some(client) = self.clients.get_mut(&token) {
+            Self::close(&mut self.t, &self.connected, token, client);
+        }
+    }
+
+    /// 连接对应的实体，实体建立之前为None
+    pub fn entity(&self, token: Token) -> Option<Entity> {
+        self.clients.get(&token).and_then(|client| client.entity)
+    }
+
+    /// 连接是否已经被关闭
+    pub fn is_closed(&self, token: Token) -> bool {
+        self.clients
+            .get(&token)
+            .map_or(true, |client| client.closed)
+    }
+
+    /// 取出已经送达客户端的响应帧
+    pub fn received(&mut self, token: Token) -> Vec<Vec<u8>> {
+        self.clients
+            .get_mut(&token)
+            .map(|client| std::mem::take(&mut client.received))
+            .unwrap_or_default()
+    }
+
+    /// 推进一步：处理ECS的响应，再送达到期的数据包，每帧dispatch之后调用一次
+    pub fn step(&mut self) {
+        // 与真实网络一样每帧flush一次，清空响应去重记录
+        self.sender.flush();
+        while let Ok(entities) = self.next_receiver.try_recv() {
+            entities
+                .into_iter()
+                .for_each(|entity| self.t.do_next(entity));
+        }
+        while let Ok((tokens, response)) = self.receiver.try_recv() {
+            let tokens = if let Response::DataAll(_) = response {
+                self.clients
+                    .iter()
+                    .filter(|(_, client)| client.entity.is_some() && !client.closed)
+                    .map(|(token, _)| *token)
+                    .collect()
+            } else {
+                tokens
+            };
+            for token in tokens {
+                self.respond(token, &response);
+            }
+        }
+        let due: Vec<_> = self.pending.range(..=self.now).map(|(at, _)| *at).collect();
+        for at in due {
+            for packet in self.pending.remove(&at).unwrap() {
+                self.deliver(packet);
+            }
+        }
+        self.now += 1;
+    }
+
+    fn schedule(&mut self, packet: Packet) {
+        if self.random.chance(self.config.drop_rate) {
+            log::debug!("packet dropped by fault injection");
+            return;
+        }
+        let jitter = if self.config.jitter > 0 {
+            self.random.next() as usize % (self.config.jitter + 1)
+        } else {
+            0
+        };
+        let at = self.now + self.config.latency + jitter;
+        self.pending.entry(at).or_default().push_back(packet);
+    }
+
+    fn respond(&mut self, token: Token, response: &Response) {
+        if !self.clients.contains_key(&token) {
+            log::error!("fake connection:{} not found", token.0);
+            return;
+        }
+        if let Response::Data(data) | Response::DataAll(data) = response {
+            self.schedule(Packet::Response(token, data.to_vec()));
+            return;
+        }
+        let client = self.clients.get_mut(&token).unwrap();
+        match response {
+            Response::Entity(entity) => {
+                client.entity = Some(*entity);
+                // 实体建立之前已经断开的连接，此时再通知ECS关闭
+                if client.closed {
+                    self.t.dispatch(RequestIdent::Close(*entity), Bytes::new());
+                }
+            }
+            Response::Close(true) => {
+                self.clients.remove(&token);
+            }
+            Response::Close(false) => Self::close(&mut self.t, &self.connected, token, client),
+            Response::Data(_) | Response::DataAll(_) | Response::Shutdown => {}
+        }
+    }
+
+    fn deliver(&mut self, packet: Packet) {
+        match packet {
+            Packet::Request(token, data) => match self.clients.get(&token) {
+                Some(FakeClient {
+                    entity: Some(entity),
+                    closed: false,
+                    ..
+                }) => self.t.dispatch(RequestIdent::Entity(*entity), data),
+                _ => log::error!("[{}]request before entity created, dropped", token.0),
+            },
+            Packet::Response(token, data) => {
+                if let Some(client) = self.clients.get_mut(&token) {
+                    if !client.closed {
+                        client.received.push(data);
+                    }
+                }
+            }
+        }
+    }
+
+    /// 与真实连接一样，实体建立之后通知ECS关闭，排队中的连接通知`HandshakeSystem`移出队列
+    fn close(t: &mut T, connected: &Sender<Connected>, token: Token, client: &mut FakeClient) {
+        if client.closed {
+            return;
+        }
+        client.closed = true;
+        if let Some(entity) = client.entity {
+            t.dispatch(RequestIdent::Close(entity), Bytes::new());
+        } else {
+            log::debug!("[{}]fake connection closed before entity created", token.0);
+            let closed = Connected {
+                token,
+                listener: 0,
+                closed: true,
+            };
+            if let Err(err) = connected.send(closed) {
+                log::error!("send closed to ecs failed:{}", err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        CloseSystem, Closing, Disconnected, HandshakeSystem, Kicked, LoginQueue, NetToken,
+        ReconnectEvent, SessionKey, SessionSystem, LOGIN_QUEUE_CMD, RESUME_CMD, SESSION_CMD,
+    };
+    use specs::{RunNow, System, World, WorldExt};
+    use std::time::Duration;
+
+    /// 请求原样转发给测试，由测试决定交给哪个系统
+    struct TestInput {
+        sender: Sender<(RequestIdent, Bytes)>,
+        next: (Sender<Vec<Entity>>, Receiver<Vec<Entity>>),
+    }
+
+    impl Input for TestInput {
+        fn dispatch(&mut self, ident: RequestIdent, data: Bytes) {
+            self.sender.send((ident, data)).unwrap();
+        }
+
+        fn next_receiver(&self) -> Receiver<Vec<Entity>> {
+            self.next.1.clone()
+        }
+
+        fn do_next(&mut self, _entity: Entity) {}
+    }
+
+    /// 只包含连接相关系统的ECS
+    struct Harness {
+        world: World,
+        network: FakeNetwork<TestInput>,
+        requests: Receiver<(RequestIdent, Bytes)>,
+        resume: Sender<(Entity, Bytes)>,
+        handshake: HandshakeSystem,
+        session: SessionSystem,
+        close: CloseSystem,
+        /// 本帧送达的普通请求
+        received: Vec<(Entity, Bytes)>,
+    }
+
+    impl Harness {
+        fn new(max_players: usize, config: FaultInjection) -> Self {
+            let (sender, requests) = crossbeam::channel::unbounded();
+            let (connected, receiver) = crossbeam::channel::unbounded();
+            let input = TestInput {
+                sender,
+                next: crossbeam::channel::unbounded(),
+            };
+            let (network, bytes_sender) = FakeNetwork::new(input, connected, config);
+            let (resume, resume_receiver) = crossbeam::channel::unbounded();
+            let mut harness = Self {
+                world: World::new(),
+                network,
+                requests,
+                resume,
+                handshake: HandshakeSystem::new(receiver),
+                session: SessionSystem::new(resume_receiver, Duration::from_secs(60)),
+                close: CloseSystem,
+                received: Vec::new(),
+            };
+            harness.world.insert(bytes_sender);
+            harness.world.insert(LoginQueue::new(max_players));
+            System::setup(&mut harness.handshake, &mut harness.world);
+            System::setup(&mut harness.session, &mut harness.world);
+            System::setup(&mut harness.close, &mut harness.world);
+            harness
+        }
+
+        /// 推进一帧，与真实网络一样重连请求交给SessionSystem，关闭通知插入Closing
+        fn frame(&mut self) {
+            self.network.step();
+            for (ident, data) in self.requests.try_iter() {
+                match ident {
+                    RequestIdent::Close(entity) => {
+                        if let Err(err) = self
+                            .world
+                            .write_storage::<Closing>()
+                            .insert(entity, Closing(true))
+                        {
+                            log::error!("insert Closing failed:{}", err);
+                        }
+                    }
+                    RequestIdent::Entity(entity) => {
+                        if BigEndian::read_u32(&data) == RESUME_CMD {
+                            self.resume.send((entity, data)).unwrap();
+                        } else {
+                            self.received.push((entity, data));
+                        }
+                    }
+                }
+            }
+            self.handshake.run_now(&self.world);
+            self.session.run_now(&self.world);
+            self.close.run_now(&self.world);
+            self.world.maintain();
+        }
+
+        fn frames(&mut self, count: usize) {
+            (0..count).for_each(|_| self.frame());
+        }
+
+        /// 建立连接并等待实体建立，丢弃建立过程中收到的响应
+        fn login(&mut self) -> (Token, Entity) {
+            let token = self.network.connect(0);
+            let entity = (0..16)
+                .find_map(|_| {
+                    self.frame();
+                    self.network.entity(token)
+                })
+                .expect("entity not created");
+            self.network.received(token);
+            (token, entity)
+        }
+
+        fn sender(&self) -> BytesSender {
+            BytesSender::clone(&self.world.read_resource())
+        }
+    }
+
+    #[test]
+    fn latency_delays_delivery() {
+        let mut harness = Harness::new(0, FaultInjection::default().with_latency(2));
+        let (token, entity) = harness.login();
+
+        harness.network.send(token, 100, &[1, 2, 3, 4]);
+        harness.frames(2);
+        assert!(harness.received.is_empty());
+        harness.frames(1);
+        assert_eq!(harness.received.len(), 1);
+        assert_eq!(harness.received[0].0, entity);
+
+        // 丢弃登录时延迟送达的会话密钥
+        harness.network.received(token);
+        harness.sender().send_bytes(token, vec![1, 2, 3, 4]);
+        harness.frames(2);
+        assert!(harness.network.received(token).is_empty());
+        harness.frames(1);
+        assert_eq!(harness.network.received(token), vec![vec![1, 2, 3, 4]]);
+    }
+
+    #[test]
+    fn drop_rate_replays_with_seed() {
+        let delivered = |seed| {
+            let config = FaultInjection::default()
+                .with_drop_rate(0.5)
+                .with_seed(seed);
+            let mut harness = Harness::new(0, config);
+            let (token, _) = harness.login();
+            for cmd in 100..132 {
+                harness.network.send(token, cmd, &[]);
+            }
+            harness.frames(1);
+            harness
+                .received
+                .iter()
+                .map(|(_, data)| BigEndian::read_u32(data))
+                .collect::<Vec<_>>()
+        };
+        let first = delivered(7);
+        assert!(!first.is_empty() && first.len() < 32);
+        assert_eq!(first, delivered(7));
+    }
+
+    /// 控制帧 length(4) | 0(4) | cmd(4) | value(4)
+    fn control(frames: &[Vec<u8>], cmd: u32) -> Option<u32> {
+        frames
+            .iter()
+            .find(|frame| frame.len() == 16 && BigEndian::read_u32(&frame[8..]) == cmd)
+            .map(|frame| BigEndian::read_u32(&frame[12..]))
+    }
+
+    #[test]
+    fn resume_rebinds_entity() {
+        let mut harness = Harness::new(0, FaultInjection::default());
+        let token = harness.network.connect(0);
+        harness.frames(2);
+        let entity = harness.network.entity(token).unwrap();
+        let session = harness
+            .network
+            .received(token)
+            .into_iter()
+            .find(|frame| frame.len() == 24 && BigEndian::read_u32(&frame[8..]) == SESSION_CMD)
+            .expect("session key not sent");
+        assert_eq!(BigEndian::read_u32(&session[12..]), entity.id());
+
+        harness.network.disconnect(token);
+        harness.frames(1);
+        assert!(harness.world.is_alive(entity));
+        assert!(harness
+            .world
+            .read_storage::<Disconnected>()
+            .contains(entity));
+        assert!(!harness.world.read_storage::<NetToken>().contains(entity));
+
+        let (token, created) = harness.login();
+        // 密钥错误时拒绝恢复，实体仍然保留
+        let mut wrong = session[12..].to_vec();
+        wrong[11] ^= 1;
+        harness.network.send(token, RESUME_CMD, &wrong);
+        harness.frames(2);
+        assert_eq!(
+            control(&harness.network.received(token), RESUME_CMD),
+            Some(0)
+        );
+        assert!(harness
+            .world
+            .read_storage::<Disconnected>()
+            .contains(entity));
+
+        harness.network.send(token, RESUME_CMD, &session[12..]);
+        harness.frames(1);
+        assert!(harness
+            .world
+            .read_storage::<ReconnectEvent>()
+            .contains(entity));
+        assert!(!harness
+            .world
+            .read_storage::<Disconnected>()
+            .contains(entity));
+        assert!(!harness.world.is_alive(created));
+
+        harness.frames(1);
+        assert_eq!(harness.network.entity(token), Some(entity));
+        assert_eq!(
+            control(&harness.network.received(token), RESUME_CMD),
+            Some(1)
+        );
+    }
+
+    #[test]
+    fn kicked_entity_not_kept() {
+        let mut harness = Harness::new(0, FaultInjection::default());
+        let (token, entity) = harness.login();
+        assert!(harness.world.read_storage::<SessionKey>().contains(entity));
+
+        harness
+            .world
+            .write_storage::<Closing>()
+            .insert(entity, Closing(false))
+            .unwrap();
+        harness.frames(1);
+        assert!(harness.world.read_storage::<Kicked>().contains(entity));
+
+        harness.frames(2);
+        assert!(harness.network.is_closed(token));
+        assert!(!harness.world.is_alive(entity));
+    }
+
+    #[test]
+    fn login_queue() {
+        let mut harness = Harness::new(1, FaultInjection::default());
+        let (first, entity) = harness.login();
+        let second = harness.network.connect(0);
+        let third = harness.network.connect(0);
+        harness.frames(2);
+        assert_eq!(harness.network.entity(second), None);
+        assert_eq!(
+            control(&harness.network.received(second), LOGIN_QUEUE_CMD),
+            Some(1)
+        );
+        assert_eq!(
+            control(&harness.network.received(third), LOGIN_QUEUE_CMD),
+            Some(2)
+        );
+
+        // 排队中断开的连接移出队列
+        harness.network.disconnect(third);
+        harness.frames(2);
+        assert_eq!(harness.world.read_resource::<LoginQueue>().len(), 1);
+
+        // 在线玩家下线后放行排队的连接
+        harness.network.disconnect(first);
+        harness.frames(3);
+        assert!(!harness.world.read_storage::<NetToken>().contains(entity));
+        assert!(harness.network.entity(second).is_some());
+        assert!(harness.world.read_resource::<LoginQueue>().is_empty());
+    }
+
+    #[test]
+    fn sync_bytes_dedupe() {
+        let mut harness = Harness::new(0, FaultInjection::default());
+        let (first, _) = harness.login();
+        let (second, _) = harness.login();
+        let sender = harness.sender();
+
+        sender.sync_bytes(vec![first, second], vec![1, 2, 3, 4]);
+        sender.sync_bytes(vec![second], vec![1, 2, 3, 4]);
+        sender.broadcast_bytes(vec![first], vec![1, 2, 3, 4]);
+        harness.network.step();
+        assert_eq!(harness.network.received(first).len(), 2);
+        assert_eq!(harness.network.received(second).len(), 1);
+
+        // 去重只在同一帧内有效
+        sender.sync_bytes(vec![second], vec![1, 2, 3, 4]);
+        harness.network.step();
+        assert_eq!(harness.network.received(second).len(), 1);
+    }
+
+    #[test]
+    fn broadcast_all_entered() {
+        let mut harness = Harness::new(2, FaultInjection::default());
+        let (first, _) = harness.login();
+        let (closed, _) = harness.login();
+        let queued = harness.network.connect(0);
+        harness.frames(2);
+        assert_eq!(harness.network.entity(queued), None);
+        harness.network.received(queued);
+        harness.network.disconnect(closed);
+
+        harness.sender().broadcast_all(vec![1, 2, 3, 4]);
+        harness.network.step();
+        assert_eq!(harness.network.received(first), vec![vec![1, 2, 3, 4]]);
+        assert!(harness.network.received(closed).is_empty());
+        assert!(harness.network.received(queued).is_empty());
+    }
+}
@@ -0,0 +1,249 @@
+use specs::{Component, Entity, LazyUpdate, World, WorldExt};
+use std::{any::TypeId, collections::HashSet};
+
+type Check = Box<dyn FnOnce(&World) -> Result<(), String> + Send + Sync>;
+type Operation = Box<dyn FnOnce(&mut World) + Send + Sync>;
+
+/// 跨组件的事务操作，在`world.maintain`时统一执行，所有校验通过才会修改组件，否则全部放弃。
+/// 校验和执行在同一次独占World的调用中完成，其他系统看不到执行到一半的状态。
+/// 同一事务中删除组件之后不能再插入或修改该组件，否则整个事务被放弃，
+/// 因此校验通过后的操作都可以执行成功
+pub struct Transaction {
+    name: String,
+    entities: Vec<Entity>,
+    checks: Vec<Check>,
+    operations: Vec<Operation>,
+    /// 已经暂存删除的组件
+    removed: HashSet<(Entity, TypeId)>,
+    /// 暂存时发现的冲突操作
+    conflict: Option<String>,
+}
+
+impl Transaction {
+    pub fn new(name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            entities: Vec::new(),
+            checks: Vec::new(),
+            operations: Vec::new(),
+            removed: HashSet::new(),
+            conflict: None,
+        }
+    }
+
+    /// 组件已经被删除时记录冲突
+    fn check_removed<C: Component>(&mut self, entity: Entity, operation: &str) {
+        if self.conflict.is_none() && self.removed.contains(&(entity, TypeId::of::<C>())) {
+            self.conflict = Some(format!(
+                "{} {} of entity:{} after remove",
+                operation,
+                std::any::type_name::<C>(),
+                entity.id()
+            ));
+        }
+    }
+
+    /// 添加校验，返回Err时整个事务被放弃
+    pub fn validate<F>(&mut self, f: F) -> &mut Self
+    where
+        F: FnOnce(&World) -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.checks.push(Box::new(f));
+        self
+    }
+
+    pub fn insert<C>(&mut self, entity: Entity, component: C) -> &mut Self
+    where
+        C: Component + Send + Sync,
+    {
+        self.check_removed::<C>(entity, "insert");
+        self.entities.push(entity);
+        self.operations.push(Box::new(move |world| {
+            if let Err(err) = world.write_storage::<C>().insert(entity, component) {
+                log::error!("insert component failed:{}", err);
+            }
+        }));
+        self
+    }
+
+    pub fn remove<C>(&mut self, entity: Entity) -> &mut Self
+    where
+        C: Component + Send + Sync,
+    {
+        self.entities.push(entity);
+        self.removed.insert((entity, TypeId::of::<C>()));
+        self.operations.push(Box::new(move |world| {
+            world.write_storage::<C>().remove(entity);
+        }));
+        self
+    }
+
+    /// 修改已有的组件，组件不存在时事务被放弃
+    pub fn update<C, F>(&mut self, entity: Entity, f: F) -> &mut Self
+    where
+        C: Component + Send + Sync,
+        F: FnOnce(&mut C) + Send + Sync + 'static,
+    {
+        self.check_removed::<C>(entity, "update");
+        self.entities.push(entity);
+        self.checks.push(Box::new(move |world| {
+            if world.read_storage::<C>().contains(entity) {
+                Ok(())
+            } else {
+                Err(format!(
+                    "entity:{} has no {}",
+                    entity.id(),
+                    std::any::type_name::<C>()
+                ))
+            }
+        }));
+        self.operations.push(Box::new(move |world| {
+            match world.write_storage::<C>().get_mut(entity) {
+                Some(c) => f(c),
+                None => log::error!(
+                    "entity:{} lost {} during transaction",
+                    entity.id(),
+                    std::any::type_name::<C>()
+                ),
+            }
+        }));
+        self
+    }
+
+    /// 提交到LazyUpdate，在`world.maintain`时执行
+    pub fn commit(self, lazy_update: &LazyUpdate) {
+        lazy_update.exec_mut(move |world| {
+            self.apply(world);
+        });
+    }
+
+    /// 立即执行，返回事务是否执行
+    pub fn apply(self, world: &mut World) -> bool {
+        let Transaction {
+            name,
+            entities,
+            checks,
+            operations,
+            conflict,
+            ..
+        } = self;
+        if let Some(conflict) = conflict {
+            log::warn!("transaction {} aborted:{}", name, conflict);
+            return false;
+        }
+        if let Some(entity) = entities
+            .iter()
+            .find(|entity| !world.entities().is_alive(**entity))
+        {
+            log::warn!(
+                "transaction {} aborted, entity:{} is dead",
+                name,
+                entity.id()
+            );
+            return false;
+        }
+        for check in checks {
+            if let Err(err) = check(world) {
+                log::warn!("transaction {} aborted:{}", name, err);
+                return false;
+            }
+        }
+        for operation in operations {
+            operation(world);
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::{Builder, VecStorage};
+
+    #[derive(Debug, PartialEq)]
+    struct Hp(u32);
+
+    impl Component for Hp {
+        type Storage = VecStorage<Self>;
+    }
+
+    #[derive(Debug, PartialEq)]
+    struct Mp(u32);
+
+    impl Component for Mp {
+        type Storage = VecStorage<Self>;
+    }
+
+    fn setup() -> (World, Entity) {
+        let mut world = World::new();
+        world.register::<Hp>();
+        world.register::<Mp>();
+        let entity = world.create_entity().with(Hp(10)).build();
+        (world, entity)
+    }
+
+    #[test]
+    fn apply_all() {
+        let (mut world, entity) = setup();
+        let mut transaction = Transaction::new("all");
+        transaction
+            .update::<Hp, _>(entity, |hp| hp.0 -= 5)
+            .insert(entity, Mp(3));
+        assert!(transaction.apply(&mut world));
+        assert_eq!(world.read_storage::<Hp>().get(entity), Some(&Hp(5)));
+        assert_eq!(world.read_storage::<Mp>().get(entity), Some(&Mp(3)));
+    }
+
+    #[test]
+    fn validation_failed() {
+        let (mut world, entity) = setup();
+        let mut transaction = Transaction::new("validate");
+        transaction
+            .update::<Hp, _>(entity, |hp| hp.0 -= 5)
+            .update::<Mp, _>(entity, |mp| mp.0 -= 1);
+        assert!(!transaction.apply(&mut world));
+        assert_eq!(world.read_storage::<Hp>().get(entity), Some(&Hp(10)));
+
+        let mut transaction = Transaction::new("check");
+        transaction
+            .validate(|_| Err("not enough".into()))
+            .insert(entity, Mp(3));
+        assert!(!transaction.apply(&mut world));
+        assert!(!world.read_storage::<Mp>().contains(entity));
+    }
+
+    #[test]
+    fn update_after_remove() {
+        let (mut world, entity) = setup();
+        let mut transaction = Transaction::new("remove");
+        transaction
+            .insert(entity, Mp(3))
+            .remove::<Hp>(entity)
+            .update::<Hp, _>(entity, |hp| hp.0 -= 5);
+        assert!(!transaction.apply(&mut world));
+        assert_eq!(world.read_storage::<Hp>().get(entity), Some(&Hp(10)));
+        assert!(!world.read_storage::<Mp>().contains(entity));
+    }
+
+    #[test]
+    fn dead_entity() {
+        let (mut world, entity) = setup();
+        let other = world.create_entity().build();
+        world.delete_entity(other).unwrap();
+        let mut transaction = Transaction::new("dead");
+        transaction.insert(entity, Mp(3)).insert(other, Mp(3));
+        assert!(!transaction.apply(&mut world));
+        assert!(!world.read_storage::<Mp>().contains(entity));
+    }
+
+    #[test]
+    fn commit_on_maintain() {
+        let (mut world, entity) = setup();
+        let mut transaction = Transaction::new("commit");
+        transaction.update::<Hp, _>(entity, |hp| hp.0 += 1);
+        transaction.commit(&world.read_resource::<LazyUpdate>());
+        assert_eq!(world.read_storage::<Hp>().get(entity), Some(&Hp(10)));
+        world.maintain();
+        assert_eq!(world.read_storage::<Hp>().get(entity), Some(&Hp(11)));
+    }
+}